@@ -1,3 +1,7 @@
 fn main() {
-    prost_build::compile_protos(&["src/messages.proto", "src/list.proto"], &["src/"]).unwrap();
+    prost_build::compile_protos(
+        &["src/messages.proto", "src/list.proto", "src/raft.proto"],
+        &["src/"],
+    )
+    .unwrap();
 }
@@ -1,18 +1,33 @@
 #!/usr/bin/env python3
 import sys
 
-# Pass the type X as a command-line argument
-type_x = sys.argv[1]
+import sdfs_keys
 
-for line in sys.stdin:
-    line = line.strip()
-    parts = line.split(',')
+def map_detections(input_file, output_prefix, type_x):
+    # Open the input file
+    with open('/home/sdfs/mrin/' + input_file, mode='r', errors='replace') as file:
+        for line in file:
+            line = line.strip()
+            parts = line.split(',')
 
-    # Check if the line is a header or if it has enough parts
-    if len(parts) > 10 and parts[0] != 'X':
-        interconne = parts[10]
-        detection = parts[9]
+            # Check if the line is a header or if it has enough parts
+            if len(parts) > 10 and parts[0] != 'X':
+                interconne = parts[10]
+                detection = parts[9]
 
-        # Output the line if Interconne matches type X
-        if interconne == type_x:
-            print(f'{detection}\t1')
\ No newline at end of file
+                # Output the line if Interconne matches type X
+                if interconne == type_x:
+                    with open(sdfs_keys.output_path(output_prefix, detection), 'a') as out_file:
+                        out_file.write(f'{detection}\t1\n')
+
+if __name__ == "__main__":
+    if len(sys.argv) < 4:
+        print("Usage: python map.py <input_file> <output_prefix> <type_x>")
+        sys.exit(1)
+
+    input_file = sys.argv[1]
+    output_prefix = sys.argv[2]
+    # Pass the type X as a command-line argument
+    type_x = sys.argv[3]
+
+    map_detections(input_file, output_prefix, type_x)
@@ -1,29 +1,31 @@
 #!/usr/bin/env python3
 import sys
 
-current_detection = None
-current_count = 0
-detection_count = {}
+import sdfs_keys
 
-for line in sys.stdin:
-    line = line.strip()
-    detection, count = line.split('\t', 1)
-    count = int(count)
+def reduce_detections(input_prefix, destination_file):
+    detection_count = {}
 
-    if current_detection == detection:
-        current_count += count
-    else:
-        if current_detection:
-            # Store the count for the current detection type
-            detection_count[current_detection] = current_count
-        current_detection = detection
-        current_count = count
+    # Every key file holds the counts of a single detection type
+    for _detection, path in sdfs_keys.input_keys(input_prefix):
+        with open(path, 'r') as file:
+            for line in file:
+                line = line.strip()
+                detection, count = line.split('\t', 1)
+                detection_count[detection] = detection_count.get(detection, 0) + int(count)
 
-# Don't forget to output the last detection type
-if current_detection == detection:
-    detection_count[current_detection] = current_count
+    total = sum(detection_count.values())
+    with open('/home/sdfs/mrout/' + destination_file, 'w') as dest_file:
+        for detection, count in detection_count.items():
+            percent = (count / total) * 100
+            dest_file.write(f'{detection}\t{percent:.2f}%\n')
 
-total = sum(detection_count.values())
-for detection, count in detection_count.items():
-    percent = (count / total) * 100
-    print(f'{detection}\t{percent:.2f}%')
\ No newline at end of file
+if __name__ == "__main__":
+    if len(sys.argv) < 3:
+        print("Usage: python reduce.py <input_prefix> <destination_file>")
+        sys.exit(1)
+
+    input_prefix = sys.argv[1]
+    destination_file = sys.argv[2]
+
+    reduce_detections(input_prefix, destination_file)
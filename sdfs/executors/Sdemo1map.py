@@ -2,6 +2,8 @@
 import sys
 import re
 
+import sdfs_keys
+
 def map_interconne(input_file, output_prefix, type_x):
     # Create a dictionary to count occurrences of each detection
     detection_count = {}
@@ -26,13 +28,10 @@ def map_interconne(input_file, output_prefix, type_x):
 
     # Create output files for each detection
     for detection, count in detection_count.items():
-        with open(f'/home/sdfs/mrout/{output_prefix}_{detection}', 'a') as file:
+        with open(sdfs_keys.output_path(output_prefix, detection), 'a') as file:
             file.write(f'{detection}\t{count}\n')
             file.flush()
 
-    for detection in detection_count.keys():
-        print(detection)
-
 if __name__ == "__main__":
     if len(sys.argv) < 4:
         print("Usage: python map.py <type_x> <output_prefix> [input_files]")
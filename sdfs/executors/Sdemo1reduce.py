@@ -1,30 +1,30 @@
 #!/usr/bin/env python3
 import sys
-import os
+
+import sdfs_keys
 
 def reduce_detections(input_prefix, destination_file):
 
     with open('/home/sdfs/mrout/'+ destination_file, 'w') as dest_file:
         detection_count = {}
         total_count = 0
-        for filename in os.listdir('/home/sdfs/mrin'):
-            if filename.startswith(input_prefix):
-                try:
-                    file = open('/home/sdfs/mrin/' + filename, 'r')
-                except FileNotFoundError:
-                    print("Error")
-                else:
-                    with file:
-                        lines = file.readlines()
-                        for line in lines:
-                            detection, count = line.strip().split('\t')
-                            count = int(count)
+        for _detection, path in sdfs_keys.input_keys(input_prefix):
+            try:
+                file = open(path, 'r')
+            except FileNotFoundError:
+                print("Error")
+            else:
+                with file:
+                    lines = file.readlines()
+                    for line in lines:
+                        detection, count = line.strip().split('\t')
+                        count = int(count)
 
-                            if detection in detection_count:
-                                detection_count[detection] += count
-                            else:
-                                detection_count[detection] = count
-                            total_count += count
+                        if detection in detection_count:
+                            detection_count[detection] += count
+                        else:
+                            detection_count[detection] = count
+                        total_count += count
         
         for detection, count in detection_count.items():
             percent = (count / total_count) * 100
@@ -0,0 +1,73 @@
+"""Key file and counter helpers for SDFS executors.
+
+Keys can be any byte string. A key file is named `<prefix>_<encoded key>`,
+where every byte outside [A-Za-z0-9.-] is percent-encoded, so the last `_`
+always separates the prefix from the key.
+
+Custom counters are reported on stderr and summed into the job's counters.
+"""
+import os
+import sys
+
+MRIN = '/home/sdfs/mrin'
+MROUT = '/home/sdfs/mrout'
+
+SAFE = frozenset(b'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789.-')
+HEX = '0123456789ABCDEF'
+
+
+def _to_bytes(key):
+    if isinstance(key, str):
+        return key.encode('utf-8', 'surrogateescape')
+    return bytes(key)
+
+
+def encode(key):
+    """Encodes a str or bytes key into a file name component"""
+    return ''.join(chr(b) if b in SAFE else '%{:02X}'.format(b) for b in _to_bytes(key))
+
+
+def decode(encoded):
+    """Returns the key bytes of an encoded key, raising ValueError if it is malformed"""
+    key = bytearray()
+    i = 0
+    while i < len(encoded):
+        c = encoded[i]
+        if c == '%':
+            digits = encoded[i + 1:i + 3]
+            if len(digits) != 2 or any(d not in HEX for d in digits):
+                raise ValueError('malformed escape in key {!r}'.format(encoded))
+            b = int(digits, 16)
+            if b in SAFE:
+                raise ValueError('needless escape in key {!r}'.format(encoded))
+            key.append(b)
+            i += 3
+        elif ord(c) < 128 and ord(c) in SAFE:
+            key.append(ord(c))
+            i += 1
+        else:
+            raise ValueError('unexpected character in key {!r}'.format(encoded))
+    return bytes(key)
+
+
+def output_path(prefix, key):
+    """Path a map executor writes the values of `key` to"""
+    return os.path.join(MROUT, '{}_{}'.format(prefix, encode(key)))
+
+
+def input_keys(prefix):
+    """Yields (key bytes, path) for every key file of `prefix` fetched for a reduce"""
+    start = prefix + '_'
+    for name in sorted(os.listdir(MRIN)):
+        if not name.startswith(start):
+            continue
+        try:
+            key = decode(name[len(start):])
+        except ValueError:
+            continue
+        yield key, os.path.join(MRIN, name)
+
+
+def increment(name, amount=1):
+    """Adds `amount` to the job counter `name`"""
+    sys.stderr.write('sdfs:counter:{}:{}\n'.format(name, int(amount)))
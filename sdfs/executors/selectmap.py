@@ -1,6 +1,8 @@
 import sys
 import re
 
+import sdfs_keys
+
 def map_filter(input_file, output_prefix, regex):
     # Compile the regular expression
     pattern = re.compile(regex)
@@ -13,10 +15,9 @@ def map_filter(input_file, output_prefix, regex):
     for line in lines:
         if pattern.search(line):
             # Write the matching line to the output file
-            with open(f'/home/sdfs/mrout/{output_prefix}_filtered', 'a') as out_file:
+            with open(sdfs_keys.output_path(output_prefix, 'filtered'), 'a') as out_file:
                 out_file.write(line)
                 out_file.flush()
-    print('filtered')
 
 if __name__ == "__main__":
     if len(sys.argv) != 4:
@@ -1,18 +1,18 @@
 import sys
-import os
+
+import sdfs_keys
 
 def reduce_filter(input_prefix, destination_file):
     # Aggregate filtered results from multiple map outputs
     with open('/home/sdfs/mrout/'+ destination_file, 'w') as dest_file:
-        for filename in os.listdir('/home/sdfs/mrin'):
-            if filename.startswith(input_prefix):
-                try:
-                    file = open('/home/sdfs/mrin/' + filename, 'r')
-                except FileNotFoundError:
-                    print("Error")
-                else:
-                    with file:
-                        dest_file.write(file.read())
+        for _key, path in sdfs_keys.input_keys(input_prefix):
+            try:
+                file = open(path, 'r')
+            except FileNotFoundError:
+                print("Error")
+            else:
+                with file:
+                    dest_file.write(file.read())
         dest_file.flush()
 
 if __name__ == "__main__":
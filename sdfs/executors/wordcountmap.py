@@ -1,6 +1,8 @@
 import sys
 import re
 
+import sdfs_keys
+
 def map_words(input_file, output_prefix):
     # Create a dictionary to count occurrences of each word
     word_count = {}
@@ -11,6 +13,7 @@ def map_words(input_file, output_prefix):
 
     # Split text into words using regular expression
     words = re.findall(r'\w+', text.lower())
+    sdfs_keys.increment('words', len(words))
 
     for word in words:
         if word in word_count:
@@ -20,12 +23,9 @@ def map_words(input_file, output_prefix):
 
     # Create output files for each word
     for word, count in word_count.items():
-        with open(f'/home/sdfs/mrout/{output_prefix}_{word}', 'w') as file:
+        with open(sdfs_keys.output_path(output_prefix, word), 'w') as file:
             file.write(f'{word}\t{count}\n')
 
-    for word in word_count.keys():
-        print(word)
-
 if __name__ == "__main__":
     if len(sys.argv) < 3:
         print("Usage: python map.py <output_prefix> [input_files]")
@@ -1,21 +1,21 @@
 import sys
-import os
+
+import sdfs_keys
 
 def reduce_words(input_prefix, destination_file):
     with open('/home/sdfs/mrout/'+ destination_file, 'w') as dest_file:
         total_count = 0
-        for filename in os.listdir('/home/sdfs/mrin'):
-            if filename.startswith(input_prefix):
-                try:
-                    file = open('/home/sdfs/mrin/' + filename, 'r')
-                except FileNotFoundError:
-                    print("Error")
-                else:
-                    with file:
-                        lines = file.readlines()
-                        # Assume the format is 'word\tcount\n'
-                        _word, count = lines[0].strip().split('\t')
-                        total_count += int(count)
+        for _word, path in sdfs_keys.input_keys(input_prefix):
+            try:
+                file = open(path, 'r')
+            except FileNotFoundError:
+                print("Error")
+            else:
+                with file:
+                    lines = file.readlines()
+                    # Assume the format is 'word\tcount\n'
+                    _word, count = lines[0].strip().split('\t')
+                    total_count += int(count)
         dest_file.write(f'{total_count}\n')
 
 if __name__ == "__main__":
@@ -1,13 +1,24 @@
-use crate::helpers::client_get_helper;
+use crate::erasure;
+use crate::helpers::{
+    copy_chunks, copy_range, file_digest, parallel_get, quorum_read, write_leader_request, Digester,
+};
+use crate::message_types::get_req::Range;
 use crate::message_types::sdfs_command::Type;
 use crate::message_types::{
-    Ack, Delete, Fail, GetReq, LsReq, LsRes, MapReq, MultiRead, MultiWrite, PutReq, ReduceReq,
-    SdfsCommand,
+    Ack, AppendReq, DecommissionReq, Delete, ErasureLayout, ErasureReq, ExecutorLimits, Fail,
+    FileDigest, FileInfo, GetReq, JobCounters, JobState, JobStatusReq, LsAllReq, LsReq, LsRes,
+    MapReq, MkdirReq, MultiRead, MultiWrite, PutReq, ReduceReq, RenameReq, RmdirReq, SdfsCommand,
+    SetRepReq, StatReq, StatRes,
 };
+use crate::namespace;
+use crate::pipeline::{Pipeline, Stage};
+use chrono::{offset::Local, NaiveDateTime, TimeZone};
 use futures::stream::{self, StreamExt};
 use prost::Message;
+use std::io::SeekFrom;
 use std::{path::Path, sync::Arc, time::Instant};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{stdout, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::time::{sleep, Duration};
 use tokio::{fs, net::TcpStream, sync::RwLock};
 use tracing::{error, info, instrument, warn};
 
@@ -16,9 +27,386 @@ pub struct Client {
     leader_ip: Arc<RwLock<String>>,
 }
 
-struct PutInProgress {
-    server_address: String,
-    server_stream: TcpStream,
+// How long a client waits for an election to produce a reachable leader
+const LEADER_WAIT: Duration = Duration::from_secs(60);
+
+// Times a PUT reopens a broken stream to a server before giving up on it
+const PUT_RESUMES: usize = 3;
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+/// Copies an executable into the local SDFS directory so the leader can have it uploaded to workers
+async fn stage_executable(executable_name: &str) -> Result<String, String> {
+    let Some(file_name) = Path::new(executable_name)
+        .file_name()
+        .and_then(|f| f.to_str())
+    else {
+        return Err("Malformed executable name".to_string());
+    };
+    if let Err(e) = fs::copy(executable_name, format!("/home/sdfs/{}", file_name)).await {
+        return Err(format!("Unable to copy executable with error: {}", e));
+    }
+    Ok(file_name.to_string())
+}
+
+/// Map and reduce jobs answer with either an Ack or a Fail wrapped in an SdfsCommand
+fn decode_job_response(buffer: &[u8]) -> Result<Ack, String> {
+    match SdfsCommand::decode(buffer) {
+        Ok(SdfsCommand {
+            r#type: Some(Type::Ack(ack)),
+        }) => Ok(ack),
+        Ok(SdfsCommand {
+            r#type: Some(Type::Fail(fail)),
+        }) => Err(fail.message),
+        Ok(_) => Err("Unexpected response from leader".to_string()),
+        Err(e) => Err(format!("Unable to decode leader response: {}", e)),
+    }
+}
+
+/// Reports the stored replicas of a PUT to the leader and waits until it commits them
+async fn report_put(mut leader_stream: TcpStream, stored: LsRes) -> Result<(), String> {
+    leader_stream
+        .write_all(&stored.encode_to_vec())
+        .await
+        .map_err(|e| format!("Unable to report to leader: {}", e))?;
+    let _ = leader_stream.shutdown().await;
+    let mut res_buffer = Vec::new();
+    let _ = leader_stream.read_to_end(&mut res_buffer).await;
+    decode_job_response(&res_buffer).map(|_| ())
+}
+
+/// Sends a PutReq to a server and waits for it to accept the data
+async fn open_put(server_address: &str, put_req: &PutReq) -> Result<(TcpStream, Ack), String> {
+    let mut server_stream = TcpStream::connect(server_address)
+        .await
+        .map_err(|e| format!("Unable to connect to server {}: {}", server_address, e))?;
+    let req_buffer = SdfsCommand {
+        r#type: Some(Type::PutReq(put_req.clone())),
+    }
+    .encode_to_vec();
+    server_stream
+        .write_all(&req_buffer)
+        .await
+        .map_err(|e| format!("Unable to send request to {}: {}", server_address, e))?;
+    let mut ack_buffer = [0; 1024];
+    let n = server_stream
+        .read(&mut ack_buffer)
+        .await
+        .map_err(|e| format!("No response from {}: {}", server_address, e))?;
+    match Ack::decode(&ack_buffer[..n]) {
+        Ok(ack) if n > 0 => Ok((server_stream, ack)),
+        _ => Err(format!("{} refused the PUT", server_address)),
+    }
+}
+
+/// Streams a local file to a server starting at byte `offset`
+async fn send_from(
+    local_file_name: &str,
+    offset: u64,
+    server_stream: &mut TcpStream,
+) -> std::io::Result<()> {
+    let mut file = fs::File::open(local_file_name).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    copy_chunks(&mut file, server_stream).await?;
+    Ok(())
+}
+
+/// Streams a local file to one server. A stream that breaks is reopened as a resumed PUT,
+/// which continues at the offset the server confirms it holds. Returns the server's digest
+/// once it reports the data durable, which must match `expected` when that is known.
+async fn put_to_server(
+    local_file_name: &str,
+    machine: &str,
+    put_req: &PutReq,
+    expected: Option<&FileDigest>,
+) -> Result<Option<FileDigest>, String> {
+    let server_address = format!("{machine}:56552");
+    let (mut server_stream, _) = open_put(&server_address, put_req).await?;
+    let resume_req = PutReq {
+        resume: true,
+        ..put_req.clone()
+    };
+    let mut offset = 0;
+    let mut resumes = 0;
+    while let Err(e) = send_from(local_file_name, offset, &mut server_stream).await {
+        warn!(
+            "Lost {} after sending from byte {}: {}",
+            server_address, offset, e
+        );
+        let (resumed_stream, ack) = loop {
+            if resumes == PUT_RESUMES {
+                return Err(format!(
+                    "Gave up on {} after {} resumes",
+                    server_address, resumes
+                ));
+            }
+            resumes += 1;
+            sleep(RESUME_DELAY).await;
+            match open_put(&server_address, &resume_req).await {
+                Ok(resumed) => break resumed,
+                Err(e) => warn!("{}", e),
+            }
+        };
+        info!("Resuming PUT to {} at byte {}", server_address, ack.offset);
+        server_stream = resumed_stream;
+        offset = ack.offset;
+    }
+    let _ = server_stream.shutdown().await;
+    let mut stored_buffer = Vec::new();
+    let _ = server_stream.read_to_end(&mut stored_buffer).await;
+    let stored = match Ack::decode(stored_buffer.as_slice()) {
+        Ok(stored) if !stored_buffer.is_empty() => stored,
+        _ => return Err(format!("Server {} did not store the file", server_address)),
+    };
+    match expected {
+        Some(expected) if stored.digest.as_ref() != Some(expected) => Err(format!(
+            "Server {} stored data other than {}",
+            server_address, local_file_name
+        )),
+        _ => Ok(stored.digest),
+    }
+}
+
+/// Streams a local file to every server at once, returning the report for the leader of the
+/// servers that have the data durable. Servers that disagree on what they stored fail the
+/// PUT, reported as no server storing it.
+async fn send_to_servers(local_file_name: &str, machines: Vec<String>, put_req: PutReq) -> LsRes {
+    // Appends are measured with the file they extend, which only the servers hold
+    let expected = match put_req.append {
+        true => None,
+        false => match file_digest(local_file_name).await {
+            Ok(digest) => Some(digest),
+            Err(e) => {
+                warn!("Unable to digest {}: {}", local_file_name, e);
+                return LsRes::default();
+            }
+        },
+    };
+    let (put_req, expected) = (&put_req, expected.as_ref());
+    let writers = machines.len().max(1);
+    let stored: Vec<(String, Option<FileDigest>)> = stream::iter(machines)
+        .map(|machine| async move {
+            match put_to_server(local_file_name, &machine, put_req, expected).await {
+                Ok(digest) => Some((machine, digest)),
+                Err(e) => {
+                    warn!("{}, ignoring server", e);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(writers)
+        .filter_map(|stored| async { stored })
+        .collect()
+        .await;
+    let digest = stored.iter().find_map(|(_, digest)| digest.clone());
+    let mismatch = stored
+        .iter()
+        .find(|(_, stored_digest)| stored_digest.is_some() && *stored_digest != digest);
+    if let Some((machine, _)) = mismatch {
+        println!("Replicas stored different data, failing the PUT");
+        warn!("{} reported a digest other than {:?}", machine, digest);
+        return LsRes::default();
+    }
+    LsRes {
+        digest,
+        machines: stored.into_iter().map(|(machine, _)| machine).collect(),
+        ..Default::default()
+    }
+}
+
+/// Local time of a unix timestamp in milliseconds, `-` if it was never recorded
+fn format_millis(millis: u64) -> String {
+    match NaiveDateTime::from_timestamp_millis(millis as i64) {
+        Some(time) if millis > 0 => Local
+            .from_utc_datetime(&time)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        _ => "-".to_string(),
+    }
+}
+
+fn print_file_info(info: &FileInfo) {
+    println!("File: {}", info.file_name);
+    match &info.digest {
+        Some(digest) => {
+            println!("Size: {} bytes", digest.size);
+            println!("Lines: {}", digest.lines);
+            println!("Checksum: {:08x}", digest.checksum);
+        }
+        None => println!("Size: unknown, the file was not written by a PUT"),
+    }
+    println!("Version: {}", info.version);
+    println!("Created: {}", format_millis(info.created_millis));
+    println!("Modified: {}", format_millis(info.modified_millis));
+    match &info.erasure {
+        Some(layout) => {
+            println!(
+                "Erasure-coded {}+{}",
+                layout.data_shards, layout.parity_shards
+            );
+            for (index, machine) in info.machines.iter().enumerate() {
+                match machine.as_str() {
+                    "" => println!("Fragment {}: lost", index),
+                    machine => println!("Fragment {}: {}", index, machine),
+                }
+            }
+        }
+        None => println!("Replicas: {}", info.machines.join(", ")),
+    }
+}
+
+/// Streams a range of a file from the first replica that answers to stdout
+async fn stream_range(
+    machines: Vec<String>,
+    sdfs_file_name: &str,
+    range: Option<Range>,
+) -> Result<(), String> {
+    let get_req = SdfsCommand {
+        r#type: Some(Type::GetReq(GetReq {
+            file_name: sdfs_file_name.to_string(),
+            range,
+            ..Default::default()
+        })),
+    }
+    .encode_to_vec();
+    for machine in machines {
+        let server_address = machine + ":56552";
+        let Ok(mut server_stream) = TcpStream::connect(&server_address).await else {
+            warn!(
+                "Unable to connect to server {}, moving to the next",
+                server_address
+            );
+            continue;
+        };
+        if let Err(e) = server_stream.write_all(&get_req).await {
+            warn!("Unable to send request to server {}: {}", server_address, e);
+            continue;
+        }
+        let mut out = stdout();
+        return match copy_chunks(&mut server_stream, &mut out).await {
+            Ok(_) => out.flush().await.map_err(|e| e.to_string()),
+            Err(e) => Err(format!("Lost connection to {}: {}", server_address, e)),
+        };
+    }
+    Err("Unable to reach any replica".to_string())
+}
+
+/// Writes a range of an erasure-coded file to stdout. The file is decoded into a scratch
+/// file first, lines can only be found in the decoded data.
+async fn cat_erasure_range(
+    sdfs_file_name: &str,
+    machines: &[String],
+    layout: &ErasureLayout,
+    range: Option<Range>,
+) -> Result<(), String> {
+    let scratch = std::env::temp_dir().join(format!("sdfs-cat-{:016x}", rand::random::<u64>()));
+    let result = async {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&scratch)
+            .await
+            .map_err(|e| format!("Unable to create scratch file: {}", e))?;
+        erasure::read_file(sdfs_file_name, machines, layout, &mut file).await?;
+        copy_range(&mut file, range.as_ref(), &mut stdout())
+            .await
+            .map_err(|e| format!("Unable to write output: {}", e))
+    }
+    .await;
+    let _ = fs::remove_file(&scratch).await;
+    result
+}
+
+/// Streams a local file to the head of a chain of servers that forward it to each other,
+/// returning the report for the leader of the servers that have the data durable. A chain
+/// that stored data other than `expected` stored nothing.
+async fn send_to_chain(
+    mut file: fs::File,
+    mut machines: Vec<String>,
+    put_req: PutReq,
+    expected: Option<FileDigest>,
+) -> LsRes {
+    // An unreachable head is skipped, the next server heads the rest of the chain
+    while !machines.is_empty() {
+        let head = machines.remove(0);
+        let server_address = head.clone() + ":56552";
+        let Ok(mut head_stream) = TcpStream::connect(&server_address).await else {
+            warn!(
+                "Unable to connect to server {}, skipping it",
+                server_address
+            );
+            continue;
+        };
+        let req_buffer = SdfsCommand {
+            r#type: Some(Type::PutReq(PutReq {
+                chained: true,
+                chain: machines.clone(),
+                ..put_req.clone()
+            })),
+        }
+        .encode_to_vec();
+        if let Err(e) = head_stream.write_all(&req_buffer).await {
+            warn!("Unable to send request to server: {}, skipping it", e);
+            continue;
+        }
+        let mut ack_buffer = [0; 1024];
+        match head_stream.read(&mut ack_buffer).await {
+            Ok(n) if n > 0 && Ack::decode(&ack_buffer[..n]).is_ok() => {}
+            _ => {
+                warn!("No server response to request, skipping {}", head);
+                continue;
+            }
+        }
+        if let Err(e) = copy_chunks(&mut file, &mut head_stream).await {
+            warn!("Unable to stream to the chain head {}: {}", head, e);
+            break;
+        }
+        let _ = head_stream.shutdown().await;
+        let mut stored_buffer = Vec::new();
+        let _ = head_stream.read_to_end(&mut stored_buffer).await;
+        let Ok(SdfsCommand {
+            r#type: Some(Type::LsRes(stored)),
+        }) = SdfsCommand::decode(stored_buffer.as_slice())
+        else {
+            warn!("Chain head {} did not store the file", head);
+            break;
+        };
+        if expected.is_some() && stored.digest != expected {
+            warn!("Chain headed by {} stored data other than the file", head);
+            break;
+        }
+        return LsRes {
+            machines: [vec![head], stored.machines].concat(),
+            digest: stored.digest,
+            ..Default::default()
+        };
+    }
+    LsRes::default()
+}
+
+fn print_job_counters(counters: &JobCounters) {
+    println!("Input records: {}", counters.input_records);
+    println!("Output keys: {}", counters.output_keys);
+    println!("Bytes shuffled: {}", counters.bytes_shuffled);
+    println!("Task retries: {}", counters.task_retries);
+    if let (Some(min), Some(max)) = (
+        counters.task_millis.iter().min(),
+        counters.task_millis.iter().max(),
+    ) {
+        let total: u64 = counters.task_millis.iter().sum();
+        println!(
+            "Task durations: {} tasks, min {}ms, avg {}ms, max {}ms",
+            counters.task_millis.len(),
+            min,
+            total / counters.task_millis.len() as u64,
+            max
+        );
+    }
+    let mut custom: Vec<_> = counters.custom.iter().collect();
+    custom.sort();
+    for (name, value) in custom {
+        println!("{}: {}", name, value);
+    }
 }
 
 impl Client {
@@ -26,9 +414,109 @@ impl Client {
         Client { leader_ip }
     }
 
+    /// Connects to the current leader, following elections until one accepts or we give up
+    async fn connect_leader(&self) -> Option<(String, TcpStream)> {
+        let deadline = Instant::now() + LEADER_WAIT;
+        loop {
+            let leader_ip = self.leader_ip.read().await.clone();
+            match TcpStream::connect(format!("{leader_ip}:56553")).await {
+                Ok(stream) => return Some((leader_ip, stream)),
+                Err(e) => warn!("Unable to contact leader {}: {}", leader_ip, e),
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn wait_for_new_leader(&self, old_leader: &str) -> bool {
+        let deadline = Instant::now() + LEADER_WAIT;
+        while Instant::now() < deadline {
+            if *self.leader_ip.read().await != old_leader {
+                return true;
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+        false
+    }
+
+    /// Sends a request to the leader and reads its response, either a single read or
+    /// everything until the leader closes the stream. A leader that fails before answering
+    /// gets the request resent to its successor once the election picks one, so only requests
+    /// that are safe to repeat go through here.
+    async fn leader_request(
+        &self,
+        request: &[u8],
+        until_close: bool,
+    ) -> Option<(TcpStream, Vec<u8>)> {
+        loop {
+            let (leader_ip, mut leader_stream) = self.connect_leader().await?;
+            let mut response = Vec::new();
+            let received = match write_leader_request(&mut leader_stream, request).await {
+                Err(e) => Err(e),
+                Ok(()) if until_close => leader_stream.read_to_end(&mut response).await,
+                Ok(()) => {
+                    let mut res_buffer = [0; 1024];
+                    leader_stream
+                        .read(&mut res_buffer)
+                        .await
+                        .inspect(|n| response.extend_from_slice(&res_buffer[..*n]))
+                }
+            };
+            match received {
+                Ok(_) if !response.is_empty() => return Some((leader_stream, response)),
+                Ok(_) => warn!(
+                    "Leader {} closed the connection without answering",
+                    leader_ip
+                ),
+                Err(e) => warn!("Lost connection to leader {}: {}", leader_ip, e),
+            }
+            if !self.wait_for_new_leader(&leader_ip).await {
+                return None;
+            }
+            println!("Leader changed, retrying request against the new leader");
+        }
+    }
+
+    /// Submits a map, reduce or pipeline job and waits for the leader to close the stream.
+    /// Jobs are not idempotent, so once sent a job is never resent to a new leader: the
+    /// failed leader may already have started it.
+    async fn submit_job(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let (leader_ip, mut leader_stream) = self.connect_leader().await?;
+        let mut response = Vec::new();
+        let received = match write_leader_request(&mut leader_stream, request).await {
+            Ok(()) => leader_stream.read_to_end(&mut response).await,
+            Err(e) => Err(e),
+        };
+        match received {
+            Ok(_) if !response.is_empty() => return Some(response),
+            Ok(_) => warn!(
+                "Leader {} closed the connection without answering",
+                leader_ip
+            ),
+            Err(e) => warn!("Lost connection to leader {}: {}", leader_ip, e),
+        }
+        println!(
+            "Lost the leader after submitting the job, it may still have run. Check its output before resubmitting"
+        );
+        None
+    }
+
     #[instrument(name = "Client Put", level = "trace")]
-    pub async fn put_file(&self, local_file_name: &str, sdfs_file_name: &str) {
+    pub async fn put_file(
+        &self,
+        local_file_name: &str,
+        sdfs_file_name: &str,
+        replicas: u32,
+        erasure: Option<ErasureLayout>,
+        chain: bool,
+    ) {
         info!("Starting PUT at client to file: {}", sdfs_file_name);
+        if !namespace::is_valid(sdfs_file_name) {
+            println!("{} is not a valid SDFS path", sdfs_file_name);
+            return;
+        }
         let start_time = Instant::now();
         // Read the local file
         let Ok(file) = fs::File::open(local_file_name).await else {
@@ -41,124 +529,172 @@ impl Client {
         //     return;
         // };
 
-        let leader_address = {
-            let locked = self.leader_ip.read().await;
-            locked.clone() + ":56553"
-        };
-
-        let Ok(mut leader_stream) = TcpStream::connect(leader_address).await else {
-            error!("Unable to contact leader, aborting");
-            return;
-        };
         let req_buffer = SdfsCommand {
             r#type: Some(Type::PutReq(PutReq {
                 file_name: sdfs_file_name.to_string(),
+                replicas,
+                erasure: erasure.clone(),
+                ..Default::default()
             })),
         }
         .encode_to_vec();
-        let _ = leader_stream.write_all(&req_buffer).await;
-
-        let mut res_buffer = [0; 1024];
-        let Ok(n) = leader_stream.read(&mut res_buffer).await else {
-            error!("No leader response to request: ");
+        let Some((leader_stream, res_buffer)) = self.leader_request(&req_buffer, false).await
+        else {
+            error!("Unable to contact leader, aborting");
             return;
         };
-        let Ok(machine_list) = LsRes::decode(&res_buffer[..n]) else {
+        let Ok(machine_list) = LsRes::decode(res_buffer.as_slice()) else {
             error!("Unable to decode leader response, aborting");
             return;
         };
+        if let Some(fail) = machine_list.fail {
+            println!("PUT refused: {}", fail.message);
+            return;
+        }
 
         info!(
             "Client received list from server: {:?}",
             machine_list.machines
         );
-        let mut servers_in_prog = Vec::new();
-
-        for machine in machine_list.machines {
-            let server_address = machine.clone() + ":56552";
-            let Ok(mut server_stream) = TcpStream::connect(&server_address).await else {
-                warn!(
-                    "Unable to connect to server {}, ignoring server",
-                    server_address
-                );
-                continue;
-            };
-            //println!("Request buffer: {:?}", req_buffer);
-            if let Err(e) = server_stream.write_all(&req_buffer).await {
-                warn!("Unable to send request to server: {}, ignoring server", e);
-                continue;
-            }
-            info!("Sent request to server maybe");
-            let mut ack_buffer = [0; 1024];
-            let Ok(n) = server_stream.read(&mut ack_buffer).await else {
-                warn!("No server response to request, ignoring server");
-                continue;
-            };
-            info!("Received ACK from server");
-            if let Err(e) = Ack::decode(&ack_buffer[..n]) {
-                warn!("Unable to decode ACK server message {}, ignoring serve", e);
-                continue;
-            };
-            info!("Decoded ACK from server");
-            info!("Established communication with server");
-            servers_in_prog.push(PutInProgress {
-                server_address: machine,
-                server_stream,
-            });
-        }
-
-        let mut file_buf = Vec::new();
-        let mut buf_reader = BufReader::new(file);
-
-        while let Ok(size) = buf_reader.read_until(b'\n', &mut file_buf).await {
-            if size == 0 {
-                break;
-            }
-            info!("Put read file with size: {size}");
-
-            let send_buffer_references = stream::repeat(&file_buf);
-
-            servers_in_prog = stream::iter(servers_in_prog)
-                .zip(send_buffer_references)
-                .filter_map(|(mut server, buffer)| async move {
-                    match server.server_stream.write_all(buffer).await {
-                        Ok(_) => Some(server),
-                        Err(e) => {
-                            warn!(
-                                "Unable to write to server {} with error {}, ignoring server",
-                                server.server_address, e
-                            );
-                            None
-                        }
-                    }
-                })
-                .collect()
+        if let Some(layout) = erasure {
+            self.put_fragments(file, sdfs_file_name, layout, machine_list, leader_stream)
                 .await;
-            file_buf.clear();
+            let duration = start_time.elapsed();
+            println!("Total time taken to write the file: {:?}", duration);
+            return;
         }
+        // Servers keep the data staged until the leader commits the PUT
+        let server_req = PutReq {
+            file_name: sdfs_file_name.to_string(),
+            staging: machine_list.staging,
+            ..Default::default()
+        };
+        let stored = match chain {
+            true => {
+                let expected = file_digest(local_file_name).await.ok();
+                send_to_chain(file, machine_list.machines, server_req, expected).await
+            }
+            false => send_to_servers(local_file_name, machine_list.machines, server_req).await,
+        };
 
-        if servers_in_prog.is_empty() {
+        if stored.machines.is_empty() {
             println!("PUT failed because the filesystem is not responding");
             return;
         }
 
-        for server in &mut servers_in_prog {
-            let _ = server.server_stream.shutdown().await;
-        }
-
-        let ls_buffer = LsRes {
-            machines: servers_in_prog
-                .into_iter()
-                .map(|server| server.server_address)
-                .collect(),
+        if let Err(e) = report_put(leader_stream, stored).await {
+            println!("PUT failed: {}", e);
+            return;
         }
-        .encode_to_vec();
 
         println!("File PUT successful");
         let duration = start_time.elapsed();
         println!("Total time taken to write the file: {:?}", duration);
-        let _ = leader_stream.write_all(&ls_buffer).await;
-        let _ = leader_stream.shutdown().await;
+    }
+
+    /// Encodes a local file and stores fragment i on the i-th machine the leader picked
+    async fn put_fragments(
+        &self,
+        mut file: fs::File,
+        sdfs_file_name: &str,
+        mut layout: ErasureLayout,
+        machine_list: LsRes,
+        leader_stream: TcpStream,
+    ) {
+        layout.size = match file.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                println!("Unable to read file: {}", e);
+                return;
+            }
+        };
+        // Fragment holders only see their fragment, so the digest covers the data sent
+        let mut digester = Digester::default();
+        let stored = match erasure::put_file(
+            &mut file,
+            sdfs_file_name,
+            &machine_list.machines,
+            &machine_list.staging,
+            &layout,
+            &mut digester,
+        )
+        .await
+        {
+            Ok(stored) => stored,
+            Err(e) => {
+                // The leader drops the staged fragments once the PUT is not reported
+                println!("PUT failed: {}", e);
+                return;
+            }
+        };
+        let placed = stored.iter().filter(|machine| !machine.is_empty()).count();
+        if placed < layout.data_shards as usize {
+            println!("PUT failed because the filesystem is not responding");
+            return;
+        }
+        let fragments = stored.len();
+        let stored = LsRes {
+            machines: stored,
+            erasure: Some(layout),
+            digest: Some(digester.finish()),
+            ..Default::default()
+        };
+        if let Err(e) = report_put(leader_stream, stored).await {
+            println!("PUT failed: {}", e);
+            return;
+        }
+        println!(
+            "File PUT successful, {} of {} fragments stored",
+            placed, fragments
+        );
+    }
+
+    #[instrument(name = "Client Append", level = "trace")]
+    pub async fn append_file(&self, local_file_name: &str, sdfs_file_name: &str) {
+        info!("Starting APPEND at client to file: {}", sdfs_file_name);
+        let start_time = Instant::now();
+        // Checked before the leader serialises the append, each server reopens the file
+        if let Err(e) = fs::File::open(local_file_name).await {
+            println!("Unable to open file: {}", e);
+            return;
+        }
+        let req_buffer = SdfsCommand {
+            r#type: Some(Type::AppendReq(AppendReq {
+                file_name: sdfs_file_name.to_string(),
+            })),
+        }
+        .encode_to_vec();
+        let Some((leader_stream, res_buffer)) = self.leader_request(&req_buffer, false).await
+        else {
+            error!("Unable to contact leader, aborting");
+            return;
+        };
+        let Ok(machine_list) = LsRes::decode(res_buffer.as_slice()) else {
+            error!("Unable to decode leader response, aborting");
+            return;
+        };
+        if let Some(fail) = machine_list.fail {
+            println!("Unable to append: {}", fail.message);
+            return;
+        }
+        // Each replica stages its copy of the current version with the data added
+        let server_req = PutReq {
+            file_name: sdfs_file_name.to_string(),
+            staging: machine_list.staging,
+            version: machine_list.version,
+            append: true,
+            ..Default::default()
+        };
+        let stored = send_to_servers(local_file_name, machine_list.machines, server_req).await;
+        if let Err(e) = report_put(leader_stream, stored).await {
+            println!("APPEND failed: {}", e);
+            return;
+        }
+        println!("File APPEND successful");
+        println!(
+            "Total time taken to append to the file: {:?}",
+            start_time.elapsed()
+        );
     }
 
     #[instrument(name = "Client Get", level = "trace")]
@@ -168,39 +704,37 @@ impl Client {
         // Create and send a GetReq message to the leader
 
         // Connect to the leader and send the message
-        let leader_address = {
-            let locked = self.leader_ip.read().await;
-            locked.clone() + ":56553"
-        };
-        let Ok(mut leader_stream) = TcpStream::connect(leader_address).await else {
-            error!("Unable to contact leader, aborting");
-            return;
-        };
         let get_req = GetReq {
             file_name: sdfs_file_name.to_string(),
+            range: None,
+            ..Default::default()
         };
         let req_buffer = SdfsCommand {
             r#type: Some(Type::GetReq(get_req)),
         }
         .encode_to_vec();
-        let _ = leader_stream.write_all(&req_buffer).await;
-
         // Await the response from the leader
-        let mut res_buffer = [0; 1024];
-        let Ok(n) = leader_stream.read(&mut res_buffer).await else {
-            error!("No leader response to request: ");
+        let Some((mut leader_stream, res_buffer)) = self.leader_request(&req_buffer, false).await
+        else {
+            error!("Unable to contact leader, aborting");
             return;
         };
-        let Ok(machine_list) = LsRes::decode(&res_buffer[..n]) else {
+        let Ok(machine_list) = LsRes::decode(res_buffer.as_slice()) else {
             error!("Unable to decode leader response, aborting");
             return;
         };
 
         let ack_buffer = Ack {
             message: "File GET completed successfully".to_string(),
+            ..Default::default()
         }
         .encode_to_vec();
 
+        if let Some(fail) = machine_list.fail {
+            println!("File GET unsuccessful: {}", fail.message);
+            let _ = leader_stream.write_all(&ack_buffer).await;
+            return;
+        }
         if machine_list.machines.is_empty() {
             error!("No replicas available or file not found");
             let _ = leader_stream.write_all(&ack_buffer).await;
@@ -210,11 +744,32 @@ impl Client {
         // Use the list of replicas from the leader's response to fetch the file from one of the replicas.
         // For simplicity, we'll just use the first replica. In real-world scenarios, you might want to add
         // fault tolerance here by trying the next replica if one fails.
-        match client_get_helper(machine_list.machines, sdfs_file_name, local_file_name, None).await
-        {
+        let result = match &machine_list.erasure {
+            Some(layout) => {
+                erasure::get_file(
+                    sdfs_file_name,
+                    &machine_list.machines,
+                    layout,
+                    local_file_name,
+                )
+                .await
+            }
+            None => match quorum_read(
+                machine_list.machines,
+                sdfs_file_name,
+                machine_list.read_quorum as usize,
+            )
+            .await
+            {
+                Ok(fresh) => parallel_get(fresh, sdfs_file_name, local_file_name).await,
+                Err(e) => Err(e),
+            },
+        };
+        match result {
             Ok(_) => {
                 let ack_buffer = Ack {
                     message: "File getting completed successfully".to_string(),
+                    ..Default::default()
                 }
                 .encode_to_vec();
                 let _ = leader_stream.write_all(&ack_buffer).await;
@@ -232,71 +787,135 @@ impl Client {
         let _ = leader_stream.shutdown().await;
     }
 
-    #[instrument(name = "Client Delete", level = "trace")]
-    pub async fn delete_file(&self, sdfs_file_name: &str) {
-        info!("Starting Delete on client side");
-        let leader_address = {
-            let locked = self.leader_ip.read().await;
-            locked.clone() + ":56553"
+    /// Streams a file, or the part of it in `range`, to stdout without storing it locally
+    #[instrument(name = "Client Cat", level = "trace")]
+    pub async fn cat_file(&self, sdfs_file_name: &str, range: Option<Range>) {
+        let req_buffer = SdfsCommand {
+            r#type: Some(Type::GetReq(GetReq {
+                file_name: sdfs_file_name.to_string(),
+                range: None,
+                ..Default::default()
+            })),
+        }
+        .encode_to_vec();
+        let Some((mut leader_stream, res_buffer)) = self.leader_request(&req_buffer, false).await
+        else {
+            eprintln!("Unable to contact leader, aborting");
+            return;
         };
-        let Ok(mut leader_stream) = TcpStream::connect(leader_address).await else {
-            error!("Unable to contact leader, aborting");
+        let Ok(machine_list) = LsRes::decode(res_buffer.as_slice()) else {
+            eprintln!("Unable to decode leader response, aborting");
             return;
         };
+        let result = match (&machine_list.fail, &machine_list.erasure) {
+            (Some(fail), _) => Err(fail.message.clone()),
+            _ if machine_list.machines.is_empty() => Err(format!("{} not found", sdfs_file_name)),
+            (None, Some(layout)) if range.is_none() => {
+                erasure::read_file(
+                    sdfs_file_name,
+                    &machine_list.machines,
+                    layout,
+                    &mut stdout(),
+                )
+                .await
+            }
+            // Fragments hold no lines, so the range is cut out of the decoded file
+            (None, Some(layout)) => {
+                cat_erasure_range(sdfs_file_name, &machine_list.machines, layout, range).await
+            }
+            (None, None) => match quorum_read(
+                machine_list.machines,
+                sdfs_file_name,
+                machine_list.read_quorum as usize,
+            )
+            .await
+            {
+                Ok(fresh) => stream_range(fresh.machines, sdfs_file_name, range).await,
+                Err(e) => Err(e),
+            },
+        };
+        let response = match result {
+            Ok(()) => Ack {
+                message: "File GET completed successfully".to_string(),
+                ..Default::default()
+            }
+            .encode_to_vec(),
+            Err(message) => {
+                eprintln!("{}", message);
+                Fail { message }.encode_to_vec()
+            }
+        };
+        let _ = leader_stream.write_all(&response).await;
+        let _ = leader_stream.shutdown().await;
+    }
+
+    #[instrument(name = "Client Delete", level = "trace")]
+    pub async fn delete_file(&self, sdfs_file_name: &str) {
+        info!("Starting Delete on client side");
         let del_req = Delete {
             file_name: sdfs_file_name.to_string(),
+            ..Default::default()
         };
         let req_buffer = SdfsCommand {
             r#type: Some(Type::Del(del_req)),
         }
         .encode_to_vec();
-        let _ = leader_stream.write_all(&req_buffer).await;
-
-        let mut res_buffer = [0; 1024];
-        let Ok(n) = leader_stream.read(&mut res_buffer).await else {
-            error!("No leader response to request: ");
+        let Some((_, res_buffer)) = self.leader_request(&req_buffer, false).await else {
+            error!("Unable to contact leader, aborting");
             println!("File delete failed");
             return;
         };
-        if let Err(e) = Ack::decode(&res_buffer[..n]) {
-            error!("Unable to decode leader ACK: {}", e);
-            println!("File delete failed");
+        if let Err(e) = decode_job_response(&res_buffer) {
+            println!("File delete failed: {}", e);
             return;
         };
         println!("File Delete successful");
     }
 
     #[instrument(name = "Client Ls", level = "trace")]
-    pub async fn list_file(&self, sdfs_file_name: &str) {
+    pub async fn list_file(&self, sdfs_file_name: &str, recursive: bool) {
         info!("Starting Ls on client side");
-        let leader_address = {
-            let locked = self.leader_ip.read().await;
-            locked.clone() + ":56553"
-        };
-        let Ok(mut leader_stream) = TcpStream::connect(leader_address).await else {
-            error!("Unable to contact leader, aborting");
-            return;
-        };
         let ls_req = LsReq {
             file_name: sdfs_file_name.to_string(),
+            recursive,
         };
         let req_buffer = SdfsCommand {
             r#type: Some(Type::LsReq(ls_req)),
         }
         .encode_to_vec();
-        let _ = leader_stream.write_all(&req_buffer).await;
-
-        let mut res_buffer = [0; 1024];
-        let Ok(n) = leader_stream.read(&mut res_buffer).await else {
-            error!("No leader response to request: ");
+        let Some((_, res_buffer)) = self.leader_request(&req_buffer, true).await else {
+            error!("Unable to contact leader, aborting");
             return;
         };
-        let Ok(machine_list) = LsRes::decode(&res_buffer[..n]) else {
+        let Ok(machine_list) = LsRes::decode(res_buffer.as_slice()) else {
             error!("Unable to decode leader response, aborting");
             return;
         };
-        for machine in machine_list.machines {
-            println!("{}", machine);
+        if let Some(fail) = machine_list.fail {
+            println!("{}", fail.message);
+            return;
+        }
+        if machine_list.directory {
+            for entry in machine_list.entries {
+                println!("{}", entry);
+            }
+            return;
+        }
+        let Some(layout) = machine_list.erasure else {
+            for machine in machine_list.machines {
+                println!("{}", machine);
+            }
+            return;
+        };
+        println!(
+            "Erasure-coded {}+{}, {} bytes",
+            layout.data_shards, layout.parity_shards, layout.size
+        );
+        for (index, machine) in machine_list.machines.iter().enumerate() {
+            match machine.as_str() {
+                "" => println!("Fragment {}: lost", index),
+                machine => println!("Fragment {}: {}", index, machine),
+            }
         }
     }
 
@@ -387,55 +1006,46 @@ impl Client {
         file_name_prefix: &str,
         input_dir: &str,
         arguments: &[&str],
+        limits: ExecutorLimits,
     ) {
         let start_time = Instant::now();
         info!("Starting Map on client side");
-        let Some(file_name) = Path::new(executable_name)
-            .file_name()
-            .and_then(|f| f.to_str())
-        else {
-            println!("Malformed executable name, aborting");
-            return;
-        };
-        if let Err(e) = fs::copy(executable_name, format!("/home/sdfs/{}", file_name)).await {
-            println!("Unable to copy executable with error: {}, aborting", e);
-            return;
-        }
-
-        let leader_address = {
-            let locked = self.leader_ip.read().await;
-            locked.clone() + ":56553"
-        };
-        let Ok(mut leader_stream) = TcpStream::connect(leader_address).await else {
-            error!("Unable to contact leader, aborting");
-            return;
+        let file_name = match stage_executable(executable_name).await {
+            Ok(file_name) => file_name,
+            Err(e) => {
+                println!("{}, aborting", e);
+                return;
+            }
         };
 
         let map_req_buffer = SdfsCommand {
             r#type: Some(Type::MapReq(MapReq {
-                executable: file_name.to_string(),
+                executable: file_name,
                 num_workers,
                 file_name_prefix: file_name_prefix.to_string(),
                 input_dir: input_dir.to_string(),
                 arguments: arguments.iter().map(|arg| arg.to_string()).collect(),
+                limits: Some(limits),
             })),
         }
         .encode_to_vec();
 
-        let _ = leader_stream.write_all(&map_req_buffer).await;
-
-        let mut res_buffer = [0; 1024];
-        let Ok(n) = leader_stream.read(&mut res_buffer).await else {
-            error!("No leader response to request: ");
+        // Counters can outgrow a single read, the leader closes the stream after responding
+        let Some(res_buffer) = self.submit_job(&map_req_buffer).await else {
+            error!("Unable to contact leader, aborting");
             println!("Map failed, aborting");
             return;
         };
-        if let Err(e) = Ack::decode(&res_buffer[..n]) {
-            error!("Unable to decode leader ack response: {}", e);
-            println!("Map failed, aborting");
-        } else {
-            info!("Map successful");
-            println!("Map successful");
+        match decode_job_response(&res_buffer) {
+            Ok(ack) => {
+                info!("Map successful");
+                println!("{}", ack.message);
+                print_job_counters(&ack.counters.unwrap_or_default());
+            }
+            Err(e) => {
+                error!("Map failed: {}", e);
+                println!("{}, aborting", e);
+            }
         };
         let duration = start_time.elapsed();
         println!("Time taken for map: {:?}", duration);
@@ -448,73 +1058,267 @@ impl Client {
         file_name_prefix: &str,
         input_dir: &str,
         is_delete: bool,
+        limits: ExecutorLimits,
     ) {
         let start_time = Instant::now();
         info!("Starting Reduce on client side");
-        let Some(file_name) = Path::new(executable_name)
-            .file_name()
-            .and_then(|f| f.to_str())
-        else {
-            println!("Malformed executable name, aborting");
-            return;
-        };
-        if let Err(e) = fs::copy(executable_name, format!("/home/sdfs/{}", file_name)).await {
-            println!("Unable to copy executable with error: {}, aborting", e);
-            return;
-        }
-
-        let leader_address = {
-            let locked = self.leader_ip.read().await;
-            locked.clone() + ":56553"
-        };
-        let Ok(mut leader_stream) = TcpStream::connect(leader_address).await else {
-            error!("Unable to contact leader, aborting");
-            return;
+        let file_name = match stage_executable(executable_name).await {
+            Ok(file_name) => file_name,
+            Err(e) => {
+                println!("{}, aborting", e);
+                return;
+            }
         };
 
         let reduce_req_buffer = SdfsCommand {
             r#type: Some(Type::RedReq(ReduceReq {
-                executable: file_name.to_string(),
+                executable: file_name,
                 num_workers,
                 file_name_prefix: file_name_prefix.to_string(),
                 output_file: input_dir.to_string(),
                 delete: is_delete,
+                limits: Some(limits),
             })),
         }
         .encode_to_vec();
 
-        let _ = leader_stream.write_all(&reduce_req_buffer).await;
-
-        let mut res_buffer = [0; 1024];
-        let Ok(n) = leader_stream.read(&mut res_buffer).await else {
-            error!("No leader response to request: ");
+        // Counters can outgrow a single read, the leader closes the stream after responding
+        let Some(res_buffer) = self.submit_job(&reduce_req_buffer).await else {
+            error!("Unable to contact leader, aborting");
             println!("Reduce failed, aborting");
             return;
         };
-        if let Err(e) = Ack::decode(&res_buffer[..n]) {
-            error!("Unable to decode leader ack response: {}", e);
-            println!("Reduce failed, aborting");
-        } else {
-            info!("Reduce successful");
-            println!("Reduce successful");
+        match decode_job_response(&res_buffer) {
+            Ok(ack) => {
+                info!("Reduce successful");
+                println!("{}", ack.message);
+                print_job_counters(&ack.counters.unwrap_or_default());
+            }
+            Err(e) => {
+                error!("Reduce failed: {}", e);
+                println!("{}, aborting", e);
+            }
         };
         let duration = start_time.elapsed();
         println!("Time taken for reduce: {:?}", duration);
     }
 
-    pub async fn filter(&self, dataset: &str, regex: &str) {
+    pub async fn pipeline(&self, pipeline: &Pipeline) {
         let start_time = Instant::now();
+        info!("Starting Pipeline on client side");
+        let pipeline_req = match pipeline.to_request() {
+            Ok(pipeline_req) => pipeline_req,
+            Err(e) => {
+                println!("Invalid pipeline: {}", e);
+                return;
+            }
+        };
+        for executable_name in pipeline.executables() {
+            if let Err(e) = stage_executable(executable_name).await {
+                println!("{}, aborting", e);
+                return;
+            }
+        }
+
+        let pipeline_req_buffer = SdfsCommand {
+            r#type: Some(Type::PipelineReq(pipeline_req)),
+        }
+        .encode_to_vec();
+
+        // Counters can outgrow a single read, the leader closes the stream after responding
+        let Some(res_buffer) = self.submit_job(&pipeline_req_buffer).await else {
+            error!("Unable to contact leader, aborting");
+            println!("Pipeline failed, aborting");
+            return;
+        };
+        match decode_job_response(&res_buffer) {
+            Ok(ack) => {
+                info!("Pipeline successful");
+                println!("{}", ack.message);
+                print_job_counters(&ack.counters.unwrap_or_default());
+            }
+            Err(e) => {
+                error!("Pipeline failed: {}", e);
+                println!("{}, aborting", e);
+            }
+        };
+        let duration = start_time.elapsed();
+        println!("Time taken for pipeline: {:?}", duration);
+    }
+
+    pub async fn job_status(&self, job_id: u64) {
+        let status_req_buffer = SdfsCommand {
+            r#type: Some(Type::JobStatusReq(JobStatusReq { job_id })),
+        }
+        .encode_to_vec();
+        let Some((_, res_buffer)) = self.leader_request(&status_req_buffer, true).await else {
+            error!("Unable to contact leader, aborting");
+            println!("Status failed, aborting");
+            return;
+        };
+        let Ok(SdfsCommand {
+            r#type: Some(Type::JobStatusRes(status)),
+        }) = SdfsCommand::decode(res_buffer.as_slice())
+        else {
+            println!("Unable to decode leader response, aborting");
+            return;
+        };
+        let state = match status.state() {
+            JobState::Unknown => {
+                println!("Job {} is unknown to the leader", job_id);
+                return;
+            }
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        };
+        println!("Job {}: {}", status.job_id, status.description);
+        println!("State: {}", state);
+        if !status.message.is_empty() {
+            println!("{}", status.message);
+        }
+        print_job_counters(&status.counters.unwrap_or_default());
+    }
+
+    /// Sends a request the leader answers with a StatRes or a Fail
+    async fn stat_request(&self, command: Type) -> Result<StatRes, String> {
+        let req_buffer = SdfsCommand {
+            r#type: Some(command),
+        }
+        .encode_to_vec();
+        let Some((_, res_buffer)) = self.leader_request(&req_buffer, true).await else {
+            error!("Unable to contact leader, aborting");
+            return Err("Unable to contact leader, aborting".to_string());
+        };
+        match SdfsCommand::decode(res_buffer.as_slice()) {
+            Ok(SdfsCommand {
+                r#type: Some(Type::StatRes(stat_res)),
+            }) => Ok(stat_res),
+            Ok(SdfsCommand {
+                r#type: Some(Type::Fail(fail)),
+            }) => Err(fail.message),
+            Ok(_) => Err("Unexpected response from leader".to_string()),
+            Err(e) => Err(format!("Unable to decode leader response: {}", e)),
+        }
+    }
+
+    pub async fn stat(&self, sdfs_file_name: &str) {
+        let stat_req = StatReq {
+            file_name: sdfs_file_name.to_string(),
+        };
+        match self.stat_request(Type::StatReq(stat_req)).await {
+            Ok(stat_res) => stat_res.files.iter().for_each(print_file_info),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    /// Lists every file in the system whose name starts with `prefix`
+    pub async fn list_all(&self, prefix: &str) {
+        let ls_all_req = LsAllReq {
+            prefix: prefix.to_string(),
+        };
+        let stat_res = match self.stat_request(Type::LsAllReq(ls_all_req)).await {
+            Ok(stat_res) => stat_res,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        for info in &stat_res.files {
+            let size = info
+                .digest
+                .as_ref()
+                .map_or("-".to_string(), |digest| digest.size.to_string());
+            println!(
+                "{:>12}  {:>19}  v{:<4}  {}",
+                size,
+                format_millis(info.modified_millis),
+                info.version,
+                info.file_name
+            );
+        }
+        println!("{} files", stat_res.files.len());
+    }
 
-        self.map("executors/selectmap.py", 7, "sqlfilter", dataset, &[regex])
+    /// Sends a request the leader answers with an Ack or a Fail and prints the answer
+    async fn leader_command(&self, command: Type) {
+        let req_buffer = SdfsCommand {
+            r#type: Some(command),
+        }
+        .encode_to_vec();
+        let Some((_, res_buffer)) = self.leader_request(&req_buffer, true).await else {
+            error!("Unable to contact leader, aborting");
+            println!("Unable to contact leader, aborting");
+            return;
+        };
+        match decode_job_response(&res_buffer) {
+            Ok(ack) => println!("{}", ack.message),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    pub async fn make_dir(&self, path: &str) {
+        let mkdir_req = MkdirReq {
+            path: path.to_string(),
+        };
+        self.leader_command(Type::MkdirReq(mkdir_req)).await;
+    }
+
+    pub async fn remove_dir(&self, path: &str) {
+        let rmdir_req = RmdirReq {
+            path: path.to_string(),
+        };
+        self.leader_command(Type::RmdirReq(rmdir_req)).await;
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) {
+        let rename_req = RenameReq {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        self.leader_command(Type::RenameReq(rename_req)).await;
+    }
+
+    pub async fn set_replication(&self, sdfs_file_name: &str, replicas: u32) {
+        let set_rep_req = SetRepReq {
+            file_name: sdfs_file_name.to_string(),
+            replicas,
+        };
+        self.leader_command(Type::SetRepReq(set_rep_req)).await;
+    }
+
+    pub async fn convert_erasure(&self, sdfs_file_name: &str, layout: ErasureLayout) {
+        let erasure_req = ErasureReq {
+            file_name: sdfs_file_name.to_string(),
+            erasure: Some(layout),
+        };
+        self.leader_command(Type::ErasureReq(erasure_req)).await;
+    }
+
+    pub async fn decommission(&self, machine: &str) {
+        let decommission_req = DecommissionReq {
+            machine: machine.to_string(),
+        };
+        println!("Draining {}, this can take a while", machine);
+        self.leader_command(Type::DecommissionReq(decommission_req))
             .await;
-        self.reduce(
-            "executors/selectreduce.py",
-            7,
-            "sqlfilter",
-            &format!("{dataset}_filter"),
-            true,
-        )
-        .await;
+    }
+
+    pub async fn filter(&self, dataset: &str, regex: &str) {
+        let start_time = Instant::now();
+
+        let pipeline = Pipeline::new("sqlfilter")
+            .stage(
+                Stage::map("select", "executors/selectmap.py")
+                    .input(dataset)
+                    .args([regex]),
+            )
+            .stage(
+                Stage::reduce("collect", "executors/selectreduce.py")
+                    .input("select")
+                    .output(&format!("{dataset}_filter")),
+            );
+        self.pipeline(&pipeline).await;
 
         let duration = start_time.elapsed();
         println!("Time taken for filter: {:?}", duration);
@@ -523,20 +1327,26 @@ impl Client {
     pub async fn join(&self, d1: &str, d2: &str, d1_field: &str, d2_field: &str) {
         let start_time = Instant::now();
 
-        self.map("executors/joinmap.py", 7, "sqljoin", d1, &[d1_field])
-            .await;
-        self.map("executors/joinmap.py", 7, "sqljoin", d2, &[d2_field])
-            .await;
-        self.reduce(
-            "executors/joinreduce.py",
-            7,
-            "sqljoin",
-            &format!("{d1}_{d2}_join"),
-            true,
-        )
-        .await;
+        let pipeline = Pipeline::new("sqljoin")
+            .stage(
+                Stage::map("left", "executors/joinmap.py")
+                    .input(d1)
+                    .args([d1_field]),
+            )
+            .stage(
+                Stage::map("right", "executors/joinmap.py")
+                    .input(d2)
+                    .args([d2_field]),
+            )
+            .stage(
+                Stage::reduce("join", "executors/joinreduce.py")
+                    .input("left")
+                    .input("right")
+                    .output(&format!("{d1}_{d2}_join")),
+            );
+        self.pipeline(&pipeline).await;
 
         let duration = start_time.elapsed();
-        println!("Time taken for filter: {:?}", duration);
+        println!("Time taken for join: {:?}", duration);
     }
 }
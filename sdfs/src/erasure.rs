@@ -0,0 +1,501 @@
+use crate::helpers::Digester;
+use crate::message_types::get_req::Range;
+use crate::message_types::{
+    sdfs_command::Type, Ack, ByteRange, ErasureLayout, GetReq, PutReq, SdfsCommand,
+};
+use futures::future::join_all;
+use prost::Message;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::iter::zip;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// Name fragment `index` of an erasure-coded file is stored under
+pub fn fragment_name(file_name: &str, index: usize) -> String {
+    format!("{file_name}.ec{index}")
+}
+
+/// Splits a fragment name into the file name and the fragment index
+pub fn split_fragment(name: &str) -> Option<(&str, usize)> {
+    let (file_name, index) = name.rsplit_once(".ec")?;
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((file_name, index.parse().ok()?))
+}
+
+/// Parses a `<data>+<parity>` layout such as `6+3`
+pub fn parse_layout(spec: &str) -> Option<ErasureLayout> {
+    let (data, parity) = spec.split_once('+')?;
+    let layout = ErasureLayout {
+        data_shards: data.parse().ok()?,
+        parity_shards: parity.parse().ok()?,
+        size: 0,
+        block_size: BLOCK_SIZE,
+    };
+    codec(&layout).ok()?;
+    Some(layout)
+}
+
+pub fn fragments(layout: &ErasureLayout) -> usize {
+    (layout.data_shards + layout.parity_shards) as usize
+}
+
+fn codec(layout: &ErasureLayout) -> Result<ReedSolomon, String> {
+    ReedSolomon::new(layout.data_shards as usize, layout.parity_shards as usize)
+        .map_err(|e| format!("Invalid erasure layout: {}", e))
+}
+
+// Bytes each fragment holds of a stripe, files are coded one stripe at a time
+const BLOCK_SIZE: u64 = 1 << 20;
+
+/// Bytes each fragment holds of every stripe but the last
+fn block_size(layout: &ErasureLayout) -> u64 {
+    match layout.block_size {
+        // Files coded before they were striped are a single stripe
+        0 => layout.size.div_ceil(layout.data_shards as u64).max(1),
+        block_size => block_size,
+    }
+}
+
+fn stripe_size(layout: &ErasureLayout) -> u64 {
+    block_size(layout) * layout.data_shards as u64
+}
+
+/// Number of stripes a file is coded in, an empty file still has one
+pub fn stripes(layout: &ErasureLayout) -> u64 {
+    layout.size.div_ceil(stripe_size(layout)).max(1)
+}
+
+/// Bytes of the original file in a stripe
+pub fn stripe_len(layout: &ErasureLayout, stripe: u64) -> usize {
+    let start = stripe * stripe_size(layout);
+    layout.size.saturating_sub(start).min(stripe_size(layout)) as usize
+}
+
+// Every block has at least one byte so an empty fragment always means a missing one
+fn block_len(data_len: usize, data_shards: usize) -> usize {
+    data_len.div_ceil(data_shards).max(1)
+}
+
+/// Splits a stripe of a file into its data blocks and computes the parity blocks
+pub fn encode(data: &[u8], layout: &ErasureLayout) -> Result<Vec<Vec<u8>>, String> {
+    let codec = codec(layout)?;
+    let block_len = block_len(data.len(), layout.data_shards as usize);
+    let mut shards: Vec<Vec<u8>> = (0..fragments(layout))
+        .map(|index| {
+            let start = (index * block_len).min(data.len());
+            let end = ((index + 1) * block_len).min(data.len());
+            let mut shard = data[start..end].to_vec();
+            shard.resize(block_len, 0);
+            shard
+        })
+        .collect();
+    codec.encode(&mut shards).map_err(|e| e.to_string())?;
+    Ok(shards)
+}
+
+/// Rebuilds a stripe of the original file from any `data_shards` of its blocks
+pub fn decode(
+    mut shards: Vec<Option<Vec<u8>>>,
+    layout: &ErasureLayout,
+    stripe: u64,
+) -> Result<Vec<u8>, String> {
+    codec(layout)?
+        .reconstruct_data(&mut shards)
+        .map_err(|e| e.to_string())?;
+    let mut data: Vec<u8> = shards
+        .into_iter()
+        .take(layout.data_shards as usize)
+        .flatten()
+        .flatten()
+        .collect();
+    data.truncate(stripe_len(layout, stripe));
+    Ok(data)
+}
+
+/// Recomputes a single lost block of a stripe from any `data_shards` of the others
+pub fn rebuild(
+    mut shards: Vec<Option<Vec<u8>>>,
+    layout: &ErasureLayout,
+    index: usize,
+) -> Result<Vec<u8>, String> {
+    codec(layout)?
+        .reconstruct(&mut shards)
+        .map_err(|e| e.to_string())?;
+    shards
+        .into_iter()
+        .nth(index)
+        .flatten()
+        .ok_or("Fragment index out of range".to_string())
+}
+
+/// A fragment streamed block by block to the server that stores it, through its regular
+/// put path
+pub struct FragmentUpload {
+    stream: TcpStream,
+    server_address: String,
+}
+
+impl FragmentUpload {
+    /// Starts the put, staged under `staging` if set
+    pub async fn start(
+        machine: &str,
+        fragment_name: &str,
+        staging: &str,
+    ) -> Result<FragmentUpload, String> {
+        let server_address = format!("{machine}:56552");
+        let mut stream = TcpStream::connect(&server_address)
+            .await
+            .map_err(|e| format!("Unable to connect to {}: {}", server_address, e))?;
+        let put_req = SdfsCommand {
+            r#type: Some(Type::PutReq(PutReq {
+                file_name: fragment_name.to_string(),
+                staging: staging.to_string(),
+                ..Default::default()
+            })),
+        }
+        .encode_to_vec();
+        stream
+            .write_all(&put_req)
+            .await
+            .map_err(|e| format!("Unable to send put to {}: {}", server_address, e))?;
+        let mut ack_buffer = [0; 1024];
+        let n = stream
+            .read(&mut ack_buffer)
+            .await
+            .map_err(|e| format!("No put ack from {}: {}", server_address, e))?;
+        Ack::decode(&ack_buffer[..n])
+            .map_err(|e| format!("Unable to decode put ack from {}: {}", server_address, e))?;
+        Ok(FragmentUpload {
+            stream,
+            server_address,
+        })
+    }
+
+    pub async fn write(&mut self, block: &[u8]) -> Result<(), String> {
+        self.stream
+            .write_all(block)
+            .await
+            .map_err(|e| format!("Unable to send fragment to {}: {}", self.server_address, e))
+    }
+
+    /// Ends the fragment and waits until the server stored it
+    pub async fn finish(mut self) -> Result<(), String> {
+        let _ = self.stream.shutdown().await;
+        let mut stored_buffer = Vec::new();
+        let _ = self.stream.read_to_end(&mut stored_buffer).await;
+        if stored_buffer.is_empty() || Ack::decode(stored_buffer.as_slice()).is_err() {
+            return Err(format!(
+                "{} did not store the fragment",
+                self.server_address
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads a file of `layout.size` bytes from `source` a stripe at a time and streams fragment
+/// i to `machines[i]`, staged under `staging` if set. Returns the machines that stored their
+/// fragment, empty for those that did not, once every stripe is sent.
+pub async fn put_file<R: AsyncRead + Unpin>(
+    source: &mut R,
+    file_name: &str,
+    machines: &[String],
+    staging: &str,
+    layout: &ErasureLayout,
+    digester: &mut Digester,
+) -> Result<Vec<String>, String> {
+    let starts = machines
+        .iter()
+        .enumerate()
+        .map(|(index, machine)| async move {
+            let fragment_name = fragment_name(file_name, index);
+            FragmentUpload::start(machine, &fragment_name, staging)
+                .await
+                .inspect_err(|e| warn!("Unable to store {}: {}", fragment_name, e))
+                .ok()
+        });
+    let mut uploads: Vec<Option<FragmentUpload>> = join_all(starts).await;
+    for stripe in 0..stripes(layout) {
+        if uploads.iter().flatten().count() < layout.data_shards as usize {
+            return Err("Too few fragment holders are storing their fragment".to_string());
+        }
+        let mut data = vec![0; stripe_len(layout, stripe)];
+        source
+            .read_exact(&mut data)
+            .await
+            .map_err(|e| format!("Unable to read stripe {}: {}", stripe, e))?;
+        digester.update(&data);
+        let shards = encode(&data, layout)?;
+        let writes = zip(&mut uploads, &shards).map(|(upload, shard)| async move {
+            let Some(writer) = upload else {
+                return;
+            };
+            if let Err(e) = writer.write(shard).await {
+                warn!("{}", e);
+                *upload = None;
+            }
+        });
+        join_all(writes).await;
+    }
+    let finishes = zip(uploads, machines).map(|(upload, machine)| async move {
+        match upload?.finish().await {
+            Ok(()) => Some(machine.clone()),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    });
+    let stored = join_all(finishes).await;
+    Ok(stored.into_iter().map(Option::unwrap_or_default).collect())
+}
+
+/// Opens a fragment for reading from `offset` on
+async fn open_fragment(machine: &str, fragment_name: &str, offset: u64) -> Option<TcpStream> {
+    let server_address = format!("{machine}:56552");
+    let Ok(mut stream) = TcpStream::connect(&server_address).await else {
+        warn!("Unable to connect to fragment holder {}", server_address);
+        return None;
+    };
+    let get_req = SdfsCommand {
+        r#type: Some(Type::GetReq(GetReq {
+            file_name: fragment_name.to_string(),
+            range: Some(Range::Bytes(ByteRange {
+                offset,
+                ..Default::default()
+            })),
+            ..Default::default()
+        })),
+    }
+    .encode_to_vec();
+    stream.write_all(&get_req).await.ok()?;
+    Some(stream)
+}
+
+/// Reads an erasure-coded file a stripe at a time from the holders of its fragments,
+/// `machines[i]` holds fragment i or is empty if it is lost
+pub struct StripeReader<'a> {
+    file_name: &'a str,
+    machines: &'a [String],
+    layout: &'a ErasureLayout,
+    skip: Option<usize>, // fragment not to read, such as the one being rebuilt
+    readers: Vec<Option<TcpStream>>,
+    failed: Vec<bool>,
+}
+
+impl<'a> StripeReader<'a> {
+    pub fn new(
+        file_name: &'a str,
+        machines: &'a [String],
+        layout: &'a ErasureLayout,
+        skip: Option<usize>,
+    ) -> Self {
+        let count = fragments(layout).min(machines.len());
+        StripeReader {
+            file_name,
+            machines,
+            layout,
+            skip,
+            readers: (0..count).map(|_| None).collect(),
+            failed: vec![false; count],
+        }
+    }
+
+    /// Blocks of a stripe from any `data_shards` fragments. A fragment that fails is not
+    /// read again and the next one takes its place from this stripe on.
+    pub async fn read_stripe(&mut self, stripe: u64) -> Result<Vec<Option<Vec<u8>>>, String> {
+        let data_shards = self.layout.data_shards as usize;
+        let block_len = block_len(stripe_len(self.layout, stripe), data_shards);
+        let offset = stripe * block_size(self.layout);
+        let mut shards = vec![None; fragments(self.layout)];
+        let mut fetched = 0;
+        for (index, shard) in shards.iter_mut().enumerate().take(self.readers.len()) {
+            if fetched == data_shards {
+                break;
+            }
+            if self.skip == Some(index) || self.failed[index] || self.machines[index].is_empty() {
+                continue;
+            }
+            let fragment_name = fragment_name(self.file_name, index);
+            if self.readers[index].is_none() {
+                let machine = &self.machines[index];
+                self.readers[index] = open_fragment(machine, &fragment_name, offset).await;
+            }
+            let Some(reader) = &mut self.readers[index] else {
+                self.failed[index] = true;
+                continue;
+            };
+            let mut block = vec![0; block_len];
+            if let Err(e) = reader.read_exact(&mut block).await {
+                warn!(
+                    "Unable to read {} from {}: {}",
+                    fragment_name, self.machines[index], e
+                );
+                self.readers[index] = None;
+                self.failed[index] = true;
+                continue;
+            }
+            *shard = Some(block);
+            fetched += 1;
+        }
+        if fetched < data_shards {
+            return Err(format!(
+                "Only {} of the {} fragments needed are reachable",
+                fetched, data_shards
+            ));
+        }
+        Ok(shards)
+    }
+}
+
+/// Decodes an erasure-coded file into `sink` a stripe at a time
+pub async fn read_file<W: AsyncWrite + Unpin>(
+    file_name: &str,
+    machines: &[String],
+    layout: &ErasureLayout,
+    sink: &mut W,
+) -> Result<(), String> {
+    let mut reader = StripeReader::new(file_name, machines, layout, None);
+    for stripe in 0..stripes(layout) {
+        let data = decode(reader.read_stripe(stripe).await?, layout, stripe)?;
+        sink.write_all(&data)
+            .await
+            .map_err(|e| format!("Unable to write decoded data: {}", e))?;
+    }
+    sink.flush()
+        .await
+        .map_err(|e| format!("Unable to write decoded data: {}", e))
+}
+
+/// GETs an erasure-coded file into a local file
+pub async fn get_file(
+    file_name: &str,
+    machines: &[String],
+    layout: &ErasureLayout,
+    local_file_name: &str,
+) -> Result<(), String> {
+    let mut file = fs::File::create(local_file_name)
+        .await
+        .map_err(|e| format!("Unable to write local file: {}", e))?;
+    read_file(file_name, machines, layout, &mut file).await?;
+    info!("Decoded {} from its fragments", file_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(data_shards: u32, parity_shards: u32, size: u64, block_size: u64) -> ErasureLayout {
+        ErasureLayout {
+            data_shards,
+            parity_shards,
+            size,
+            block_size,
+        }
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Encodes `data` stripe by stripe and decodes it again with `lost` fragments missing
+    fn round_trip(data: &[u8], layout: &ErasureLayout, lost: &[usize]) -> Vec<u8> {
+        let stripe_size = stripe_size(layout) as usize;
+        let mut decoded = Vec::new();
+        for stripe in 0..stripes(layout) {
+            let start = stripe as usize * stripe_size;
+            let chunk = &data[start..start + stripe_len(layout, stripe)];
+            let shards: Vec<_> = encode(chunk, layout)
+                .unwrap()
+                .into_iter()
+                .enumerate()
+                .map(|(index, shard)| (!lost.contains(&index)).then_some(shard))
+                .collect();
+            decoded.extend(decode(shards, layout, stripe).unwrap());
+        }
+        decoded
+    }
+
+    #[test]
+    fn layouts_are_parsed() {
+        let parsed = parse_layout("6+3").unwrap();
+        assert_eq!((parsed.data_shards, parsed.parity_shards), (6, 3));
+        assert_eq!(parsed.block_size, BLOCK_SIZE);
+        assert!(parse_layout("6").is_none());
+        assert!(parse_layout("a+3").is_none());
+        assert!(parse_layout("0+3").is_none());
+    }
+
+    #[test]
+    fn fragment_names_split_back() {
+        assert_eq!(
+            split_fragment(&fragment_name("a/b.txt", 12)),
+            Some(("a/b.txt", 12))
+        );
+        assert_eq!(split_fragment("x.ec1.ec2"), Some(("x.ec1", 2)));
+        assert_eq!(split_fragment("x.ec"), None);
+        assert_eq!(split_fragment("x.ecb"), None);
+        assert_eq!(split_fragment("plain"), None);
+    }
+
+    #[test]
+    fn files_are_split_into_stripes() {
+        let striped = layout(4, 2, 100, 10);
+        assert_eq!(stripes(&striped), 3);
+        assert_eq!(stripe_len(&striped, 0), 40);
+        assert_eq!(stripe_len(&striped, 2), 20);
+        // A file coded before striping is one stripe
+        let legacy = layout(4, 2, 100, 0);
+        assert_eq!(stripes(&legacy), 1);
+        assert_eq!(stripe_len(&legacy, 0), 100);
+        let empty = layout(4, 2, 0, 10);
+        assert_eq!(stripes(&empty), 1);
+        assert_eq!(stripe_len(&empty, 0), 0);
+    }
+
+    #[test]
+    fn stripes_decode_with_lost_fragments() {
+        let data = sample(1000);
+        let striped = layout(4, 2, data.len() as u64, 64);
+        assert_eq!(round_trip(&data, &striped, &[]), data);
+        assert_eq!(round_trip(&data, &striped, &[0, 3]), data);
+        assert_eq!(round_trip(&data, &striped, &[4, 5]), data);
+        let legacy = layout(4, 2, data.len() as u64, 0);
+        assert_eq!(round_trip(&data, &legacy, &[1, 2]), data);
+        assert_eq!(
+            round_trip(&[], &layout(4, 2, 0, 64), &[0]),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn too_many_lost_fragments_fail_to_decode() {
+        let data = sample(100);
+        let striped = layout(4, 2, data.len() as u64, 64);
+        let shards: Vec<_> = encode(&data, &striped)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(index, shard)| (index > 2).then_some(shard))
+            .collect();
+        assert!(decode(shards, &striped, 0).is_err());
+    }
+
+    #[test]
+    fn lost_blocks_are_rebuilt() {
+        let data = sample(300);
+        let striped = layout(3, 2, data.len() as u64, 128);
+        let shards = encode(&data, &striped).unwrap();
+        for index in 0..fragments(&striped) {
+            let mut survivors: Vec<_> = shards.iter().cloned().map(Some).collect();
+            survivors[index] = None;
+            survivors[(index + 1) % 5] = None;
+            assert_eq!(rebuild(survivors, &striped, index).unwrap(), shards[index]);
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use crate::message_types::{ExecutorLimits, TaskFailure};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::time::{timeout, Duration};
+use tracing::{info, instrument, warn};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 600;
+pub const DEFAULT_MEMORY_BYTES: u64 = 2 << 30;
+pub const DEFAULT_OUTPUT_BYTES: u64 = 1 << 30;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Executors report custom counters on stderr as `sdfs:counter:<name>:<amount>` lines
+const COUNTER_MARKER: &[u8] = b"sdfs:counter:";
+
+// Python module executors import to name key files and decode keys
+const KEY_HELPER: &str = include_str!("../executors/sdfs_keys.py");
+
+static CGROUP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes in `mib` MiB, None if they do not fit in a u64
+pub fn mebibytes(mib: u64) -> Option<u64> {
+    mib.checked_mul(1 << 20)
+}
+
+pub fn default_limits() -> ExecutorLimits {
+    ExecutorLimits {
+        timeout_secs: DEFAULT_TIMEOUT_SECS,
+        memory_bytes: DEFAULT_MEMORY_BYTES,
+        output_bytes: DEFAULT_OUTPUT_BYTES,
+    }
+}
+
+#[derive(Debug)]
+pub struct ExecutorOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ExecutorError {
+    Spawn(io::Error),
+    Timeout(u64),
+    Memory(u64),
+    Output(u64),
+}
+
+impl ExecutorError {
+    pub fn reason(&self) -> TaskFailure {
+        match self {
+            ExecutorError::Spawn(_) => TaskFailure::Executor,
+            ExecutorError::Timeout(_) => TaskFailure::Timeout,
+            ExecutorError::Memory(_) => TaskFailure::Memory,
+            ExecutorError::Output(_) => TaskFailure::Output,
+        }
+    }
+}
+
+impl std::fmt::Display for ExecutorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecutorError::Spawn(e) => write!(f, "unable to start executor: {}", e),
+            ExecutorError::Timeout(secs) => write!(f, "executor ran longer than {}s", secs),
+            ExecutorError::Memory(bytes) => write!(f, "executor used more than {} bytes", bytes),
+            ExecutorError::Output(bytes) => {
+                write!(f, "executor output exceeded {} bytes", bytes)
+            }
+        }
+    }
+}
+
+/// Places the key helper next to the executors so `import sdfs_keys` works
+pub async fn install_key_helper() {
+    if let Err(e) = fs::write("/home/sdfs/sdfs_keys.py", KEY_HELPER).await {
+        warn!("Executor: unable to install the key helper: {}", e);
+    }
+}
+
+/// Sums the custom counters an executor reported on stderr
+pub fn parse_counters(stderr: &[u8]) -> HashMap<String, u64> {
+    let mut counters: HashMap<String, u64> = HashMap::new();
+    for line in stderr.split(|b| *b == b'\n') {
+        let Some(counter) = line.strip_prefix(COUNTER_MARKER) else {
+            continue;
+        };
+        let Some((name, amount)) = std::str::from_utf8(counter)
+            .ok()
+            .and_then(|counter| counter.trim_end().rsplit_once(':'))
+        else {
+            warn!("Executor: malformed counter line {:?}", line);
+            continue;
+        };
+        let Ok(amount) = amount.parse::<u64>() else {
+            warn!("Executor: malformed amount for counter {}", name);
+            continue;
+        };
+        let total = counters.entry(name.to_string()).or_default();
+        *total = total.saturating_add(amount);
+    }
+    counters
+}
+
+/// Human readable description of a failure reported by a worker
+pub fn describe_failure(reason: TaskFailure) -> &'static str {
+    match reason {
+        TaskFailure::None => "no failure",
+        TaskFailure::Executor => "executor could not be run",
+        TaskFailure::Timeout => "executor exceeded the wall-clock limit",
+        TaskFailure::Memory => "executor exceeded the memory limit",
+        TaskFailure::Output => "executor exceeded the output size limit",
+        TaskFailure::StaleEpoch => "worker follows a newer leader",
+    }
+}
+
+/// Limit violations are deterministic and a replaced leader is fenced off everywhere,
+/// retrying the task elsewhere will not help
+pub fn is_fatal_failure(reason: TaskFailure) -> bool {
+    matches!(
+        reason,
+        TaskFailure::Timeout | TaskFailure::Memory | TaskFailure::Output | TaskFailure::StaleEpoch
+    )
+}
+
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    async fn create(memory_bytes: u64) -> Option<Cgroup> {
+        // Children of the root only get the controllers it delegates to its subtree
+        let controllers = fs::read_to_string(format!("{CGROUP_ROOT}/cgroup.subtree_control"))
+            .await
+            .ok()?;
+        if !controllers.split_whitespace().any(|c| c == "memory") {
+            return None;
+        }
+        let id = CGROUP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = PathBuf::from(format!(
+            "{CGROUP_ROOT}/sdfs-executor-{}-{id}",
+            std::process::id()
+        ));
+        fs::create_dir(&path).await.ok()?;
+        let cgroup = Cgroup { path };
+        if fs::write(cgroup.path.join("memory.max"), memory_bytes.to_string())
+            .await
+            .is_err()
+        {
+            cgroup.remove().await;
+            return None;
+        }
+        // Keep the executor from dodging the limit by swapping
+        let _ = fs::write(cgroup.path.join("memory.swap.max"), "0").await;
+        Some(cgroup)
+    }
+
+    fn procs_path(&self) -> Option<CString> {
+        CString::new(self.path.join("cgroup.procs").into_os_string().into_vec()).ok()
+    }
+
+    async fn oom_killed(&self) -> bool {
+        let Ok(events) = fs::read_to_string(self.path.join("memory.events")).await else {
+            return false;
+        };
+        events
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .any(|(name, count)| name == "oom_kill" && count.trim() != "0")
+    }
+
+    async fn remove(self) {
+        if let Err(e) = fs::remove_dir(&self.path).await {
+            warn!("Executor: unable to remove cgroup {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// The resource type differs between libc implementations, glibc uses an unsigned enum
+fn set_rlimit(resource: libc::c_int, limit: u64) -> io::Result<()> {
+    let rlim = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the struct we pass in and is async-signal-safe
+    if unsafe { libc::setrlimit(resource as _, &rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves the calling process into the cgroup whose `cgroup.procs` is at `procs`
+fn join_cgroup(procs: &CStr) -> io::Result<()> {
+    // SAFETY: open, write and close are async-signal-safe and only read the buffers we pass
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Writing 0 moves the writer itself
+        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written != 1 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Reads up to `limit` bytes, 0 meaning no limit, and whether the reader had more. With
+/// `drain` the rest is read and dropped, so the writer never blocks on a full pipe.
+async fn read_limited<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    limit: u64,
+    drain: bool,
+) -> (Vec<u8>, bool) {
+    let mut buf = Vec::new();
+    let Some(mut reader) = reader else {
+        return (buf, false);
+    };
+    if limit == 0 {
+        let _ = reader.read_to_end(&mut buf).await;
+        return (buf, false);
+    }
+    let mut limited = reader.take(limit + 1);
+    let _ = limited.read_to_end(&mut buf).await;
+    let exceeded = buf.len() as u64 > limit;
+    if exceeded && drain {
+        let _ = tokio::io::copy(&mut limited.into_inner(), &mut tokio::io::sink()).await;
+    }
+    buf.truncate(limit as usize);
+    (buf, exceeded)
+}
+
+/// Collects a child's stdout and stderr, keeping at most `limit` bytes of each. A child that
+/// writes more than that to stdout is killed and reported, stderr past the limit is dropped.
+async fn collect_output(child: &mut Child, limit: u64) -> (Vec<u8>, Vec<u8>, bool) {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let read_stdout = async {
+        let (stdout, exceeded) = read_limited(stdout, limit, false).await;
+        if exceeded {
+            let _ = child.start_kill();
+        }
+        (stdout, exceeded)
+    };
+    let ((stdout, exceeded), (stderr, _)) =
+        tokio::join!(read_stdout, read_limited(stderr, limit, true));
+    (stdout, stderr, exceeded)
+}
+
+/// Runs a python executor stored in the local SDFS directory under the given limits
+#[instrument(name = "Executor run", level = "trace")]
+pub async fn run_executable(
+    executable: &str,
+    args: &[&str],
+    limits: &ExecutorLimits,
+) -> Result<ExecutorOutput, ExecutorError> {
+    let cgroup = if limits.memory_bytes > 0 {
+        Cgroup::create(limits.memory_bytes).await
+    } else {
+        None
+    };
+    // Fall back to an address space rlimit when cgroups are not available
+    let rlimit_memory = if cgroup.is_none() {
+        limits.memory_bytes
+    } else {
+        0
+    };
+    let output_bytes = limits.output_bytes;
+    let cgroup_procs = cgroup.as_ref().and_then(Cgroup::procs_path);
+
+    let mut command = Command::new("python3");
+    command
+        .arg(format!("/home/sdfs/{executable}"))
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // SAFETY: the hook only makes async-signal-safe calls on data prepared before the fork
+    unsafe {
+        command.pre_exec(move || {
+            // Joining before exec means the executor never runs outside its memory limit
+            if let Some(procs) = &cgroup_procs {
+                join_cgroup(procs)?;
+            }
+            if rlimit_memory > 0 {
+                set_rlimit(libc::RLIMIT_AS as libc::c_int, rlimit_memory)?;
+            }
+            if output_bytes > 0 {
+                set_rlimit(libc::RLIMIT_FSIZE as libc::c_int, output_bytes)?;
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            if let Some(cgroup) = cgroup {
+                cgroup.remove().await;
+            }
+            return Err(ExecutorError::Spawn(e));
+        }
+    };
+    let run = async {
+        let (stdout, stderr, stdout_exceeded) = collect_output(&mut child, output_bytes).await;
+        let status = child.wait().await;
+        (status, stdout, stderr, stdout_exceeded)
+    };
+
+    let outcome = if limits.timeout_secs > 0 {
+        timeout(Duration::from_secs(limits.timeout_secs), run).await
+    } else {
+        Ok(run.await)
+    };
+
+    let result = match outcome {
+        Err(_) => {
+            warn!("Executor: {} timed out, killing it", executable);
+            let _ = child.kill().await;
+            Err(ExecutorError::Timeout(limits.timeout_secs))
+        }
+        Ok((Err(e), _, _, _)) => Err(ExecutorError::Spawn(e)),
+        Ok((Ok(_), _, _, true)) => Err(ExecutorError::Output(output_bytes)),
+        Ok((Ok(status), stdout, stderr, false)) => {
+            let oom_killed = match &cgroup {
+                Some(cgroup) => cgroup.oom_killed().await,
+                None => {
+                    rlimit_memory > 0
+                        && !status.success()
+                        && stderr.windows(11).any(|w| w == b"MemoryError")
+                }
+            };
+            if oom_killed {
+                Err(ExecutorError::Memory(limits.memory_bytes))
+            } else if status.signal() == Some(libc::SIGXFSZ) {
+                Err(ExecutorError::Output(output_bytes))
+            } else {
+                info!("Executor: {} exited with {}", executable, status);
+                Ok(ExecutorOutput {
+                    status,
+                    stdout,
+                    stderr,
+                })
+            }
+        }
+    };
+
+    if let Some(cgroup) = cgroup {
+        cgroup.remove().await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_summed_by_name() {
+        let stderr =
+            b"sdfs:counter:words:3\nTraceback\nsdfs:counter:words:4\nsdfs:counter:lines:1\n";
+        let counters = parse_counters(stderr);
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters["words"], 7);
+        assert_eq!(counters["lines"], 1);
+    }
+
+    #[test]
+    fn counter_names_may_contain_colons() {
+        let counters = parse_counters(b"sdfs:counter:a:b:5\r\n");
+        assert_eq!(counters["a:b"], 5);
+    }
+
+    #[test]
+    fn malformed_counter_lines_are_skipped() {
+        let stderr = b"sdfs:counter:missing\nsdfs:counter:negative:-1\nsdfs:counter:\xff:1\n";
+        assert!(parse_counters(stderr).is_empty());
+    }
+
+    fn spawn(script: &str) -> Child {
+        Command::new("sh")
+            .args(["-c", script])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stdout_past_the_limit_kills_the_child() {
+        let mut child = spawn("yes");
+        let collected = timeout(Duration::from_secs(10), collect_output(&mut child, 4096));
+        let (stdout, _, exceeded) = collected.await.unwrap();
+        assert!(exceeded);
+        assert_eq!(stdout.len(), 4096);
+        assert!(!child.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn stderr_past_the_limit_is_drained() {
+        // More than a pipe holds, the child would block if stderr stopped being read
+        let mut child = spawn("head -c 1000000 /dev/zero >&2; echo done");
+        let collected = timeout(Duration::from_secs(10), collect_output(&mut child, 4096));
+        let (stdout, stderr, exceeded) = collected.await.unwrap();
+        assert!(!exceeded);
+        assert_eq!(stdout, b"done\n");
+        assert_eq!(stderr.len(), 4096);
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[test]
+    fn counters_saturate_instead_of_overflowing() {
+        let stderr = format!("sdfs:counter:big:{}\nsdfs:counter:big:1\n", u64::MAX);
+        assert_eq!(parse_counters(stderr.as_bytes())["big"], u64::MAX);
+    }
+}
@@ -1,22 +1,106 @@
-use crate::message_types::{sdfs_command::Type, GetReq, SdfsCommand};
+use crate::message_types::{
+    get_req::Range, sdfs_command::Type, ByteRange, FileDigest, GetReq, LineRange, RepairReq,
+    SdfsCommand, VersionReq, VersionRes,
+};
+use futures::stream::{self, FuturesUnordered, StreamExt};
 use prost::Message;
+use std::io::SeekFrom;
 use std::ops::Deref;
 use tokio::fs;
-use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
 use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Duration, Instant};
 use tracing::{error, info, instrument, warn};
 
+// Servers answer leader puts with this once a newer leader has contacted them
+pub const STALE_EPOCH: &[u8] = b"STALE_EPOCH";
+
+// How long a read keeps hearing versions from replicas once it has its quorum
+const VERSION_GRACE: Duration = Duration::from_millis(200);
+
+// Files larger than this are fetched in ranges of this size from several replicas at once
+const PARALLEL_CHUNK: u64 = 16 * 1024 * 1024;
+
+// File data moves between disks and sockets in chunks of this size, whatever its contents
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
 #[derive(Debug, Clone)]
 pub struct FileKey {
     name: String,
 }
 
+fn is_key_safe(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-'
+}
+
+/// Decodes a key encoded by `sdfs_keys.encode`, which percent-encodes every byte outside
+/// `[A-Za-z0-9.-]`. Anything that encoding would not have produced is rejected.
+pub fn decode_key(encoded: &str) -> Option<Vec<u8>> {
+    let bytes = encoded.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = encoded.get(idx + 1..idx + 3)?;
+            if !hex
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(&b))
+            {
+                return None;
+            }
+            let byte = u8::from_str_radix(hex, 16).ok()?;
+            if is_key_safe(byte) {
+                return None;
+            }
+            key.push(byte);
+            idx += 3;
+        } else if is_key_safe(bytes[idx]) {
+            key.push(bytes[idx]);
+            idx += 1;
+        } else {
+            return None;
+        }
+    }
+    Some(key)
+}
+
 impl FileKey {
-    pub fn new(file_prefix: &str, key: &str) -> FileKey {
+    /// Key file of an already encoded key
+    pub fn new(file_prefix: &str, encoded_key: &str) -> FileKey {
         FileKey {
-            name: format!("{file_prefix}_{key}"),
+            name: format!("{file_prefix}_{encoded_key}"),
         }
     }
+
+    /// Splits a key file name into its prefix and encoded key
+    pub fn split(file_name: &str) -> Option<(&str, &str)> {
+        let (prefix, encoded_key) = file_name.rsplit_once('_')?;
+        decode_key(encoded_key)?;
+        Some((prefix, encoded_key))
+    }
+
+    /// Prefix the key files of a single maple job are written under
+    pub fn namespace(file_prefix: &str, job_id: u64) -> String {
+        format!("{file_prefix}.j{job_id}")
+    }
+
+    /// Prefix one task writes its local key files under, apart from other tasks of its job
+    pub fn task(namespace: &str, task_id: u64) -> String {
+        format!("{namespace}.t{task_id}")
+    }
+
+    /// Job that wrote a namespaced key file, if the name has the shape `prefix.j<id>_key`
+    pub fn job_id(file_name: &str) -> Option<u64> {
+        let (namespace, _) = FileKey::split(file_name)?;
+        let (_, id) = namespace.rsplit_once(".j")?;
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        id.parse().ok()
+    }
 }
 
 impl Deref for FileKey {
@@ -33,45 +117,380 @@ impl std::fmt::Display for FileKey {
     }
 }
 
+/// What a GET has written so far, an interrupted GET resumes after it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Received {
+    pub bytes: u64,
+    pub lines: u64,
+    pub partial: u64, // bytes written of a line whose newline has not arrived yet
+}
+
+/// Copies `reader` into `writer` in `CHUNK_SIZE` chunks through a single buffer, whatever the
+/// data holds. Stored files sent to a socket go through `send_span` instead.
+pub async fn copy_chunks<R, W>(reader: &mut R, writer: &mut W) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, reader);
+    tokio::io::copy_buf(&mut reader, writer).await
+}
+
+/// Writes a GET stream to `buffer` chunk by chunk, counting what arrived in `received`.
+/// The first `skip` bytes were already written by a replica that broke off mid-line.
 #[instrument(name = "Buf write helper", level = "trace")]
 pub async fn write_to_buf<T: AsyncWrite + std::marker::Unpin + std::fmt::Debug>(
     buffer: &mut T,
-    stream: TcpStream,
-    line_range: Option<(u32, u32)>,
-) {
-    let mut read_buf = Vec::new();
-    let mut buf_reader = BufReader::new(stream);
-    if let Some((start_line, end_line)) = line_range {
-        let mut line_count = 0;
-        while let Ok(size) = buf_reader.read_until(b'\n', &mut read_buf).await {
-            info!("Read from stream with size: {}", size);
-            if size == 0 {
-                break;
+    mut stream: TcpStream,
+    received: &mut Received,
+    mut skip: u64,
+) -> std::io::Result<()> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        let skipped = skip.min(n as u64);
+        skip -= skipped;
+        let data = &chunk[skipped as usize..n];
+        if data.is_empty() {
+            continue;
+        }
+        buffer.write_all(data).await?;
+        received.bytes += data.len() as u64;
+        match data.iter().rposition(|byte| *byte == b'\n') {
+            Some(last) => {
+                received.lines += data.iter().filter(|byte| **byte == b'\n').count() as u64;
+                received.partial = (data.len() - last - 1) as u64;
             }
+            None => received.partial += data.len() as u64,
+        }
+    }
+}
 
-            if start_line <= line_count && line_count <= end_line {
-                info!("Currently at line {}, within range", line_count);
-                if let Err(e) = buffer.write_all(&read_buf).await {
-                    error!("Unable to write to file with error {}", e);
-                    break;
-                }
-            }
+/// The part of `range` still missing once `received` has been written
+fn resume_range(range: Option<&Range>, received: Received) -> Option<Range> {
+    let remaining = |count: u64, done: u64| match count {
+        0 => 0,
+        count => count - done,
+    };
+    match range {
+        None if received.bytes == 0 => None,
+        None => Some(Range::Bytes(ByteRange {
+            offset: received.bytes,
+            length: 0,
+            from_end: false,
+        })),
+        Some(Range::Bytes(bytes)) => Some(Range::Bytes(ByteRange {
+            offset: match bytes.from_end {
+                true => bytes.offset.saturating_sub(received.bytes),
+                false => bytes.offset + received.bytes,
+            },
+            length: remaining(bytes.length, received.bytes),
+            from_end: bytes.from_end,
+        })),
+        Some(Range::Lines(lines)) => Some(Range::Lines(LineRange {
+            start: match lines.from_end {
+                true => lines.start.saturating_sub(received.lines),
+                false => lines.start + received.lines,
+            },
+            count: remaining(lines.count, received.lines),
+            from_end: lines.from_end,
+        })),
+    }
+}
+
+/// Version and size of a file on a replica, None if the replica does not store it
+async fn fetch_version(machine: &str, file_name: &str) -> Result<Option<(u64, u64)>, String> {
+    let server_address = format!("{machine}:56552");
+    let mut stream = TcpStream::connect(&server_address)
+        .await
+        .map_err(|e| format!("Unable to connect to {}: {}", server_address, e))?;
+    let version_req = SdfsCommand {
+        r#type: Some(Type::VersionReq(VersionReq {
+            file_name: file_name.to_string(),
+        })),
+    }
+    .encode_to_vec();
+    let _ = stream.write_all(&version_req).await;
+    let mut res = Vec::new();
+    stream
+        .read_to_end(&mut res)
+        .await
+        .map_err(|e| format!("No version from {}: {}", server_address, e))?;
+    let version_res = VersionRes::decode(res.as_slice())
+        .map_err(|e| format!("Unable to decode version from {}: {}", server_address, e))?;
+    Ok(version_res
+        .stored
+        .then_some((version_res.version, version_res.size)))
+}
+
+/// Asks a replica holding the newest version to push it to a stale one
+async fn repair_replica(source: String, stale: String, file_name: String) {
+    let Ok(mut stream) = TcpStream::connect(format!("{source}:56552")).await else {
+        warn!("Unable to connect to {} to repair {}", source, stale);
+        return;
+    };
+    let repair_req = SdfsCommand {
+        r#type: Some(Type::RepairReq(RepairReq {
+            file_name,
+            machine: stale,
+        })),
+    }
+    .encode_to_vec();
+    let _ = stream.write_all(&repair_req).await;
+    let mut res = Vec::new();
+    let _ = stream.read_to_end(&mut res).await;
+}
 
-            line_count += 1;
-            read_buf.clear();
+/// Replicas holding the newest version of a file
+pub struct FreshReplicas {
+    pub machines: Vec<String>,
+    pub version: u64,
+    pub size: u64,
+}
+
+/// Hears the version of a file from `quorum` replicas and returns the ones holding the newest
+/// version. Replicas found behind it are repaired in the background.
+pub async fn quorum_read(
+    machines: Vec<String>,
+    file_name: &str,
+    quorum: usize,
+) -> Result<FreshReplicas, String> {
+    let mut pending: FuturesUnordered<_> = machines
+        .into_iter()
+        .map(|machine| async move {
+            let version = fetch_version(&machine, file_name).await;
+            (machine, version)
+        })
+        .collect();
+    let quorum = quorum.max(1);
+    let mut answers = Vec::new();
+    while answers.len() < quorum {
+        let Some((machine, version)) = pending.next().await else {
+            return Err(format!(
+                "Only {} of the {} replicas needed answered",
+                answers.len(),
+                quorum
+            ));
+        };
+        match version {
+            Ok(version) => answers.push((machine, version)),
+            Err(e) => warn!("{}", e),
         }
-    } else {
-        while let Ok(size) = buf_reader.read_until(b'\n', &mut read_buf).await {
-            if size == 0 {
-                break;
-            }
-            if let Err(e) = buffer.write_all(&read_buf).await {
-                error!("Unable to write to file with error {}", e);
+    }
+    // Replicas answering right after the quorum can serve ranges of a parallel GET too
+    let newest_size = answers
+        .iter()
+        .filter_map(|(_, version)| *version)
+        .max()
+        .map_or(0, |(_, size)| size);
+    let deadline = match newest_size > PARALLEL_CHUNK {
+        true => Instant::now() + VERSION_GRACE,
+        false => Instant::now(),
+    };
+    while let Ok(Some((machine, version))) = timeout_at(deadline, pending.next()).await {
+        if let Ok(version) = version {
+            answers.push((machine, version));
+        }
+    }
+    let Some((newest, size)) = answers.iter().filter_map(|(_, version)| *version).max() else {
+        return Err("No replica stores the file".to_string());
+    };
+    let (fresh, stale): (Vec<_>, Vec<_>) = answers
+        .into_iter()
+        .partition(|(_, version)| version.is_some_and(|(version, _)| version == newest));
+    let fresh: Vec<String> = fresh.into_iter().map(|(machine, _)| machine).collect();
+    for (machine, version) in stale {
+        info!(
+            "Replica {} holds version {:?} of {}, repairing to {}",
+            machine,
+            version.map(|(version, _)| version),
+            file_name,
+            newest
+        );
+        tokio::spawn(repair_replica(
+            fresh[0].clone(),
+            machine,
+            file_name.to_string(),
+        ));
+    }
+    Ok(FreshReplicas {
+        machines: fresh,
+        version: newest,
+        size,
+    })
+}
+
+/// Fetches `length` bytes at `offset` of a version of a file from one replica into the same
+/// place locally, returning how many bytes arrived before the replica finished or the stream
+/// broke. A replica holding another version sends nothing.
+async fn fetch_range(
+    machine: &str,
+    sdfs_file_name: &str,
+    version: u64,
+    local_file_name: &str,
+    offset: u64,
+    length: u64,
+) -> Result<u64, String> {
+    let server_address = format!("{machine}:56552");
+    let mut server_stream = TcpStream::connect(&server_address)
+        .await
+        .map_err(|e| format!("Unable to connect to {}: {}", server_address, e))?;
+    let get_req = SdfsCommand {
+        r#type: Some(Type::GetReq(GetReq {
+            file_name: sdfs_file_name.to_string(),
+            range: Some(Range::Bytes(ByteRange {
+                offset,
+                length,
+                from_end: false,
+            })),
+            version,
+        })),
+    }
+    .encode_to_vec();
+    server_stream
+        .write_all(&get_req)
+        .await
+        .map_err(|e| format!("Unable to send request to {}: {}", server_address, e))?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(local_file_name)
+        .await
+        .map_err(|e| format!("Unable to open local file: {}", e))?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(|e| format!("Unable to seek local file: {}", e))?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        let n = match server_stream.read(&mut buffer).await {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Lost connection to {}: {}", server_address, e);
                 break;
             }
-            read_buf.clear();
+        };
+        if n == 0 {
+            break;
         }
+        file.write_all(&buffer[..n])
+            .await
+            .map_err(|e| format!("Unable to write local file: {}", e))?;
+        received += n as u64;
     }
+    Ok(received)
+}
+
+/// Fetches a range of a file, resuming after the last byte written when a replica stops
+/// early. The same replica is retried while it makes progress, otherwise the next one.
+async fn fetch_resumable(
+    fresh: &FreshReplicas,
+    first: usize,
+    sdfs_file_name: &str,
+    local_file_name: &str,
+    offset: u64,
+    length: u64,
+) -> Result<(), String> {
+    let machines = &fresh.machines;
+    let mut replica = first % machines.len();
+    let mut stalled = 0;
+    let mut done = 0;
+    while done < length {
+        let machine = &machines[replica];
+        let received = fetch_range(
+            machine,
+            sdfs_file_name,
+            fresh.version,
+            local_file_name,
+            offset + done,
+            length - done,
+        )
+        .await
+        .inspect_err(|e| warn!("{}", e))
+        .unwrap_or_default();
+        done += received;
+        if done == length {
+            break;
+        }
+        info!(
+            "{} stopped at byte {} of {}, resuming",
+            machine,
+            offset + done,
+            sdfs_file_name
+        );
+        if received > 0 {
+            stalled = 0;
+            continue;
+        }
+        stalled += 1;
+        if stalled == machines.len() {
+            return Err(format!(
+                "No replica could send bytes {} to {}",
+                offset + done,
+                offset + length
+            ));
+        }
+        replica = (replica + 1) % machines.len();
+    }
+    Ok(())
+}
+
+/// GETs the newest version of a file from the replicas holding it, splitting it into ranges
+/// fetched from every replica at once when it is large enough. A range that breaks off
+/// resumes where it stopped. Every range comes from the same version.
+#[instrument(name = "Parallel GET helper", level = "trace", skip(fresh))]
+pub async fn parallel_get(
+    fresh: FreshReplicas,
+    sdfs_file_name: &str,
+    local_file_name: &str,
+) -> Result<(), String> {
+    let size = fresh.size;
+    if fresh.machines.is_empty() {
+        return Err("No replica stores the file".to_string());
+    }
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(local_file_name)
+        .await
+        .map_err(|e| format!("Unable to open local file: {}", e))?;
+    file.set_len(size)
+        .await
+        .map_err(|e| format!("Unable to size local file: {}", e))?;
+    // Small files are a single range from one replica
+    let ranges: Vec<_> = (0..size)
+        .step_by(PARALLEL_CHUNK as usize)
+        .map(|offset| (offset, PARALLEL_CHUNK.min(size - offset)))
+        .collect();
+    info!(
+        "Fetching {} in {} ranges from {} replicas",
+        sdfs_file_name,
+        ranges.len(),
+        fresh.machines.len()
+    );
+    let fresh = &fresh;
+    let results: Vec<Result<(), String>> = stream::iter(ranges.into_iter().enumerate())
+        // Range i starts on replica i, spreading the ranges over every replica
+        .map(|(index, (offset, length))| {
+            fetch_resumable(
+                fresh,
+                index,
+                sdfs_file_name,
+                local_file_name,
+                offset,
+                length,
+            )
+        })
+        .buffer_unordered(fresh.machines.len())
+        .collect()
+        .await;
+    results.into_iter().collect::<Result<(), String>>()?;
+    file.sync_all()
+        .await
+        .map_err(|e| format!("Unable to sync file: {}", e))
 }
 
 #[instrument(name = "GET helper function", level = "trace")]
@@ -79,7 +498,7 @@ pub async fn client_get_helper(
     machines: Vec<String>,
     sdfs_file_name: &str,
     local_file_name: &str,
-    line_range: Option<(u32, u32)>,
+    range: Option<Range>,
 ) -> Result<(), String> {
     let Ok(mut file) = fs::OpenOptions::new()
         .write(true)
@@ -92,12 +511,9 @@ pub async fn client_get_helper(
         return Err("Unable to open local file".to_string());
     };
 
+    // A replica that breaks off is followed by the next one, which sends only what is missing
+    let mut received = Received::default();
     for machine in machines {
-        if let Err(e) = file.rewind().await {
-            error!("Unable to rewind file {}, aborting", e);
-            return Err("Operation on file failed".to_string());
-        };
-
         let server_address = machine + ":56552";
         let Ok(mut server_stream) = TcpStream::connect(&server_address).await else {
             warn!(
@@ -108,6 +524,8 @@ pub async fn client_get_helper(
         };
         let get_req = GetReq {
             file_name: sdfs_file_name.to_string(),
+            range: resume_range(range.as_ref(), received),
+            ..Default::default()
         };
         let server_req_buffer = SdfsCommand {
             r#type: Some(Type::GetReq(get_req)),
@@ -122,8 +540,18 @@ pub async fn client_get_helper(
         };
         info!("Successfully sent to server");
 
-        // Receive the file data from the replica
-        write_to_buf(&mut file, server_stream, line_range).await;
+        // A line range restarts at the line that broke off, whose start is already written
+        let skip = match range {
+            Some(Range::Lines(_)) => received.partial,
+            _ => 0,
+        };
+        if let Err(e) = write_to_buf(&mut file, server_stream, &mut received, skip).await {
+            warn!(
+                "Lost {} after {} bytes: {}, resuming on the next replica",
+                server_address, received.bytes, e
+            );
+            continue;
+        }
 
         info!("Client GET finished");
         if let Err(e) = file.sync_all().await {
@@ -136,12 +564,449 @@ pub async fn client_get_helper(
     Err("Unable to successfully get file from any server".to_string())
 }
 
+/// Byte offset of the first of the last `lines` lines of `source`, `len` bytes long
+async fn tail_offset<R: AsyncRead + AsyncSeek + Unpin>(
+    source: &mut R,
+    len: u64,
+    lines: u64,
+) -> std::io::Result<u64> {
+    if lines == 0 {
+        return Ok(len);
+    }
+    let mut chunk = vec![0; 64 * 1024];
+    let mut end = len;
+    let mut newlines = 0;
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        source.seek(SeekFrom::Start(start)).await?;
+        source.read_exact(chunk).await?;
+        for (index, byte) in chunk.iter().enumerate().rev() {
+            let position = start + index as u64;
+            // A newline ending the file does not start another line
+            if *byte != b'\n' || position + 1 == len {
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                return Ok(position + 1);
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Where a byte range of a file `len` bytes long starts, and how many bytes it covers
+pub fn byte_span(bytes: &ByteRange, len: u64) -> (u64, u64) {
+    let start = match bytes.from_end {
+        true => len.saturating_sub(bytes.offset),
+        false => bytes.offset.min(len),
+    };
+    let length = match bytes.length {
+        0 => len - start,
+        length => length.min(len - start),
+    };
+    (start, length)
+}
+
+/// Sends `length` bytes of `file` starting at `offset` to a socket with sendfile, so the data
+/// goes from the page cache to the socket without being copied through user space
+pub async fn send_span(
+    file: &fs::File,
+    mut offset: u64,
+    length: u64,
+    stream: &TcpStream,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let end = offset + length;
+    while offset < end {
+        stream.writable().await?;
+        let count = (end - offset).min(CHUNK_SIZE as u64) as usize;
+        let sent = stream.try_io(tokio::io::Interest::WRITABLE, || {
+            let mut file_offset = offset as libc::off_t;
+            let sent = unsafe {
+                libc::sendfile(
+                    stream.as_raw_fd(),
+                    file.as_raw_fd(),
+                    &mut file_offset,
+                    count,
+                )
+            };
+            match sent {
+                -1 => Err(std::io::Error::last_os_error()),
+                sent => Ok(sent as u64),
+            }
+        });
+        match sent {
+            // The file is shorter than it was when the span was worked out
+            Ok(0) => return Ok(()),
+            Ok(sent) => offset += sent,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copies the part of `source` a GET asks for into `sink`. Byte ranges and the lines at
+/// the end of the file are found by seeking, leading lines have to be read past.
+pub async fn copy_range<R, W>(
+    source: &mut R,
+    range: Option<&Range>,
+    sink: &mut W,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let len = source.seek(SeekFrom::End(0)).await?;
+    match range {
+        None => {
+            source.rewind().await?;
+            copy_chunks(source, sink).await?;
+        }
+        Some(Range::Bytes(bytes)) => {
+            let (start, length) = byte_span(bytes, len);
+            source.seek(SeekFrom::Start(start)).await?;
+            copy_chunks(&mut source.take(length), sink).await?;
+        }
+        Some(Range::Lines(lines)) => {
+            let (start, skip) = match lines.from_end {
+                true => (tail_offset(source, len, lines.start).await?, 0),
+                false => (0, lines.start),
+            };
+            source.seek(SeekFrom::Start(start)).await?;
+            let mut reader = BufReader::new(source);
+            let mut line = Vec::new();
+            let mut index = 0;
+            while lines.count == 0 || index < skip + lines.count {
+                line.clear();
+                if reader.read_until(b'\n', &mut line).await? == 0 {
+                    break;
+                }
+                if index >= skip {
+                    sink.write_all(&line).await?;
+                }
+                index += 1;
+            }
+        }
+    }
+    sink.flush().await
+}
+
+/// Builds the digest of a file's contents chunk by chunk
+#[derive(Clone, Debug, Default)]
+pub struct Digester {
+    size: u64,
+    lines: u64,
+    open_line: bool, // the data so far does not end in a newline
+    hasher: crc32fast::Hasher,
+}
+
+impl Digester {
+    pub fn update(&mut self, data: &[u8]) {
+        let Some(last) = data.last() else {
+            return;
+        };
+        self.size += data.len() as u64;
+        self.lines += data.iter().filter(|byte| **byte == b'\n').count() as u64;
+        self.open_line = *last != b'\n';
+        self.hasher.update(data);
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// A last line without a newline still counts as a line
+    pub fn finish(self) -> FileDigest {
+        FileDigest {
+            size: self.size,
+            lines: self.lines + self.open_line as u64,
+            checksum: self.hasher.finalize(),
+        }
+    }
+}
+
+/// A digester that has seen the whole file, so more data can be added to it
+pub async fn file_digester(path: &str) -> std::io::Result<Digester> {
+    let mut file = fs::File::open(path).await?;
+    let mut digester = Digester::default();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(digester);
+        }
+        digester.update(&buffer[..n]);
+    }
+}
+
+pub async fn file_digest(path: &str) -> std::io::Result<FileDigest> {
+    file_digester(path).await.map(Digester::finish)
+}
+
+/// Copies a file, sharing its blocks where the filesystem supports reflinks. Elsewhere the
+/// kernel copies the data without passing it through user space.
+pub async fn clone_file(from: &str, to: &str) -> std::io::Result<()> {
+    let (from, to) = (from.to_string(), to.to_string());
+    tokio::task::spawn_blocking(move || {
+        use std::os::fd::AsRawFd;
+        let source = std::fs::File::open(&from)?;
+        let target = std::fs::File::create(&to)?;
+        if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE as _, source.as_raw_fd()) } == 0 {
+            return Ok(());
+        }
+        // Uses copy_file_range
+        std::fs::copy(&from, &to).map(|_| ())
+    })
+    .await?
+}
+
+/// Largest request the leader reads, pipelines with many stages stay far below it
+const MAX_LEADER_REQUEST: usize = 16 * 1024 * 1024;
+
+/// Sends a request to the leader prefixed with its length, so it is read whole however large
+pub async fn write_leader_request<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    request: &[u8],
+) -> std::io::Result<()> {
+    stream.write_u32(request.len() as u32).await?;
+    stream.write_all(request).await
+}
+
+/// Reads a request sent with `write_leader_request`
+pub async fn read_leader_request<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<SdfsCommand, String> {
+    let len = stream.read_u32().await.map_err(|e| e.to_string())? as usize;
+    if len > MAX_LEADER_REQUEST {
+        return Err(format!("Request of {} bytes is too large", len));
+    }
+    let mut buffer = vec![0; len];
+    stream
+        .read_exact(&mut buffer)
+        .await
+        .map_err(|e| e.to_string())?;
+    SdfsCommand::decode(buffer.as_slice()).map_err(|e| format!("Unable to decode request: {}", e))
+}
+
 pub fn split_id_to_components<T: Deref<Target = [u8]>>(raw_id: &T) -> Option<(&str, &str)> {
     let Ok(id) = std::str::from_utf8(raw_id) else {
         return None;
     };
-    let id_elems = id.split('_').collect::<Vec<_>>();
-    let ip = id_elems[0];
-    let port = id_elems[1];
+    // Ids are the address, port and join time separated by underscores
+    let (ip, rest) = id.split_once('_')?;
+    let port = rest.split_once('_').map_or(rest, |(port, _)| port);
     Some((ip, port))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_split_into_address_and_port() {
+        let id = b"172.22.94.1_12307_2023-11-05T10:00:00+00:00".to_vec();
+        assert_eq!(split_id_to_components(&id), Some(("172.22.94.1", "12307")));
+        assert_eq!(
+            split_id_to_components(&b"172.22.94.1_12307".to_vec()),
+            Some(("172.22.94.1", "12307"))
+        );
+        assert_eq!(split_id_to_components(&b"172.22.94.1".to_vec()), None);
+        assert_eq!(split_id_to_components(&vec![0xff, b'_', b'1']), None);
+    }
+
+    #[test]
+    fn a_digest_built_in_pieces_matches_the_whole() {
+        let data = b"first\nsecond\nthird";
+        let mut whole = Digester::default();
+        whole.update(data);
+        let mut pieces = Digester::default();
+        for piece in data.chunks(4) {
+            pieces.update(piece);
+        }
+        let digest = pieces.finish();
+        assert_eq!(digest, whole.finish());
+        assert_eq!(digest.size, data.len() as u64);
+        // The last line has no newline
+        assert_eq!(digest.lines, 3);
+        assert_eq!(digest.checksum, crc32fast::hash(data));
+    }
+
+    #[tokio::test]
+    async fn an_appended_digest_matches_the_cloned_file() {
+        let dir = std::env::temp_dir().join(format!("sdfs-helpers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (from, to) = (dir.join("from"), dir.join("to"));
+        let (from, to) = (from.to_str().unwrap(), to.to_str().unwrap());
+        std::fs::write(from, b"committed\n").unwrap();
+        let mut digester = file_digester(from).await.unwrap();
+
+        clone_file(from, to).await.unwrap();
+        let mut appended = fs::OpenOptions::new().append(true).open(to).await.unwrap();
+        appended.write_all(b"appended\n").await.unwrap();
+        appended.flush().await.unwrap();
+        digester.update(b"appended\n");
+        assert_eq!(std::fs::read(to).unwrap(), b"committed\nappended\n");
+        assert_eq!(digester.finish(), file_digest(to).await.unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn an_interrupted_get_resumes_after_what_arrived() {
+        let received = Received {
+            bytes: 10,
+            lines: 2,
+            partial: 3,
+        };
+        assert_eq!(resume_range(None, Received::default()), None);
+        let Some(Range::Bytes(bytes)) = resume_range(None, received) else {
+            panic!("a whole-file GET resumes as a byte range");
+        };
+        assert_eq!((bytes.offset, bytes.length), (10, 0));
+
+        let head = Range::Bytes(ByteRange {
+            offset: 5,
+            length: 30,
+            from_end: false,
+        });
+        let Some(Range::Bytes(bytes)) = resume_range(Some(&head), received) else {
+            panic!("a byte range resumes as a byte range");
+        };
+        assert_eq!((bytes.offset, bytes.length), (15, 20));
+
+        let tail = Range::Lines(LineRange {
+            start: 5,
+            count: 0,
+            from_end: true,
+        });
+        let Some(Range::Lines(lines)) = resume_range(Some(&tail), received) else {
+            panic!("a line range resumes as a line range");
+        };
+        // Restarts at the line that broke off
+        assert_eq!((lines.start, lines.count, lines.from_end), (3, 0, true));
+    }
+
+    #[tokio::test]
+    async fn a_resumed_line_range_skips_what_was_written() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"third line\nfourth").await.unwrap();
+        });
+        // The first replica broke off after "thi"
+        let mut buffer = b"first\nsecond\nthi".to_vec();
+        let mut received = Received {
+            bytes: buffer.len() as u64,
+            lines: 2,
+            partial: 3,
+        };
+        let stream = TcpStream::connect(address).await.unwrap();
+        let skip = received.partial;
+        write_to_buf(&mut buffer, stream, &mut received, skip)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"first\nsecond\nthird line\nfourth");
+        assert_eq!(received.bytes, buffer.len() as u64);
+        assert_eq!((received.lines, received.partial), (3, 6));
+    }
+
+    #[tokio::test]
+    async fn leader_requests_are_read_whole() {
+        let (mut client, mut leader) = tokio::io::duplex(1024);
+        let request = SdfsCommand {
+            r#type: Some(Type::GetReq(GetReq {
+                file_name: "a/".repeat(2048),
+                ..Default::default()
+            })),
+        };
+        let sent = request.clone();
+        let writer = tokio::spawn(async move {
+            write_leader_request(&mut client, &sent.encode_to_vec())
+                .await
+                .unwrap();
+        });
+        assert_eq!(read_leader_request(&mut leader).await, Ok(request));
+        writer.await.unwrap();
+
+        // A request that is not an SdfsCommand is refused rather than trusted
+        let (mut client, mut leader) = tokio::io::duplex(1024);
+        write_leader_request(&mut client, &[0xff; 8]).await.unwrap();
+        assert!(read_leader_request(&mut leader).await.is_err());
+    }
+
+    async fn copied(data: &[u8], range: Range) -> Vec<u8> {
+        let mut sink = Vec::new();
+        copy_range(&mut std::io::Cursor::new(data), Some(&range), &mut sink)
+            .await
+            .unwrap();
+        sink
+    }
+
+    #[tokio::test]
+    async fn ranges_are_cut_out_of_the_file() {
+        let data = b"one\ntwo\nthree\nfour";
+        let bytes = |offset, length, from_end| {
+            Range::Bytes(ByteRange {
+                offset,
+                length,
+                from_end,
+            })
+        };
+        let lines = |start, count, from_end| {
+            Range::Lines(LineRange {
+                start,
+                count,
+                from_end,
+            })
+        };
+        assert_eq!(copied(data, bytes(4, 3, false)).await, b"two");
+        assert_eq!(copied(data, bytes(4, 0, true)).await, b"four");
+        assert_eq!(copied(data, bytes(40, 0, false)).await, b"");
+        assert_eq!(copied(data, lines(1, 2, false)).await, b"two\nthree\n");
+        // The last line counts without its newline
+        assert_eq!(copied(data, lines(2, 0, true)).await, b"three\nfour");
+        assert_eq!(copied(data, lines(9, 0, true)).await, data);
+    }
+
+    #[test]
+    fn byte_spans_stay_within_the_file() {
+        let span = |offset, length, from_end| {
+            let bytes = ByteRange {
+                offset,
+                length,
+                from_end,
+            };
+            byte_span(&bytes, 100)
+        };
+        assert_eq!(span(10, 20, false), (10, 20));
+        assert_eq!(span(10, 0, false), (10, 90));
+        assert_eq!(span(90, 20, false), (90, 10));
+        assert_eq!(span(150, 0, false), (100, 0));
+        assert_eq!(span(30, 0, true), (70, 30));
+        assert_eq!(span(150, 0, true), (0, 100));
+    }
+
+    #[tokio::test]
+    async fn spans_are_sent_from_the_file() {
+        let path = std::env::temp_dir().join(format!("sdfs-span-{}", std::process::id()));
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let file = fs::File::open(&path).await.unwrap();
+        let sender = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            send_span(&file, 7, 2 * CHUNK_SIZE as u64, &stream).await
+        });
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(received, &data[7..7 + 2 * CHUNK_SIZE]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,18 +1,35 @@
+use crate::erasure;
+use crate::executor::{describe_failure, is_fatal_failure};
+use crate::helpers::read_leader_request;
 use crate::helpers::FileKey;
+use crate::helpers::STALE_EPOCH;
 use crate::message_types::sdfs_command::Type;
 use crate::message_types::{
-    Ack, Delete, FileSizeReq, FileSizeRes, GetReq, KeyServers, LeaderMapReq, LeaderPutReq,
-    LeaderReduceReq, LsRes, MapReq, PutReq, ReduceReq, SdfsCommand, ServerMapRes, ServerRedRes,
+    Ack, AppendReq, DecommissionReq, Delete, EncodeReq, ErasureLayout, ErasureReq, ExecutorLimits,
+    Fail, FileDigest, FileInfo, FileSizeReq, FileSizeRes, GetReq, JobCounters, JobState,
+    JobStatusReq, JobStatusRes, KeyServers, LeaderMapReq, LeaderPutReq, LeaderReduceReq,
+    LeaderStoreReq, LeaderStoreRes, LinkReq, LsAllReq, LsRes, MapReq, MkdirReq, PipelineReq,
+    PublishReq, PutReq, RebuildReq, ReduceReq, RenameReq, RmdirReq, SdfsCommand, ServerMapRes,
+    ServerRedRes, SetRepReq, StatReq, StatRes, TaskFailure,
 };
+use crate::namespace;
 use crate::node::Node;
-use dashmap::DashMap;
+use crate::pipeline::{self, PlannedJob};
+use crate::raft::{Committed, Raft};
+use crate::raft_types::{
+    table_op, DeleteFiles, DropNamespace, FileStat, Machines, MakeDir, Published, PutErasure,
+    PutFile, RecordedStat, RegisterKeys, Relink, Relinked, RemoveDir, Rename, SetDecommissioned,
+    TableOp, TableSnapshot, Unpublished,
+};
+use dashmap::{DashMap, DashSet};
 use prost::Message;
 use rand::seq::{IteratorRandom, SliceRandom};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::iter::{repeat, zip};
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, oneshot, Mutex, Notify, RwLock, Semaphore};
@@ -23,13 +40,97 @@ use tracing::{error, info, instrument, warn};
 // Define the file table and queues
 #[derive(Debug)]
 struct FileTable {
-    // Map of the file name to the VMs that have the file
+    // Map of the file name to the VMs that have the file, only changed through `raft`
     table: DashMap<String, Vec<Ipv4Addr>>,
+    // Replication factor each file is kept at, files without one use `default_replicas`
+    replication: DashMap<String, usize>,
+    default_replicas: usize,
+    versions: DashMap<String, u64>, // latest committed version of each file
+    // Configured quorums, unset ones default to a majority of writes and W + R > N
+    write_quorum: Option<usize>,
+    read_quorum: Option<usize>,
+    erasure: DashMap<String, Erasure>, // erasure-coded files, kept out of `table`
+    // Directories created with mkdir, others exist while files lie below them
+    directories: DashSet<String>,
+    stats: DashMap<String, FileMeta>, // contents and times of files written by PUT or append
+    // Committed writes by staging id, until every server published its copy
+    unpublished: DashMap<String, Unpublished>,
+    // Copies a rename could not link yet by their machine and new name
+    relinks: DashMap<(String, String), Relink>,
+    // Per file, PUTs and appends hold both permits and reads one
+    permits: DashMap<String, Arc<Semaphore>>,
+    raft: Arc<Raft>,
     actors: DashMap<String, mpsc::Sender<RequestInfo>>, // channel to actor processes
-    keys: DashMap<String, Vec<FileKey>>,
+    // Map of a key prefix to the namespace reduces over that prefix read from
+    namespaces: DashMap<String, Namespace>,
+    intermediates: DashMap<String, u64>, // key file -> job that wrote it
+    running_jobs: DashSet<u64>,
+    decommissioned: DashSet<Ipv4Addr>, // machines being drained, no new replicas or tasks
+    next_job_id: AtomicU64,
+    jobs: DashMap<u64, JobStatusRes>, // status and counters of recent jobs
     map_reduce_actor: Mutex<mpsc::Sender<MapReduceReqInfo>>, // channel to mapreduce process
 }
 
+/// A write whose data servers hold staged until the leader commits it
+struct StagedWrite {
+    file_name: String,
+    staged: Vec<(Ipv4Addr, String)>, // server and the name it stages its copy for
+    staging: String,
+    version: u64,
+    write_quorum: usize,
+    replicas: u32, // replication factor to record, 0 keeps the current one
+    erasure: Option<ErasureLayout>, // layout the PUT asked for, fragment i is staged[i]
+}
+
+/// What the leader knows of a file's contents, recorded when a PUT or append commits
+#[derive(Debug, Clone)]
+struct FileMeta {
+    digest: FileDigest,
+    created_millis: u64,
+    modified_millis: u64,
+}
+
+/// Fragments of an erasure-coded file, `fragments[i]` holds fragment i or is None if it is lost
+#[derive(Debug, Clone)]
+struct Erasure {
+    layout: ErasureLayout,
+    fragments: Vec<Option<Ipv4Addr>>,
+}
+
+impl Erasure {
+    fn machines(&self) -> Vec<String> {
+        self.fragments
+            .iter()
+            .map(|vm| vm.map_or(String::new(), |vm| vm.to_string()))
+            .collect()
+    }
+
+    fn put_erasure(&self, file_name: &str) -> PutErasure {
+        PutErasure {
+            file_name: file_name.to_string(),
+            data_shards: self.layout.data_shards,
+            parity_shards: self.layout.parity_shards,
+            size: self.layout.size,
+            fragments: self.machines(),
+            stat: None,
+            block_size: self.layout.block_size,
+            staging: String::new(),
+        }
+    }
+
+    fn put_op(&self, file_name: &str) -> table_op::Type {
+        table_op::Type::PutErasure(self.put_erasure(file_name))
+    }
+}
+
+/// Key files written under a prefix by a single job
+#[derive(Debug, Clone)]
+struct Namespace {
+    job_id: u64,
+    name: String,
+    keys: HashSet<String>,
+}
+
 struct MapReduceReqInfo {
     request: MapReduceAccType,
     stream: TcpStream,
@@ -39,6 +140,7 @@ struct MapReduceReqInfo {
 enum MapReduceAccType {
     Map(MapReq),
     Reduce(ReduceReq),
+    Pipeline(PipelineReq),
 }
 
 struct RequestInfo {
@@ -50,33 +152,145 @@ struct RequestInfo {
 enum AccessType {
     Read(GetReq),
     Write(PutReq),
+    Append(AppendReq),
+}
+
+impl AccessType {
+    /// Writes and appends change the file, so they run alone and in the order they arrived
+    fn is_write(&self) -> bool {
+        matches!(self, AccessType::Write(_) | AccessType::Append(_))
+    }
 }
 
 struct ReduceResult {
     succ_worker: Option<Ipv4Addr>,
     fail_blocks: Vec<(String, KeyServers)>,
     replicators: Vec<String>,
+    failure: Option<TaskFailure>,
+    counters: JobCounters,
+}
+
+/// Replicas a node stores, as seen by the rebalancer
+#[derive(Debug, Default, Clone, Copy)]
+struct Load {
+    files: usize,
+    bytes: u64,
 }
 
 struct MapResult {
     succ_worker: Option<Ipv4Addr>,
-    fail_blocks: Vec<(String, KeyServers)>,
     keys: Vec<String>,
     replicators: Vec<String>,
+    failure: Option<TaskFailure>,
+    counters: JobCounters,
+}
+
+// Replicas of a file when neither its put nor SDFS_REPLICAS choose otherwise
+const DEFAULT_REPLICAS: usize = 4;
+
+// Finished jobs the leader keeps the status of
+const MAX_JOB_HISTORY: usize = 100;
+
+fn merge_counters(total: &mut JobCounters, counters: JobCounters) {
+    total.input_records = total.input_records.saturating_add(counters.input_records);
+    total.output_keys = total.output_keys.saturating_add(counters.output_keys);
+    total.bytes_shuffled = total.bytes_shuffled.saturating_add(counters.bytes_shuffled);
+    total.task_retries = total.task_retries.saturating_add(counters.task_retries);
+    total.task_millis.extend(counters.task_millis);
+    for (name, amount) in counters.custom {
+        let value = total.custom.entry(name).or_default();
+        *value = value.saturating_add(amount);
+    }
+}
+
+// Applied entries between snapshots of the file table that let Raft compact its log
+const SNAPSHOT_INTERVAL: u64 = 10_000;
+// How often the leader retries publishing committed writes
+const PUBLISH_RETRY: Duration = Duration::from_secs(5);
+// How often the leader retries linking copies a rename could not reach
+const RELINK_INTERVAL: Duration = Duration::from_secs(10);
+// How often the leader sweeps for key files no job can read anymore
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the leader compares node loads and moves replicas between them
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(30);
+// Bytes the rebalancer copies per round at most, which caps its bandwidth
+const REBALANCE_BUDGET: u64 = 256 << 20;
+// Gaps in bytes and file counts between two nodes the rebalancer leaves alone
+const REBALANCE_BYTE_SLACK: u64 = 64 << 20;
+const REBALANCE_COUNT_SLACK: usize = 2;
+
+// Grace period on top of the executor wall-clock limit for fetching inputs and shuffling outputs
+const TASK_GRACE: Duration = Duration::from_secs(300);
+
+async fn read_task_response(
+    stream: &mut TcpStream,
+    limits: &Option<ExecutorLimits>,
+) -> Result<Vec<u8>, String> {
+    let mut res = Vec::new();
+    let timeout_secs = limits.as_ref().map_or(0, |limits| limits.timeout_secs);
+    if timeout_secs == 0 {
+        stream
+            .read_to_end(&mut res)
+            .await
+            .map_err(|e| e.to_string())?;
+        return Ok(res);
+    }
+    match tokio::time::timeout(
+        Duration::from_secs(timeout_secs) + TASK_GRACE,
+        stream.read_to_end(&mut res),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Ok(res),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("worker did not respond in time".to_string()),
+    }
+}
+
+fn peer_ipv4(socket: &TcpStream) -> Option<Ipv4Addr> {
+    match socket.peer_addr().ok()?.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) => ip.to_ipv4(),
+    }
+}
+
+/// Answer to a file request the leader refuses, so the client does not wait for another leader
+fn refusal(message: String) -> LsRes {
+    LsRes {
+        fail: Some(Fail { message }),
+        ..Default::default()
+    }
+}
+
+async fn send_job_response(socket: &mut TcpStream, response: Type) {
+    let buffer = SdfsCommand {
+        r#type: Some(response),
+    }
+    .encode_to_vec();
+    if let Err(e) = socket.write_all(&buffer).await {
+        warn!("Failed to send job response to client: {:?}", e);
+    }
 }
 
 async fn send_leader_reduce_req(vm: Ipv4Addr, command: LeaderReduceReq) -> ReduceResult {
     info!("Leader reduce: Starting reduce task");
+    let start_time = Instant::now();
     let mut succ = ReduceResult {
         succ_worker: Some(vm),
         fail_blocks: Vec::new(),
         replicators: Vec::new(),
+        failure: None,
+        counters: JobCounters::default(),
     };
     let fail = ReduceResult {
         succ_worker: None,
         fail_blocks: command.key_server_map.clone().into_iter().collect(),
         replicators: Vec::new(),
+        failure: None,
+        counters: JobCounters::default(),
     };
+    let limits = command.limits.clone();
     let message = SdfsCommand {
         r#type: Some(Type::LeaderRedReq(command)),
     }
@@ -98,14 +312,16 @@ async fn send_leader_reduce_req(vm: Ipv4Addr, command: LeaderReduceReq) -> Reduc
             e
         );
     }
-    let mut res = Vec::new();
-    if let Err(e) = stream.read_to_end(&mut res).await {
-        error!(
-            "Leader reduce: Failed to get ack from reduce worker {}: {}",
-            vm, e
-        );
-        return fail;
-    }
+    let res = match read_task_response(&mut stream, &limits).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!(
+                "Leader reduce: Failed to get ack from reduce worker {}: {}",
+                vm, e
+            );
+            return fail;
+        }
+    };
     let Ok(res) = ServerRedRes::decode(res.as_slice()) else {
         error!(
             "Leader reduce: Failed to decode ack from reduce worker {}",
@@ -113,28 +329,45 @@ async fn send_leader_reduce_req(vm: Ipv4Addr, command: LeaderReduceReq) -> Reduc
         );
         return fail;
     };
+    if res.failure() != TaskFailure::None {
+        warn!(
+            "Leader reduce: reduce worker {} failed: {}",
+            vm,
+            describe_failure(res.failure())
+        );
+        return ReduceResult {
+            failure: Some(res.failure()),
+            ..fail
+        };
+    }
     info!(
         "Leader reduce: Successfully executed reduce at worker {}",
         vm
     );
     succ.replicators = res.replicators;
+    succ.counters = res.counters.unwrap_or_default();
+    succ.counters.task_millis = vec![start_time.elapsed().as_millis() as u64];
     succ
 }
 
 async fn send_leader_map_req(vm: Ipv4Addr, command: LeaderMapReq) -> MapResult {
     info!("Leader map: Starting map task");
+    let start_time = Instant::now();
     let mut succ = MapResult {
         succ_worker: Some(vm),
-        fail_blocks: Vec::new(),
         keys: Vec::new(),
         replicators: Vec::new(),
+        failure: None,
+        counters: JobCounters::default(),
     };
     let fail = MapResult {
         succ_worker: None,
-        fail_blocks: command.file_server_map.clone().into_iter().collect(),
         keys: Vec::new(),
         replicators: Vec::new(),
+        failure: None,
+        counters: JobCounters::default(),
     };
+    let limits = command.limits.clone();
     let message = SdfsCommand {
         r#type: Some(Type::LeaderMapReq(command)),
     }
@@ -148,24 +381,197 @@ async fn send_leader_map_req(vm: Ipv4Addr, command: LeaderMapReq) -> MapResult {
     };
 
     let _ = stream.write_all(&message).await;
-    let mut res = Vec::new();
-    if let Err(e) = stream.read_to_end(&mut res).await {
-        warn!(
-            "Leader map: Failed to get ack from map worker {}: {}",
-            vm, e
-        );
-        return fail;
-    }
+    let res = match read_task_response(&mut stream, &limits).await {
+        Ok(res) => res,
+        Err(e) => {
+            warn!(
+                "Leader map: Failed to get ack from map worker {}: {}",
+                vm, e
+            );
+            return fail;
+        }
+    };
     let Ok(res) = ServerMapRes::decode(res.as_slice()) else {
         warn!("Leader map: Failed to decode ack from map worker {}", vm);
         return fail;
     };
+    if res.failure() != TaskFailure::None {
+        warn!(
+            "Leader map: map worker {} failed: {}",
+            vm,
+            describe_failure(res.failure())
+        );
+        return MapResult {
+            failure: Some(res.failure()),
+            ..fail
+        };
+    }
     info!("Leader map: Successfully executed map at worker {}", vm);
     succ.keys = res.keys;
     succ.replicators = res.replicators;
+    succ.counters = res.counters.unwrap_or_default();
+    succ.counters.task_millis = vec![start_time.elapsed().as_millis() as u64];
     succ
 }
 
+/// Has a server drop its copy of a file, refused by servers that follow a newer leader
+async fn send_delete(machine: Ipv4Addr, file_name: &str, epoch: u64) {
+    let del_buffer = SdfsCommand {
+        r#type: Some(Type::Del(Delete {
+            file_name: file_name.to_string(),
+            epoch,
+        })),
+    }
+    .encode_to_vec();
+    let server_address = machine.to_string() + ":56552";
+    let Ok(mut server_stream) = TcpStream::connect(&server_address).await else {
+        warn!(
+            "Unable to connect to server {}, ignoring server",
+            server_address
+        );
+        return;
+    };
+
+    if let Err(e) = server_stream.write_all(&del_buffer).await {
+        warn!("Unable to send request to server: {}", e);
+        return;
+    }
+    info!("Sent request to server maybe");
+    let mut ack_buffer = [0; 1024];
+    let Ok(n) = server_stream.read(&mut ack_buffer).await else {
+        info!("Nothing to delete");
+        return;
+    };
+    info!("Received ACK from server");
+    if &ack_buffer[..n] == STALE_EPOCH {
+        warn!(
+            "Server {} follows a newer leader, {} kept",
+            machine, file_name
+        );
+        return;
+    }
+    if let Err(e) = Ack::decode(&ack_buffer[..n]) {
+        warn!("Unable to decode ACK server message {}", e);
+        return;
+    };
+    info!("File {} deleted from machine: {}", file_name, machine);
+}
+
+/// Asks a server to rebuild a lost fragment, true once it stores it
+async fn send_rebuild_req(machine: Ipv4Addr, rebuild_req: RebuildReq) -> bool {
+    let message = SdfsCommand {
+        r#type: Some(Type::RebuildReq(rebuild_req)),
+    }
+    .encode_to_vec();
+    let server_address = machine.to_string() + ":56552";
+    let Ok(mut stream) = TcpStream::connect(&server_address).await else {
+        warn!("Unable to connect to server {}", server_address);
+        return false;
+    };
+    let _ = stream.write_all(&message).await;
+    let mut res = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut res).await {
+        warn!("No rebuild response from {}: {}", server_address, e);
+        return false;
+    }
+    !res.is_empty() && res != STALE_EPOCH && Ack::decode(res.as_slice()).is_ok()
+}
+
+/// Has a server store its copy of a file under a second name
+async fn send_link_req(machine: Ipv4Addr, link_req: LinkReq) -> bool {
+    let message = SdfsCommand {
+        r#type: Some(Type::LinkReq(link_req)),
+    }
+    .encode_to_vec();
+    let server_address = machine.to_string() + ":56552";
+    let Ok(mut stream) = TcpStream::connect(&server_address).await else {
+        warn!("Unable to connect to server {}", server_address);
+        return false;
+    };
+    let _ = stream.write_all(&message).await;
+    let mut res = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut res).await {
+        warn!("No link response from {}: {}", server_address, e);
+        return false;
+    }
+    !res.is_empty() && res != STALE_EPOCH && Ack::decode(res.as_slice()).is_ok()
+}
+
+/// Has a server rename its staged copy of a PUT into place, or drop it if `discard` is set
+async fn send_publish_req(machine: Ipv4Addr, publish_req: PublishReq) -> bool {
+    let message = SdfsCommand {
+        r#type: Some(Type::PublishReq(publish_req)),
+    }
+    .encode_to_vec();
+    let server_address = machine.to_string() + ":56552";
+    let Ok(mut stream) = TcpStream::connect(&server_address).await else {
+        warn!("Unable to connect to server {}", server_address);
+        return false;
+    };
+    let _ = stream.write_all(&message).await;
+    let mut res = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut res).await {
+        warn!("No publish response from {}: {}", server_address, e);
+        return false;
+    }
+    !res.is_empty() && res != STALE_EPOCH && Ack::decode(res.as_slice()).is_ok()
+}
+
+/// Asks a replica holder to encode a file into fragments, returning the file's layout
+async fn send_encode_req(machine: Ipv4Addr, encode_req: EncodeReq) -> Option<ErasureLayout> {
+    let message = SdfsCommand {
+        r#type: Some(Type::EncodeReq(encode_req)),
+    }
+    .encode_to_vec();
+    let server_address = machine.to_string() + ":56552";
+    let Ok(mut stream) = TcpStream::connect(&server_address).await else {
+        warn!("Unable to connect to server {}", server_address);
+        return None;
+    };
+    let _ = stream.write_all(&message).await;
+    let mut res = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut res).await {
+        warn!("No encode response from {}: {}", server_address, e);
+        return None;
+    }
+    if res.is_empty() || res == STALE_EPOCH {
+        warn!("Server {} did not encode the file", server_address);
+        return None;
+    }
+    ErasureLayout::decode(res.as_slice()).ok()
+}
+
+async fn fetch_stored_files(machine: Ipv4Addr) -> Option<LeaderStoreRes> {
+    let message = SdfsCommand {
+        r#type: Some(Type::LeaderStoreReq(LeaderStoreReq {
+            message: "Give me your files".to_string(),
+        })),
+    }
+    .encode_to_vec();
+    let server_address = machine.to_string() + ":56552";
+    let Ok(mut stream) = TcpStream::connect(&server_address).await else {
+        warn!("Unable to connect to server {}", server_address);
+        return None;
+    };
+    if let Err(e) = stream.write_all(&message).await {
+        warn!("Unable to send store request to {}: {}", server_address, e);
+        return None;
+    }
+    let mut res = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut res).await {
+        warn!(
+            "Unable to read store response from {}: {}",
+            server_address, e
+        );
+        return None;
+    }
+    let Ok(res) = LeaderStoreRes::decode(res.as_slice()) else {
+        warn!("Unable to decode store response from {}", server_address);
+        return None;
+    };
+    Some(res)
+}
+
 async fn send_leader_put_req<'recv>(
     sender: &Ipv4Addr,
     command: LeaderPutReq,
@@ -192,6 +598,11 @@ async fn send_leader_put_req<'recv>(
         fail_receivers.push(receiver);
         return;
     }
+    if res.is_empty() || res == STALE_EPOCH {
+        error!("Sender machine {} refused the put", sender);
+        fail_receivers.push(receiver);
+        return;
+    }
     if let Err(e) = Ack::decode(res.as_slice()) {
         error!("Failed to decode ack from sender machine {}: {}", sender, e);
         fail_receivers.push(receiver);
@@ -213,350 +624,1375 @@ async fn get_active_vms(members: Arc<RwLock<Vec<Node>>>) -> Vec<Ipv4Addr> {
         .collect::<Vec<_>>()
 }
 
+fn parse_machines(machines: &[String]) -> Vec<Ipv4Addr> {
+    machines.iter().filter_map(|vm| vm.parse().ok()).collect()
+}
+
+fn quorum_from_env(var: &str) -> Option<usize> {
+    std::env::var(var)
+        .ok()
+        .and_then(|quorum| quorum.parse().ok())
+        .filter(|quorum| *quorum > 0)
+}
+
 impl FileTable {
-    fn new(map_req_tx: mpsc::Sender<MapReduceReqInfo>) -> Self {
+    fn new(raft: Arc<Raft>, map_req_tx: mpsc::Sender<MapReduceReqInfo>) -> Self {
         FileTable {
             table: DashMap::new(),
+            replication: DashMap::new(),
+            erasure: DashMap::new(),
+            default_replicas: std::env::var("SDFS_REPLICAS")
+                .ok()
+                .and_then(|replicas| replicas.parse().ok())
+                .filter(|replicas| *replicas > 0)
+                .unwrap_or(DEFAULT_REPLICAS),
+            versions: DashMap::new(),
+            write_quorum: quorum_from_env("SDFS_WRITE_QUORUM"),
+            read_quorum: quorum_from_env("SDFS_READ_QUORUM"),
+            raft,
             actors: DashMap::new(),
-            keys: DashMap::new(),
+            namespaces: DashMap::new(),
+            intermediates: DashMap::new(),
+            running_jobs: DashSet::new(),
+            decommissioned: DashSet::new(),
+            directories: DashSet::new(),
+            stats: DashMap::new(),
+            unpublished: DashMap::new(),
+            relinks: DashMap::new(),
+            permits: DashMap::new(),
+            // Seed from the clock so a restarted leader does not reuse job ids
+            next_job_id: AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |d| d.as_millis() as u64),
+            ),
+            jobs: DashMap::new(),
             map_reduce_actor: Mutex::new(map_req_tx),
         }
     }
 
-    #[instrument(name = "Leader map processor", level = "trace")]
-    async fn start_map(
-        &self,
-        map_req: MapReq,
-        mut socket: TcpStream,
-        members: Arc<RwLock<Vec<Node>>>,
-    ) {
-        info!("Leader map: Processing map on leader");
-        // Step 1: Find files with the prefix map_req.input_dir
-        info!("Leader map: Looking for prefix: {}", map_req.input_dir);
-        let mut file_server_map: Vec<_> = self
-            .table
-            .iter()
-            .filter(|elem| elem.key().starts_with(&map_req.input_dir))
-            .map(|elem| {
-                (
-                    elem.key().clone(),
-                    KeyServers {
-                        servers: elem
-                            .value()
-                            .clone()
-                            .into_iter()
-                            .map(|ip| ip.to_string())
-                            .collect(),
-                    },
-                )
-            })
-            .take(1)
-            .collect();
-
-        if file_server_map.is_empty() {
-            warn!("Found no server containing the file");
-            return;
+    fn start_job(&self, description: String) -> u64 {
+        let job_id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        self.running_jobs.insert(job_id);
+        info!("Leader: starting job {}: {}", job_id, description);
+        if self.jobs.len() >= MAX_JOB_HISTORY {
+            let oldest = self
+                .jobs
+                .iter()
+                .filter(|job| job.state() != JobState::Running)
+                .map(|job| *job.key())
+                .min();
+            if let Some(oldest) = oldest {
+                self.jobs.remove(&oldest);
+            }
         }
+        let mut status = JobStatusRes {
+            job_id,
+            description,
+            counters: Some(JobCounters::default()),
+            ..Default::default()
+        };
+        status.set_state(JobState::Running);
+        self.jobs.insert(job_id, status);
+        job_id
+    }
 
-        info!(
-            "Leader map: Found files matching prefix: {:?}",
-            file_server_map
-        );
-
-        // Step 2: Find active workers containing the executable
-        let active_vms = get_active_vms(members.clone()).await;
-        if active_vms.is_empty() {
-            warn!("Leader map: Unable to pick a target VM");
-            return;
+    fn add_counters(&self, job_id: u64, counters: JobCounters) {
+        if let Some(mut job) = self.jobs.get_mut(&job_id) {
+            merge_counters(job.counters.get_or_insert_with(Default::default), counters);
         }
-        let target_vms: Vec<_> = active_vms
-            .choose_multiple(&mut rand::thread_rng(), 4)
-            .map(|ip| ip.to_string())
-            .collect();
+    }
 
-        let mut worker_vms = active_vms.clone();
-        {
-            // let Some(executable_servers) = self.table.get(&map_req.executable) else {
-            //     warn!("Leader map: No executable found in the file system, aborting");
-            //     return;
-            // };
-            // worker_vms.retain(|vm| executable_servers.contains(vm));
-            worker_vms.truncate(map_req.num_workers as usize);
+    /// Records the outcome of a job and builds the final response to its client
+    fn finish_job(&self, job_id: u64, result: Result<(), String>, success: &str) -> Type {
+        self.running_jobs.remove(&job_id);
+        let mut counters = JobCounters::default();
+        if let Some(mut job) = self.jobs.get_mut(&job_id) {
+            counters = job.counters.clone().unwrap_or_default();
+            match &result {
+                Ok(()) => {
+                    job.set_state(JobState::Succeeded);
+                    job.message = success.to_string();
+                }
+                Err(message) => {
+                    job.set_state(JobState::Failed);
+                    job.message = message.clone();
+                }
+            }
         }
+        match result {
+            Ok(()) => Type::Ack(Ack {
+                message: format!("{success} (job {job_id})"),
+                job_id,
+                counters: Some(counters),
+                ..Default::default()
+            }),
+            Err(message) => Type::Fail(Fail {
+                message: format!("{message} (job {job_id})"),
+            }),
+        }
+    }
 
-        info!("Leader map: Found active workers: {:?}", worker_vms);
-
-        // have the peer upload executable to workers
-        let Ok(peer_addr) = socket.peer_addr() else {
-            warn!("Unable to get peer address info, aborting");
-            return;
-        };
-        let ip = match peer_addr.ip() {
-            IpAddr::V4(ip) => ip,
-            IpAddr::V6(ip) => ip.to_ipv4().unwrap(),
+    async fn job_status(&self, status_req: JobStatusReq, mut socket: TcpStream) {
+        let status = match self.jobs.get(&status_req.job_id) {
+            Some(job) => job.clone(),
+            None => JobStatusRes {
+                job_id: status_req.job_id,
+                ..Default::default()
+            },
         };
-        let mut succ_receivers = Vec::new();
-        let mut fail_receivers = Vec::new();
-        for vm in &worker_vms {
-            let command = LeaderPutReq {
-                machine: vm.to_string(),
-                file_name: map_req.executable.clone(),
-            };
-            send_leader_put_req(&ip, command, &mut fail_receivers, vm, &mut succ_receivers).await;
-        }
-        worker_vms = succ_receivers.into_iter().copied().collect();
-        info!("Uploaded executable to workers: {:?}", worker_vms);
+        send_job_response(&mut socket, Type::JobStatusRes(status)).await;
+    }
 
-        // get file count
-        let message = SdfsCommand {
-            r#type: Some(Type::FileSizeReq(FileSizeReq {
-                file_name: file_server_map[0].0.clone(),
-            })),
+    /// A job's key files are live while it runs or while its namespace is the one reduces read
+    fn is_live_job(&self, job_id: u64) -> bool {
+        self.running_jobs.contains(&job_id)
+            || self
+                .namespaces
+                .iter()
+                .any(|namespace| namespace.job_id == job_id)
+    }
+
+    /// Points reduces over `prefix` at the job's keys
+    fn register_namespace(&self, prefix: &str, job_id: u64, keys: Vec<String>) {
+        let mut entry = self
+            .namespaces
+            .entry(prefix.to_string())
+            .or_insert_with(|| Namespace {
+                job_id,
+                name: FileKey::namespace(prefix, job_id),
+                keys: HashSet::new(),
+            });
+        if entry.job_id != job_id {
+            *entry = Namespace {
+                job_id,
+                name: FileKey::namespace(prefix, job_id),
+                keys: HashSet::new(),
+            };
         }
-        .encode_to_vec();
+        // Several map stages of one pipeline can feed the same prefix
+        entry.keys.extend(keys);
+    }
 
-        let mut file_size = None;
-        for server in &file_server_map[0].1.servers {
-            let res = async {
-                let server_addr = format!("{server}:56552");
-                let Ok(mut stream) = TcpStream::connect(&server_addr).await else {
-                    warn!("Leader map: Failed to contact map worker {}", server_addr);
-                    return None;
+    /// Applies a committed file table change, on the leader and on every follower
+    fn apply(&self, op: TableOp) {
+        match op.r#type {
+            Some(table_op::Type::Put(put)) => {
+                if put.replicas > 0 {
+                    self.replication
+                        .insert(put.file_name.clone(), put.replicas as usize);
+                }
+                if put.version > 0 {
+                    self.versions.insert(put.file_name.clone(), put.version);
+                }
+                match put.stat {
+                    Some(stat) => self.record_stat(&put.file_name, stat),
+                    // Written without a digest, what was recorded describes an older version
+                    None if !put.staging.is_empty() => {
+                        self.stats.remove(&put.file_name);
+                    }
+                    None => {}
+                }
+                if !put.staging.is_empty() {
+                    // A newer version supersedes any write still being published or copy
+                    // still to be linked
+                    self.unpublished
+                        .retain(|_, unpublished| unpublished.file_name != put.file_name);
+                    self.relinks
+                        .retain(|_, relink| relink.file_name != put.file_name);
+                    let unpublished = Unpublished {
+                        staging: put.staging.clone(),
+                        version: put.version,
+                        machines: put.machines.clone(),
+                        names: vec![put.file_name.clone(); put.machines.len()],
+                        file_name: put.file_name.clone(),
+                    };
+                    self.unpublished.insert(put.staging, unpublished);
+                }
+                self.erasure.remove(&put.file_name);
+                self.table
+                    .insert(put.file_name, parse_machines(&put.machines));
+            }
+            Some(table_op::Type::PutErasure(put)) => {
+                self.table.remove(&put.file_name);
+                self.replication.remove(&put.file_name);
+                self.versions.remove(&put.file_name);
+                match put.stat {
+                    Some(stat) => self.record_stat(&put.file_name, stat),
+                    None if !put.staging.is_empty() => {
+                        self.stats.remove(&put.file_name);
+                    }
+                    None => {}
+                }
+                if !put.staging.is_empty() {
+                    self.unpublished
+                        .retain(|_, unpublished| unpublished.file_name != put.file_name);
+                    self.relinks
+                        .retain(|_, relink| relink.file_name != put.file_name);
+                    let (machines, names) = put
+                        .fragments
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, vm)| !vm.is_empty())
+                        .map(|(index, vm)| {
+                            (vm.clone(), erasure::fragment_name(&put.file_name, index))
+                        })
+                        .unzip();
+                    let unpublished = Unpublished {
+                        staging: put.staging.clone(),
+                        version: 0,
+                        machines,
+                        names,
+                        file_name: put.file_name.clone(),
+                    };
+                    self.unpublished.insert(put.staging, unpublished);
+                }
+                let coded = Erasure {
+                    layout: ErasureLayout {
+                        data_shards: put.data_shards,
+                        parity_shards: put.parity_shards,
+                        size: put.size,
+                        block_size: put.block_size,
+                    },
+                    fragments: put.fragments.iter().map(|vm| vm.parse().ok()).collect(),
                 };
-
-                let _ = stream.write_all(&message).await;
-                let mut res = Vec::new();
-                if let Err(e) = stream.read_to_end(&mut res).await {
-                    warn!(
-                        "Leader map: Failed to get ack from map worker {}: {}",
-                        server_addr, e
-                    );
-                    return None;
+                self.erasure.insert(put.file_name, coded);
+            }
+            Some(table_op::Type::Delete(delete)) => {
+                // Publishing a deleted file would bring it back on disk
+                self.unpublished
+                    .retain(|_, unpublished| !delete.file_names.contains(&unpublished.file_name));
+                self.relinks
+                    .retain(|_, relink| !delete.file_names.contains(&relink.file_name));
+                for file_name in delete.file_names {
+                    self.table.remove(&file_name);
+                    self.replication.remove(&file_name);
+                    self.versions.remove(&file_name);
+                    self.erasure.remove(&file_name);
+                    self.stats.remove(&file_name);
+                    self.intermediates.remove(&file_name);
                 }
-                let Ok(res) = FileSizeRes::decode(res.as_slice()) else {
-                    warn!(
-                        "Leader map: Failed to decode ack from map worker {}",
-                        server_addr
-                    );
-                    return None;
+            }
+            Some(table_op::Type::RegisterKeys(register)) => {
+                let replicas = parse_machines(&register.machines);
+                let namespace = FileKey::namespace(&register.prefix, register.job_id);
+                for key in &register.keys {
+                    let file_key = FileKey::new(&namespace, key);
+                    self.intermediates
+                        .insert(file_key.to_string(), register.job_id);
+                    self.table
+                        .entry(file_key.to_string())
+                        .or_insert(replicas.clone());
+                }
+                self.register_namespace(&register.prefix, register.job_id, register.keys);
+            }
+            Some(table_op::Type::DropNamespace(drop)) => {
+                self.namespaces
+                    .remove_if(&drop.prefix, |_, namespace| namespace.job_id == drop.job_id);
+            }
+            Some(table_op::Type::SetDecommissioned(set)) => {
+                let Ok(machine) = set.machine.parse() else {
+                    return;
                 };
-                Some(res.size)
+                if set.decommissioned {
+                    self.decommissioned.insert(machine);
+                } else {
+                    self.decommissioned.remove(&machine);
+                }
             }
-            .await;
-            if res.is_some() {
-                file_size = res;
-                break;
+            Some(table_op::Type::MakeDir(make_dir)) => {
+                for dir in namespace::ancestors(&make_dir.path) {
+                    self.directories.insert(dir.to_string());
+                }
+                self.directories.insert(make_dir.path);
+            }
+            Some(table_op::Type::RemoveDir(remove_dir)) => {
+                self.directories.remove(&remove_dir.path);
+            }
+            Some(table_op::Type::Rename(rename)) => {
+                self.apply_rename(&rename.from, &rename.to);
+                for relink in rename.relinks {
+                    self.relinks
+                        .insert((relink.machine.clone(), relink.to.clone()), relink);
+                }
             }
+            Some(table_op::Type::Published(published)) => {
+                self.unpublished.remove(&published.staging);
+            }
+            Some(table_op::Type::Relinked(relinked)) => {
+                for relink in relinked.relinks {
+                    self.relinks.remove(&(relink.machine, relink.to));
+                }
+            }
+            Some(table_op::Type::Noop(_)) | None => {}
         }
+    }
 
-        let Some(size) = file_size else {
-            return;
-        };
-
-        let mut succ_target_vms: HashSet<_> = target_vms.iter().cloned().collect();
-        // Step 3: Distribute files among workers
-        let mut keys = Vec::new();
-        loop {
-            info!("Leader map: Initiating map at workers");
-            let mut num_workers = worker_vms.len() as u32;
-            if num_workers > size {
-                num_workers = size;
-            }
+    /// Everything committed changes recorded in the table
+    fn snapshot(&self) -> TableSnapshot {
+        TableSnapshot {
+            files: self
+                .table
+                .iter()
+                .map(|elem| {
+                    let machines = elem.value().iter().map(|vm| vm.to_string()).collect();
+                    (elem.key().clone(), Machines { machines })
+                })
+                .collect(),
+            replication: self
+                .replication
+                .iter()
+                .map(|elem| (elem.key().clone(), *elem.value() as u32))
+                .collect(),
+            versions: self
+                .versions
+                .iter()
+                .map(|elem| (elem.key().clone(), *elem.value()))
+                .collect(),
+            erasure: self
+                .erasure
+                .iter()
+                .map(|elem| elem.value().put_erasure(elem.key()))
+                .collect(),
+            directories: self.directories.iter().map(|dir| dir.clone()).collect(),
+            stats: self
+                .stats
+                .iter()
+                .map(|elem| {
+                    let meta = elem.value();
+                    let stat = FileStat {
+                        size: meta.digest.size,
+                        lines: meta.digest.lines,
+                        checksum: meta.digest.checksum,
+                        modified_millis: meta.modified_millis,
+                    };
+                    let recorded = RecordedStat {
+                        stat: Some(stat),
+                        created_millis: meta.created_millis,
+                    };
+                    (elem.key().clone(), recorded)
+                })
+                .collect(),
+            namespaces: self
+                .namespaces
+                .iter()
+                .map(|elem| RegisterKeys {
+                    prefix: elem.key().clone(),
+                    job_id: elem.job_id,
+                    keys: elem.keys.iter().cloned().collect(),
+                    machines: Vec::new(),
+                })
+                .collect(),
+            intermediates: self
+                .intermediates
+                .iter()
+                .map(|elem| (elem.key().clone(), *elem.value()))
+                .collect(),
+            decommissioned: self
+                .decommissioned
+                .iter()
+                .map(|vm| vm.to_string())
+                .collect(),
+            unpublished: self
+                .unpublished
+                .iter()
+                .map(|elem| elem.value().clone())
+                .collect(),
+            relinks: self
+                .relinks
+                .iter()
+                .map(|elem| elem.value().clone())
+                .collect(),
+        }
+    }
 
-            let mut task_handlers = JoinSet::new();
-            let mut map_results = Vec::new();
+    /// Replaces everything committed changes recorded with a snapshot
+    fn restore(&self, snapshot: TableSnapshot) {
+        // Applied first, recording a coded file clears what the other maps hold for it
+        self.erasure.clear();
+        for put in snapshot.erasure {
+            self.apply(TableOp {
+                r#type: Some(table_op::Type::PutErasure(put)),
+            });
+        }
+        self.table.clear();
+        for (file_name, machines) in snapshot.files {
+            self.table
+                .insert(file_name, parse_machines(&machines.machines));
+        }
+        self.replication.clear();
+        for (file_name, replicas) in snapshot.replication {
+            self.replication.insert(file_name, replicas as usize);
+        }
+        self.versions.clear();
+        for (file_name, version) in snapshot.versions {
+            self.versions.insert(file_name, version);
+        }
+        self.directories.clear();
+        for dir in snapshot.directories {
+            self.directories.insert(dir);
+        }
+        self.stats.clear();
+        for (file_name, recorded) in snapshot.stats {
+            let stat = recorded.stat.unwrap_or_default();
+            let meta = FileMeta {
+                digest: FileDigest {
+                    size: stat.size,
+                    lines: stat.lines,
+                    checksum: stat.checksum,
+                },
+                created_millis: recorded.created_millis,
+                modified_millis: stat.modified_millis,
+            };
+            self.stats.insert(file_name, meta);
+        }
+        self.namespaces.clear();
+        for register in snapshot.namespaces {
+            self.register_namespace(&register.prefix, register.job_id, register.keys);
+        }
+        self.intermediates.clear();
+        for (file_name, job_id) in snapshot.intermediates {
+            self.intermediates.insert(file_name, job_id);
+        }
+        self.decommissioned.clear();
+        for vm in parse_machines(&snapshot.decommissioned) {
+            self.decommissioned.insert(vm);
+        }
+        self.unpublished.clear();
+        for unpublished in snapshot.unpublished {
+            self.unpublished
+                .insert(unpublished.staging.clone(), unpublished);
+        }
+        self.relinks.clear();
+        for relink in snapshot.relinks {
+            self.relinks
+                .insert((relink.machine.clone(), relink.to.clone()), relink);
+        }
+    }
 
-            info!("Leader map: sending tasks");
-            let chunk_size = (size).div_ceil(num_workers);
-            let task_permit: Arc<Semaphore> = Arc::new(Semaphore::new(num_workers as usize));
+    /// A rewrite keeps the creation time of the file it replaces
+    fn record_stat(&self, file_name: &str, stat: FileStat) {
+        let created_millis = self
+            .stats
+            .get(file_name)
+            .map_or(stat.modified_millis, |meta| meta.created_millis);
+        let meta = FileMeta {
+            digest: FileDigest {
+                size: stat.size,
+                lines: stat.lines,
+                checksum: stat.checksum,
+            },
+            created_millis,
+            modified_millis: stat.modified_millis,
+        };
+        self.stats.insert(file_name.to_string(), meta);
+    }
 
-            for (vm, chunk) in zip(worker_vms.into_iter().cycle(), 0..num_workers) {
-                let file = file_server_map[0].0.clone();
-                let servers = file_server_map[0].1.clone();
-                info!(
-                    "Chunk {}: start line {}, end line {}",
-                    chunk,
-                    chunk * chunk_size,
-                    (chunk + 1) * chunk_size - 1
-                );
-                let command = LeaderMapReq {
-                    executable: map_req.executable.clone(),
-                    output_prefix: map_req.file_name_prefix.clone(),
-                    file_server_map: HashMap::from([(file, servers)]),
-                    target_servers: target_vms.clone(),
-                    start_line: chunk * chunk_size,
-                    end_line: (chunk + 1) * chunk_size - 1,
-                    arguments: map_req.arguments.clone(),
-                };
-                let task_permit_cloned = task_permit.clone();
-                if let Ok(permit) = task_permit_cloned.acquire_owned().await {
-                    task_handlers.spawn(async move {
-                        let res = send_leader_map_req(vm, command).await;
-                        drop(permit);
-                        res
-                    });
+    /// Everything the leader records of a file, None if it does not exist
+    fn file_info(&self, file_name: &str) -> Option<FileInfo> {
+        let located = self.locate(file_name)?;
+        let meta = self.stats.get(file_name).map(|meta| meta.clone());
+        Some(FileInfo {
+            file_name: file_name.to_string(),
+            machines: located.machines,
+            erasure: located.erasure,
+            version: self.versions.get(file_name).map_or(0, |version| *version),
+            digest: meta.as_ref().map(|meta| meta.digest.clone()),
+            created_millis: meta.as_ref().map_or(0, |meta| meta.created_millis),
+            modified_millis: meta.map_or(0, |meta| meta.modified_millis),
+        })
+    }
+
+    /// Where a file is stored, None if it does not exist
+    fn locate(&self, file_name: &str) -> Option<LsRes> {
+        if let Some(coded) = self.erasure.get(file_name) {
+            return Some(LsRes {
+                machines: coded.machines(),
+                erasure: Some(coded.layout.clone()),
+                ..Default::default()
+            });
+        }
+        let vms = self.table.get(file_name)?;
+        // Reads can only hear from replicas that are listed
+        let (_, read_quorum) = self.quorums(self.replication_of(file_name));
+        Some(LsRes {
+            machines: vms.iter().map(|ip| ip.to_string()).collect(),
+            read_quorum: read_quorum.min(vms.len()) as u32,
+            ..Default::default()
+        })
+    }
+
+    /// Every stored copy of a file as its stored name and machine, the fragments of an
+    /// erasure-coded file or the replicas of any other
+    fn stored_copies(&self, file_name: &str) -> Option<Vec<(String, Ipv4Addr)>> {
+        if let Some(coded) = self.erasure.get(file_name) {
+            return Some(
+                coded
+                    .fragments
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, vm)| {
+                        Some((erasure::fragment_name(file_name, index), (*vm)?))
+                    })
+                    .collect(),
+            );
+        }
+        let vms = self.table.get(file_name)?;
+        Some(vms.iter().map(|vm| (file_name.to_string(), *vm)).collect())
+    }
+
+    /// Moves every entry at or below `from` to the same place below `to`
+    fn apply_rename(&self, from: &str, to: &str) {
+        fn rebase_keys<V>(map: &DashMap<String, V>, from: &str, to: &str) {
+            let moved: Vec<_> = map
+                .iter()
+                .filter_map(|elem| {
+                    Some((elem.key().clone(), namespace::rebase(elem.key(), from, to)?))
+                })
+                .collect();
+            for (old, new) in moved {
+                if let Some((_, value)) = map.remove(&old) {
+                    map.insert(new, value);
                 }
             }
+        }
+        rebase_keys(&self.table, from, to);
+        rebase_keys(&self.replication, from, to);
+        rebase_keys(&self.versions, from, to);
+        rebase_keys(&self.erasure, from, to);
+        rebase_keys(&self.stats, from, to);
+        for mut elem in self.unpublished.iter_mut() {
+            let unpublished = elem.value_mut();
+            let Some(file_name) = namespace::rebase(&unpublished.file_name, from, to) else {
+                continue;
+            };
+            // Fragment names add their index to the file name
+            let old_len = unpublished.file_name.len();
+            for name in &mut unpublished.names {
+                *name = format!("{}{}", file_name, &name[old_len..]);
+            }
+            unpublished.file_name = file_name;
+        }
+        let moved: Vec<_> = self
+            .relinks
+            .iter()
+            .filter_map(|elem| {
+                let file_name = namespace::rebase(&elem.file_name, from, to)?;
+                Some((elem.key().clone(), file_name))
+            })
+            .collect();
+        for (key, file_name) in moved {
+            let Some((_, mut relink)) = self.relinks.remove(&key) else {
+                continue;
+            };
+            relink.to = format!("{}{}", file_name, &relink.to[relink.file_name.len()..]);
+            relink.file_name = file_name;
+            self.relinks
+                .insert((relink.machine.clone(), relink.to.clone()), relink);
+        }
+        let moved: Vec<_> = self
+            .directories
+            .iter()
+            .filter_map(|dir| Some((dir.key().clone(), namespace::rebase(dir.key(), from, to)?)))
+            .collect();
+        for (old, new) in moved {
+            self.directories.remove(&old);
+            self.directories.insert(new);
+        }
+    }
+
+    fn is_file(&self, name: &str) -> bool {
+        self.table.contains_key(name) || self.erasure.contains_key(name)
+    }
+
+    /// Every file in the namespace, replicated or erasure-coded
+    fn file_names(&self) -> Vec<String> {
+        self.table
+            .iter()
+            .map(|elem| elem.key().clone())
+            .chain(self.erasure.iter().map(|elem| elem.key().clone()))
+            .collect()
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        path.is_empty()
+            || self.directories.contains(path)
+            || self
+                .directories
+                .iter()
+                .any(|dir| namespace::is_under(dir.key(), path))
+            || self
+                .file_names()
+                .iter()
+                .any(|name| namespace::is_under(name, path))
+    }
+
+    /// Why no file can be created at `name`, None if one can
+    fn path_conflict(&self, name: &str) -> Option<String> {
+        if !namespace::is_valid(name) {
+            return Some(format!("{} is not a valid path", name));
+        }
+        if self.is_dir(name) {
+            return Some(format!("{} is a directory", name));
+        }
+        namespace::ancestors(name)
+            .find(|dir| self.is_file(dir))
+            .map(|file| format!("{} is a file", file))
+    }
+
+    /// The children of a directory, or everything below it if `recursive` is set
+    fn list_dir(&self, dir: &str, recursive: bool) -> Vec<String> {
+        let files = self.file_names().into_iter().map(|name| (name, false));
+        let dirs = self.directories.iter().map(|dir| (dir.key().clone(), true));
+        let mut entries = BTreeSet::new();
+        for (name, is_dir) in files.chain(dirs) {
+            if !namespace::is_under(&name, dir) {
+                continue;
+            }
+            let relative = namespace::relative(&name, dir);
+            if !recursive {
+                entries.insert(match relative.split_once('/') {
+                    Some((child, _)) => format!("{child}/"),
+                    None if is_dir => format!("{relative}/"),
+                    None => relative.to_string(),
+                });
+                continue;
+            }
+            entries.extend(namespace::ancestors(relative).map(|ancestor| format!("{ancestor}/")));
+            entries.insert(match is_dir {
+                true => format!("{relative}/"),
+                false => relative.to_string(),
+            });
+        }
+        entries.into_iter().collect()
+    }
 
-            while let Some(join) = task_handlers.join_next().await {
-                if let Ok(res) = join {
-                    map_results.push(res);
+    /// Write and read quorums for a file with `replicas` replicas
+    fn quorums(&self, replicas: usize) -> (usize, usize) {
+        let replicas = replicas.max(1);
+        let write = self
+            .write_quorum
+            .unwrap_or(replicas / 2 + 1)
+            .clamp(1, replicas);
+        let read = self
+            .read_quorum
+            .unwrap_or(replicas + 1 - write)
+            .clamp(1, replicas);
+        (write, read)
+    }
+
+    fn replication_of(&self, file_name: &str) -> usize {
+        self.replication
+            .get(file_name)
+            .map_or(self.default_replicas, |replicas| *replicas)
+    }
+
+    /// Active members that can take new replicas and tasks
+    async fn schedulable_vms(&self, members: Arc<RwLock<Vec<Node>>>) -> Vec<Ipv4Addr> {
+        let mut vms = get_active_vms(members).await;
+        vms.retain(|vm| !self.decommissioned.contains(vm));
+        vms
+    }
+
+    async fn apply_committed(&self, mut committed_rx: mpsc::UnboundedReceiver<Committed>) {
+        let mut snapshot_index = 0;
+        while let Some(committed) = committed_rx.recv().await {
+            let index = match committed {
+                Committed::Entry { index, op, done } => {
+                    self.apply(op);
+                    if let Some(done) = done {
+                        let _ = done.send(());
+                    }
+                    index
                 }
-            }
-            info!("Leader map: Joined map tasks");
-            let (succ_workers_replicators_iter, fail_keys_blocks_iter): (Vec<_>, Vec<_>) =
-                map_results
-                    .into_iter()
-                    .map(
-                        |MapResult {
-                             succ_worker,
-                             fail_blocks,
-                             keys,
-                             replicators,
-                         }| {
-                            ((succ_worker, replicators), (fail_blocks, keys))
-                        },
-                    )
-                    .unzip();
-            let (succ_workers_iter, replicators): (Vec<_>, Vec<_>) =
-                succ_workers_replicators_iter.into_iter().unzip();
-            let (fail_blocks_iter, keys_iter): (Vec<_>, Vec<_>) =
-                fail_keys_blocks_iter.into_iter().unzip();
-            (worker_vms, file_server_map) = (
-                succ_workers_iter.into_iter().flatten().collect(),
-                fail_blocks_iter.into_iter().flatten().collect(),
-            );
-            keys.extend(keys_iter.into_iter().flatten());
-            for replicator in replicators {
-                if replicator.is_empty() {
+                Committed::Snapshot { index, table } => {
+                    match TableSnapshot::decode(table.as_slice()) {
+                        Ok(snapshot) => self.restore(snapshot),
+                        Err(e) => error!("Leader: unable to decode file table snapshot: {}", e),
+                    }
+                    snapshot_index = index;
                     continue;
                 }
-                succ_target_vms = &succ_target_vms & &replicator.into_iter().collect();
+            };
+            // Entries a snapshot covers no longer need to be kept in the log
+            if index >= snapshot_index + SNAPSHOT_INTERVAL {
+                let table = self.snapshot().encode_to_vec();
+                self.raft.compact(index, table).await;
+                snapshot_index = index;
             }
-            if file_server_map.is_empty() {
-                break;
+        }
+    }
+
+    /// Replicates a file table change through the metadata group and waits until it applies
+    async fn commit(&self, op: table_op::Type) -> bool {
+        match self.raft.propose(op).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Leader: unable to commit file table change: {}", e);
+                false
             }
         }
-        info!("Leader map: Workers successfully ran map in workers");
+    }
 
-        // Step 4: Once successful, populate FileTable.keys with the key and the file name
-        for key in keys {
-            self.keys
-                .entry(key.clone())
-                .and_modify(|file_keys| {
-                    file_keys.push(FileKey::new(&map_req.file_name_prefix, &key))
-                })
-                .or_insert(Vec::from([FileKey::new(&map_req.file_name_prefix, &key)]));
-            let file_key = FileKey::new(&map_req.file_name_prefix, &key);
-            self.table.entry(file_key.to_string()).or_insert(
-                succ_target_vms
-                    .iter()
-                    .map(|ip| ip.parse::<Ipv4Addr>().unwrap())
-                    .collect(),
-            );
+    async fn drop_namespace(&self, namespace: Namespace) {
+        info!("Leader: removing namespace {}", namespace.name);
+        let files = namespace
+            .keys
+            .iter()
+            .map(|key| FileKey::new(&namespace.name, key).to_string())
+            .collect();
+        self.remove_files(files).await;
+    }
+
+    /// Drops the namespace of `prefix` if it was written by the given job
+    async fn drop_job_namespace(&self, prefix: &str, job_id: u64) {
+        let Some(namespace) = self
+            .namespaces
+            .get(prefix)
+            .filter(|namespace| namespace.job_id == job_id)
+            .map(|namespace| namespace.clone())
+        else {
+            return;
+        };
+        let drop = DropNamespace {
+            prefix: prefix.to_string(),
+            job_id,
+        };
+        if self.commit(table_op::Type::DropNamespace(drop)).await {
+            self.drop_namespace(namespace).await;
         }
+    }
 
-        info!("Leader map: Put files in filetable");
+    #[instrument(name = "Leader garbage collector", level = "trace")]
+    async fn collect_garbage(&self, members: Arc<RwLock<Vec<Node>>>) {
+        let mut interval = tokio::time::interval(GC_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(epoch) = self.raft.epoch().await else {
+                continue;
+            };
+            // Key files in the table whose namespace was replaced or never registered
+            let stale: Vec<_> = self
+                .intermediates
+                .iter()
+                .filter(|elem| !self.is_live_job(*elem.value()))
+                .map(|elem| elem.key().clone())
+                .collect();
+            if !stale.is_empty() {
+                info!("Leader GC: removing stale key files {:?}", stale);
+                self.remove_files(stale).await;
+            }
+
+            // Key files shuffled by failed jobs that never made it into the table
+            for vm in get_active_vms(members.clone()).await {
+                let Some(stored) = fetch_stored_files(vm).await else {
+                    continue;
+                };
+                // Staged writes that never committed, or whose commit was forgotten
+                for staging in stored.staged {
+                    if self.unpublished.contains_key(&staging) {
+                        continue;
+                    }
+                    info!("Leader GC: dropping stale staging {} on {}", staging, vm);
+                    let publish_req = PublishReq {
+                        staging,
+                        discard: true,
+                        epoch,
+                        ..Default::default()
+                    };
+                    send_publish_req(vm, publish_req).await;
+                }
+                for file in stored.files {
+                    let Some(job_id) = FileKey::job_id(&file) else {
+                        continue;
+                    };
+                    let is_fragment = erasure::split_fragment(&file)
+                        .is_some_and(|(file_name, _)| self.erasure.contains_key(file_name));
+                    if self.table.contains_key(&file) || is_fragment || self.is_live_job(job_id) {
+                        continue;
+                    }
+                    info!("Leader GC: removing orphaned key file {} on {}", file, vm);
+                    send_delete(vm, &file, epoch).await;
+                }
+            }
+        }
+    }
 
-        // Step 5: Send a message to the client that the map is successful
-        let ack_buffer = Ack {
-            message: "Map successful".to_string(),
+    #[instrument(name = "Leader rebalancer", level = "trace")]
+    async fn rebalance(&self, members: Arc<RwLock<Vec<Node>>>) {
+        let mut interval = tokio::time::interval(REBALANCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !self.raft.is_leader().await {
+                continue;
+            }
+            if let Some(moved) = self.rebalance_round(members.clone()).await {
+                if moved > 0 {
+                    info!("Leader rebalancer: moved {} bytes", moved);
+                }
+            }
         }
-        .encode_to_vec();
+    }
 
-        info!("Leader map: Sent ack to client");
+    /// Moves replicas from the most to the least loaded node until their gap is within the
+    /// slack or the round's budget is spent, returning the bytes moved or None if leadership
+    /// was lost
+    async fn rebalance_round(&self, members: Arc<RwLock<Vec<Node>>>) -> Option<u64> {
+        let epoch = self.raft.epoch().await?;
+        let vms = self.schedulable_vms(members).await;
+        if vms.len() < 2 {
+            return Some(0);
+        }
+        let mut sizes: HashMap<String, u64> = HashMap::new();
+        for vm in &vms {
+            let Some(stored) = fetch_stored_files(*vm).await else {
+                continue;
+            };
+            for (file, size) in zip(stored.files, stored.sizes) {
+                let known = sizes.entry(file).or_default();
+                *known = (*known).max(size);
+            }
+        }
+        let table: Vec<(String, Vec<Ipv4Addr>)> = self
+            .table
+            .iter()
+            .map(|elem| (elem.key().clone(), elem.value().clone()))
+            .collect();
+        let mut loads: HashMap<Ipv4Addr, Load> =
+            vms.iter().map(|vm| (*vm, Load::default())).collect();
+        for (file_name, replicas) in &table {
+            let size = sizes.get(file_name).copied().unwrap_or(0);
+            for vm in replicas {
+                if let Some(load) = loads.get_mut(vm) {
+                    load.files += 1;
+                    load.bytes += size;
+                }
+            }
+        }
+        // Fragments stay where they are but take up space on their holders
+        for elem in self.erasure.iter() {
+            for (index, vm) in elem.fragments.iter().enumerate() {
+                let Some(load) = vm.and_then(|vm| loads.get_mut(&vm)) else {
+                    continue;
+                };
+                let fragment = erasure::fragment_name(elem.key(), index);
+                load.files += 1;
+                load.bytes += sizes.get(&fragment).copied().unwrap_or(0);
+            }
+        }
+        // Key files of running jobs stay where their tasks expect them
+        let mut placement: HashMap<String, Vec<Ipv4Addr>> = table
+            .into_iter()
+            .filter(|(file_name, _)| {
+                !self
+                    .intermediates
+                    .get(file_name)
+                    .is_some_and(|job_id| self.running_jobs.contains(&*job_id))
+            })
+            .collect();
 
-        if let Err(e) = socket.write_all(&ack_buffer).await {
-            warn!("Leader map: Failed to send map ack to client: {:?}", e);
+        let mut budget = REBALANCE_BUDGET;
+        loop {
+            let by_load = |(vm, load): (&Ipv4Addr, &Load)| ((load.bytes, load.files), *vm);
+            let (Some((_, src)), Some((_, dst))) = (
+                loads.iter().map(by_load).max(),
+                loads.iter().map(by_load).min(),
+            ) else {
+                break;
+            };
+            let (src_load, dst_load) = (loads[&src], loads[&dst]);
+            let byte_gap = src_load.bytes - dst_load.bytes;
+            let count_gap = src_load.files.saturating_sub(dst_load.files);
+            let candidates = placement
+                .iter()
+                .filter(|(_, replicas)| replicas.contains(&src) && !replicas.contains(&dst))
+                .map(|(file_name, _)| (file_name, sizes.get(file_name).copied().unwrap_or(0)))
+                .filter(|(_, size)| *size <= budget);
+            let choice = if byte_gap > REBALANCE_BYTE_SLACK {
+                // The largest file that narrows the byte gap
+                candidates
+                    .filter(|(_, size)| size * 2 <= byte_gap)
+                    .max_by_key(|(_, size)| *size)
+            } else if count_gap > REBALANCE_COUNT_SLACK {
+                // The smallest file that keeps the byte gap within the slack
+                candidates
+                    .filter(|(_, size)| size * 2 <= byte_gap + REBALANCE_BYTE_SLACK)
+                    .min_by_key(|(_, size)| *size)
+            } else {
+                break;
+            };
+            let Some((file_name, size)) = choice.map(|(file_name, size)| (file_name.clone(), size))
+            else {
+                break;
+            };
+            info!(
+                "Leader rebalancer: moving {} ({} bytes) from {} to {}",
+                file_name, size, src, dst
+            );
+            if !self.move_replica(&file_name, src, dst, epoch).await? {
+                break;
+            }
+            budget -= size;
+            if let Some(replicas) = placement.get_mut(&file_name) {
+                replicas.retain(|vm| *vm != src);
+                replicas.push(dst);
+            }
+            if let Some(load) = loads.get_mut(&src) {
+                load.files -= 1;
+                load.bytes -= size;
+            }
+            if let Some(load) = loads.get_mut(&dst) {
+                load.files += 1;
+                load.bytes += size;
+            }
         }
+        Some(REBALANCE_BUDGET - budget)
     }
 
-    // Implement the `get_file_line_count` and `send_map_task_to_worker` functions as needed
+    /// Has `src` copy its replica of a file to `dst`, then moves the file table entry over and
+    /// deletes the copy on `src`. Writes of the file wait until the move is committed. Returns
+    /// whether the copy went through, or None if the new placement could not be committed.
+    async fn move_replica(
+        &self,
+        file_name: &str,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        epoch: u64,
+    ) -> Option<bool> {
+        // Holds off writes of the file until the new placement is committed
+        let Ok(_permits) = self.file_permits(file_name).acquire_many_owned(2).await else {
+            return Some(false);
+        };
+        // Placements that changed while waiting for the permits
+        let movable = self
+            .table
+            .get(file_name)
+            .is_some_and(|vms| vms.contains(&src) && !vms.contains(&dst));
+        if !movable {
+            return Some(false);
+        }
+        let command = LeaderPutReq {
+            machine: dst.to_string(),
+            file_name: file_name.to_string(),
+            epoch,
+        };
+        let mut succ_receivers = Vec::new();
+        let mut fail_receivers = Vec::new();
+        send_leader_put_req(
+            &src,
+            command,
+            &mut fail_receivers,
+            &dst,
+            &mut succ_receivers,
+        )
+        .await;
+        if succ_receivers.is_empty() {
+            return Some(false);
+        }
+        // Re-read the entry so changes made while copying are kept
+        let Some(mut replicas) = self.table.get(file_name).map(|vms| vms.clone()) else {
+            // Deleted while copying
+            send_delete(dst, file_name, epoch).await;
+            return Some(true);
+        };
+        replicas.retain(|vm| *vm != src);
+        if !replicas.contains(&dst) {
+            replicas.push(dst);
+        }
+        let put = PutFile {
+            file_name: file_name.to_string(),
+            machines: replicas.iter().map(|vm| vm.to_string()).collect(),
+            ..Default::default()
+        };
+        if !self.commit(table_op::Type::Put(put)).await {
+            return None;
+        }
+        send_delete(src, file_name, epoch).await;
+        Some(true)
+    }
 
-    #[instrument(name = "Leader reduce processor", level = "trace")]
-    async fn start_reduce(
+    #[instrument(name = "Leader map processor", level = "trace")]
+    async fn start_map(
         &self,
-        red_req: ReduceReq,
+        map_req: MapReq,
         mut socket: TcpStream,
         members: Arc<RwLock<Vec<Node>>>,
     ) {
-        info!("Leader reduce: starting reduce on leader");
-        // fetch active workers containing prefix
-        let key_file_map = self.keys.clone();
-        key_file_map.retain(|_, v| {
-            v.retain(|e| e.starts_with(&red_req.file_name_prefix));
-            !v.is_empty()
-        });
-        let key_files = key_file_map
-            .into_iter()
-            .map(|(_, v)| (*v[0]).clone())
-            .collect::<Vec<_>>();
+        let Some(client_ip) = peer_ipv4(&socket) else {
+            warn!("Unable to get peer address info, aborting");
+            return;
+        };
+        let job_id = self.start_job(format!(
+            "maple {} into {}",
+            map_req.executable, map_req.file_name_prefix
+        ));
+        let result = self.run_map(map_req, job_id, client_ip, members).await;
+        let response = self.finish_job(job_id, result, "Map successful");
+        send_job_response(&mut socket, response).await;
+        info!("Leader map: Sent response to client");
+    }
 
-        let mut file_server_map = Vec::new();
+    async fn run_map(
+        &self,
+        map_req: MapReq,
+        job_id: u64,
+        client_ip: Ipv4Addr,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Result<(), String> {
+        info!("Leader map: Processing map on leader");
+        let Some(epoch) = self.raft.epoch().await else {
+            return Err("Map failed: this node is no longer the leader".to_string());
+        };
+        // Key files go under a per-job namespace so reruns never append to stale output
+        let namespace = FileKey::namespace(&map_req.file_name_prefix, job_id);
+        // Step 1: Find every file the input matches, erasure-coded ones included
+        info!("Leader map: Looking for input: {}", map_req.input_dir);
+        let mut names: Vec<String> = self
+            .table
+            .iter()
+            .map(|elem| elem.key().clone())
+            .chain(self.erasure.iter().map(|elem| elem.key().clone()))
+            .filter(|name| namespace::matches_input(&map_req.input_dir, name))
+            .collect();
+        names.sort();
+        let inputs: Vec<(String, KeyServers)> = names
+            .into_iter()
+            .filter_map(|name| {
+                let located = self.locate(&name)?;
+                let servers = KeyServers {
+                    servers: located.machines,
+                    erasure: located.erasure,
+                };
+                Some((name, servers))
+            })
+            .collect();
 
-        for file in key_files {
-            let Some(storing_servers) = self.table.get(&file) else {
-                error!("Unable to find a key file, aborting reduce");
-                return;
-            };
-            file_server_map.push((
-                file,
-                KeyServers {
-                    servers: storing_servers
-                        .clone()
-                        .into_iter()
-                        .map(|ip| ip.to_string())
-                        .collect(),
-                },
-            ));
+        if inputs.is_empty() {
+            warn!("Found no server containing the file");
+            return Err(format!("No input found for {}", map_req.input_dir));
         }
 
-        let active_vms = get_active_vms(members.clone()).await;
+        info!("Leader map: Found files matching input: {:?}", inputs);
+
+        // Step 2: Find active workers containing the executable
+        let active_vms = self.schedulable_vms(members.clone()).await;
+        if active_vms.is_empty() {
+            warn!("Leader map: Unable to pick a target VM");
+            return Err("Map failed: no active workers".to_string());
+        }
         let target_vms: Vec<_> = active_vms
-            .choose_multiple(&mut rand::thread_rng(), 4)
+            .choose_multiple(&mut rand::thread_rng(), self.default_replicas)
             .map(|ip| ip.to_string())
             .collect();
 
         let mut worker_vms = active_vms.clone();
         {
-            // let Some(executable_servers) = self.table.get(&red_req.executable) else {
-            //     info!("No executable found in the file system, abortin");
+            // let Some(executable_servers) = self.table.get(&map_req.executable) else {
+            //     warn!("Leader map: No executable found in the file system, aborting");
             //     return;
             // };
             // worker_vms.retain(|vm| executable_servers.contains(vm));
-            worker_vms.truncate(red_req.num_workers as usize);
+            worker_vms.truncate(map_req.num_workers as usize);
         }
 
-        info!("Leader reduce: found active servers: {:?}", worker_vms);
+        info!("Leader map: Found active workers: {:?}", worker_vms);
 
         // have the peer upload executable to workers
-        let Ok(peer_addr) = socket.peer_addr() else {
-            warn!("Unable to get peer address info, aborting");
-            return;
-        };
-        let ip = match peer_addr.ip() {
-            IpAddr::V4(ip) => ip,
-            IpAddr::V6(ip) => ip.to_ipv4().unwrap(),
-        };
+        let ip = client_ip;
         let mut succ_receivers = Vec::new();
         let mut fail_receivers = Vec::new();
         for vm in &worker_vms {
             let command = LeaderPutReq {
                 machine: vm.to_string(),
-                file_name: red_req.executable.clone(),
+                file_name: map_req.executable.clone(),
+                epoch,
             };
             send_leader_put_req(&ip, command, &mut fail_receivers, vm, &mut succ_receivers).await;
         }
         worker_vms = succ_receivers.into_iter().copied().collect();
         info!("Uploaded executable to workers: {:?}", worker_vms);
 
-        let mut succ_target_vms: HashSet<_> = target_vms.iter().cloned().collect();
-        // send reduce requests to workers
-        loop {
-            info!("Leader reduce: sending reduce requests to workers");
-            let worker_vms_num = worker_vms.len();
-
-            let mut task_handlers = JoinSet::new();
+        // Step 3: Split every input into line ranges, about one per worker
+        let mut tasks = Vec::new();
+        for (file, servers) in inputs {
+            let Some(lines) = self.input_lines(&file, &servers).await else {
+                return Err(format!("Unable to count the lines of {}", file));
+            };
+            let Ok(lines) = u32::try_from(lines) else {
+                return Err(format!("{} has too many lines to map", file));
+            };
+            if lines == 0 {
+                continue;
+            }
+            let chunk_size = lines.div_ceil((worker_vms.len() as u32).clamp(1, lines));
+            for start_line in (0..lines).step_by(chunk_size as usize) {
+                tasks.push(LeaderMapReq {
+                    executable: map_req.executable.clone(),
+                    output_prefix: namespace.clone(),
+                    file_server_map: HashMap::from([(file.clone(), servers.clone())]),
+                    target_servers: target_vms.clone(),
+                    start_line,
+                    end_line: start_line.saturating_add(chunk_size).min(lines) - 1,
+                    arguments: map_req.arguments.clone(),
+                    limits: map_req.limits.clone(),
+                    epoch,
+                });
+            }
+        }
+
+        let mut succ_target_vms: HashSet<_> = target_vms.iter().cloned().collect();
+        // Step 4: Run the tasks, retrying the failed ones on the workers that never failed
+        let mut keys = Vec::new();
+        while !tasks.is_empty() {
+            info!("Leader map: Initiating map at workers");
+            if worker_vms.is_empty() {
+                error!("Leader map: No workers left to run map tasks");
+                return Err("Map failed: no workers left".to_string());
+            }
+
+            let mut task_handlers = JoinSet::new();
+            info!("Leader map: sending {} tasks", tasks.len());
+            let task_permit: Arc<Semaphore> = Arc::new(Semaphore::new(worker_vms.len()));
+            for (vm, command) in zip(worker_vms.iter().copied().cycle(), tasks.drain(..)) {
+                info!(
+                    "Map task on {}: start line {}, end line {}",
+                    vm, command.start_line, command.end_line
+                );
+                let task_permit_cloned = task_permit.clone();
+                if let Ok(permit) = task_permit_cloned.acquire_owned().await {
+                    task_handlers.spawn(async move {
+                        let res = send_leader_map_req(vm, command.clone()).await;
+                        drop(permit);
+                        (vm, command, res)
+                    });
+                }
+            }
+
+            let mut map_results = Vec::new();
+            while let Some(join) = task_handlers.join_next().await {
+                if let Ok(res) = join {
+                    map_results.push(res);
+                }
+            }
+            info!("Leader map: Joined map tasks");
+            let mut round_counters = JobCounters::default();
+            for (_, _, res) in map_results.iter_mut() {
+                if res.succ_worker.is_none() {
+                    round_counters.task_retries += 1;
+                }
+                merge_counters(&mut round_counters, std::mem::take(&mut res.counters));
+            }
+            self.add_counters(job_id, round_counters);
+            if let Some(reason) = map_results
+                .iter()
+                .filter_map(|(_, _, res)| res.failure)
+                .find(|reason| is_fatal_failure(*reason))
+            {
+                error!("Leader map: Aborting map, {}", describe_failure(reason));
+                return Err(format!("Map failed: {}", describe_failure(reason)));
+            }
+            for (vm, command, res) in map_results {
+                if res.succ_worker.is_none() {
+                    worker_vms.retain(|worker| *worker != vm);
+                    tasks.push(command);
+                    continue;
+                }
+                keys.extend(res.keys);
+                if !res.replicators.is_empty() {
+                    succ_target_vms = &succ_target_vms & &res.replicators.into_iter().collect();
+                }
+            }
+        }
+        info!("Leader map: Workers successfully ran map in workers");
+
+        // Step 5: Once successful, put the key files in the table and publish the namespace
+        let stale = self
+            .namespaces
+            .get(&map_req.file_name_prefix)
+            .filter(|namespace| namespace.job_id != job_id)
+            .map(|namespace| namespace.clone());
+        let register = RegisterKeys {
+            prefix: map_req.file_name_prefix.clone(),
+            job_id,
+            keys,
+            machines: succ_target_vms.into_iter().collect(),
+        };
+        if !self.commit(table_op::Type::RegisterKeys(register)).await {
+            return Err(format!(
+                "Unable to record the keys of namespace {namespace}"
+            ));
+        }
+        info!("Leader map: Put files in filetable");
+
+        if let Some(stale) = stale {
+            self.drop_namespace(stale).await;
+        }
+
+        // Step 6: Let the caller tell the client that the map is successful
+        Ok(())
+    }
+
+    /// Lines in an input file, as measured when it was written or else counted by a replica
+    async fn input_lines(&self, file: &str, servers: &KeyServers) -> Option<u64> {
+        if let Some(meta) = self.stats.get(file) {
+            return Some(meta.digest.lines);
+        }
+        // Fragments hold no whole lines
+        if servers.erasure.is_some() {
+            return None;
+        }
+        let message = SdfsCommand {
+            r#type: Some(Type::FileSizeReq(FileSizeReq {
+                file_name: file.to_string(),
+            })),
+        }
+        .encode_to_vec();
+        for server in &servers.servers {
+            let server_addr = format!("{server}:56552");
+            let Ok(mut stream) = TcpStream::connect(&server_addr).await else {
+                warn!("Leader map: Failed to contact map worker {}", server_addr);
+                continue;
+            };
+            let _ = stream.write_all(&message).await;
+            let mut res = Vec::new();
+            if let Err(e) = stream.read_to_end(&mut res).await {
+                warn!(
+                    "Leader map: Failed to get ack from map worker {}: {}",
+                    server_addr, e
+                );
+                continue;
+            }
+            match FileSizeRes::decode(res.as_slice()) {
+                Ok(res) => return Some(res.size as u64),
+                Err(_) => warn!(
+                    "Leader map: Failed to decode ack from map worker {}",
+                    server_addr
+                ),
+            }
+        }
+        None
+    }
+
+    #[instrument(name = "Leader reduce processor", level = "trace")]
+    async fn start_reduce(
+        &self,
+        red_req: ReduceReq,
+        mut socket: TcpStream,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) {
+        let Some(client_ip) = peer_ipv4(&socket) else {
+            warn!("Unable to get peer address info, aborting");
+            return;
+        };
+        let job_id = self.start_job(format!(
+            "juice {} over {}",
+            red_req.executable, red_req.file_name_prefix
+        ));
+        let result = self.run_reduce(red_req, job_id, client_ip, members).await;
+        let response = self.finish_job(job_id, result, "Reduce successful");
+        send_job_response(&mut socket, response).await;
+    }
+
+    async fn run_reduce(
+        &self,
+        red_req: ReduceReq,
+        job_id: u64,
+        client_ip: Ipv4Addr,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Result<(), String> {
+        info!("Leader reduce: starting reduce on leader");
+        let Some(epoch) = self.raft.epoch().await else {
+            return Err("Reduce failed: this node is no longer the leader".to_string());
+        };
+        // fetch the key files of the latest map into the prefix
+        let Some((map_job_id, key_files)) =
+            self.namespaces
+                .get(&red_req.file_name_prefix)
+                .map(|namespace| {
+                    (
+                        namespace.job_id,
+                        namespace
+                            .keys
+                            .iter()
+                            .map(|key| FileKey::new(&namespace.name, key).to_string())
+                            .collect::<Vec<_>>(),
+                    )
+                })
+        else {
+            warn!(
+                "Leader reduce: no map output for prefix {}",
+                red_req.file_name_prefix
+            );
+            return Err(format!(
+                "Reduce failed: no map output for prefix {}",
+                red_req.file_name_prefix
+            ));
+        };
+
+        let mut file_server_map = Vec::new();
+
+        for file in key_files {
+            let Some(storing_servers) = self.table.get(&file) else {
+                error!("Unable to find a key file, aborting reduce");
+                return Err(format!("Reduce failed: missing key file {}", file));
+            };
+            file_server_map.push((
+                file,
+                KeyServers {
+                    servers: storing_servers
+                        .clone()
+                        .into_iter()
+                        .map(|ip| ip.to_string())
+                        .collect(),
+                    ..Default::default()
+                },
+            ));
+        }
+
+        let active_vms = self.schedulable_vms(members.clone()).await;
+        let target_vms: Vec<_> = active_vms
+            .choose_multiple(&mut rand::thread_rng(), self.default_replicas)
+            .map(|ip| ip.to_string())
+            .collect();
+
+        let mut worker_vms = active_vms.clone();
+        {
+            // let Some(executable_servers) = self.table.get(&red_req.executable) else {
+            //     info!("No executable found in the file system, abortin");
+            //     return;
+            // };
+            // worker_vms.retain(|vm| executable_servers.contains(vm));
+            worker_vms.truncate(red_req.num_workers as usize);
+        }
+
+        info!("Leader reduce: found active servers: {:?}", worker_vms);
+
+        // have the peer upload executable to workers
+        let ip = client_ip;
+        let mut succ_receivers = Vec::new();
+        let mut fail_receivers = Vec::new();
+        for vm in &worker_vms {
+            let command = LeaderPutReq {
+                machine: vm.to_string(),
+                file_name: red_req.executable.clone(),
+                epoch,
+            };
+            send_leader_put_req(&ip, command, &mut fail_receivers, vm, &mut succ_receivers).await;
+        }
+        worker_vms = succ_receivers.into_iter().copied().collect();
+        info!("Uploaded executable to workers: {:?}", worker_vms);
+
+        let mut succ_target_vms: HashSet<_> = target_vms.iter().cloned().collect();
+        // send reduce requests to workers
+        loop {
+            info!("Leader reduce: sending reduce requests to workers");
+            let worker_vms_num = worker_vms.len();
+            if worker_vms_num == 0 {
+                error!("Leader reduce: No workers left to run reduce tasks");
+                return Err("Reduce failed: no workers left".to_string());
+            }
+
+            let mut task_handlers = JoinSet::new();
             let mut reduce_results = Vec::new();
 
             let chunk_size = (file_server_map.len()).div_ceil(worker_vms_num);
@@ -571,6 +2007,8 @@ impl FileTable {
                     target_servers: target_vms.clone(),
                     output_file: red_req.output_file.clone(),
                     executable: red_req.executable.clone(),
+                    limits: red_req.limits.clone(),
+                    epoch,
                 };
                 let task_permit_cloned = task_permit.clone();
                 if let Ok(permit) = task_permit_cloned.acquire_owned().await {
@@ -587,6 +2025,25 @@ impl FileTable {
                     reduce_results.push(res);
                 }
             }
+            let mut round_counters = JobCounters::default();
+            for res in reduce_results.iter_mut() {
+                if res.succ_worker.is_none() {
+                    round_counters.task_retries += 1;
+                }
+                merge_counters(&mut round_counters, std::mem::take(&mut res.counters));
+            }
+            self.add_counters(job_id, round_counters);
+            if let Some(reason) = reduce_results
+                .iter()
+                .filter_map(|res| res.failure)
+                .find(|reason| is_fatal_failure(*reason))
+            {
+                error!(
+                    "Leader reduce: Aborting reduce, {}",
+                    describe_failure(reason)
+                );
+                return Err(format!("Reduce failed: {}", describe_failure(reason)));
+            }
             let (succ_fail_iter, replicators): (Vec<_>, Vec<_>) = reduce_results
                 .into_iter()
                 .map(
@@ -594,6 +2051,7 @@ impl FileTable {
                          succ_worker,
                          fail_blocks,
                          replicators,
+                         ..
                      }| ((succ_worker, fail_blocks), replicators),
                 )
                 .unzip();
@@ -615,23 +2073,81 @@ impl FileTable {
         }
         info!("Leader reduce: sent reduce requests");
 
-        self.table.insert(
-            red_req.output_file,
-            succ_target_vms
-                .into_iter()
-                .map(|ip| ip.parse::<Ipv4Addr>().unwrap())
-                .collect::<Vec<_>>(),
-        );
+        let put = PutFile {
+            file_name: red_req.output_file.clone(),
+            machines: succ_target_vms.into_iter().collect(),
+            ..Default::default()
+        };
+        if !self.commit(table_op::Type::Put(put)).await {
+            return Err(format!(
+                "Unable to record output file {}",
+                red_req.output_file
+            ));
+        }
 
-        // end request
-        let ack_buffer = Ack {
-            message: "Reduce successful".to_string(),
+        if red_req.delete {
+            self.drop_job_namespace(&red_req.file_name_prefix, map_job_id)
+                .await;
         }
-        .encode_to_vec();
 
-        if let Err(e) = socket.write_all(&ack_buffer).await {
-            warn!("Failed to send reduce ack to client: {:?}", e);
+        Ok(())
+    }
+
+    #[instrument(name = "Leader pipeline processor", level = "trace")]
+    async fn start_pipeline(
+        &self,
+        pipeline_req: PipelineReq,
+        mut socket: TcpStream,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) {
+        let Some(client_ip) = peer_ipv4(&socket) else {
+            warn!("Unable to get peer address info, aborting");
+            return;
+        };
+        // Every stage runs under the pipeline's job id
+        let job_id = self.start_job(format!("pipeline {}", pipeline_req.name));
+        let result = self
+            .run_pipeline(pipeline_req, job_id, client_ip, members)
+            .await;
+        let response = self.finish_job(job_id, result, "Pipeline successful");
+        send_job_response(&mut socket, response).await;
+    }
+
+    async fn run_pipeline(
+        &self,
+        pipeline_req: PipelineReq,
+        job_id: u64,
+        client_ip: Ipv4Addr,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Result<(), String> {
+        info!("Leader pipeline: starting pipeline {}", pipeline_req.name);
+        let plan = pipeline::plan(&pipeline_req)?;
+        let mut result = Ok(());
+        for (stage, job) in plan.jobs {
+            info!("Leader pipeline: running stage {}", stage);
+            let res = match job {
+                PlannedJob::Map(map_req) => {
+                    self.run_map(map_req, job_id, client_ip, members.clone())
+                        .await
+                }
+                PlannedJob::Reduce(red_req) => {
+                    self.run_reduce(red_req, job_id, client_ip, members.clone())
+                        .await
+                }
+            };
+            if let Err(e) = res {
+                error!("Leader pipeline: stage {} failed: {}", stage, e);
+                result = Err(format!("Pipeline stage {} failed: {}", stage, e));
+                break;
+            }
         }
+
+        info!("Leader pipeline: cleaning up intermediate data");
+        for prefix in plan.intermediate_prefixes {
+            self.drop_job_namespace(&prefix, job_id).await;
+        }
+        self.remove_files(plan.intermediate_files).await;
+        result
     }
 
     #[instrument(name = "Leader read processor", level = "trace")]
@@ -642,10 +2158,7 @@ impl FileTable {
 
         // Perform the file reading.
         // Check if the file is available on any VMs.
-        if let Some(vms) = self.table.get(file_name) {
-            let response = LsRes {
-                machines: vms.iter().map(|ip| ip.to_string()).collect(),
-            };
+        if let Some(response) = self.locate(file_name) {
             info!("Ls Response for get: {:?}", response);
             let buffer = response.encode_to_vec();
             if let Err(e) = socket.write_all(&buffer).await {
@@ -653,7 +2166,8 @@ impl FileTable {
             }
         } else {
             info!("File not found: {}", get_req.file_name);
-            if let Err(e) = socket.write_all(b"FILE_NOT_FOUND").await {
+            let response = refusal(format!("{} does not exist", file_name));
+            if let Err(e) = socket.write_all(&response.encode_to_vec()).await {
                 warn!("Failed to send 'file not found' notification: {:?}", e);
             }
         }
@@ -669,161 +2183,1172 @@ impl FileTable {
         };
     }
 
-    #[instrument(name = "Leader ls processor", level = "trace")]
-    async fn start_ls(&self, file_name: &str, mut socket: TcpStream) {
-        // Perform the file reading.
-        // Check if the file is available on any VMs.
-        info!("Starting Ls at leader");
-        if let Some(vms) = self.table.get(file_name) {
-            let response = LsRes {
-                machines: vms.iter().map(|ip| ip.to_string()).collect(),
-            };
-            info!("Ls Response for LsReq: {:?}", response);
-            let mut buffer = Vec::new();
-            response.encode(&mut buffer).unwrap();
-            if let Err(e) = socket.write_all(&buffer).await {
-                warn!("Failed to send information to client: {:?}", e);
-            }
+    #[instrument(name = "Leader ls processor", level = "trace")]
+    async fn start_ls(&self, file_name: &str, recursive: bool, mut socket: TcpStream) {
+        info!("Starting Ls at leader");
+        let path = namespace::trim(file_name);
+        // Files answer with where they are stored, directories with what lies below them
+        let response = match self.locate(path) {
+            Some(response) => response,
+            None if self.is_dir(path) => LsRes {
+                directory: true,
+                entries: self.list_dir(path, recursive),
+                ..Default::default()
+            },
+            None => {
+                info!("File not found: {}", file_name);
+                refusal(format!("{} does not exist", file_name))
+            }
+        };
+        info!("Ls Response for LsReq: {:?}", response);
+        if let Err(e) = socket.write_all(&response.encode_to_vec()).await {
+            warn!("Failed to send information to client: {:?}", e);
+        }
+    }
+
+    #[instrument(name = "Leader stat processor", level = "trace")]
+    async fn stat(&self, stat_req: StatReq, mut socket: TcpStream) {
+        let file_name = namespace::trim(&stat_req.file_name);
+        let response = match self.file_info(file_name) {
+            Some(info) => Type::StatRes(StatRes { files: vec![info] }),
+            None => Type::Fail(Fail {
+                message: format!("{} not found", file_name),
+            }),
+        };
+        send_job_response(&mut socket, response).await;
+    }
+
+    /// Every file whose name starts with the prefix, in name order
+    #[instrument(name = "Leader lsall processor", level = "trace")]
+    async fn list_all(&self, ls_all_req: LsAllReq, mut socket: TcpStream) {
+        let mut file_names = self.file_names();
+        file_names.retain(|name| name.starts_with(&ls_all_req.prefix));
+        file_names.sort();
+        let files = file_names
+            .iter()
+            .filter_map(|name| self.file_info(name))
+            .collect();
+        send_job_response(&mut socket, Type::StatRes(StatRes { files })).await;
+    }
+
+    #[instrument(name = "Leader mkdir processor", level = "trace")]
+    async fn make_dir(&self, mkdir_req: MkdirReq, mut socket: TcpStream) {
+        let path = namespace::trim(&mkdir_req.path);
+        let conflict = match self.is_file(path) {
+            true => Some(format!("{} is a file", path)),
+            false => self.path_conflict(path),
+        };
+        let response = match conflict {
+            Some(message) => Type::Fail(Fail { message }),
+            None => {
+                let make_dir = MakeDir {
+                    path: path.to_string(),
+                };
+                if !self.commit(table_op::Type::MakeDir(make_dir)).await {
+                    // Not the leader anymore, the client retries against the next one
+                    return;
+                }
+                Type::Ack(Ack {
+                    message: format!("Created directory {}", path),
+                    ..Default::default()
+                })
+            }
+        };
+        send_job_response(&mut socket, response).await;
+    }
+
+    #[instrument(name = "Leader rmdir processor", level = "trace")]
+    async fn remove_dir(&self, rmdir_req: RmdirReq, mut socket: TcpStream) {
+        let path = namespace::trim(&rmdir_req.path);
+        let response = if path.is_empty() || !self.is_dir(path) {
+            Type::Fail(Fail {
+                message: format!("{} is not a directory", rmdir_req.path),
+            })
+        } else if !self.list_dir(path, false).is_empty() {
+            Type::Fail(Fail {
+                message: format!("{} is not empty", path),
+            })
+        } else {
+            let remove_dir = RemoveDir {
+                path: path.to_string(),
+            };
+            if !self.commit(table_op::Type::RemoveDir(remove_dir)).await {
+                return;
+            }
+            Type::Ack(Ack {
+                message: format!("Removed directory {}", path),
+                ..Default::default()
+            })
+        };
+        send_job_response(&mut socket, response).await;
+    }
+
+    #[instrument(name = "Leader rename processor", level = "trace")]
+    async fn rename(&self, rename_req: RenameReq, mut socket: TcpStream) {
+        let from = namespace::trim(&rename_req.from);
+        let to = namespace::trim(&rename_req.to);
+        let Some(result) = self.move_path(from, to).await else {
+            // Not the leader anymore, the client retries against the next one
+            return;
+        };
+        let response = match result {
+            Ok(to) => Type::Ack(Ack {
+                message: format!("Renamed {} to {}", from, to),
+                ..Default::default()
+            }),
+            Err(message) => Type::Fail(Fail { message }),
+        };
+        send_job_response(&mut socket, response).await;
+    }
+
+    /// The permits PUTs, appends and reads of a file share
+    fn file_permits(&self, file_name: &str) -> Arc<Semaphore> {
+        self.permits
+            .entry(file_name.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(2)))
+            .clone()
+    }
+
+    /// Renames a file or a directory without copying data: every stored copy is linked under
+    /// its new name, the rename is committed and the old names are dropped. Copies whose
+    /// servers are down keep their old name until they are relinked. Returns the new path, or
+    /// None if leadership was lost.
+    async fn move_path(&self, from: &str, to: &str) -> Option<Result<String, String>> {
+        let epoch = self.raft.epoch().await?;
+        if from.is_empty() || !(self.is_file(from) || self.is_dir(from)) {
+            return Some(Err(format!("{} not found", from)));
+        }
+        // Moving onto a directory moves into it
+        let to = match self.is_dir(to) {
+            true => namespace::join(to, namespace::base_name(from)),
+            false => to.to_string(),
+        };
+        if let Err(message) = self.check_move_target(from, &to) {
+            return Some(Err(message));
+        }
+        let moved = self.files_under(from);
+        // Waits for writes to the old and new names and holds off new ones, taken in order so
+        // concurrent renames cannot wait on each other
+        let locked: BTreeSet<String> = moved
+            .iter()
+            .flat_map(|file| [Some(file.clone()), namespace::rebase(file, from, &to)])
+            .flatten()
+            .collect();
+        let mut permits = Vec::with_capacity(locked.len());
+        for file in &locked {
+            let Ok(permit) = self.file_permits(file).acquire_many_owned(2).await else {
+                return Some(Err(format!("Unable to lock {}", file)));
+            };
+            permits.push(permit);
+        }
+        // Writes that finished while the permits were taken
+        if self.files_under(from) != moved {
+            return Some(Err(format!("{} changed during the rename", from)));
+        }
+        if let Err(message) = self.check_move_target(from, &to) {
+            return Some(Err(message));
+        }
+        // Copies are named after their file, fragments add their index to the name
+        let mut links = Vec::new();
+        for file in &moved {
+            let Some(new_file) = namespace::rebase(file, from, &to) else {
+                continue;
+            };
+            for (name, vm) in self.stored_copies(file).unwrap_or_default() {
+                let relink = Relink {
+                    machine: vm.to_string(),
+                    to: format!("{}{}", new_file, &name[file.len()..]),
+                    from: name,
+                    file_name: file.clone(),
+                };
+                links.push((vm, relink));
+            }
+        }
+        let mut linkers = JoinSet::new();
+        for (vm, relink) in links {
+            let link_req = LinkReq {
+                from: relink.from.clone(),
+                to: relink.to.clone(),
+                epoch,
+            };
+            linkers.spawn(async move { (vm, relink, send_link_req(vm, link_req).await) });
+        }
+        let mut linked = Vec::new();
+        let mut unlinked = Vec::new();
+        while let Some(joined) = linkers.join_next().await {
+            let Ok((vm, relink, succ)) = joined else {
+                continue;
+            };
+            match succ {
+                true => linked.push((vm, relink)),
+                false => unlinked.push(relink),
+            }
+        }
+        // Every file stays readable under its new name while the rest are relinked
+        let unreadable = moved.iter().find(|file| {
+            let readable = linked
+                .iter()
+                .filter(|(_, relink)| relink.file_name == **file)
+                .count();
+            readable < self.copies_to_read(file)
+        });
+        if let Some(file) = unreadable {
+            for (vm, relink) in linked {
+                send_delete(vm, &relink.to, epoch).await;
+            }
+            return Some(Err(format!(
+                "Unable to rename, too few servers linked {}",
+                file
+            )));
+        }
+        for relink in &unlinked {
+            warn!(
+                "{} did not link {}, relinking it later",
+                relink.machine, relink.to
+            );
+        }
+        let rename = Rename {
+            from: from.to_string(),
+            to: to.clone(),
+            relinks: unlinked,
+        };
+        if !self.commit(table_op::Type::Rename(rename)).await {
+            return None;
+        }
+        drop(permits);
+        for (vm, relink) in linked {
+            send_delete(vm, &relink.from, epoch).await;
+        }
+        Some(Ok(to))
+    }
+
+    /// Files at or below `path`
+    fn files_under(&self, path: &str) -> BTreeSet<String> {
+        self.file_names()
+            .into_iter()
+            .filter(|file| file == path || namespace::is_under(file, path))
+            .collect()
+    }
+
+    fn check_move_target(&self, from: &str, to: &str) -> Result<(), String> {
+        if to == from || namespace::is_under(to, from) {
+            return Err(format!("Unable to move {} into itself", from));
+        }
+        if self.is_file(to) {
+            return Err(format!("{} already exists", to));
+        }
+        match self.path_conflict(to) {
+            Some(conflict) => Err(conflict),
+            None => Ok(()),
+        }
+    }
+
+    /// Stored copies a read of the file needs, fragments for erasure-coded files
+    fn copies_to_read(&self, file_name: &str) -> usize {
+        if let Some(coded) = self.erasure.get(file_name) {
+            return coded.layout.data_shards as usize;
+        }
+        let copies = self.table.get(file_name).map_or(0, |vms| vms.len());
+        let (_, read_quorum) = self.quorums(self.replication_of(file_name));
+        read_quorum.min(copies)
+    }
+
+    /// Links the copies a rename left under their old name once their servers are back
+    #[instrument(name = "Leader relinker", level = "trace")]
+    async fn relink_moved(&self) {
+        let mut interval = tokio::time::interval(RELINK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(epoch) = self.raft.epoch().await else {
+                continue;
+            };
+            let pending: Vec<Relink> = self
+                .relinks
+                .iter()
+                .map(|elem| elem.value().clone())
+                .collect();
+            let mut relinked = Vec::new();
+            for relink in pending {
+                let Ok(vm) = relink.machine.parse::<Ipv4Addr>() else {
+                    continue;
+                };
+                let listed = self
+                    .stored_copies(&relink.file_name)
+                    .unwrap_or_default()
+                    .contains(&(relink.to.clone(), vm));
+                // Copies repaired onto other servers since no longer need their name
+                if listed {
+                    let link_req = LinkReq {
+                        from: relink.from.clone(),
+                        to: relink.to.clone(),
+                        epoch,
+                    };
+                    if !send_link_req(vm, link_req).await {
+                        continue;
+                    }
+                }
+                send_delete(vm, &relink.from, epoch).await;
+                relinked.push(relink);
+            }
+            if relinked.is_empty() {
+                continue;
+            }
+            info!("Leader: relinked {} moved copies", relinked.len());
+            let relinked = Relinked { relinks: relinked };
+            self.commit(table_op::Type::Relinked(relinked)).await;
+        }
+    }
+
+    #[instrument(name = "Leader delete processor", level = "trace")]
+    async fn delete_file(&self, del_req: Delete, mut socket: TcpStream) {
+        info!("Starting Delete at leader");
+        let Some(removed) = self.remove_files(vec![del_req.file_name.clone()]).await else {
+            // Not the leader anymore, the client retries against the next one
+            return;
+        };
+        let response = match removed {
+            0 => {
+                info!("File not found: {}", del_req.file_name);
+                Type::Fail(Fail {
+                    message: format!("{} does not exist", del_req.file_name),
+                })
+            }
+            _ => Type::Ack(Ack {
+                message: "File DELETE successful".to_string(),
+                ..Default::default()
+            }),
+        };
+        let response = SdfsCommand {
+            r#type: Some(response),
+        };
+        if let Err(e) = socket.write_all(&response.encode_to_vec()).await {
+            warn!("Failed to send information to client: {:?}", e);
+        }
+    }
+
+    /// Removes files from the file table and deletes their replicas, returning how many of
+    /// them existed or None if the removal could not be committed
+    async fn remove_files(&self, file_names: Vec<String>) -> Option<usize> {
+        let epoch = self.raft.epoch().await?;
+        let replicas: Vec<_> = file_names
+            .into_iter()
+            .filter_map(|file_name| {
+                let copies = self.stored_copies(&file_name)?;
+                Some((file_name, copies))
+            })
+            .collect();
+        if replicas.is_empty() {
+            return Some(0);
+        }
+        let delete = DeleteFiles {
+            file_names: replicas
+                .iter()
+                .map(|(file_name, _)| file_name.clone())
+                .collect(),
+        };
+        if !self.commit(table_op::Type::Delete(delete)).await {
+            return None;
+        }
+        for (file_name, copies) in &replicas {
+            self.actors.remove(file_name);
+            for (stored_name, machine) in copies {
+                send_delete(*machine, stored_name, epoch).await;
+            }
+        }
+        Some(replicas.len())
+    }
+
+    #[instrument(name = "Leader write processor", level = "trace")]
+    async fn start_write(
+        &self,
+        put_req: PutReq,
+        mut socket: TcpStream,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) {
+        info!("Starting Write at leader");
+        let file_name = &put_req.file_name;
+
+        if let Some(conflict) = self.path_conflict(file_name) {
+            warn!("Refusing write: {}", conflict);
+            let _ = socket.write_all(&refusal(conflict).encode_to_vec()).await;
+            return;
+        }
+        let active_vms = self.schedulable_vms(members.clone()).await;
+
+        let start_time = Instant::now(); // Capture the start time
+        info!("Active VMs (write handler task): {:?}", active_vms);
+        // Every fragment of an erasure-coded file goes to a different VM
+        let replicas = match (&put_req.erasure, put_req.replicas) {
+            (Some(layout), _) => erasure::fragments(layout),
+            // A rewrite keeps the factor the file was stored at
+            (None, 0) => self.replication_of(file_name),
+            (None, replicas) => replicas as usize,
+        };
+        // Check if we have enough active VMs for every replica.
+        if active_vms.len() < replicas {
+            error!("Not enough active VMs to place {} replicas.", replicas);
+            let message = format!(
+                "Only {} servers are available for {} replicas",
+                active_vms.len(),
+                replicas
+            );
+            let _ = socket.write_all(&refusal(message).encode_to_vec()).await;
+            return;
+        }
+
+        // Now, select VMs at random from the list of active VMs.
+        let selected_vm_names: Vec<_>;
+        {
+            let mut rng = rand::thread_rng(); // Create a random number generator.
+                                              // Convert Bytes back to String if necessary, depending on your setup.
+            selected_vm_names = active_vms
+                .choose_multiple(&mut rng, replicas)
+                .cloned()
+                .collect();
+        }
+
+        info!("Selected VMs for write: {:?}", selected_vm_names);
+        // Update the state to indicate that a write operation is ongoing.
+
+        // Servers stage the data under this id, it only replaces the file once committed
+        let staging = format!("{:016x}", rand::random::<u64>());
+        let staged: Vec<(Ipv4Addr, String)> = selected_vm_names
+            .iter()
+            .enumerate()
+            .map(|(index, vm)| match put_req.erasure {
+                Some(_) => (*vm, erasure::fragment_name(file_name, index)),
+                None => (*vm, file_name.to_string()),
+            })
+            .collect();
+
+        let version = self.versions.get(file_name).map_or(0, |version| *version) + 1;
+        let (write_quorum, _) = self.quorums(replicas);
+
+        // Send back the response to the client.
+        let response = LsRes {
+            machines: selected_vm_names.iter().map(|ip| ip.to_string()).collect(),
+            erasure: put_req.erasure.clone(),
+            staging: staging.clone(),
+            write_quorum: write_quorum as u32,
+            ..Default::default()
+        };
+        let buffer = response.encode_to_vec();
+        if let Err(e) = socket.write_all(&buffer).await {
+            warn!("Failed to send information to client: {:?}", e);
+            warn!("File reps not sent: {}", put_req.file_name);
+        }
+        info!("Ls Response for put: {:?}", response);
+        // Update the state to indicate that the write operation is complete.
+        let write = StagedWrite {
+            file_name: file_name.to_string(),
+            staged,
+            staging,
+            version,
+            write_quorum,
+            replicas: put_req.replicas,
+            erasure: put_req.erasure.clone(),
+        };
+        let published = self.commit_staged(write, &mut socket).await;
+        if published && put_req.erasure.is_some() {
+            // Fragments the client could not store are rebuilt from the others
+            if let Some(epoch) = self.raft.epoch().await {
+                self.repair_erasure(file_name, &[], epoch, members).await;
+            }
+        }
+        let duration = start_time.elapsed();
+        info!("Total time taken to write the file: {:?}", duration);
+    }
+
+    /// Waits for the client to report which servers stored a staged write, commits the new
+    /// version if a write quorum did and has every server publish or drop its staged copy
+    async fn commit_staged(&self, write: StagedWrite, socket: &mut TcpStream) -> bool {
+        let StagedWrite {
+            file_name,
+            staged,
+            staging,
+            version,
+            write_quorum,
+            replicas,
+            erasure,
+        } = write;
+        let file_name = file_name.as_str();
+        let mut client_ls_buffer = [0; 1024];
+
+        let succ_vms = match socket.read(&mut client_ls_buffer).await {
+            Ok(n) => LsRes::decode(&client_ls_buffer[..n]).unwrap_or_else(|_| {
+                warn!("Unable to decode ACK server message");
+                LsRes::default()
+            }),
+            Err(_) => {
+                warn!("received no ack from client");
+                LsRes::default()
+            }
+        };
+        info!("Received ACK from client");
+
+        // Only servers the write was staged on count, whatever the client reports
+        let machines: Vec<String> = match &erasure {
+            // Fragments that were not stored, or not where they were placed, are empty holders
+            Some(_) => staged
+                .iter()
+                .enumerate()
+                .map(|(index, (vm, _))| {
+                    let vm = vm.to_string();
+                    match succ_vms.machines.get(index) == Some(&vm) {
+                        true => vm,
+                        false => String::new(),
+                    }
+                })
+                .collect(),
+            None => staged
+                .iter()
+                .map(|(vm, _)| vm.to_string())
+                .filter(|vm| succ_vms.machines.contains(vm))
+                .collect(),
+        };
+        // The layout is the one asked for, only the size is learnt from the client
+        let erasure = erasure.map(|requested| ErasureLayout {
+            size: succ_vms
+                .erasure
+                .as_ref()
+                .map_or(0, |reported| reported.size),
+            ..requested
+        });
+        let stored = machines.iter().filter(|vm| !vm.is_empty()).count();
+        let needed = match &erasure {
+            Some(layout) => layout.data_shards as usize,
+            None => write_quorum,
+        };
+        let below_quorum = stored < needed;
+        // Copies of the version being replaced, kept on disk only where the new one lands
+        let superseded = self.stored_copies(file_name).unwrap_or_default();
+        // The commit time is the file's modification time on every member
+        let stat = succ_vms.digest.as_ref().map(|digest| FileStat {
+            size: digest.size,
+            lines: digest.lines,
+            checksum: digest.checksum,
+            modified_millis: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        });
+        let published = match erasure {
+            _ if stored == 0 || below_quorum => false,
+            Some(layout) => {
+                let coded = Erasure {
+                    layout,
+                    fragments: machines.iter().map(|vm| vm.parse().ok()).collect(),
+                };
+                let put = PutErasure {
+                    stat,
+                    staging: staging.clone(),
+                    ..coded.put_erasure(file_name)
+                };
+                self.commit(table_op::Type::PutErasure(put)).await
+            }
+            None => {
+                let put = PutFile {
+                    file_name: file_name.to_string(),
+                    machines: machines.clone(),
+                    replicas,
+                    version,
+                    stat,
+                    staging: staging.clone(),
+                };
+                self.commit(table_op::Type::Put(put)).await
+            }
+        };
+        if !published {
+            warn!("File reps not recorded: {}", file_name);
+        }
+        match self.raft.epoch().await {
+            Some(epoch) => {
+                let kept = self
+                    .publish_staged(&staging, staged, &machines, published, epoch)
+                    .await;
+                // Such as the replicas of a file now erasure-coded, or the fragments of a file
+                // now replicated
+                for (name, vm) in superseded {
+                    if published && !kept.contains(&(name.clone(), vm)) {
+                        send_delete(vm, &name, epoch).await;
+                    }
+                }
+            }
+            // Servers refuse commands without a current epoch, the next leader publishes
+            None => warn!("Leader: lost the lease before publishing {}", file_name),
+        }
+        let response = if published {
+            Type::Ack(Ack {
+                message: format!("Committed version {} of {}", version, file_name),
+                ..Default::default()
+            })
+        } else if below_quorum {
+            Type::Fail(Fail {
+                message: format!(
+                    "only {} of the {} copies needed stored {}",
+                    stored, needed, file_name
+                ),
+            })
+        } else {
+            Type::Fail(Fail {
+                message: format!("{} was not committed", file_name),
+            })
+        };
+        send_job_response(socket, response).await;
+        published
+    }
+
+    /// Has the servers that stored a committed write publish their copies and the others drop
+    /// theirs. Returns the copies the write keeps, as their name and machine.
+    async fn publish_staged(
+        &self,
+        staging: &str,
+        staged: Vec<(Ipv4Addr, String)>,
+        stored: &[String],
+        published: bool,
+        epoch: u64,
+    ) -> HashSet<(String, Ipv4Addr)> {
+        let mut kept = HashSet::new();
+        let mut discarders = JoinSet::new();
+        for (vm, name) in staged {
+            if published && stored.contains(&vm.to_string()) {
+                kept.insert((name, vm));
+                continue;
+            }
+            let publish_req = PublishReq {
+                file_name: name,
+                staging: staging.to_string(),
+                discard: true,
+                epoch,
+                version: 0,
+            };
+            // Staged copies left behind are collected once they are old enough
+            discarders.spawn(async move { send_publish_req(vm, publish_req).await });
+        }
+        if published {
+            if let Some(unpublished) = self.unpublished.get(staging).map(|elem| elem.clone()) {
+                self.roll_forward(unpublished, epoch).await;
+            }
+        }
+        while discarders.join_next().await.is_some() {}
+        kept
+    }
+
+    /// Has every server still listed for a committed write publish its staged copy, and
+    /// records the write as published once all of them did. Publishing a copy twice is
+    /// harmless, so this is retried until it succeeds.
+    async fn roll_forward(&self, unpublished: Unpublished, epoch: u64) -> bool {
+        let listed = self
+            .stored_copies(&unpublished.file_name)
+            .unwrap_or_default();
+        let mut publishers = JoinSet::new();
+        for (machine, name) in zip(unpublished.machines, unpublished.names) {
+            let Ok(vm) = machine.parse::<Ipv4Addr>() else {
+                continue;
+            };
+            // Copies replaced or moved since no longer need to be published
+            if !listed.contains(&(name.clone(), vm)) {
+                continue;
+            }
+            let publish_req = PublishReq {
+                file_name: name,
+                staging: unpublished.staging.clone(),
+                discard: false,
+                epoch,
+                version: unpublished.version,
+            };
+            publishers.spawn(async move {
+                let published = send_publish_req(vm, publish_req).await;
+                if !published {
+                    warn!("Server {} did not publish its staged copy", vm);
+                }
+                published
+            });
+        }
+        let mut all_published = true;
+        while let Some(joined) = publishers.join_next().await {
+            all_published &= joined.unwrap_or(false);
+        }
+        if !all_published {
+            return false;
+        }
+        let published = Published {
+            staging: unpublished.staging,
+        };
+        self.commit(table_op::Type::Published(published)).await
+    }
+
+    /// Retries publishing committed writes, such as those a previous leader committed but
+    /// did not get every server to publish
+    #[instrument(name = "Leader publisher", level = "trace")]
+    async fn publish_committed(&self) {
+        let mut interval = tokio::time::interval(PUBLISH_RETRY);
+        loop {
+            interval.tick().await;
+            let Some(epoch) = self.raft.epoch().await else {
+                continue;
+            };
+            let pending: Vec<Unpublished> = self
+                .unpublished
+                .iter()
+                .map(|elem| elem.value().clone())
+                .collect();
+            for unpublished in pending {
+                self.roll_forward(unpublished, epoch).await;
+            }
+        }
+    }
+
+    #[instrument(name = "Leader append processor", level = "trace")]
+    async fn start_append(&self, append_req: AppendReq, mut socket: TcpStream) {
+        info!("Starting Append at leader");
+        let file_name = &append_req.file_name;
+        // Every replica adds the data to the latest version, one append at a time
+        let Some(replicas) = self.table.get(file_name).map(|vms| vms.clone()) else {
+            info!("File not found for append: {}", file_name);
+            let message = match self.erasure.contains_key(file_name) {
+                true => format!("{} is erasure-coded", file_name),
+                false => format!("{} does not exist", file_name),
+            };
+            let _ = socket.write_all(&refusal(message).encode_to_vec()).await;
+            return;
+        };
+        let base_version = self.versions.get(file_name).map_or(0, |version| *version);
+        let (write_quorum, _) = self.quorums(self.replication_of(file_name));
+        let staging = format!("{:016x}", rand::random::<u64>());
+        let response = LsRes {
+            machines: replicas.iter().map(|ip| ip.to_string()).collect(),
+            staging: staging.clone(),
+            write_quorum: write_quorum as u32,
+            version: base_version,
+            ..Default::default()
+        };
+        if let Err(e) = socket.write_all(&response.encode_to_vec()).await {
+            warn!("Failed to send information to client: {:?}", e);
+        }
+        let write = StagedWrite {
+            file_name: file_name.to_string(),
+            staged: replicas
+                .into_iter()
+                .map(|vm| (vm, file_name.to_string()))
+                .collect(),
+            staging,
+            version: base_version + 1,
+            write_quorum,
+            replicas: 0,
+            erasure: None,
+        };
+        self.commit_staged(write, &mut socket).await;
+    }
+
+    #[instrument(name = "Leader setrep processor", level = "trace")]
+    async fn set_replication(
+        &self,
+        set_rep_req: SetRepReq,
+        mut socket: TcpStream,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) {
+        let file_name = set_rep_req.file_name;
+        let target = set_rep_req.replicas as usize;
+        let response = match self.table.get(&file_name).map(|vms| vms.clone()) {
+            _ if target == 0 => Type::Fail(Fail {
+                message: "A file needs at least one replica".to_string(),
+            }),
+            None if self.erasure.contains_key(&file_name) => Type::Fail(Fail {
+                message: format!("{} is erasure-coded and has no replicas", file_name),
+            }),
+            None => Type::Fail(Fail {
+                message: format!("File {} not found", file_name),
+            }),
+            Some(_) => {
+                let Some(stored) = self.change_replication(&file_name, target, members).await
+                else {
+                    // Not the leader anymore, the client retries against the next one
+                    return;
+                };
+                let mut message = format!("Replication factor of {} set to {}", file_name, target);
+                if stored < target {
+                    message += &format!(", only {} replicas could be placed", stored);
+                }
+                Type::Ack(Ack {
+                    message,
+                    ..Default::default()
+                })
+            }
+        };
+        send_job_response(&mut socket, response).await;
+    }
+
+    /// Copies a file to more members or deletes its extra replicas until it has `target` of
+    /// them, returning how many it ends up with or None if leadership was lost. Writes of the
+    /// file wait until the new placement is committed.
+    async fn change_replication(
+        &self,
+        file_name: &str,
+        target: usize,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Option<usize> {
+        let epoch = self.raft.epoch().await?;
+        let Ok(_permits) = self.file_permits(file_name).acquire_many_owned(2).await else {
+            return Some(0);
+        };
+        // Read once the permits are held, so no write lands between the copy and the commit
+        let Some(replicas) = self.table.get(file_name).map(|vms| vms.clone()) else {
+            return Some(0);
+        };
+        let mut candidates: Vec<_> = if target > replicas.len() {
+            self.schedulable_vms(members)
+                .await
+                .into_iter()
+                .filter(|vm| !replicas.contains(vm))
+                .collect()
         } else {
-            let response = LsRes {
-                machines: Vec::new(),
+            Vec::new()
+        };
+        candidates.shuffle(&mut rand::thread_rng());
+        let mut added = Vec::new();
+        for receiver in &candidates {
+            if replicas.len() + added.len() >= target {
+                break;
             }
-            .encode_to_vec();
-            info!("File not found: {}", file_name);
-            if let Err(e) = socket.write_all(&response).await {
-                warn!("Failed to send 'file not found' notification: {:?}", e);
+            let Some(sender) = replicas.choose(&mut rand::thread_rng()) else {
+                break;
+            };
+            let command = LeaderPutReq {
+                machine: receiver.to_string(),
+                file_name: file_name.to_string(),
+                epoch,
+            };
+            let mut fail_receivers = Vec::new();
+            send_leader_put_req(sender, command, &mut fail_receivers, receiver, &mut added).await;
+        }
+        // Re-read the entry so changes made while copying are kept
+        let Some(mut current) = self.table.get(file_name).map(|vms| vms.clone()) else {
+            // Deleted while copying
+            for vm in added {
+                send_delete(*vm, file_name, epoch).await;
+            }
+            return Some(0);
+        };
+        for vm in added {
+            if !current.contains(vm) {
+                current.push(*vm);
             }
         }
+        let extra = current.split_off(target.min(current.len()));
+        let put = PutFile {
+            file_name: file_name.to_string(),
+            machines: current.iter().map(|vm| vm.to_string()).collect(),
+            replicas: target as u32,
+            ..Default::default()
+        };
+        if !self.commit(table_op::Type::Put(put)).await {
+            return None;
+        }
+        for vm in extra {
+            send_delete(vm, file_name, epoch).await;
+        }
+        Some(current.len())
     }
 
-    #[instrument(name = "Leader delete processor", level = "trace")]
-    async fn delete_file(&self, del_req: Delete, mut socket: TcpStream) {
-        info!("Starting Delete at leader");
-        let file_name = &del_req.file_name;
-        if let Some(vms) = self.table.get(file_name) {
-            for machine in vms.iter() {
-                let server_address = machine.to_string() + ":56552";
-                let Ok(mut server_stream) = TcpStream::connect(&server_address).await else {
-                    warn!(
-                        "Unable to connect to server {}, ignoring server",
-                        server_address
-                    );
-                    continue;
+    #[instrument(name = "Leader erasure conversion processor", level = "trace")]
+    async fn convert_erasure(
+        &self,
+        erasure_req: ErasureReq,
+        mut socket: TcpStream,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) {
+        let file_name = erasure_req.file_name;
+        let replicas = self.table.get(&file_name).map(|vms| vms.clone());
+        let response = match (replicas, erasure_req.erasure) {
+            (_, None) => Type::Fail(Fail {
+                message: "Missing erasure layout".to_string(),
+            }),
+            (None, _) if self.erasure.contains_key(&file_name) => Type::Fail(Fail {
+                message: format!("{} is already erasure-coded", file_name),
+            }),
+            (None, _) => Type::Fail(Fail {
+                message: format!("File {} not found", file_name),
+            }),
+            (Some(replicas), Some(layout)) => {
+                let Some(result) = self
+                    .encode_file(&file_name, replicas, layout, members)
+                    .await
+                else {
+                    // Not the leader anymore, the client retries against the next one
+                    return;
                 };
-
-                let del_buffer = SdfsCommand {
-                    r#type: Some(Type::Del(del_req.clone())),
+                match result {
+                    Ok(layout) => Type::Ack(Ack {
+                        message: format!(
+                            "Converted {} to {}+{} erasure coding",
+                            file_name, layout.data_shards, layout.parity_shards
+                        ),
+                        ..Default::default()
+                    }),
+                    Err(message) => Type::Fail(Fail { message }),
                 }
-                .encode_to_vec();
+            }
+        };
+        send_job_response(&mut socket, response).await;
+    }
 
-                // println!("Request buffer: {:?}", del_buffer);
-                if let Err(e) = server_stream.write_all(&del_buffer).await {
-                    warn!("Unable to send request to server: {}", e);
-                    continue;
-                }
-                info!("Sent request to server maybe");
-                let mut ack_buffer = [0; 1024];
-                let Ok(n) = server_stream.read(&mut ack_buffer).await else {
-                    info!("Nothing to delete");
-                    continue;
-                };
-                info!("Received ACK from server");
-                if let Err(e) = Ack::decode(&ack_buffer[..n]) {
-                    warn!("Unable to decode ACK server message {}", e);
-                    continue;
-                };
-                info!("File delete from machine: {}", machine);
+    /// Has a replica holder encode a replicated file into fragments on distinct members, then
+    /// drops the whole copies. None if leadership was lost.
+    async fn encode_file(
+        &self,
+        file_name: &str,
+        replicas: Vec<Ipv4Addr>,
+        layout: ErasureLayout,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Option<Result<ErasureLayout, String>> {
+        let epoch = self.raft.epoch().await?;
+        let count = erasure::fragments(&layout);
+        let active_vms = self.schedulable_vms(members).await;
+        if active_vms.len() < count {
+            return Some(Err(format!(
+                "{} fragments need {} active members, only {} are available",
+                count,
+                count,
+                active_vms.len()
+            )));
+        }
+        let targets: Vec<Ipv4Addr> = active_vms
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .copied()
+            .collect();
+        let mut encoded = None;
+        for encoder in &replicas {
+            let encode_req = EncodeReq {
+                file_name: file_name.to_string(),
+                erasure: Some(layout.clone()),
+                machines: targets.iter().map(|vm| vm.to_string()).collect(),
+                epoch,
+            };
+            encoded = send_encode_req(*encoder, encode_req).await;
+            if encoded.is_some() {
+                break;
             }
-            // Send ack to client
-            let ack_buffer = Ack {
-                message: "File DELETE successful".to_string(),
+        }
+        let Some(layout) = encoded else {
+            for (index, vm) in targets.iter().enumerate() {
+                send_delete(*vm, &erasure::fragment_name(file_name, index), epoch).await;
             }
-            .encode_to_vec();
+            return Some(Err(format!("No replica of {} could encode it", file_name)));
+        };
+        // Read the replicas again so copies added while encoding are dropped too
+        let replicas = self
+            .table
+            .get(file_name)
+            .map_or(replicas, |vms| vms.clone());
+        let coded = Erasure {
+            layout: layout.clone(),
+            fragments: targets.into_iter().map(Some).collect(),
+        };
+        if !self.commit(coded.put_op(file_name)).await {
+            return None;
+        }
+        for vm in replicas {
+            send_delete(vm, file_name, epoch).await;
+        }
+        Some(Ok(layout))
+    }
 
-            if let Err(e) = socket.write_all(&ack_buffer).await {
-                warn!("Failed to send information to client: {:?}", e);
+    /// Forgets the fragments of an erasure-coded file stored on `failed` machines and has
+    /// other members rebuild every lost fragment from the surviving ones. Returns whether all
+    /// fragments are stored again, or None if leadership was lost.
+    async fn repair_erasure(
+        &self,
+        file_name: &str,
+        failed: &[Ipv4Addr],
+        epoch: u64,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Option<bool> {
+        let Some(mut coded) = self.erasure.get(file_name).map(|coded| coded.clone()) else {
+            return Some(true);
+        };
+        for fragment in &mut coded.fragments {
+            if fragment.is_some_and(|vm| failed.contains(&vm)) {
+                *fragment = None;
             }
-        } else {
-            info!("File not found: {}", del_req.file_name);
-            if let Err(e) = socket.write_all(b"FILE_NOT_FOUND").await {
-                warn!("Failed to send 'file not found' notification: {:?}", e);
+        }
+        let surviving = coded.fragments.iter().flatten().count();
+        if surviving < coded.layout.data_shards as usize {
+            warn!("Too few fragments of {} survive to rebuild it", file_name);
+            return self.commit(coded.put_op(file_name)).await.then_some(false);
+        }
+        let mut candidates: Vec<_> = self
+            .schedulable_vms(members)
+            .await
+            .into_iter()
+            .filter(|vm| !coded.fragments.contains(&Some(*vm)))
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        for index in 0..coded.fragments.len() {
+            if coded.fragments[index].is_some() {
+                continue;
+            }
+            while let Some(target) = candidates.pop() {
+                info!(
+                    "Rebuilding fragment {} of {} on {}",
+                    index, file_name, target
+                );
+                let rebuild_req = RebuildReq {
+                    file_name: file_name.to_string(),
+                    erasure: Some(coded.layout.clone()),
+                    machines: coded.machines(),
+                    fragment: index as u32,
+                    epoch,
+                };
+                if send_rebuild_req(target, rebuild_req).await {
+                    coded.fragments[index] = Some(target);
+                    break;
+                }
             }
         }
-        if let Some(tx) = self.actors.get(file_name) {
-            drop(tx);
+        if !self.erasure.contains_key(file_name) {
+            // Deleted while rebuilding
+            return Some(true);
         }
-        // Remove the file from the file table
-        self.table.remove(file_name);
-        self.actors.remove(file_name);
+        if !self.commit(coded.put_op(file_name)).await {
+            return None;
+        }
+        Some(!coded.fragments.contains(&None))
     }
 
-    #[instrument(name = "Leader write processor", level = "trace")]
-    async fn start_write(
+    #[instrument(name = "Leader decommission processor", level = "trace")]
+    async fn start_decommission(
         &self,
-        put_req: PutReq,
+        decommission_req: DecommissionReq,
         mut socket: TcpStream,
         members: Arc<RwLock<Vec<Node>>>,
     ) {
-        info!("Starting Write at leader");
-        let file_name = &put_req.file_name;
-
-        let active_vms = get_active_vms(members).await;
+        let machine_name = decommission_req.machine;
+        let response = match machine_name.parse::<Ipv4Addr>() {
+            Err(_) => Type::Fail(Fail {
+                message: format!("Invalid machine address {}", machine_name),
+            }),
+            Ok(machine) if !get_active_vms(members.clone()).await.contains(&machine) => {
+                Type::Fail(Fail {
+                    message: format!("{} is not an active member", machine),
+                })
+            }
+            Ok(machine) => {
+                let Some(result) = self.decommission(machine, members).await else {
+                    // Not the leader anymore, the client retries against the next one
+                    return;
+                };
+                match result {
+                    Ok(moved) => Type::Ack(Ack {
+                        message: format!(
+                            "Moved {} files off {}, it is safe to shut down",
+                            moved, machine
+                        ),
+                        ..Default::default()
+                    }),
+                    Err(stuck) => Type::Fail(Fail {
+                        message: format!(
+                            "Unable to move {} files off {}: {}",
+                            stuck.len(),
+                            machine,
+                            stuck.join(", ")
+                        ),
+                    }),
+                }
+            }
+        };
+        send_job_response(&mut socket, response).await;
+    }
 
-        let start_time = Instant::now(); // Capture the start time
-        info!("Active VMs (write handler task): {:?}", active_vms);
-        // Check if we have at least 4 active VMs.
-        if active_vms.len() < 4 {
-            error!("Not enough active VMs to proceed.");
-            return;
+    /// Stops placing work on `machine` and moves every file it holds to another member,
+    /// returning how many files moved or the ones that could not be, and None if leadership
+    /// was lost along the way
+    async fn decommission(
+        &self,
+        machine: Ipv4Addr,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> Option<Result<usize, Vec<String>>> {
+        let epoch = self.raft.epoch().await?;
+        let set = SetDecommissioned {
+            machine: machine.to_string(),
+            decommissioned: true,
+        };
+        if !self.commit(table_op::Type::SetDecommissioned(set)).await {
+            return None;
         }
-
-        // Now, select 4 VMs at random from the list of active VMs.
-        let selected_vm_names: Vec<_>;
-        {
-            let mut rng = rand::thread_rng(); // Create a random number generator.
-                                              // Convert Bytes back to String if necessary, depending on your setup.
-            selected_vm_names = active_vms.choose_multiple(&mut rng, 4).cloned().collect();
+        info!("Leader: draining {}", machine);
+        let files: Vec<String> = self
+            .table
+            .iter()
+            .filter(|elem| elem.value().contains(&machine))
+            .map(|elem| elem.key().clone())
+            .collect();
+        let mut moved = 0;
+        let mut stuck = Vec::new();
+        for file_name in files {
+            // Written, moved or deleted since the scan
+            let Some(replicas) = self.table.get(&file_name).map(|vms| vms.clone()) else {
+                continue;
+            };
+            if !replicas.contains(&machine) {
+                continue;
+            }
+            let mut candidates: Vec<_> = self
+                .schedulable_vms(members.clone())
+                .await
+                .into_iter()
+                .filter(|vm| !replicas.contains(vm))
+                .collect();
+            candidates.shuffle(&mut rand::thread_rng());
+            // The decommissioned machine still serves its copy, so it sends the file itself.
+            // Each move holds the file's permits from the copy until the commit.
+            let mut copied = false;
+            for receiver in candidates {
+                if self
+                    .move_replica(&file_name, machine, receiver, epoch)
+                    .await?
+                {
+                    copied = true;
+                    break;
+                }
+            }
+            let still_held = self
+                .table
+                .get(&file_name)
+                .is_some_and(|vms| vms.contains(&machine));
+            if !copied && still_held {
+                warn!("Leader: no member took {} off {}", file_name, machine);
+                stuck.push(file_name);
+                continue;
+            }
+            moved += 1;
         }
-
-        info!("Selected VMs for write: {:?}", selected_vm_names);
-        // Update the state to indicate that a write operation is ongoing.
-
-        // Send back the response to the client.
-        let response = LsRes {
-            machines: selected_vm_names.iter().map(|ip| ip.to_string()).collect(),
-        };
-        let buffer = response.encode_to_vec();
-        if let Err(e) = socket.write_all(&buffer).await {
-            warn!("Failed to send information to client: {:?}", e);
-            warn!("File reps not sent: {}", put_req.file_name);
+        // Fragments are rebuilt elsewhere from the other fragments of their file
+        let coded: Vec<String> = self
+            .erasure
+            .iter()
+            .filter(|elem| elem.fragments.contains(&Some(machine)))
+            .map(|elem| elem.key().clone())
+            .collect();
+        for file_name in coded {
+            if self
+                .repair_erasure(&file_name, &[machine], epoch, members.clone())
+                .await?
+            {
+                moved += 1;
+            } else {
+                warn!(
+                    "Leader: unable to rebuild fragments of {} off {}",
+                    file_name, machine
+                );
+                stuck.push(file_name);
+            }
         }
-        info!("Ls Response for put: {:?}", response);
-        // Update the state to indicate that the write operation is complete.
-        let mut client_ls_buffer = [0; 1024];
-
-        let Ok(n) = socket.read(&mut client_ls_buffer).await else {
-            warn!("received no ack from client");
-            return;
-        };
-        info!("Received ACK from client");
-        let Ok(succ_vms) = LsRes::decode(&client_ls_buffer[..n]) else {
-            warn!("Unable to decode ACK server message");
-            return;
-        };
-
-        if !succ_vms.machines.is_empty() {
-            // TODO: Write this after insert is complete
-            self.table.insert(
-                file_name.to_string(),
-                succ_vms
-                    .machines
-                    .into_iter()
-                    .map(|v| v.parse().unwrap())
-                    .collect(),
-            );
+        info!("Leader: drained {} files off {}", moved, machine);
+        if stuck.is_empty() {
+            Some(Ok(moved))
+        } else {
+            Some(Err(stuck))
         }
-        let duration = start_time.elapsed();
-        info!("Total time taken to write the file: {:?}", duration);
     }
 
     #[instrument(name = "Leader failure listener", level = "trace")]
@@ -837,22 +3362,72 @@ impl FileTable {
                 "Detected failures from machines: {:?}, leader responding",
                 machine
             );
-            //let mut files_stored = Vec::new();
-            for mut elem in self.table.iter_mut() {
-                let (key, val) = elem.pair_mut();
-                let prev_size = val.len();
-                val.retain(|elem| !machine.contains(&elem.to_string()));
+            let Some(epoch) = self.raft.epoch().await else {
+                continue;
+            };
+            // A decommissioned machine that is gone can come back as a regular member
+            for vm in parse_machines(&machine) {
+                if self.decommissioned.contains(&vm) {
+                    let set = SetDecommissioned {
+                        machine: vm.to_string(),
+                        decommissioned: false,
+                    };
+                    self.commit(table_op::Type::SetDecommissioned(set)).await;
+                }
+            }
+            // Erasure-coded files rebuild their lost fragments instead of copying whole files
+            let failed = parse_machines(&machine);
+            let coded: Vec<String> = self
+                .erasure
+                .iter()
+                .filter(|elem| {
+                    elem.fragments
+                        .iter()
+                        .flatten()
+                        .any(|vm| failed.contains(vm))
+                })
+                .map(|elem| elem.key().clone())
+                .collect();
+            for file_name in coded {
+                self.repair_erasure(&file_name, &failed, epoch, members.clone())
+                    .await;
+            }
+            // Copy the affected names out, changes go through the metadata group
+            let affected: Vec<String> = self
+                .table
+                .iter()
+                .filter(|elem| elem.value().iter().any(|vm| failed.contains(vm)))
+                .map(|elem| elem.key().clone())
+                .collect();
+            for key in affected {
+                // Holds off writes of the file until its new placement is committed
+                let Ok(_permits) = self.file_permits(&key).acquire_many_owned(2).await else {
+                    continue;
+                };
+                let Some(mut val) = self.table.get(&key).map(|vms| vms.clone()) else {
+                    continue;
+                };
+                val.retain(|vm| !failed.contains(vm));
 
-                let mut missing = prev_size - val.len();
+                // Restore the file's replication factor, not just the replicas that were lost
+                let mut missing = self.replication_of(&key).saturating_sub(val.len());
                 warn!("Missing {} replicas", missing);
-                if missing == 0 || missing == prev_size {
-                    info!("No need to replicate");
+                if val.is_empty() || missing == 0 {
+                    if val.is_empty() {
+                        warn!("Every replica of {} is gone, unable to replicate", key);
+                    }
+                    let put = PutFile {
+                        file_name: key,
+                        machines: val.iter().map(|vm| vm.to_string()).collect(),
+                        ..Default::default()
+                    };
+                    self.commit(table_op::Type::Put(put)).await;
                     continue;
                 }
                 let start_time = Instant::now();
                 info!("Replicating file: {}", key);
 
-                let active_vms = get_active_vms(members.clone()).await;
+                let active_vms = self.schedulable_vms(members.clone()).await;
 
                 info!("Active VMs (failure task) {:?}", active_vms);
 
@@ -884,6 +3459,7 @@ impl FileTable {
                         let command = LeaderPutReq {
                             machine: receiver.to_string(),
                             file_name: key.to_string(),
+                            epoch,
                         };
                         send_leader_put_req(
                             sender,
@@ -901,7 +3477,26 @@ impl FileTable {
                     machines_to_recv.retain(|elem| fail_receivers.contains(elem));
                     fail_receivers.clear();
                 }
-                val.extend(succ_receivers.into_iter().cloned());
+                // Re-read the entry so changes made while copying are kept
+                let Some(mut current) = self.table.get(&key).map(|vms| vms.clone()) else {
+                    // Deleted while copying
+                    for vm in succ_receivers {
+                        send_delete(*vm, &key, epoch).await;
+                    }
+                    continue;
+                };
+                current.retain(|vm| !failed.contains(vm));
+                for vm in succ_receivers {
+                    if !current.contains(vm) {
+                        current.push(*vm);
+                    }
+                }
+                let put = PutFile {
+                    file_name: key,
+                    machines: current.iter().map(|vm| vm.to_string()).collect(),
+                    ..Default::default()
+                };
+                self.commit(table_op::Type::Put(put)).await;
                 let duration = start_time.elapsed();
                 info!("Total time taken to replicate the file: {:?}", duration);
             }
@@ -909,6 +3504,29 @@ impl FileTable {
     }
 }
 
+/// Sends the request to this file's helper task, or spawns the helper if not running
+async fn send_to_actor(
+    file_table: Arc<FileTable>,
+    file_name: String,
+    request: RequestInfo,
+    members: Arc<RwLock<Vec<Node>>>,
+) {
+    if let Some(tx) = file_table.actors.get_mut(&file_name) {
+        info!("Actor already running, sending request");
+        let _ = tx.send(request).await;
+    } else {
+        info!("Actor not running, starting up actor and sending request");
+        let (tx, rx) = mpsc::channel(10);
+        let _ = tx.send(request).await;
+        let permits = file_table.file_permits(&file_name);
+        file_table.actors.insert(file_name, tx);
+        let file_table_cloned = file_table.clone();
+        tokio::spawn(async move {
+            process_operations(file_table_cloned, members, rx, permits).await;
+        });
+    }
+}
+
 #[instrument(name = "Leader request handler", level = "trace")]
 async fn handle_request(
     file_table: Arc<FileTable>,
@@ -918,62 +3536,34 @@ async fn handle_request(
 ) {
     match command.r#type {
         Some(Type::GetReq(get_req)) => {
-            // Send the request to this file's helper task, or spawn the helper if not running
-            if let Some(tx) = file_table.actors.get_mut(&get_req.file_name) {
-                info!("Actor already running, sending READ");
-                let _ = tx
-                    .send(RequestInfo {
-                        request: AccessType::Read(get_req),
-                        stream,
-                    })
-                    .await;
-            } else {
-                info!("Actor not running, starting up actor and send READ");
-                let (tx, rx) = mpsc::channel(10);
-                let file_name = get_req.file_name.clone();
-                let _ = tx
-                    .send(RequestInfo {
-                        request: AccessType::Read(get_req),
-                        stream,
-                    })
-                    .await;
-                file_table.actors.insert(file_name, tx);
-                let file_table_cloned = file_table.clone();
-                tokio::spawn(async move {
-                    process_operations(file_table_cloned, members, rx).await;
-                });
-            }
+            let file_name = get_req.file_name.clone();
+            let request = RequestInfo {
+                request: AccessType::Read(get_req),
+                stream,
+            };
+            send_to_actor(file_table, file_name, request, members).await;
         }
         Some(Type::PutReq(put_req)) => {
-            // Similar to GET
-            if let Some(tx) = file_table.actors.get_mut(&put_req.file_name) {
-                info!("Actor already running, sending WRITE");
-                let _ = tx
-                    .send(RequestInfo {
-                        request: AccessType::Write(put_req),
-                        stream,
-                    })
-                    .await;
-            } else {
-                info!("Actor not running, starting up actor and send WRITE");
-                let (tx, rx) = mpsc::channel(10);
-                let file_name = put_req.file_name.clone();
-                let _ = tx
-                    .send(RequestInfo {
-                        request: AccessType::Write(put_req),
-                        stream,
-                    })
-                    .await;
-                file_table.actors.insert(file_name, tx);
-                let file_table_cloned = file_table.clone();
-                tokio::spawn(async move {
-                    process_operations(file_table_cloned, members, rx).await;
-                });
-            }
+            let file_name = put_req.file_name.clone();
+            let request = RequestInfo {
+                request: AccessType::Write(put_req),
+                stream,
+            };
+            send_to_actor(file_table, file_name, request, members).await;
+        }
+        Some(Type::AppendReq(append_req)) => {
+            let file_name = append_req.file_name.clone();
+            let request = RequestInfo {
+                request: AccessType::Append(append_req),
+                stream,
+            };
+            send_to_actor(file_table, file_name, request, members).await;
         }
         Some(Type::LsReq(ls_req)) => {
             // Similarly, enqueue the write request and then determine if it can start.
-            file_table.start_ls(&ls_req.file_name, stream).await;
+            file_table
+                .start_ls(&ls_req.file_name, ls_req.recursive, stream)
+                .await;
         }
         Some(Type::Del(del_req)) => {
             file_table.delete_file(del_req, stream).await;
@@ -996,6 +3586,48 @@ async fn handle_request(
                 })
                 .await;
         }
+        Some(Type::JobStatusReq(status_req)) => {
+            file_table.job_status(status_req, stream).await;
+        }
+        Some(Type::MkdirReq(mkdir_req)) => {
+            file_table.make_dir(mkdir_req, stream).await;
+        }
+        Some(Type::StatReq(stat_req)) => {
+            file_table.stat(stat_req, stream).await;
+        }
+        Some(Type::LsAllReq(ls_all_req)) => {
+            file_table.list_all(ls_all_req, stream).await;
+        }
+        Some(Type::RmdirReq(rmdir_req)) => {
+            file_table.remove_dir(rmdir_req, stream).await;
+        }
+        Some(Type::RenameReq(rename_req)) => {
+            file_table.rename(rename_req, stream).await;
+        }
+        Some(Type::SetRepReq(set_rep_req)) => {
+            file_table
+                .set_replication(set_rep_req, stream, members)
+                .await;
+        }
+        Some(Type::ErasureReq(erasure_req)) => {
+            file_table
+                .convert_erasure(erasure_req, stream, members)
+                .await;
+        }
+        Some(Type::DecommissionReq(decommission_req)) => {
+            file_table
+                .start_decommission(decommission_req, stream, members)
+                .await;
+        }
+        Some(Type::PipelineReq(pipeline_req)) => {
+            let mr_tx = file_table.map_reduce_actor.lock().await;
+            let _ = mr_tx
+                .send(MapReduceReqInfo {
+                    request: MapReduceAccType::Pipeline(pipeline_req),
+                    stream,
+                })
+                .await;
+        }
         _ => {
             stream.write_all(b"INVALID_COMMAND").await.unwrap();
         }
@@ -1019,28 +3651,16 @@ async fn actor_listener(
             .iter()
             .enumerate()
             .collect::<Vec<_>>();
-        match &info.request {
-            AccessType::Read(_) => {
-                for window in slice_with_idx.as_slice().windows(4) {
-                    if let [(_, (AccessType::Write(_), _)), (_, (AccessType::Write(_), _)), (_, (AccessType::Write(_), _)), (idx, (AccessType::Write(_), _))] =
-                        window
-                    {
-                        info!("Potential starvation detected for READ request, rescheduling");
-                        insert_idx = *idx;
-                        break;
-                    }
-                }
-            }
-            AccessType::Write(_) => {
-                for window in slice_with_idx.as_slice().windows(4) {
-                    if let [(_, (AccessType::Read(_), _)), (_, (AccessType::Read(_), _)), (_, (AccessType::Read(_), _)), (idx, (AccessType::Read(_), _))] =
-                        window
-                    {
-                        info!("Potential starvation detected for WRITE request, rescheduling");
-                        insert_idx = *idx;
-                        break;
-                    }
-                }
+        // A request waiting behind four of the other kind jumps ahead of the last of them
+        let is_write = info.request.is_write();
+        for window in slice_with_idx.as_slice().windows(4) {
+            if window
+                .iter()
+                .all(|(_, (request, _))| request.is_write() != is_write)
+            {
+                info!("Potential starvation detected, rescheduling");
+                insert_idx = window[3].0;
+                break;
             }
         }
         queued_requests.insert(insert_idx, (info.request, info.stream));
@@ -1055,6 +3675,7 @@ async fn process_operations(
     file_table: Arc<FileTable>,
     members: Arc<RwLock<Vec<Node>>>,
     rx: mpsc::Receiver<RequestInfo>,
+    task_permit: Arc<Semaphore>,
 ) {
     info!("Starting operation processor");
     let queued_requests: Arc<Mutex<VecDeque<(AccessType, TcpStream)>>> =
@@ -1068,8 +3689,6 @@ async fn process_operations(
 
     let notifiee = notifier.clone();
 
-    let task_permit: Arc<Semaphore> = Arc::new(Semaphore::new(2));
-
     let listener_handler = tokio::spawn(async move {
         actor_listener(rx, queue_clone, stop_tx, notifier).await;
     });
@@ -1110,6 +3729,19 @@ async fn process_operations(
                     error!("Failed to acquire write permit");
                 }
             }
+            Some((AccessType::Append(append_req), stream)) => {
+                let file_table_cloned = file_table.clone();
+                let task_permit_cloned = task_permit.clone();
+                if let Ok(permit) = task_permit_cloned.acquire_many_owned(2).await {
+                    info!("Machine acquired append permit");
+                    tokio::spawn(async move {
+                        file_table_cloned.start_append(append_req, stream).await;
+                        drop(permit);
+                    });
+                } else {
+                    error!("Failed to acquire append permit");
+                }
+            }
             None => notifiee.notified().await,
         };
         match stop_rx.try_recv() {
@@ -1172,6 +3804,11 @@ async fn process_map_reduce(
                     .start_reduce(red_req, stream, members.clone())
                     .await
             }
+            Some((MapReduceAccType::Pipeline(pipeline_req), stream)) => {
+                file_table
+                    .start_pipeline(pipeline_req, stream, members.clone())
+                    .await
+            }
             None => notifiee.notified().await,
         };
         match stop_rx.try_recv() {
@@ -1187,12 +3824,21 @@ async fn process_map_reduce(
 
 #[instrument(name = "Leader startup and listener", level = "trace")]
 pub async fn run_leader(
+    raft: Arc<Raft>,
+    committed_rx: mpsc::UnboundedReceiver<Committed>,
     mut rx_leader: mpsc::Receiver<Vec<String>>,
     members: Arc<RwLock<Vec<Node>>>,
-    timeout: Duration,
+    leader_wakeup: Arc<Notify>,
 ) {
     let (map_req_tx, map_req_rx) = mpsc::channel::<MapReduceReqInfo>(10);
-    let file_table = Arc::new(FileTable::new(map_req_tx));
+    let file_table = Arc::new(FileTable::new(raft, map_req_tx));
+
+    // Followers keep their copy of the file table current so they can take over at any time
+    let applier = file_table.clone();
+    tokio::spawn(async move { applier.apply_committed(committed_rx).await });
+
+    leader_wakeup.notified().await;
+    println!("Elected metadata leader, serving SDFS requests");
 
     // Getting the hostname and binding should be done without blocking.
     let raw_machine_name = hostname::get().unwrap().into_string().unwrap();
@@ -1202,65 +3848,6 @@ pub async fn run_leader(
         .unwrap();
     info!("Leader listening on port 56553");
 
-    // // Sleep before doing anything
-    // sleep(timeout).await;
-    // println!("Leader starting after wait...Will collect files from other machines");
-    // // TODO: Fetch list stores from servers
-    // // For every member in the membership list, send a request to get the list of files stored on that machine
-    // //loop over every machine in the membership list
-    // for node in members.read().await.iter() {
-    //     // Skip if the node is marked as failed
-    //     if node.fail() {
-    //         warn!("Skipping failed node {:?}", node.id());
-    //         continue;
-    //     }
-    //     info!("Sending LeaderStoreReq to node {:?}", node.id());
-    //     // Create a LeaderStoreReq message
-    //     let message = SdfsCommand {
-    //         r#type: Some(Type::LeaderStoreReq(LeaderStoreReq {
-    //             message: "Give me your files".to_string(),
-    //         })),
-    //     }
-    //     .encode_to_vec();
-
-    //     // Connect to the node and send the request
-    //     let machine_name = String::from_utf8(node.id().to_vec()).unwrap();
-    //     let ip_address = machine_name.split('_').next().unwrap();
-    //     info!("Connecting to node {}", ip_address);
-    //     let addr = format!("{}:56552", ip_address);
-    //     match TcpStream::connect(addr).await {
-    //         Ok(mut stream) => {
-    //             if stream.write_all(&message).await.is_ok() {
-    //                 // Read the response
-    //                 let mut res_buf = Vec::new();
-    //                 match stream.read_to_end(&mut res_buf).await {
-    //                     Ok(_) => {
-    //                         // Decode the LeaderStoreRes message
-    //                         if let Ok(res) = LeaderStoreRes::decode(res_buf.as_slice()) {
-    //                             // Update the file table
-    //                             info!("LeaderStoreRes from {}: {:?}", machine_name, res);
-    //                             let file_table = file_table.clone();
-    //                             for file_name in res.files {
-    //                                 file_table
-    //                                     .table
-    //                                     .entry(file_name)
-    //                                     .and_modify(|e| e.push(ip_address.parse().unwrap()))
-    //                                     .or_insert_with(|| vec![ip_address.parse().unwrap()]);
-    //                             }
-    //                         } else {
-    //                             warn!("Failed to decode LeaderStoreRes from {}", machine_name);
-    //                         }
-    //                     }
-    //                     Err(e) => warn!("Failed to read from node {}: {}", machine_name, e),
-    //                 }
-    //             } else {
-    //                 warn!("Failed to send LeaderStoreReq to {}", machine_name);
-    //             }
-    //         }
-    //         Err(e) => warn!("Failed to connect to node {}: {}", machine_name, e),
-    //     }
-    // }
-
     while rx_leader.try_recv().is_ok() {}
 
     let file_table_cloned = file_table.clone();
@@ -1271,6 +3858,28 @@ pub async fn run_leader(
             .await
     });
 
+    let gc_file_table = file_table.clone();
+    let gc_mem = members.clone();
+    tokio::spawn(async move {
+        gc_file_table.collect_garbage(gc_mem).await;
+    });
+
+    let publish_file_table = file_table.clone();
+    tokio::spawn(async move {
+        publish_file_table.publish_committed().await;
+    });
+
+    let relink_file_table = file_table.clone();
+    tokio::spawn(async move {
+        relink_file_table.relink_moved().await;
+    });
+
+    let rebalance_file_table = file_table.clone();
+    let rebalance_mem = members.clone();
+    tokio::spawn(async move {
+        rebalance_file_table.rebalance(rebalance_mem).await;
+    });
+
     let mr_file_table = file_table.clone();
     let mr_mem = members.clone();
     tokio::spawn(async move {
@@ -1284,14 +3893,18 @@ pub async fn run_leader(
         let processor_ft = file_table.clone();
         let mem = members.clone();
         tokio::spawn(async move {
-            let mut buffer = [0u8; 1024];
-
-            let n = socket.read(&mut buffer).await.unwrap();
-            if n == 0 {
+            // Closing without an answer makes clients retry against the current leader
+            if !processor_ft.raft.is_leader().await {
                 return;
             }
-
-            let command: SdfsCommand = SdfsCommand::decode(&buffer[..n]).unwrap();
+            let command = match read_leader_request(&mut socket).await {
+                Ok(command) => command,
+                Err(message) => {
+                    warn!("Leader: refusing request: {}", message);
+                    send_job_response(&mut socket, Type::Fail(Fail { message })).await;
+                    return;
+                }
+            };
             handle_request(processor_ft, command, socket, mem).await;
         });
     }
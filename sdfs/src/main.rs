@@ -1,7 +1,14 @@
 mod client;
+mod erasure;
+mod executor;
 mod helpers;
 mod leader;
+mod membership;
+mod namespace;
 mod node;
+mod pipeline;
+mod raft;
+mod raft_log;
 mod receiver;
 mod sender;
 mod server;
@@ -13,15 +20,17 @@ pub mod message_types {
 pub mod member_list {
     include!(concat!(env!("OUT_DIR"), "/member_list.rs"));
 }
-use crate::member_list::MemberList;
+pub mod raft_types {
+    include!(concat!(env!("OUT_DIR"), "/raft_types.rs"));
+}
+use crate::message_types::get_req::Range;
+use crate::message_types::{ByteRange, ExecutorLimits, LineRange};
 use anyhow::{anyhow, Result};
-use bytes::Bytes;
-use chrono::offset::Local;
 use inquire::Text;
-use prost::Message;
-use std::{fs::File, net::IpAddr, process::Command, str::FromStr, sync::Arc, time::Duration};
+use membership::Membership;
+use std::{fs::File, net::IpAddr, process::Command, str::FromStr, sync::Arc};
+use tokio::signal::ctrl_c;
 use tokio::sync::{mpsc, Mutex, Notify, RwLock};
-use tokio::{net::UdpSocket, signal::ctrl_c};
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, instrument, trace_span, Instrument};
 
@@ -51,61 +60,31 @@ async fn main() {
         return;
     };
 
-    let Ok(sock) = UdpSocket::bind(format!("{local_ip}:12307")).await else {
-        println!("Unable to bind UDP Socket to {}:12307", local_ip);
-        return;
-    };
-
-    let local_time_raw = Local::now();
-    let local_time = local_time_raw.to_rfc3339();
-
-    let sender_id: Arc<str> = Arc::from(format!("{local_ip}_12307_{local_time}"));
-    let initial_node = Node::new(
-        Bytes::copy_from_slice(sender_id.as_ref().as_bytes()),
-        0,
-        local_time_raw.into(),
-        false,
-    );
-    let members: Arc<RwLock<Vec<Node>>> = Arc::new(RwLock::new(Vec::from([initial_node])));
+    let members: Arc<RwLock<Vec<Node>>> = Arc::new(RwLock::new(Vec::new()));
 
-    let receiver_members = members.clone();
     let cmd_members = members.clone();
     let leader_members = members.clone();
-    let receiver_sender_id = sender_id.clone();
-    let cmd_sender_id = sender_id.clone();
     let local_file_list: Arc<Mutex<LocalFileList>> = Arc::new(Mutex::new(LocalFileList::new()));
     let server_local_file_list = local_file_list.clone();
     let leader_ip = Arc::from(RwLock::from("172.22.158.225".to_string()));
     let cmd_leader_ip = leader_ip.clone();
-    let receiver_leader_ip = leader_ip.clone();
 
     let (tx_leader, rx_leader) = mpsc::channel::<Vec<String>>(10);
+    let mut membership = Membership::new(
+        local_ip,
+        membership::seeds(),
+        members.clone(),
+        leader_ip.clone(),
+        tx_leader,
+    );
 
     async {
-        if vm_num != 5 {
-            let member_list = MemberList {
-                sender: sender_id.to_string(),
-                machines: Vec::new(),
-            }
-            .encode_to_vec();
-            if let Err(e) = sock
-                .send_to(&member_list, "fa23-cs425-6805.cs.illinois.edu:12307")
-                .await
-            {
-                error!("Unable to contact introducer: {}", e);
-                return;
-            }
-            error!("Contacted introducer");
-        } else {
-            // Update: Made the introduce as machine 2
-            //TODO: Would need to check this because 1 is both introducer and leader and
-            //we would need to demo leader creashing and new leader being selected
-            error!("Introducer booted up");
+        if let Err(e) = membership.join(None).await {
+            println!("Unable to join the group: {}", e);
+            return;
         }
-        let (stop_tx, mut stop_rx) = tokio::sync::mpsc::channel::<()>(1);
 
-        let notifier = Arc::new(Notify::new());
-        let notified = notifier.clone();
+        let leader_wakeup = Arc::new(Notify::new());
 
         let cancel_token = CancellationToken::new();
         let cloned_token = cancel_token.clone();
@@ -121,56 +100,26 @@ async fn main() {
             };
         });
 
-        let recv = tokio::spawn(async move {
-            receiver::receiver(
-                receiver_members,
-                receiver_sender_id,
-                sock,
-                receiver_leader_ip,
-                tx_leader,
-                notifier,
-            )
-            .await;
-        });
-
-        let send = tokio::spawn(async move {
-            sender::sender(members, sender_id).await;
-        });
         let cmd_listener = tokio::spawn(async move {
-            command_listener(
-                cmd_members,
-                cmd_sender_id,
-                stop_tx,
-                local_file_list,
-                cmd_leader_ip,
-            )
-            .await;
+            command_listener(cmd_members, membership, local_file_list, cmd_leader_ip).await;
         });
         let server = tokio::spawn(async move {
             server::run_server(server_local_file_list).await;
         });
         let leader = tokio::spawn(async move {
-            leader_runner(leader_ip, rx_leader, local_ip, leader_members, notified).await;
+            leader_runner(leader_ip, rx_leader, local_ip, leader_members, leader_wakeup).await;
         });
         tokio::select! {
-            _ = stop_rx.recv() => {
-                info!("Stopping tasks");
-            }
             _ = cancel_token.cancelled() => {
                 info!("Stopping tasks");
+                // The newest leader epoch outlives the data, a rejoining server still refuses older leaders
                 match tokio::task::block_in_place(||Command::new("/usr/bin/find")
-                .args(["/home/sdfs/", "-mindepth", "1", "-type", "f", "-delete"])
+                .args(["/home/sdfs/", "-mindepth", "1", "-type", "f", "!", "-name", ".epoch", "-delete"])
                 .output()) {
                     Err(e) => println!("Failed to clear local storage: {}", e),
                     Ok(output) => println!("{} {}", std::str::from_utf8(&output.stdout).unwrap(), std::str::from_utf8(&output.stderr).unwrap()),
                 };
             }
-            _ = recv => {
-                error!("Failure detector stopped. This should never happen.");
-            }
-            _ = send => {
-                error!("Failure detector stopped. This should never happen.");
-            }
             _ = cmd_listener => {
                 error!("Command listener stopped. This should never happen.");
             }
@@ -194,23 +143,33 @@ async fn leader_runner(
     leader_mem: Arc<RwLock<Vec<Node>>>,
     leader_wakeup: Arc<Notify>,
 ) {
-    let leader_ip = {
-        let locked = leader_ip.read().await;
-        locked.clone()
-    };
-    if leader_ip == format!("{local_ip}") {
-        leader::run_leader(rx_leader, leader_mem, Duration::from_secs(15)).await;
-    } else {
-        leader_wakeup.notified().await;
-        leader::run_leader(rx_leader, leader_mem, Duration::from_secs(2)).await;
+    // Only members of the Raft group can hold the leader role
+    let group = raft::group().await;
+    let local_ip = local_ip.to_string();
+    if !group.contains(&local_ip) {
+        info!("Not a metadata group member, group is {:?}", group);
+        return std::future::pending().await;
     }
+    let raft = raft::Raft::new(
+        local_ip,
+        group,
+        leader_ip,
+        leader_wakeup.clone(),
+        leader_mem.clone(),
+    );
+    let Ok((raft, committed_rx)) = raft.inspect_err(|e| error!("Unable to load Raft state: {}", e))
+    else {
+        return std::future::pending().await;
+    };
+    let raft_member = raft.clone();
+    tokio::spawn(async move { raft_member.run().await });
+    leader::run_leader(raft, committed_rx, rx_leader, leader_mem, leader_wakeup).await;
 }
 
-#[instrument(name = "Command listener loop", level = "trace")]
+#[instrument(name = "Command listener loop", level = "trace", skip(membership))]
 async fn command_listener(
     members: Arc<RwLock<Vec<Node>>>,
-    sender_id: Arc<str>,
-    stop_tx: tokio::sync::mpsc::Sender<()>,
+    mut membership: Membership,
     local_file_list: Arc<Mutex<LocalFileList>>,
     leader_ip: Arc<RwLock<String>>,
 ) {
@@ -221,8 +180,12 @@ async fn command_listener(
         let command: Vec<_> = input.split_whitespace().collect();
         match command.as_slice() {
             ["leave"] => {
-                let _ = stop_tx.send(()).await;
-                return;
+                if !membership.joined() {
+                    println!("Not a member of the group");
+                    continue;
+                }
+                membership.leave().await;
+                println!("Left the group, use rejoin <introducer> to return");
             }
             ["list_mem"] => {
                 let guard = members.read().await;
@@ -232,19 +195,117 @@ async fn command_listener(
                 }
             }
             ["list_self"] => {
-                println!("Self's ID: {}", sender_id);
+                if !membership.joined() {
+                    println!("Not a member of the group");
+                    continue;
+                }
+                println!("Self's ID: {}", membership.sender_id());
+            }
+            ["put", local_file_name, sdfs_file_name, flags @ ..] => {
+                let chain = flags.contains(&"--chain");
+                let flags: Vec<_> = flags
+                    .iter()
+                    .copied()
+                    .filter(|flag| *flag != "--chain")
+                    .collect();
+                let (replicas, erasure) = match flags.as_slice() {
+                    [] => (0, None),
+                    ["--replicas", replicas] => match replicas.parse::<u32>() {
+                        Ok(replicas) if replicas > 0 => (replicas, None),
+                        _ => {
+                            println!("Invalid input");
+                            continue;
+                        }
+                    },
+                    // Fragments differ per server, so they cannot be forwarded down a chain
+                    ["--erasure", layout] if !chain => match erasure::parse_layout(layout) {
+                        Some(layout) => (0, Some(layout)),
+                        None => {
+                            println!("Invalid input");
+                            continue;
+                        }
+                    },
+                    _ => {
+                        println!("Invalid input");
+                        continue;
+                    }
+                };
+                client
+                    .put_file(local_file_name, sdfs_file_name, replicas, erasure, chain)
+                    .await;
             }
-            ["put", local_file_name, sdfs_file_name] => {
-                client.put_file(local_file_name, sdfs_file_name).await;
+            ["append", local_file_name, sdfs_file_name] => {
+                client.append_file(local_file_name, sdfs_file_name).await;
             }
             ["get", sdfs_file_name, local_file_name] => {
                 client.get_file(sdfs_file_name, local_file_name).await;
             }
+            ["cat", sdfs_file_name] => {
+                client.cat_file(sdfs_file_name, None).await;
+            }
+            ["cat", sdfs_file_name, offset, length] => {
+                let (Ok(offset), Ok(length)) = (offset.parse::<u64>(), length.parse::<u64>())
+                else {
+                    println!("Invalid input");
+                    continue;
+                };
+                let range = Range::Bytes(ByteRange {
+                    offset,
+                    length,
+                    from_end: false,
+                });
+                client.cat_file(sdfs_file_name, Some(range)).await;
+            }
+            [tool @ ("head" | "tail"), sdfs_file_name, args @ ..] if args.len() <= 1 => {
+                let Ok(lines) = args.first().map_or(Ok(10), |lines| lines.parse::<u64>()) else {
+                    println!("Invalid input");
+                    continue;
+                };
+                // head sends the first lines, tail starts that many lines before the end
+                let range = match *tool {
+                    "head" => LineRange {
+                        start: 0,
+                        count: lines,
+                        from_end: false,
+                    },
+                    _ => LineRange {
+                        start: lines,
+                        count: 0,
+                        from_end: true,
+                    },
+                };
+                if lines > 0 {
+                    client
+                        .cat_file(sdfs_file_name, Some(Range::Lines(range)))
+                        .await;
+                }
+            }
             ["delete", sdfs_file_name] => {
                 client.delete_file(sdfs_file_name).await;
             }
             ["ls", sdfs_file_name] => {
-                client.list_file(sdfs_file_name).await;
+                client.list_file(sdfs_file_name, false).await;
+            }
+            ["ls", "-r", sdfs_dir] => {
+                client.list_file(sdfs_dir, true).await;
+            }
+            ["lsall"] => {
+                client.list_all("").await;
+            }
+            ["lsall", prefix] => {
+                client.list_all(prefix).await;
+            }
+            ["stat", sdfs_file_name] => {
+                client.stat(sdfs_file_name).await;
+            }
+            ["mkdir", sdfs_dir] => {
+                client.make_dir(sdfs_dir).await;
+            }
+            ["rmdir", sdfs_dir] => {
+                client.remove_dir(sdfs_dir).await;
+            }
+            ["mv", from, to] => {
+                client.rename(from, to).await;
             }
             ["store"] => {
                 println!("{}", *local_file_list.lock().await);
@@ -260,7 +321,9 @@ async fn command_listener(
                     .await;
             }
             ["maple", executable_name, num_workers, file_name_prefix, input_dir, arguments @ ..] => {
-                let Ok(num_workers) = num_workers.parse::<u32>() else {
+                let (Ok(num_workers), Some((limits, arguments))) =
+                    (num_workers.parse::<u32>(), split_limit_flags(arguments))
+                else {
                     println!("Invalid input");
                     continue;
                 };
@@ -270,17 +333,25 @@ async fn command_listener(
                         num_workers,
                         file_name_prefix,
                         input_dir,
-                        arguments,
+                        &arguments,
+                        limits,
                     )
                     .await;
             }
-            ["juice", executable_name, num_workers, file_name_prefix, input_dir, is_delete] => {
-                let (Ok(num_workers), Ok(is_delete)) =
-                    (num_workers.parse::<u32>(), bool::from_str(is_delete))
-                else {
+            ["juice", executable_name, num_workers, file_name_prefix, input_dir, is_delete, flags @ ..] =>
+            {
+                let (Ok(num_workers), Ok(is_delete), Some((limits, rest))) = (
+                    num_workers.parse::<u32>(),
+                    bool::from_str(is_delete),
+                    split_limit_flags(flags),
+                ) else {
                     println!("Invalid input");
                     continue;
                 };
+                if !rest.is_empty() {
+                    println!("Invalid input");
+                    continue;
+                }
                 client
                     .reduce(
                         executable_name,
@@ -288,17 +359,55 @@ async fn command_listener(
                         file_name_prefix,
                         input_dir,
                         is_delete,
+                        limits,
                     )
                     .await;
             }
+            ["pipeline", pipeline_file] => match pipeline::Pipeline::from_file(pipeline_file) {
+                Ok(pipeline) => client.pipeline(&pipeline).await,
+                Err(e) => println!("{}", e),
+            },
+            ["status", job_id] => {
+                let Ok(job_id) = job_id.parse::<u64>() else {
+                    println!("Invalid input");
+                    continue;
+                };
+                client.job_status(job_id).await;
+            }
+            ["setrep", sdfs_file_name, replicas] => {
+                let Ok(replicas @ 1..) = replicas.parse::<u32>() else {
+                    println!("Invalid input");
+                    continue;
+                };
+                client.set_replication(sdfs_file_name, replicas).await;
+            }
+            ["erasure", sdfs_file_name, layout] => {
+                let Some(layout) = erasure::parse_layout(layout) else {
+                    println!("Invalid input");
+                    continue;
+                };
+                client.convert_erasure(sdfs_file_name, layout).await;
+            }
+            ["decommission", machine] => {
+                client.decommission(machine).await;
+            }
             ["SELECT", "ALL", "FROM", dataset, "WHERE", regex] => {
                 client.filter(dataset, regex).await;
             }
             ["SELECT", "ALL", "FROM", d1, d2, "WHERE", d1_field, "=", d2_field] => {
                 client.join(d1, d2, d1_field, d2_field).await;
             }
-            ["rejoin", introducer] => {
-                println!("Rejoining via introducer: {}", introducer);
+            ["rejoin", introducer @ ..] if introducer.len() <= 1 => {
+                let introducer = introducer.first().copied();
+                membership.leave().await;
+                match membership.join(introducer).await {
+                    Ok(()) => println!(
+                        "Rejoining via {} as {}",
+                        introducer.unwrap_or("the seed list"),
+                        membership.sender_id()
+                    ),
+                    Err(e) => println!("Unable to rejoin: {}", e),
+                }
             }
             _ => {
                 println!("Invalid command!");
@@ -307,6 +416,22 @@ async fn command_listener(
     }
 }
 
+/// Pulls `--timeout <secs>`, `--memory <MiB>` and `--output <MiB>` out of a job's arguments
+fn split_limit_flags<'a>(arguments: &[&'a str]) -> Option<(ExecutorLimits, Vec<&'a str>)> {
+    let mut limits = executor::default_limits();
+    let mut rest = Vec::new();
+    let mut iter = arguments.iter();
+    while let Some(arg) = iter.next() {
+        match *arg {
+            "--timeout" => limits.timeout_secs = iter.next()?.parse().ok()?,
+            "--memory" => limits.memory_bytes = executor::mebibytes(iter.next()?.parse().ok()?)?,
+            "--output" => limits.output_bytes = executor::mebibytes(iter.next()?.parse().ok()?)?,
+            _ => rest.push(*arg),
+        }
+    }
+    Some((limits, rest))
+}
+
 fn get_vm_num() -> Result<i8> {
     let raw_machine_name = hostname::get()?
         .into_string()
@@ -321,3 +446,48 @@ fn get_vm_num() -> Result<i8> {
     //println!("{machine_name}");
     Ok(machine_name.split_at(3).1.parse::<i8>()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_flags_are_pulled_out_of_the_arguments() {
+        let (limits, rest) = split_limit_flags(&[
+            "a",
+            "--timeout",
+            "30",
+            "b",
+            "--memory",
+            "512",
+            "--output",
+            "1",
+        ])
+        .unwrap();
+        assert_eq!(limits.timeout_secs, 30);
+        assert_eq!(limits.memory_bytes, 512 << 20);
+        assert_eq!(limits.output_bytes, 1 << 20);
+        assert_eq!(rest, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn missing_flags_keep_the_defaults() {
+        let (limits, rest) = split_limit_flags(&["input"]).unwrap();
+        assert_eq!(limits, executor::default_limits());
+        assert_eq!(rest, vec!["input"]);
+    }
+
+    #[test]
+    fn malformed_limit_flags_are_rejected() {
+        assert!(split_limit_flags(&["--timeout"]).is_none());
+        assert!(split_limit_flags(&["--memory", "lots"]).is_none());
+        assert!(split_limit_flags(&["--output", "-1"]).is_none());
+    }
+
+    #[test]
+    fn oversized_limits_do_not_overflow() {
+        assert!(split_limit_flags(&["--memory", &u64::MAX.to_string()]).is_none());
+        assert!(split_limit_flags(&["--output", "17592186044416"]).is_none());
+        assert!(split_limit_flags(&["--output", "17592186044415"]).is_some());
+    }
+}
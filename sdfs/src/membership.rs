@@ -0,0 +1,181 @@
+use crate::helpers::split_id_to_components;
+use crate::member_list::{failure_detection::Type, FailureDetection, Left, MemberList};
+use crate::node::Node;
+use crate::{receiver, sender};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use chrono::offset::Local;
+use prost::Message;
+use std::{net::IpAddr, sync::Arc};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+// Hosts a node contacts to join when SDFS_SEEDS is not set, tried in order
+const DEFAULT_SEEDS: [&str; 5] = [
+    "fa23-cs425-6805.cs.illinois.edu",
+    "fa23-cs425-6801.cs.illinois.edu",
+    "fa23-cs425-6802.cs.illinois.edu",
+    "fa23-cs425-6803.cs.illinois.edu",
+    "fa23-cs425-6804.cs.illinois.edu",
+];
+
+/// Hosts to join through, from SDFS_SEEDS or the default hosts
+pub fn seeds() -> Vec<String> {
+    match std::env::var("SDFS_SEEDS") {
+        Ok(hosts) => hosts
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_SEEDS.iter().map(|host| host.to_string()).collect(),
+    }
+}
+
+/// The gossip and failure detection tasks running under this node's current identity
+pub struct Membership {
+    local_ip: IpAddr,
+    seeds: Vec<String>,
+    members: Arc<RwLock<Vec<Node>>>,
+    leader_ip: Arc<RwLock<String>>,
+    tx_leader: mpsc::Sender<Vec<String>>,
+    sender_id: Arc<str>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Membership {
+    pub fn new(
+        local_ip: IpAddr,
+        seeds: Vec<String>,
+        members: Arc<RwLock<Vec<Node>>>,
+        leader_ip: Arc<RwLock<String>>,
+        tx_leader: mpsc::Sender<Vec<String>>,
+    ) -> Membership {
+        Membership {
+            local_ip,
+            seeds,
+            members,
+            leader_ip,
+            tx_leader,
+            sender_id: Arc::from(""),
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn sender_id(&self) -> Arc<str> {
+        self.sender_id.clone()
+    }
+
+    pub fn joined(&self) -> bool {
+        !self.tasks.is_empty()
+    }
+
+    /// Takes a fresh identity and enters the group through `introducer`, or through whichever
+    /// seed answers first when there is none. Seeds keep being contacted while this node is
+    /// alone, so the first node to start forms the group the others join.
+    pub async fn join(&mut self, introducer: Option<&str>) -> Result<()> {
+        if self.joined() {
+            return Err(anyhow!("Already a member of the group"));
+        }
+        let local_time = Local::now();
+        let sender_id: Arc<str> = Arc::from(format!(
+            "{}_12307_{}",
+            self.local_ip,
+            local_time.to_rfc3339()
+        ));
+        *self.members.write().await = vec![Node::new(
+            Bytes::copy_from_slice(sender_id.as_bytes()),
+            0,
+            local_time.into(),
+            false,
+        )];
+
+        let sock = UdpSocket::bind(format!("{}:12307", self.local_ip))
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Unable to bind UDP Socket to {}:12307: {}",
+                    self.local_ip,
+                    e
+                )
+            })?;
+        let introducers = match introducer {
+            Some(introducer) => vec![introducer.to_string()],
+            None => self.seeds.clone(),
+        };
+        let member_list = FailureDetection {
+            r#type: Some(Type::Members(MemberList {
+                sender: sender_id.to_string(),
+                machines: Vec::new(),
+            })),
+        }
+        .encode_to_vec();
+        // Any member answers a join, so every introducer is asked at once
+        for introducer in &introducers {
+            match sock
+                .send_to(&member_list, format!("{introducer}:12307"))
+                .await
+            {
+                Ok(_) => info!("Contacted introducer {}", introducer),
+                Err(e) => warn!("Unable to contact introducer {}: {}", introducer, e),
+            }
+        }
+
+        let (members, id, leader_ip, tx_leader) = (
+            self.members.clone(),
+            sender_id.clone(),
+            self.leader_ip.clone(),
+            self.tx_leader.clone(),
+        );
+        let recv = tokio::spawn(async move {
+            receiver::receiver(members, id, sock, leader_ip, tx_leader).await;
+        });
+        let (members, id) = (self.members.clone(), sender_id.clone());
+        let send = tokio::spawn(async move {
+            sender::sender(members, id, introducers).await;
+        });
+        self.sender_id = sender_id;
+        self.tasks = vec![recv, send];
+        Ok(())
+    }
+
+    /// Stops gossiping and tells every member this node left, so they drop it at once
+    pub async fn leave(&mut self) {
+        if !self.joined() {
+            return;
+        }
+        for task in self.tasks.drain(..) {
+            task.abort();
+            // Wait for the socket to be released so a rejoin can bind it again
+            let _ = task.await;
+        }
+        let left = FailureDetection {
+            r#type: Some(Type::Left(Left {
+                id: self.sender_id.to_string(),
+            })),
+        }
+        .encode_to_vec();
+        let ids: Vec<Bytes> = self
+            .members
+            .write()
+            .await
+            .drain(..)
+            .map(|node| node.id())
+            .filter(|id| id.as_ref() != self.sender_id.as_bytes())
+            .collect();
+        let Ok(sock) = UdpSocket::bind((self.local_ip, 0)).await else {
+            warn!("Unable to bind socket to announce leaving");
+            return;
+        };
+        for raw_id in ids {
+            let Some((ip, port)) = split_id_to_components(&raw_id) else {
+                continue;
+            };
+            if let Err(e) = sock.send_to(&left, format!("{ip}:{port}")).await {
+                warn!("Unable to tell {} this node is leaving: {}", ip, e);
+            }
+        }
+        info!("Left the group as {}", self.sender_id);
+    }
+}
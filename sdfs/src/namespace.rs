@@ -0,0 +1,144 @@
+/// Whether a name is a valid SDFS path: components separated by single `/`, none of them `.`
+/// or `..`, with no leading or trailing `/`
+pub fn is_valid(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .split('/')
+            .all(|component| !matches!(component, "" | "." | ".."))
+}
+
+/// Drops leading and trailing `/`, so `/` and `` both name the root
+pub fn trim(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Whether `name` lies below the directory `dir`, every name lies below the root
+pub fn is_under(name: &str, dir: &str) -> bool {
+    dir.is_empty()
+        || name
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// `name` relative to the directory `dir` it lies below
+pub fn relative<'a>(name: &'a str, dir: &str) -> &'a str {
+    match dir {
+        "" => name,
+        dir => &name[dir.len() + 1..],
+    }
+}
+
+/// Every proper ancestor directory of a path, outermost first
+pub fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(idx, _)| &path[..idx])
+}
+
+pub fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+pub fn join(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        dir => format!("{dir}/{name}"),
+    }
+}
+
+/// Where `name` ends up once `from` is renamed to `to`, None if it is neither `from` nor
+/// below it
+pub fn rebase(name: &str, from: &str, to: &str) -> Option<String> {
+    if name == from || is_under(name, from) {
+        Some(format!("{}{}", to, &name[from.len()..]))
+    } else {
+        None
+    }
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches a path against a glob where `*` and `?` stay within one component and `**`
+/// matches any number of components
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<_> = pattern.split('/').collect();
+    let name: Vec<_> = name.split('/').collect();
+    match_components(&pattern, &name)
+}
+
+fn match_components(pattern: &[&str], name: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&"**", rest)) => (0..=name.len()).any(|skip| match_components(rest, &name[skip..])),
+        Some((component, rest)) => name.split_first().is_some_and(|(first, name_rest)| {
+            match_component(component.as_bytes(), first.as_bytes())
+                && match_components(rest, name_rest)
+        }),
+    }
+}
+
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_component(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some((byte, rest)) => name.first() == Some(byte) && match_component(rest, &name[1..]),
+    }
+}
+
+/// Whether a file is an input of a job over `input`, which is a glob, a directory or a file
+pub fn matches_input(input: &str, name: &str) -> bool {
+    let input = trim(input);
+    if is_glob(input) {
+        glob_match(input, name)
+    } else {
+        name == input || is_under(name, input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_validated() {
+        assert!(is_valid("a"));
+        assert!(is_valid("a/b.txt"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("/a"));
+        assert!(!is_valid("a/"));
+        assert!(!is_valid("a//b"));
+        assert!(!is_valid("a/./b"));
+        assert!(!is_valid("../a"));
+    }
+
+    #[test]
+    fn names_lie_below_whole_components() {
+        assert!(is_under("a/b", "a"));
+        assert!(is_under("a/b/c", "a/b"));
+        assert!(is_under("a", ""));
+        assert!(!is_under("a", "a"));
+        assert!(!is_under("ab/c", "a"));
+    }
+
+    #[test]
+    fn renames_rebase_the_path_and_everything_below_it() {
+        assert_eq!(rebase("a", "a", "b"), Some("b".to_string()));
+        assert_eq!(rebase("a/x/y", "a", "b/c"), Some("b/c/x/y".to_string()));
+        assert_eq!(rebase("ab", "a", "b"), None);
+        assert_eq!(rebase("c/a", "a", "b"), None);
+    }
+
+    #[test]
+    fn globs_match_within_components() {
+        assert!(glob_match("logs/*.txt", "logs/a.txt"));
+        assert!(!glob_match("logs/*.txt", "logs/old/a.txt"));
+        assert!(glob_match("logs/?.txt", "logs/a.txt"));
+        assert!(!glob_match("logs/?.txt", "logs/ab.txt"));
+        assert!(glob_match("logs/**/*.txt", "logs/a.txt"));
+        assert!(glob_match("logs/**/*.txt", "logs/old/2023/a.txt"));
+        assert!(!glob_match("logs/**", "data/a.txt"));
+        assert!(matches_input("/logs/", "logs/a/b"));
+        assert!(!matches_input("logs", "logs2/a"));
+    }
+}
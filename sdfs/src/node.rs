@@ -7,7 +7,9 @@ pub struct Node {
     id: Bytes,
     heartbeat_count: u32,
     time: DateTime<FixedOffset>,
-    _fail: bool,
+    _fail: bool, // suspected of having failed
+    incarnation: u32,
+    suspected_at: Option<DateTime<FixedOffset>>,
 }
 
 impl Node {
@@ -17,6 +19,8 @@ impl Node {
             heartbeat_count,
             time,
             _fail: false,
+            incarnation: 0,
+            suspected_at: None,
         }
     }
     pub fn id(&self) -> Bytes {
@@ -37,8 +41,24 @@ impl Node {
     pub fn set_time(&mut self, time: DateTime<FixedOffset>) {
         self.time = time;
     }
-    pub fn set_fail(&mut self, fail: bool) {
-        self._fail = fail;
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+    pub fn suspected_at(&self) -> Option<DateTime<FixedOffset>> {
+        self.suspected_at
+    }
+    pub fn set_incarnation(&mut self, incarnation: u32) {
+        self.incarnation = incarnation;
+    }
+    pub fn suspect(&mut self, time: DateTime<FixedOffset>) {
+        if !self._fail {
+            self._fail = true;
+            self.suspected_at = Some(time);
+        }
+    }
+    pub fn clear_suspicion(&mut self) {
+        self._fail = false;
+        self.suspected_at = None;
     }
 }
 
@@ -51,6 +71,7 @@ impl fmt::Display for Node {
         )?;
         writeln!(f, "Heartbeat: {}", self.heartbeat_count)?;
         writeln!(f, "Latest time: {}", self.time)?;
-        writeln!(f, "Failure: {}", self._fail)
+        writeln!(f, "Incarnation: {}", self.incarnation)?;
+        writeln!(f, "Suspected: {}", self._fail)
     }
 }
@@ -0,0 +1,430 @@
+use crate::executor;
+use crate::message_types::{ExecutorLimits, MapReq, PipelineReq, PipelineStage, ReduceReq};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+pub const DEFAULT_STAGE_WORKERS: u32 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StageKind {
+    Map,
+    Reduce,
+}
+
+/// One maple or juice step of a pipeline
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stage {
+    name: String,
+    kind: StageKind,
+    executable: String,
+    #[serde(default)]
+    args: Vec<String>,
+    workers: Option<u32>,
+    #[serde(default)]
+    inputs: Vec<String>,
+    output: Option<String>,
+    timeout: Option<u64>,
+    memory: Option<u64>,
+    output_limit: Option<u64>,
+}
+
+impl Stage {
+    fn new(name: &str, kind: StageKind, executable: &str) -> Stage {
+        Stage {
+            name: name.to_string(),
+            kind,
+            executable: executable.to_string(),
+            args: Vec::new(),
+            workers: None,
+            inputs: Vec::new(),
+            output: None,
+            timeout: None,
+            memory: None,
+            output_limit: None,
+        }
+    }
+    pub fn map(name: &str, executable: &str) -> Stage {
+        Stage::new(name, StageKind::Map, executable)
+    }
+    pub fn reduce(name: &str, executable: &str) -> Stage {
+        Stage::new(name, StageKind::Reduce, executable)
+    }
+    pub fn args<I: IntoIterator<Item = S>, S: ToString>(mut self, args: I) -> Stage {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.to_string()));
+        self
+    }
+    /// A stage name this stage depends on, or an SDFS input for map stages
+    pub fn input(mut self, input: &str) -> Stage {
+        self.inputs.push(input.to_string());
+        self
+    }
+    /// Output prefix for map stages, output file for reduce stages
+    pub fn output(mut self, output: &str) -> Stage {
+        self.output = Some(output.to_string());
+        self
+    }
+
+    fn limits(&self) -> Result<ExecutorLimits, String> {
+        let mut limits = executor::default_limits();
+        let too_large = |mib| format!("Stage {} has a limit of {} MiB, too large", self.name, mib);
+        if let Some(secs) = self.timeout {
+            limits.timeout_secs = secs;
+        }
+        if let Some(mib) = self.memory {
+            limits.memory_bytes = executor::mebibytes(mib).ok_or_else(|| too_large(mib))?;
+        }
+        if let Some(mib) = self.output_limit {
+            limits.output_bytes = executor::mebibytes(mib).ok_or_else(|| too_large(mib))?;
+        }
+        Ok(limits)
+    }
+}
+
+/// A DAG of stages the leader runs as a single job
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pipeline {
+    name: String,
+    #[serde(default, rename = "stage")]
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new(name: &str) -> Pipeline {
+        Pipeline {
+            name: name.to_string(),
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn stage(mut self, stage: Stage) -> Pipeline {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn from_file(path: &str) -> Result<Pipeline, String> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read pipeline file {}: {}", path, e))?;
+        toml::from_str(&raw).map_err(|e| format!("Unable to parse pipeline file {}: {}", path, e))
+    }
+
+    /// Local paths of every executable the stages need
+    pub fn executables(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|stage| stage.executable.as_str())
+    }
+
+    pub fn to_request(&self) -> Result<PipelineReq, String> {
+        let stages = self
+            .stages
+            .iter()
+            .map(|stage| {
+                let executable = Path::new(&stage.executable)
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .ok_or(format!("Malformed executable name {}", stage.executable))?;
+                Ok(PipelineStage {
+                    name: stage.name.clone(),
+                    reduce: stage.kind == StageKind::Reduce,
+                    executable: executable.to_string(),
+                    arguments: stage.args.clone(),
+                    num_workers: stage.workers.unwrap_or(DEFAULT_STAGE_WORKERS),
+                    inputs: stage.inputs.clone(),
+                    output: stage.output.clone().unwrap_or_default(),
+                    limits: Some(stage.limits()?),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let request = PipelineReq {
+            name: self.name.clone(),
+            stages,
+        };
+        plan(&request)?;
+        Ok(request)
+    }
+}
+
+#[derive(Debug)]
+pub enum PlannedJob {
+    Map(MapReq),
+    Reduce(ReduceReq),
+}
+
+/// The maple and juice jobs of a pipeline in execution order
+#[derive(Debug)]
+pub struct PipelinePlan {
+    pub jobs: Vec<(String, PlannedJob)>,
+    /// Key prefixes written by map stages and consumed by reduce stages
+    pub intermediate_prefixes: Vec<String>,
+    /// Reduce outputs only consumed by later map stages
+    pub intermediate_files: Vec<String>,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Orders the stages of a pipeline and wires each stage's output into its consumers
+pub fn plan(request: &PipelineReq) -> Result<PipelinePlan, String> {
+    if !valid_name(&request.name) {
+        return Err(format!(
+            "Pipeline name {:?} must only contain letters, digits and '-'",
+            request.name
+        ));
+    }
+    let mut stages = HashMap::new();
+    for stage in &request.stages {
+        if !valid_name(&stage.name) {
+            return Err(format!(
+                "Stage name {:?} must only contain letters, digits and '-'",
+                stage.name
+            ));
+        }
+        if stages.insert(stage.name.as_str(), stage).is_some() {
+            return Err(format!("Duplicate stage {}", stage.name));
+        }
+    }
+    if stages.is_empty() {
+        return Err("Pipeline has no stages".to_string());
+    }
+
+    // Every map stage feeds at most one reduce stage, which decides its output prefix
+    let mut map_consumer = HashMap::new();
+    let mut consumers: HashMap<&str, Vec<&str>> = HashMap::new();
+    for stage in &request.stages {
+        if stage.inputs.is_empty() {
+            return Err(format!("Stage {} has no inputs", stage.name));
+        }
+        for input in &stage.inputs {
+            match (stage.reduce, stages.get(input.as_str())) {
+                (true, Some(dep)) if !dep.reduce => {
+                    if let Some(other) = map_consumer.insert(input.as_str(), stage.name.as_str()) {
+                        return Err(format!(
+                            "Map stage {} feeds both {} and {}",
+                            input, other, stage.name
+                        ));
+                    }
+                }
+                (true, _) => {
+                    return Err(format!(
+                        "Reduce stage {} can only consume map stages, not {}",
+                        stage.name, input
+                    ));
+                }
+                (false, Some(dep)) if !dep.reduce => {
+                    return Err(format!(
+                        "Map stage {} cannot consume map stage {}",
+                        stage.name, input
+                    ));
+                }
+                (false, Some(_)) | (false, None) => {}
+            }
+            if stages.contains_key(input.as_str()) {
+                consumers
+                    .entry(input.as_str())
+                    .or_default()
+                    .push(stage.name.as_str());
+            }
+        }
+    }
+
+    // Kahn's algorithm, keeping the declaration order among ready stages
+    let mut pending: HashMap<&str, usize> = request
+        .stages
+        .iter()
+        .map(|stage| {
+            let deps = stage
+                .inputs
+                .iter()
+                .filter(|input| stages.contains_key(input.as_str()))
+                .collect::<HashSet<_>>()
+                .len();
+            (stage.name.as_str(), deps)
+        })
+        .collect();
+    let mut ready: VecDeque<&str> = request
+        .stages
+        .iter()
+        .map(|stage| stage.name.as_str())
+        .filter(|name| pending[name] == 0)
+        .collect();
+    let mut order = Vec::new();
+    while let Some(name) = ready.pop_front() {
+        order.push(name);
+        let mut next = consumers.get(name).cloned().unwrap_or_default();
+        next.dedup();
+        for consumer in next {
+            let count = pending.get_mut(consumer).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push_back(consumer);
+            }
+        }
+    }
+    if order.len() != stages.len() {
+        return Err("Pipeline stages contain a cycle".to_string());
+    }
+
+    let prefix_of = |reduce_stage: &str| format!("{}-{}", request.name, reduce_stage);
+    let reduce_output = |stage: &PipelineStage| {
+        if !stage.output.is_empty() {
+            stage.output.clone()
+        } else if consumers.contains_key(stage.name.as_str()) {
+            format!("{}-{}-out", request.name, stage.name)
+        } else {
+            format!("{}-{}", request.name, stage.name)
+        }
+    };
+
+    let mut plan = PipelinePlan {
+        jobs: Vec::new(),
+        intermediate_prefixes: Vec::new(),
+        intermediate_files: Vec::new(),
+    };
+    for name in order {
+        let stage = stages[name];
+        if stage.reduce {
+            let output_file = reduce_output(stage);
+            if stage.output.is_empty() && consumers.contains_key(name) {
+                plan.intermediate_files.push(output_file.clone());
+            }
+            plan.intermediate_prefixes.push(prefix_of(name));
+            plan.jobs.push((
+                stage.name.clone(),
+                PlannedJob::Reduce(ReduceReq {
+                    executable: stage.executable.clone(),
+                    num_workers: stage.num_workers,
+                    file_name_prefix: prefix_of(name),
+                    output_file,
+                    delete: true,
+                    limits: stage.limits.clone(),
+                }),
+            ));
+        } else {
+            let output_prefix = match map_consumer.get(name) {
+                Some(reduce_stage) => prefix_of(reduce_stage),
+                None if !stage.output.is_empty() => stage.output.clone(),
+                None => format!("{}-{}", request.name, stage.name),
+            };
+            for input in &stage.inputs {
+                let input_dir = match stages.get(input.as_str()) {
+                    Some(dep) => reduce_output(dep),
+                    None => input.clone(),
+                };
+                plan.jobs.push((
+                    stage.name.clone(),
+                    PlannedJob::Map(MapReq {
+                        executable: stage.executable.clone(),
+                        num_workers: stage.num_workers,
+                        file_name_prefix: output_prefix.clone(),
+                        input_dir,
+                        arguments: stage.arguments.clone(),
+                        limits: stage.limits.clone(),
+                    }),
+                ));
+            }
+        }
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(plan: &PipelinePlan) -> Vec<(&str, &str, &str)> {
+        plan.jobs
+            .iter()
+            .map(|(stage, job)| match job {
+                PlannedJob::Map(map) => (
+                    stage.as_str(),
+                    map.input_dir.as_str(),
+                    map.file_name_prefix.as_str(),
+                ),
+                PlannedJob::Reduce(reduce) => (
+                    stage.as_str(),
+                    reduce.file_name_prefix.as_str(),
+                    reduce.output_file.as_str(),
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stages_are_ordered_and_wired_to_their_consumers() {
+        // Declared out of order on purpose
+        let request = Pipeline::new("wc")
+            .stage(Stage::map("top", "bin/top").input("sum"))
+            .stage(
+                Stage::reduce("rank", "bin/rank")
+                    .input("top")
+                    .output("ranking"),
+            )
+            .stage(Stage::map("count", "bin/count").input("logs/*.txt"))
+            .stage(Stage::reduce("sum", "bin/sum").input("count"))
+            .to_request()
+            .unwrap();
+        assert_eq!(request.stages[0].executable, "top");
+
+        let plan = plan(&request).unwrap();
+        assert_eq!(
+            jobs(&plan),
+            [
+                ("count", "logs/*.txt", "wc-sum"),
+                ("sum", "wc-sum", "wc-sum-out"),
+                ("top", "wc-sum-out", "wc-rank"),
+                ("rank", "wc-rank", "ranking"),
+            ]
+        );
+        assert_eq!(plan.intermediate_prefixes, ["wc-sum", "wc-rank"]);
+        assert_eq!(plan.intermediate_files, ["wc-sum-out"]);
+    }
+
+    #[test]
+    fn malformed_pipelines_are_refused() {
+        let refused = |pipeline: Pipeline| pipeline.to_request().unwrap_err();
+        assert!(refused(Pipeline::new("p")).contains("no stages"));
+        assert!(
+            refused(Pipeline::new("p q").stage(Stage::map("a", "a").input("x")))
+                .contains("Pipeline name")
+        );
+        assert!(refused(
+            Pipeline::new("p")
+                .stage(Stage::map("a", "a").input("x"))
+                .stage(Stage::map("a", "a").input("y"))
+        )
+        .contains("Duplicate stage"));
+        assert!(
+            refused(Pipeline::new("p").stage(Stage::reduce("r", "r").input("x")))
+                .contains("can only consume map stages")
+        );
+        assert!(refused(
+            Pipeline::new("p")
+                .stage(Stage::map("m", "m").input("x"))
+                .stage(Stage::reduce("r1", "r").input("m"))
+                .stage(Stage::reduce("r2", "r").input("m"))
+        )
+        .contains("feeds both"));
+        assert!(refused(
+            Pipeline::new("p")
+                .stage(Stage::map("m", "m").input("r"))
+                .stage(Stage::reduce("r", "r").input("m"))
+        )
+        .contains("cycle"));
+    }
+
+    #[test]
+    fn oversized_stage_limits_are_refused() {
+        let mut stage = Stage::map("m", "m").input("x");
+        stage.memory = Some(u64::MAX);
+        let refused = Pipeline::new("p").stage(stage).to_request().unwrap_err();
+        assert!(refused.contains("too large"));
+
+        let mut stage = Stage::map("m", "m").input("x");
+        stage.output_limit = Some(1024);
+        let request = Pipeline::new("p").stage(stage).to_request().unwrap();
+        let limits = request.stages[0].limits.clone().unwrap();
+        assert_eq!(limits.output_bytes, 1 << 30);
+    }
+}
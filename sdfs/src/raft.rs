@@ -0,0 +1,1095 @@
+use crate::helpers::split_id_to_components;
+use crate::member_list::{failure_detection, Coordinator, FailureDetection};
+use crate::node::Node;
+use crate::raft_log::{Loaded, RaftLog};
+use crate::raft_types::{
+    raft_message, table_op, AppendReq, AppendRes, HardState, InstallSnapshotReq, LogEntry, Noop,
+    RaftMessage, Snapshot, TableOp, VoteReq, VoteRes,
+};
+use prost::Message;
+use rand::Rng;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, PoisonError};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{error, info, instrument, warn};
+
+pub const RAFT_PORT: u16 = 56554;
+
+const TICK: Duration = Duration::from_millis(50);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MS: Range<u64> = 1500..3000;
+// Shorter than the minimum election timeout, so a lease ends before a successor can be elected
+const LEASE_DURATION: Duration = Duration::from_millis(1000);
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(10);
+// Leaders re-announce themselves so nodes that joined later learn who leads
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_APPEND_ENTRIES: usize = 256;
+const MAX_FRAME: usize = 64 << 20;
+// How long to wait before resolving the group members again
+const RESOLVE_RETRY: Duration = Duration::from_secs(5);
+
+const STATE_DIR: &str = "/home/raft";
+
+// Hosts of the metadata group when SDFS_RAFT_PEERS is not set
+const DEFAULT_GROUP: [&str; 5] = [
+    "fa23-cs425-6801.cs.illinois.edu",
+    "fa23-cs425-6802.cs.illinois.edu",
+    "fa23-cs425-6803.cs.illinois.edu",
+    "fa23-cs425-6804.cs.illinois.edu",
+    "fa23-cs425-6805.cs.illinois.edu",
+];
+
+/// What the file table applies, in log order
+#[derive(Debug)]
+pub enum Committed {
+    /// A committed change, `done` is set on the member that proposed it
+    Entry {
+        index: u64,
+        op: TableOp,
+        done: Option<oneshot::Sender<()>>,
+    },
+    /// The whole file table as of `index`, replacing everything applied before
+    Snapshot { index: u64, table: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug)]
+struct RaftState {
+    term: u64,
+    voted_for: String,
+    snapshot: Snapshot, // stands in for every entry up to its last index
+    log: Vec<LogEntry>, // entry i lives at log index snapshot.last_index + i + 1
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader: Option<String>,
+    // Term in which this member caught up as leader and started serving
+    ready_term: u64,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    waiters: HashMap<u64, oneshot::Sender<()>>,
+    election_deadline: Instant,
+    // When the newest append each peer acknowledged in this term was sent
+    acked_at: HashMap<String, Instant>,
+    lease_expiry: Instant,
+    heard_from_leader: Option<Instant>,
+}
+
+impl RaftState {
+    fn new(loaded: Loaded) -> Self {
+        let snapshot = loaded.snapshot.unwrap_or_default();
+        let mut state = RaftState {
+            term: loaded.hard_state.term,
+            voted_for: loaded.hard_state.voted_for,
+            commit_index: snapshot.last_index,
+            last_applied: snapshot.last_index,
+            snapshot,
+            log: loaded.entries,
+            role: Role::Follower,
+            leader: None,
+            ready_term: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            waiters: HashMap::new(),
+            election_deadline: Instant::now(),
+            acked_at: HashMap::new(),
+            lease_expiry: Instant::now(),
+            heard_from_leader: None,
+        };
+        state.reset_election_deadline();
+        state
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+    /// Where the entry at a log index after the snapshot lives in `log`
+    fn position(&self, index: u64) -> usize {
+        (index - self.snapshot.last_index - 1) as usize
+    }
+
+    /// Term of the entry at a log index, 0 if there is none or it was compacted away
+    fn term_at(&self, index: u64) -> u64 {
+        if index == self.snapshot.last_index {
+            return self.snapshot.last_term;
+        }
+        if index < self.snapshot.last_index {
+            return 0;
+        }
+        self.log
+            .get(self.position(index))
+            .map_or(0, |entry| entry.term)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let millis = rand::thread_rng().gen_range(ELECTION_TIMEOUT_MS);
+        self.election_deadline = Instant::now() + Duration::from_millis(millis);
+    }
+}
+
+/// Member of the Raft group that replicates the leader's file table
+#[derive(Debug)]
+pub struct Raft {
+    id: String,
+    peers: Vec<String>,
+    group_size: usize, // configured members, the quorum never shrinks below their majority
+    state: Mutex<RaftState>,
+    // Written only while `state` is locked, so changes reach the disk in order
+    store: Arc<std::sync::Mutex<RaftLog>>,
+    replicate: HashMap<String, Notify>,
+    apply_tx: mpsc::UnboundedSender<Committed>,
+    leader_ip: Arc<RwLock<String>>,
+    leader_wakeup: Arc<Notify>,
+    members: Arc<RwLock<Vec<Node>>>,
+}
+
+/// Majority of a group of `group_size` members
+fn majority(group_size: usize) -> usize {
+    group_size / 2 + 1
+}
+
+async fn resolve(host: &str) -> Option<String> {
+    let addr = match lookup_host((host, RAFT_PORT)).await {
+        Ok(mut addrs) => addrs.find(|addr| addr.is_ipv4()),
+        Err(e) => {
+            warn!("Raft: unable to resolve group member {}: {}", host, e);
+            return None;
+        }
+    };
+    if addr.is_none() {
+        warn!("Raft: group member {} has no IPv4 address", host);
+    }
+    Some(addr?.ip().to_string())
+}
+
+/// Hands every newly committed entry to the file table
+fn apply_committed(state: &mut RaftState, apply_tx: &mpsc::UnboundedSender<Committed>) {
+    while state.last_applied < state.commit_index {
+        state.last_applied += 1;
+        let index = state.last_applied;
+        let done = state.waiters.remove(&index);
+        let Some(op) = state.log[state.position(index)].op.clone() else {
+            // Nothing to apply, the proposer only needs to know it committed
+            if let Some(done) = done {
+                let _ = done.send(());
+            }
+            continue;
+        };
+        if apply_tx.send(Committed::Entry { index, op, done }).is_err() {
+            warn!("Raft: file table is gone, dropping entry {}", index);
+        }
+    }
+}
+
+/// Addresses of the metadata group, from SDFS_RAFT_PEERS or the default hosts. Members
+/// are resolved until every one of them is known, a group missing some would count its
+/// majority among fewer members and could elect a second leader.
+pub async fn group() -> Vec<String> {
+    let hosts: Vec<String> = match std::env::var("SDFS_RAFT_PEERS") {
+        Ok(hosts) => hosts
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_GROUP.iter().map(|host| host.to_string()).collect(),
+    };
+    loop {
+        let mut group = Vec::new();
+        for host in &hosts {
+            if let Some(addr) = resolve(host).await {
+                group.push(addr);
+            }
+        }
+        if group.len() == hosts.len() {
+            return group;
+        }
+        warn!(
+            "Raft: resolved {} of {} group members, retrying",
+            group.len(),
+            hosts.len()
+        );
+        sleep(RESOLVE_RETRY).await;
+    }
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Option<RaftMessage> {
+    let len = stream.read_u32().await.ok()? as usize;
+    if len > MAX_FRAME {
+        warn!("Raft: refusing {} byte message", len);
+        return None;
+    }
+    let mut buffer = vec![0; len];
+    stream.read_exact(&mut buffer).await.ok()?;
+    RaftMessage::decode(buffer.as_slice()).ok()
+}
+
+async fn write_frame(stream: &mut TcpStream, message: &RaftMessage) -> bool {
+    let buffer = message.encode_to_vec();
+    stream.write_u32(buffer.len() as u32).await.is_ok() && stream.write_all(&buffer).await.is_ok()
+}
+
+async fn call(peer: &str, request: RaftMessage) -> Option<raft_message::Type> {
+    let exchange = async {
+        let mut stream = TcpStream::connect((peer, RAFT_PORT)).await.ok()?;
+        if !write_frame(&mut stream, &request).await {
+            return None;
+        }
+        read_frame(&mut stream).await
+    };
+    timeout(RPC_TIMEOUT, exchange).await.ok()??.r#type
+}
+
+impl Raft {
+    /// Loads the persisted snapshot and log, the snapshot is handed to the returned channel
+    /// right away and committed entries are replayed through it as they are learned
+    pub fn new(
+        id: String,
+        group: Vec<String>,
+        leader_ip: Arc<RwLock<String>>,
+        leader_wakeup: Arc<Notify>,
+        members: Arc<RwLock<Vec<Node>>>,
+    ) -> io::Result<(Arc<Raft>, mpsc::UnboundedReceiver<Committed>)> {
+        let (store, loaded) = RaftLog::open(Path::new(STATE_DIR))?;
+        info!(
+            "Raft: loaded term {} with a snapshot up to entry {} and {} log entries",
+            loaded.hard_state.term,
+            loaded
+                .snapshot
+                .as_ref()
+                .map_or(0, |snapshot| snapshot.last_index),
+            loaded.entries.len()
+        );
+        let state = RaftState::new(loaded);
+        let group_size = group.len();
+        let peers: Vec<String> = group.into_iter().filter(|peer| *peer != id).collect();
+        let replicate = peers
+            .iter()
+            .map(|peer| (peer.clone(), Notify::new()))
+            .collect();
+        let (apply_tx, apply_rx) = mpsc::unbounded_channel();
+        if state.snapshot.last_index > 0 {
+            let _ = apply_tx.send(Committed::Snapshot {
+                index: state.snapshot.last_index,
+                table: state.snapshot.table.clone(),
+            });
+        }
+        let raft = Raft {
+            id,
+            peers,
+            group_size,
+            state: Mutex::new(state),
+            store: Arc::new(std::sync::Mutex::new(store)),
+            replicate,
+            apply_tx,
+            leader_ip,
+            leader_wakeup,
+            members,
+        };
+        Ok((Arc::new(raft), apply_rx))
+    }
+
+    fn quorum(&self) -> usize {
+        majority(self.group_size)
+    }
+
+    /// Runs a write to disk on the blocking pool. Callers hold the state lock across it, so
+    /// nothing is acted on before it is durable.
+    async fn write_log<T, F>(&self, write: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut RaftLog) -> io::Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || {
+            write(&mut store.lock().unwrap_or_else(PoisonError::into_inner))
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+    }
+
+    async fn save_hard_state(&self, term: u64, voted_for: String) -> io::Result<()> {
+        let hard_state = HardState { term, voted_for };
+        self.write_log(move |store| store.save_hard_state(&hard_state))
+            .await
+            .inspect_err(|e| error!("Raft: unable to persist term {}: {}", term, e))
+    }
+
+    /// Appends `entries` to the log behind its first `keep` entries after the snapshot
+    async fn append_log(&self, keep: usize, entries: Vec<LogEntry>) -> io::Result<()> {
+        self.write_log(move |store| store.append(keep, &entries))
+            .await
+            .inspect_err(|e| error!("Raft: unable to persist log entries: {}", e))
+    }
+
+    async fn save_snapshot(&self, snapshot: Snapshot, entries: Vec<LogEntry>) -> io::Result<()> {
+        let last_index = snapshot.last_index;
+        self.write_log(move |store| store.save_snapshot(&snapshot, &entries))
+            .await
+            .inspect_err(|e| error!("Raft: unable to persist snapshot {}: {}", last_index, e))
+    }
+
+    /// Stops leading or campaigning, and follows a newer term once it is on disk
+    async fn step_down(&self, state: &mut RaftState, term: u64) -> io::Result<()> {
+        if state.role == Role::Leader {
+            info!("Raft: stepping down in term {}", state.term);
+            // Proposers learn their change may not have committed
+            state.waiters.clear();
+        }
+        state.role = Role::Follower;
+        if term > state.term {
+            self.save_hard_state(term, String::new()).await?;
+            state.term = term;
+            state.voted_for.clear();
+        }
+        Ok(())
+    }
+
+    /// Checks the term of a request from a leader and follows that leader. None if the request
+    /// is stale or its term could not be stored, otherwise whether the leader is a new one.
+    async fn follow(&self, state: &mut RaftState, term: u64, leader: &str) -> Option<bool> {
+        if term < state.term {
+            return None;
+        }
+        if (term > state.term || state.role != Role::Follower)
+            && self.step_down(state, term).await.is_err()
+        {
+            return None;
+        }
+        state.reset_election_deadline();
+        state.heard_from_leader = Some(Instant::now());
+        let new_leader = state.leader.as_deref() != Some(leader);
+        state.leader = Some(leader.to_string());
+        Some(new_leader)
+    }
+
+    /// Replaces the log up to `index`, which the file table has applied, with a snapshot of
+    /// the table at that point
+    pub async fn compact(&self, index: u64, table: Vec<u8>) {
+        let mut state = self.state.lock().await;
+        if index <= state.snapshot.last_index || index > state.last_applied {
+            return;
+        }
+        let snapshot = Snapshot {
+            last_index: index,
+            last_term: state.term_at(index),
+            table,
+        };
+        let compacted = state.position(index) + 1;
+        let kept = state.log[compacted..].to_vec();
+        if self.save_snapshot(snapshot.clone(), kept).await.is_err() {
+            return;
+        }
+        state.log.drain(..compacted);
+        state.snapshot = snapshot;
+        info!("Raft: compacted the log up to entry {}", index);
+    }
+
+    /// A leader serves once it applied every earlier term's changes, and only while a
+    /// quorum recently acknowledged it
+    fn serving(&self, state: &RaftState) -> bool {
+        state.role == Role::Leader
+            && state.ready_term == state.term
+            && Instant::now() < state.lease_expiry
+    }
+
+    pub async fn is_leader(&self) -> bool {
+        let state = self.state.lock().await;
+        self.serving(&state)
+    }
+
+    /// The fencing token of the current leadership, None unless this member holds the lease
+    pub async fn epoch(&self) -> Option<u64> {
+        let state = self.state.lock().await;
+        self.serving(&state).then_some(state.term)
+    }
+
+    /// Extends the lease to the point the latest quorum acknowledgement was sent plus the lease
+    fn renew_lease(&self, state: &mut RaftState) {
+        let needed = self.quorum() - 1;
+        if needed == 0 {
+            state.lease_expiry = Instant::now() + LEASE_DURATION;
+            return;
+        }
+        let mut acked: Vec<Instant> = state.acked_at.values().copied().collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        if let Some(sent_at) = acked.get(needed - 1) {
+            state.lease_expiry = state.lease_expiry.max(*sent_at + LEASE_DURATION);
+        }
+    }
+
+    /// Appends a change to the log and waits until a quorum stores it and it is applied
+    pub async fn propose(&self, op: table_op::Type) -> Result<(), String> {
+        let done = {
+            let mut state = self.state.lock().await;
+            if state.role != Role::Leader {
+                return Err("not the metadata leader".to_string());
+            }
+            let entry = LogEntry {
+                term: state.term,
+                op: Some(TableOp { r#type: Some(op) }),
+            };
+            if let Err(e) = self.append_log(state.log.len(), vec![entry.clone()]).await {
+                return Err(format!("unable to persist the change: {e}"));
+            }
+            state.log.push(entry);
+            let (tx, rx) = oneshot::channel();
+            let index = state.last_index();
+            state.waiters.insert(index, tx);
+            self.advance_commit(&mut state);
+            rx
+        };
+        self.replicate.values().for_each(Notify::notify_one);
+        match timeout(COMMIT_TIMEOUT, done).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err("lost leadership before the change committed".to_string()),
+            Err(_) => Err("timed out waiting for a quorum".to_string()),
+        }
+    }
+
+    #[instrument(name = "Raft member", level = "trace")]
+    pub async fn run(self: Arc<Self>) {
+        let listener = match TcpListener::bind((self.id.as_str(), RAFT_PORT)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Raft: unable to bind port {}: {}", RAFT_PORT, e);
+                return;
+            }
+        };
+        info!(
+            "Raft: listening on port {} with peers {:?}",
+            RAFT_PORT, self.peers
+        );
+        let ticker = self.clone();
+        tokio::spawn(async move { ticker.tick().await });
+        for peer in self.peers.clone() {
+            let replicator = self.clone();
+            tokio::spawn(async move { replicator.replicate_loop(peer).await });
+        }
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                warn!("Raft: unable to accept connection");
+                continue;
+            };
+            let raft = self.clone();
+            tokio::spawn(async move {
+                let Some(request) = read_frame(&mut stream).await else {
+                    return;
+                };
+                let response = match request.r#type {
+                    Some(raft_message::Type::VoteReq(req)) => {
+                        raft_message::Type::VoteRes(raft.handle_vote(req).await)
+                    }
+                    Some(raft_message::Type::AppendReq(req)) => {
+                        raft_message::Type::AppendRes(raft.handle_append(req).await)
+                    }
+                    Some(raft_message::Type::InstallSnapshotReq(req)) => {
+                        raft_message::Type::AppendRes(raft.handle_install_snapshot(req).await)
+                    }
+                    _ => {
+                        warn!("Raft: unexpected message");
+                        return;
+                    }
+                };
+                let response = RaftMessage {
+                    r#type: Some(response),
+                };
+                write_frame(&mut stream, &response).await;
+            });
+        }
+    }
+
+    async fn tick(self: Arc<Self>) {
+        let mut last_announce = Instant::now();
+        loop {
+            sleep(TICK).await;
+            let (expired, serving) = {
+                let mut state = self.state.lock().await;
+                if state.role == Role::Leader {
+                    self.renew_lease(&mut state);
+                    // A leader cut off from the quorum stops leading instead of waiting to hear
+                    // about its successor
+                    let min_timeout = Duration::from_millis(ELECTION_TIMEOUT_MS.start);
+                    if Instant::now() >= state.lease_expiry + min_timeout {
+                        warn!("Raft: lost contact with the quorum");
+                        let term = state.term;
+                        let _ = self.step_down(&mut state, term).await;
+                    }
+                }
+                (
+                    state.role != Role::Leader && Instant::now() >= state.election_deadline,
+                    self.serving(&state),
+                )
+            };
+            if expired {
+                self.start_election().await;
+            } else if serving && last_announce.elapsed() >= ANNOUNCE_INTERVAL {
+                self.announce().await;
+                last_announce = Instant::now();
+            }
+        }
+    }
+
+    async fn start_election(self: &Arc<Self>) {
+        let request = {
+            let mut state = self.state.lock().await;
+            state.reset_election_deadline();
+            // A vote that is not on disk could be cast for someone else after a restart
+            let term = state.term + 1;
+            if self.save_hard_state(term, self.id.clone()).await.is_err() {
+                return;
+            }
+            state.term = term;
+            state.role = Role::Candidate;
+            state.voted_for = self.id.clone();
+            state.leader = None;
+            info!("Raft: starting election for term {}", state.term);
+            VoteReq {
+                term: state.term,
+                candidate: self.id.clone(),
+                last_log_index: state.last_index(),
+                last_log_term: state.last_term(),
+            }
+        };
+        let mut votes = 1;
+        let mut calls = JoinSet::new();
+        for peer in self.peers.clone() {
+            let request = RaftMessage {
+                r#type: Some(raft_message::Type::VoteReq(request.clone())),
+            };
+            calls.spawn(async move { call(&peer, request).await });
+        }
+        while votes < self.quorum() {
+            let Some(response) = calls.join_next().await else {
+                info!("Raft: lost election for term {}", request.term);
+                return;
+            };
+            let Ok(Some(raft_message::Type::VoteRes(response))) = response else {
+                continue;
+            };
+            let mut state = self.state.lock().await;
+            if response.term > state.term {
+                let _ = self.step_down(&mut state, response.term).await;
+                return;
+            }
+            if response.granted {
+                votes += 1;
+            }
+        }
+        self.become_leader(request.term).await;
+    }
+
+    async fn become_leader(self: &Arc<Self>, term: u64) {
+        let ready = {
+            let mut state = self.state.lock().await;
+            if state.role != Role::Candidate || state.term != term {
+                return;
+            }
+            // Committing an entry of our own term commits everything before it
+            let noop = LogEntry {
+                term,
+                op: Some(TableOp {
+                    r#type: Some(table_op::Type::Noop(Noop {})),
+                }),
+            };
+            if self
+                .append_log(state.log.len(), vec![noop.clone()])
+                .await
+                .is_err()
+            {
+                state.role = Role::Follower;
+                return;
+            }
+            state.log.push(noop);
+            info!("Raft: won election for term {}", term);
+            state.role = Role::Leader;
+            state.leader = Some(self.id.clone());
+            state.acked_at.clear();
+            state.lease_expiry = Instant::now();
+            self.renew_lease(&mut state);
+            // Peers are sent everything from the noop on until they report what they store
+            let next = state.last_index();
+            for peer in &self.peers {
+                state.next_index.insert(peer.clone(), next);
+                state.match_index.insert(peer.clone(), 0);
+            }
+            let (tx, rx) = oneshot::channel();
+            let index = state.last_index();
+            state.waiters.insert(index, tx);
+            self.advance_commit(&mut state);
+            rx
+        };
+        self.replicate.values().for_each(Notify::notify_one);
+
+        let raft = self.clone();
+        tokio::spawn(async move {
+            if ready.await.is_err() {
+                return;
+            }
+            {
+                let mut state = raft.state.lock().await;
+                if state.role != Role::Leader || state.term != term {
+                    return;
+                }
+                state.ready_term = term;
+            }
+            info!("Raft: serving as leader for term {}", term);
+            *raft.leader_ip.write().await = raft.id.clone();
+            raft.leader_wakeup.notify_one();
+            raft.announce().await;
+        });
+    }
+
+    /// Tells every member who the leader is, also nodes outside the group
+    async fn announce(&self) {
+        let term = self.state.lock().await.term;
+        let Ok(socket) = UdpSocket::bind((self.id.as_str(), 0)).await else {
+            warn!("Raft: unable to bind announcement socket");
+            return;
+        };
+        let message = FailureDetection {
+            r#type: Some(failure_detection::Type::Coord(Coordinator {
+                leader_ip: self.id.clone(),
+                term,
+            })),
+        }
+        .encode_to_vec();
+        let ids: Vec<_> = self.members.read().await.iter().map(Node::id).collect();
+        for raw_id in ids {
+            let Some((ip, port)) = split_id_to_components(&raw_id) else {
+                continue;
+            };
+            if let Err(e) = socket.send_to(&message, format!("{ip}:{port}")).await {
+                warn!("Raft: unable to announce leadership to {}: {}", ip, e);
+            }
+        }
+    }
+
+    async fn replicate_loop(self: Arc<Self>, peer: String) {
+        let notify = &self.replicate[&peer];
+        loop {
+            tokio::select! {
+                _ = notify.notified() => {}
+                _ = sleep(HEARTBEAT_INTERVAL) => {}
+            }
+            self.replicate_to(&peer).await;
+        }
+    }
+
+    async fn replicate_to(&self, peer: &str) {
+        let (request, term, prev, matched) = {
+            let state = self.state.lock().await;
+            if state.role != Role::Leader {
+                return;
+            }
+            let next = state.next_index.get(peer).copied().unwrap_or(1).max(1);
+            let prev = next - 1;
+            if next <= state.snapshot.last_index {
+                // The entries the peer needs next were compacted away, it gets the snapshot
+                let request = raft_message::Type::InstallSnapshotReq(InstallSnapshotReq {
+                    term: state.term,
+                    leader: self.id.clone(),
+                    snapshot: Some(state.snapshot.clone()),
+                });
+                (request, state.term, prev, state.snapshot.last_index)
+            } else {
+                let entries: Vec<_> = state.log[state.position(next)..]
+                    .iter()
+                    .take(MAX_APPEND_ENTRIES)
+                    .cloned()
+                    .collect();
+                let matched = prev + entries.len() as u64;
+                let request = raft_message::Type::AppendReq(AppendReq {
+                    term: state.term,
+                    leader: self.id.clone(),
+                    prev_log_index: prev,
+                    prev_log_term: state.term_at(prev),
+                    entries,
+                    leader_commit: state.commit_index,
+                });
+                (request, state.term, prev, matched)
+            }
+        };
+        let request = RaftMessage {
+            r#type: Some(request),
+        };
+        let sent_at = Instant::now();
+        let Some(raft_message::Type::AppendRes(response)) = call(peer, request).await else {
+            return;
+        };
+
+        let mut state = self.state.lock().await;
+        if response.term > state.term {
+            let _ = self.step_down(&mut state, response.term).await;
+            return;
+        }
+        // A peer unable to store our term answers with its older one and does not follow us
+        if state.role != Role::Leader || state.term != term || response.term != term {
+            return;
+        }
+        // Any answer in our term means the peer still follows us
+        state.acked_at.insert(peer.to_string(), sent_at);
+        self.renew_lease(&mut state);
+        if response.success {
+            state.match_index.insert(peer.to_string(), matched);
+            state.next_index.insert(peer.to_string(), matched + 1);
+            self.advance_commit(&mut state);
+            if matched >= state.last_index() {
+                return;
+            }
+        } else {
+            // Back up to just past the follower's hint and retry right away
+            let next = (response.match_index + 1).clamp(1, prev.max(1));
+            state.next_index.insert(peer.to_string(), next);
+        }
+        self.replicate[peer].notify_one();
+    }
+
+    /// Commits the highest entry of the current term a quorum stores
+    fn advance_commit(&self, state: &mut RaftState) {
+        let mut index = state.last_index();
+        while index > state.commit_index && state.term_at(index) == state.term {
+            let stored = 1 + self
+                .peers
+                .iter()
+                .filter(|peer| state.match_index.get(*peer).copied().unwrap_or(0) >= index)
+                .count();
+            if stored >= self.quorum() {
+                state.commit_index = index;
+                break;
+            }
+            index -= 1;
+        }
+        self.apply(state);
+    }
+
+    fn apply(&self, state: &mut RaftState) {
+        apply_committed(state, &self.apply_tx);
+    }
+
+    async fn handle_vote(&self, request: VoteReq) -> VoteRes {
+        let mut state = self.state.lock().await;
+        // Members that recently heard from a live leader ignore candidates, so no successor
+        // is elected while its lease may still hold
+        let min_timeout = Duration::from_millis(ELECTION_TIMEOUT_MS.start);
+        let leader_alive = match state.role {
+            Role::Leader => Instant::now() < state.lease_expiry,
+            _ => state
+                .heard_from_leader
+                .is_some_and(|heard| heard.elapsed() < min_timeout),
+        };
+        if leader_alive {
+            info!(
+                "Raft: ignoring vote request from {}, the leader is alive",
+                request.candidate
+            );
+            return VoteRes {
+                term: state.term,
+                granted: false,
+            };
+        }
+        if request.term > state.term && self.step_down(&mut state, request.term).await.is_err() {
+            return VoteRes {
+                term: state.term,
+                granted: false,
+            };
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (state.last_term(), state.last_index());
+        let granted = request.term == state.term
+            && (state.voted_for.is_empty() || state.voted_for == request.candidate)
+            && up_to_date;
+        // The vote has to survive a restart before it is cast
+        let granted = granted
+            && self
+                .save_hard_state(state.term, request.candidate.clone())
+                .await
+                .is_ok();
+        if granted {
+            info!(
+                "Raft: voting for {} in term {}",
+                request.candidate, request.term
+            );
+            state.voted_for = request.candidate;
+            state.reset_election_deadline();
+        }
+        VoteRes {
+            term: state.term,
+            granted,
+        }
+    }
+
+    async fn handle_append(&self, request: AppendReq) -> AppendRes {
+        let mut state = self.state.lock().await;
+        let Some(new_leader) = self.follow(&mut state, request.term, &request.leader).await else {
+            return AppendRes {
+                term: state.term,
+                success: false,
+                match_index: 0,
+            };
+        };
+
+        let mut prev = request.prev_log_index;
+        let mut prev_term = request.prev_log_term;
+        let mut entries = request.entries;
+        if prev < state.snapshot.last_index {
+            // Entries up to the snapshot are committed, so they match the leader's
+            let covered = ((state.snapshot.last_index - prev) as usize).min(entries.len());
+            if let Some(last) = entries.drain(..covered).next_back() {
+                prev_term = last.term;
+            }
+            prev += covered as u64;
+        }
+        let response = if prev < state.snapshot.last_index {
+            AppendRes {
+                term: state.term,
+                success: true,
+                match_index: prev,
+            }
+        } else if prev > state.last_index() || state.term_at(prev) != prev_term {
+            AppendRes {
+                term: state.term,
+                success: false,
+                match_index: state.last_index().min(prev.saturating_sub(1)),
+            }
+        } else {
+            self.store_entries(&mut state, prev, entries, request.leader_commit)
+                .await
+        };
+        drop(state);
+
+        if new_leader {
+            info!("Raft: following leader {}", request.leader);
+            *self.leader_ip.write().await = request.leader;
+        }
+        response
+    }
+
+    /// Stores entries that follow `prev`, which matches the leader, and acknowledges them once
+    /// they are on disk
+    async fn store_entries(
+        &self,
+        state: &mut RaftState,
+        prev: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) -> AppendRes {
+        let matched = prev + entries.len() as u64;
+        let mut keep = state.log.len();
+        let mut new_entries = Vec::new();
+        for (index, entry) in (prev + 1..).zip(entries) {
+            if new_entries.is_empty() && index <= state.last_index() {
+                if state.term_at(index) == entry.term {
+                    continue;
+                }
+                // Drop the conflicting suffix a previous leader never committed
+                keep = state.position(index);
+            }
+            new_entries.push(entry);
+        }
+        if !new_entries.is_empty() {
+            let stored = self.append_log(keep, new_entries.clone()).await;
+            // The conflicting suffix may be gone from disk even if the append failed
+            state.log.truncate(keep);
+            if stored.is_err() {
+                return AppendRes {
+                    term: state.term,
+                    success: false,
+                    match_index: state.last_index().min(prev),
+                };
+            }
+            state.log.extend(new_entries);
+        }
+        let commit = leader_commit.min(matched);
+        if commit > state.commit_index {
+            state.commit_index = commit;
+            self.apply(state);
+        }
+        AppendRes {
+            term: state.term,
+            success: true,
+            match_index: matched,
+        }
+    }
+
+    async fn handle_install_snapshot(&self, request: InstallSnapshotReq) -> AppendRes {
+        let mut state = self.state.lock().await;
+        let refused = |state: &RaftState| AppendRes {
+            term: state.term,
+            success: false,
+            match_index: 0,
+        };
+        let Some(new_leader) = self.follow(&mut state, request.term, &request.leader).await else {
+            return refused(&state);
+        };
+        let Some(snapshot) = request.snapshot else {
+            return refused(&state);
+        };
+        let response = match self.install_snapshot(&mut state, snapshot).await {
+            Ok(last_index) => AppendRes {
+                term: state.term,
+                success: true,
+                match_index: last_index,
+            },
+            Err(_) => refused(&state),
+        };
+        drop(state);
+
+        if new_leader {
+            info!("Raft: following leader {}", request.leader);
+            *self.leader_ip.write().await = request.leader;
+        }
+        response
+    }
+
+    /// Replaces the log up to the snapshot, keeping the entries after it if the log agrees
+    async fn install_snapshot(&self, state: &mut RaftState, snapshot: Snapshot) -> io::Result<u64> {
+        let last_index = snapshot.last_index;
+        if last_index <= state.snapshot.last_index {
+            return Ok(last_index);
+        }
+        let kept = match last_index < state.last_index()
+            && state.term_at(last_index) == snapshot.last_term
+        {
+            true => state.log[state.position(last_index) + 1..].to_vec(),
+            false => Vec::new(),
+        };
+        self.save_snapshot(snapshot.clone(), kept.clone()).await?;
+        info!("Raft: installed a snapshot up to entry {}", last_index);
+        state.log = kept;
+        state.commit_index = state.commit_index.max(last_index);
+        if state.last_applied < last_index {
+            state.last_applied = last_index;
+            let table = Committed::Snapshot {
+                index: last_index,
+                table: snapshot.table.clone(),
+            };
+            if self.apply_tx.send(table).is_err() {
+                warn!("Raft: file table is gone, dropping snapshot {}", last_index);
+            }
+        }
+        state.snapshot = snapshot;
+        Ok(last_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quorum_is_a_majority_of_the_configured_group() {
+        assert_eq!(majority(1), 1);
+        assert_eq!(majority(2), 2);
+        assert_eq!(majority(3), 2);
+        assert_eq!(majority(4), 3);
+        assert_eq!(majority(5), 3);
+        assert_eq!(majority(7), 4);
+    }
+
+    fn entry(term: u64, op: Option<table_op::Type>) -> LogEntry {
+        LogEntry {
+            term,
+            op: op.map(|op| TableOp { r#type: Some(op) }),
+        }
+    }
+
+    #[test]
+    fn apply_resolves_waiters_of_entries_without_an_op() {
+        let mut state = RaftState::new(Loaded {
+            entries: vec![
+                entry(1, Some(table_op::Type::Noop(Noop {}))),
+                entry(1, None),
+                entry(1, Some(table_op::Type::Noop(Noop {}))),
+            ],
+            ..Loaded::default()
+        });
+        let (apply_tx, mut apply_rx) = mpsc::unbounded_channel();
+        let mut waiters: Vec<_> = (1..=3)
+            .map(|index| {
+                let (tx, rx) = oneshot::channel();
+                state.waiters.insert(index, tx);
+                rx
+            })
+            .collect();
+        state.commit_index = 3;
+        apply_committed(&mut state, &apply_tx);
+
+        assert_eq!(state.last_applied, 3);
+        assert!(state.waiters.is_empty());
+        assert_eq!(waiters[1].try_recv(), Ok(()));
+        // Entries with an op carry their waiter to the file table
+        for _ in 0..2 {
+            let Committed::Entry { done, .. } = apply_rx.try_recv().unwrap() else {
+                panic!("expected a committed entry");
+            };
+            done.unwrap().send(()).unwrap();
+        }
+        assert!(apply_rx.try_recv().is_err());
+        assert_eq!(waiters[0].try_recv(), Ok(()));
+        assert_eq!(waiters[2].try_recv(), Ok(()));
+    }
+
+    #[test]
+    fn apply_stops_at_the_commit_index() {
+        let mut state = RaftState::new(Loaded {
+            entries: vec![entry(2, Some(table_op::Type::Noop(Noop {}))); 3],
+            ..Loaded::default()
+        });
+        let (apply_tx, mut apply_rx) = mpsc::unbounded_channel();
+        state.commit_index = 2;
+        apply_committed(&mut state, &apply_tx);
+        assert_eq!(state.last_applied, 2);
+        assert!(apply_rx.try_recv().is_ok());
+        assert!(apply_rx.try_recv().is_ok());
+        assert!(apply_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn entries_after_a_snapshot_are_indexed_past_it() {
+        let mut state = RaftState::new(Loaded {
+            snapshot: Some(Snapshot {
+                last_index: 10,
+                last_term: 3,
+                table: Vec::new(),
+            }),
+            entries: vec![entry(4, Some(table_op::Type::Noop(Noop {}))); 2],
+            ..Loaded::default()
+        });
+        assert_eq!(state.last_index(), 12);
+        assert_eq!(state.last_applied, 10);
+        assert_eq!(state.term_at(9), 0);
+        assert_eq!(state.term_at(10), 3);
+        assert_eq!(state.term_at(11), 4);
+        assert_eq!(state.term_at(13), 0);
+
+        let (apply_tx, mut apply_rx) = mpsc::unbounded_channel();
+        state.commit_index = 12;
+        apply_committed(&mut state, &apply_tx);
+        for expected in 11..=12 {
+            let Committed::Entry { index, .. } = apply_rx.try_recv().unwrap() else {
+                panic!("expected a committed entry");
+            };
+            assert_eq!(index, expected);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::raft_types::{HardState, LogEntry, Snapshot};
+use prost::Message;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const HARD_STATE_FILE: &str = "hard_state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+
+// The log file starts with the index of its first entry
+const HEADER_LEN: u64 = 8;
+
+/// What a group member finds on disk when it starts
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub hard_state: HardState,
+    pub snapshot: Option<Snapshot>,
+    pub entries: Vec<LogEntry>, // the entries after the snapshot
+}
+
+/// The durable state of a group member. Entries are appended to the log file one write at a
+/// time, everything is synced before a call returns.
+#[derive(Debug)]
+pub struct RaftLog {
+    dir: PathBuf,
+    log: File,
+    offsets: Vec<u64>, // where each entry starts in the log file
+    len: u64,
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Replaces `dir/name` with `data`, so a crash leaves either the old or the new contents
+fn write_atomically(dir: &Path, name: &str, data: &[u8]) -> io::Result<()> {
+    let tmp = dir.join(format!("{name}.tmp"));
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(name))?;
+    sync_dir(dir)
+}
+
+fn read_message<M: Message + Default>(path: &Path) -> io::Result<Option<M>> {
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    M::decode(raw.as_slice())
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Log file contents holding `entries`, the first of them at `first_index`
+fn encode_log(first_index: u64, entries: &[LogEntry]) -> Vec<u8> {
+    let mut data = first_index.to_be_bytes().to_vec();
+    for entry in entries {
+        data.extend(entry.encode_length_delimited_to_vec());
+    }
+    data
+}
+
+/// Contents of a log file
+struct DecodedLog {
+    first_index: u64,
+    entries: Vec<LogEntry>,
+    offsets: Vec<u64>,
+    end: u64, // where the last complete entry ends
+}
+
+/// Reads every complete entry of a log file. A crash mid-append leaves a torn entry at the
+/// end, which is not part of the log.
+fn decode_log(data: &[u8]) -> io::Result<DecodedLog> {
+    let Some((header, mut rest)) = data.split_first_chunk::<{ HEADER_LEN as usize }>() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "log file has no header",
+        ));
+    };
+    let mut entries = Vec::new();
+    let mut offsets = Vec::new();
+    let mut offset = HEADER_LEN;
+    while !rest.is_empty() {
+        let mut cursor = rest;
+        let Ok(entry) = LogEntry::decode_length_delimited(&mut cursor) else {
+            warn!("Raft: dropping a torn entry at the end of the log");
+            break;
+        };
+        offsets.push(offset);
+        entries.push(entry);
+        offset += (rest.len() - cursor.len()) as u64;
+        rest = cursor;
+    }
+    Ok(DecodedLog {
+        first_index: u64::from_be_bytes(*header),
+        entries,
+        offsets,
+        end: offset,
+    })
+}
+
+impl RaftLog {
+    /// Opens the state kept in `dir`, creating it empty for a new member
+    pub fn open(dir: &Path) -> io::Result<(RaftLog, Loaded)> {
+        fs::create_dir_all(dir)?;
+        let hard_state = read_message(&dir.join(HARD_STATE_FILE))?.unwrap_or_default();
+        let snapshot: Option<Snapshot> = read_message(&dir.join(SNAPSHOT_FILE))?;
+        let snapshot_index = snapshot.as_ref().map_or(0, |snapshot| snapshot.last_index);
+
+        let log_path = dir.join(LOG_FILE);
+        if !log_path.exists() {
+            write_atomically(dir, LOG_FILE, &encode_log(snapshot_index + 1, &[]))?;
+        }
+        let DecodedLog {
+            first_index,
+            mut entries,
+            offsets,
+            end,
+        } = decode_log(&fs::read(&log_path)?)?;
+        if first_index > snapshot_index + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "log starts at entry {} but the snapshot ends at {}",
+                    first_index, snapshot_index
+                ),
+            ));
+        }
+        let log = OpenOptions::new().append(true).open(&log_path)?;
+        log.set_len(end)?;
+        let mut raft_log = RaftLog {
+            dir: dir.to_path_buf(),
+            log,
+            offsets,
+            len: end,
+        };
+        // A crash between writing a snapshot and compacting the log leaves entries it covers.
+        // An installed snapshot can cover more entries than the log holds.
+        if first_index <= snapshot_index {
+            let covered = ((snapshot_index + 1 - first_index) as usize).min(entries.len());
+            entries.drain(..covered);
+            raft_log.rewrite(snapshot_index + 1, &entries)?;
+        }
+        let loaded = Loaded {
+            hard_state,
+            snapshot,
+            entries,
+        };
+        Ok((raft_log, loaded))
+    }
+
+    pub fn save_hard_state(&self, hard_state: &HardState) -> io::Result<()> {
+        write_atomically(&self.dir, HARD_STATE_FILE, &hard_state.encode_to_vec())
+    }
+
+    /// Keeps the first `keep` entries after the snapshot and appends `entries` behind them.
+    /// On error the log holds at least the first `keep` entries and none of `entries`.
+    pub fn append(&mut self, keep: usize, entries: &[LogEntry]) -> io::Result<()> {
+        let base = self.offsets.get(keep).copied().unwrap_or(self.len);
+        if base < self.len {
+            self.log.set_len(base)?;
+            self.offsets.truncate(keep);
+            self.len = base;
+        }
+        let mut data = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            offsets.push(base + data.len() as u64);
+            data.extend(entry.encode_length_delimited_to_vec());
+        }
+        let written = self.log.write_all(&data).and_then(|_| self.log.sync_data());
+        if let Err(e) = written {
+            // A partial write is not part of the log
+            let _ = self.log.set_len(base);
+            return Err(e);
+        }
+        self.offsets.extend(offsets);
+        self.len = base + data.len() as u64;
+        Ok(())
+    }
+
+    /// Stores a snapshot and replaces the log with the entries after it
+    pub fn save_snapshot(&mut self, snapshot: &Snapshot, entries: &[LogEntry]) -> io::Result<()> {
+        write_atomically(&self.dir, SNAPSHOT_FILE, &snapshot.encode_to_vec())?;
+        self.rewrite(snapshot.last_index + 1, entries)
+    }
+
+    fn rewrite(&mut self, first_index: u64, entries: &[LogEntry]) -> io::Result<()> {
+        let data = encode_log(first_index, entries);
+        write_atomically(&self.dir, LOG_FILE, &data)?;
+        self.log = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        self.offsets = decode_log(&data)?.offsets;
+        self.len = data.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_types::{table_op, Noop, TableOp};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sdfs-raft-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(term: u64) -> LogEntry {
+        LogEntry {
+            term,
+            op: Some(TableOp {
+                r#type: Some(table_op::Type::Noop(Noop {})),
+            }),
+        }
+    }
+
+    fn terms(entries: &[LogEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.term).collect()
+    }
+
+    #[test]
+    fn appended_entries_survive_a_reopen() {
+        let dir = test_dir("append");
+        let (mut log, loaded) = RaftLog::open(&dir).unwrap();
+        assert!(loaded.entries.is_empty());
+        log.append(0, &[entry(1), entry(1)]).unwrap();
+        log.append(2, &[entry(2)]).unwrap();
+        // Replacing a conflicting suffix
+        log.append(1, &[entry(3), entry(3)]).unwrap();
+        let hard_state = HardState {
+            term: 3,
+            voted_for: "a".to_string(),
+        };
+        log.save_hard_state(&hard_state).unwrap();
+        drop(log);
+
+        let (_, loaded) = RaftLog::open(&dir).unwrap();
+        assert_eq!(terms(&loaded.entries), [1, 3, 3]);
+        assert_eq!(loaded.hard_state, hard_state);
+        assert!(loaded.snapshot.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_entry_at_the_end_is_dropped() {
+        let dir = test_dir("torn");
+        let (mut log, _) = RaftLog::open(&dir).unwrap();
+        log.append(0, &[entry(1), entry(2)]).unwrap();
+        drop(log);
+        let path = dir.join(LOG_FILE);
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+        let (mut log, loaded) = RaftLog::open(&dir).unwrap();
+        assert_eq!(terms(&loaded.entries), [1]);
+        log.append(1, &[entry(4)]).unwrap();
+        drop(log);
+        let (_, loaded) = RaftLog::open(&dir).unwrap();
+        assert_eq!(terms(&loaded.entries), [1, 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_replaces_the_entries_it_covers() {
+        let dir = test_dir("snapshot");
+        let (mut log, _) = RaftLog::open(&dir).unwrap();
+        log.append(0, &[entry(1), entry(1), entry(2)]).unwrap();
+        let snapshot = Snapshot {
+            last_index: 2,
+            last_term: 1,
+            table: vec![1, 2, 3],
+        };
+        log.save_snapshot(&snapshot, &[entry(2)]).unwrap();
+        log.append(1, &[entry(5)]).unwrap();
+        drop(log);
+
+        let (_, loaded) = RaftLog::open(&dir).unwrap();
+        assert_eq!(loaded.snapshot, Some(snapshot));
+        assert_eq!(terms(&loaded.entries), [2, 5]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_ahead_of_the_log_survives_a_crash_before_compaction() {
+        let dir = test_dir("installed");
+        let (mut log, _) = RaftLog::open(&dir).unwrap();
+        log.append(0, &[entry(1), entry(1)]).unwrap();
+        drop(log);
+        // An installed snapshot written, the crash came before the log was rewritten
+        let snapshot = Snapshot {
+            last_index: 9,
+            last_term: 3,
+            table: vec![4],
+        };
+        write_atomically(&dir, SNAPSHOT_FILE, &snapshot.encode_to_vec()).unwrap();
+
+        let (mut log, loaded) = RaftLog::open(&dir).unwrap();
+        assert_eq!(loaded.snapshot, Some(snapshot));
+        assert!(loaded.entries.is_empty());
+        log.append(0, &[entry(4)]).unwrap();
+        drop(log);
+        let (_, loaded) = RaftLog::open(&dir).unwrap();
+        assert_eq!(terms(&loaded.entries), [4]);
+        let data = fs::read(dir.join(LOG_FILE)).unwrap();
+        assert_eq!(decode_log(&data).unwrap().first_index, 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
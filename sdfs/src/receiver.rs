@@ -1,210 +1,353 @@
 use crate::helpers::split_id_to_components;
 use crate::member_list::{
-    failure_detection::Type, Coordinator, Election, FailureDetection, MemberList, Ok,
+    failure_detection::Type, FailureDetection, Left, MemberList, Ping, PingAck, PingReq,
 };
 use crate::node::Node;
 use bytes::Bytes;
 use chrono::{offset::Local, DateTime, FixedOffset};
-use futures::stream::{self, StreamExt};
 use prost::Message;
-use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use std::{sync::Arc, time::Duration};
 use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, Mutex, Notify, RwLock};
-use tokio::time::sleep;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::time::{sleep, timeout};
 use tracing::{info, instrument, trace, warn};
 
-const TF_FAIL: Duration = Duration::from_millis(2200);
 const TF_CLEAN: Duration = Duration::from_millis(5000);
 
-struct LeaderElection {
-    leader_ip: Arc<RwLock<String>>,
+// One member is probed per protocol period
+const PROTOCOL_PERIOD: Duration = Duration::from_millis(1000);
+const PING_TIMEOUT: Duration = Duration::from_millis(300);
+// Members asked to probe a target that missed its direct ping
+const INDIRECT_PROBES: usize = 3;
+// How long a suspect has to refute the suspicion before it is declared failed
+const SUSPICION_TIMEOUT: Duration = Duration::from_millis(5000);
+// Failed members are remembered so stale gossip does not bring them back
+const TOMBSTONE_TTL: Duration = Duration::from_secs(60);
+// Members a LEFT notice is passed on to, besides the ones the leaving node told itself
+const LEFT_FANOUT: usize = 3;
+
+/// SWIM failure detector sharing the membership socket
+#[derive(Debug)]
+struct Swim {
+    socket: Arc<UdpSocket>,
     members: Arc<RwLock<Vec<Node>>>,
-    election_ongoing: Arc<Mutex<bool>>,
-    socket_addr: SocketAddr,
-    coord_rx: Mutex<mpsc::Receiver<String>>,
     sender_id: Arc<str>,
-    leader_wakeup: Arc<Notify>,
+    next_seq: AtomicU64,
+    acks: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    tombstones: Mutex<HashMap<Bytes, Instant>>,
 }
 
-impl LeaderElection {
-    fn new(
-        leader_ip: Arc<RwLock<String>>,
-        members: Arc<RwLock<Vec<Node>>>,
-        mut socket_addr: SocketAddr,
-        election_ongoing: Arc<Mutex<bool>>,
-        coord_rx: mpsc::Receiver<String>,
-        sender_id: Arc<str>,
-        leader_wakeup: Arc<Notify>,
-    ) -> LeaderElection {
-        socket_addr.set_port(12308);
-        let coord_rx = Mutex::new(coord_rx);
-        LeaderElection {
-            leader_ip,
-            members,
-            election_ongoing,
-            socket_addr,
-            coord_rx,
-            sender_id,
-            leader_wakeup,
-        }
+fn encode(message: Type) -> Vec<u8> {
+    FailureDetection {
+        r#type: Some(message),
     }
-    async fn run_election(self: Arc<Self>) {
-        let mut elect_lock = self.election_ongoing.lock().await;
-        if *elect_lock {
-            println!("Election already ongoing");
+    .encode_to_vec()
+}
+
+fn member_addr(raw_id: &Bytes) -> Option<String> {
+    let (ip, port) = split_id_to_components(raw_id)?;
+    Some(format!("{ip}:{port}"))
+}
+
+impl Swim {
+    async fn send(&self, message: &[u8], raw_id: &Bytes) {
+        let Some(addr) = member_addr(raw_id) else {
             return;
+        };
+        if let Err(e) = self.socket.send_to(message, &addr).await {
+            warn!("Unable to send probe to {}: {}", addr, e);
         }
-        *elect_lock = true;
-        drop(elect_lock);
+    }
 
-        loop {
-            let members_locked = self.members.read().await;
-            let mut lower_nodes: Vec<_> = members_locked
-                .iter()
-                .filter(|node| node.id().as_ref() < self.sender_id.as_bytes())
-                .collect();
-            let higher_nodes: Vec<_> = members_locked
+    async fn expect_ack(&self) -> (u64, oneshot::Receiver<()>) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.acks.lock().await.insert(seq, tx);
+        (seq, rx)
+    }
+
+    async fn wait_ack(&self, seq: u64, ack: oneshot::Receiver<()>, wait: Duration) -> bool {
+        let acked = matches!(timeout(wait, ack).await, Ok(Ok(())));
+        self.acks.lock().await.remove(&seq);
+        acked
+    }
+
+    async fn acked(&self, seq: u64) {
+        if let Some(ack) = self.acks.lock().await.remove(&seq) {
+            let _ = ack.send(());
+        }
+    }
+
+    /// Pings a member directly and waits for its ack
+    async fn ping(&self, target: &Bytes) -> bool {
+        let (seq, ack) = self.expect_ack().await;
+        let ping = encode(Type::Ping(Ping {
+            sender: self.sender_id.to_string(),
+            seq,
+        }));
+        self.send(&ping, target).await;
+        self.wait_ack(seq, ack, PING_TIMEOUT).await
+    }
+
+    /// Asks up to `INDIRECT_PROBES` other members to ping the target for us
+    async fn ping_indirect(&self, target: &Bytes, wait: Duration) -> bool {
+        let helpers: Vec<Bytes> = {
+            let guard = self.members.read().await;
+            let candidates: Vec<Bytes> = guard
                 .iter()
-                .filter(|node| node.id().as_ref() > self.sender_id.as_bytes())
+                .filter(|node| !node.fail())
+                .map(Node::id)
+                .filter(|id| id != target && id.as_ref() != self.sender_id.as_bytes())
                 .collect();
+            candidates
+                .choose_multiple(&mut rand::thread_rng(), INDIRECT_PROBES)
+                .cloned()
+                .collect()
+        };
+        if helpers.is_empty() {
+            return false;
+        }
+        let (seq, ack) = self.expect_ack().await;
+        let ping_req = encode(Type::PingReq(PingReq {
+            sender: self.sender_id.to_string(),
+            seq,
+            target: String::from_utf8_lossy(target).into_owned(),
+        }));
+        for helper in &helpers {
+            self.send(&ping_req, helper).await;
+        }
+        self.wait_ack(seq, ack, wait).await
+    }
 
-            println!("Lower nodes: {:?}", lower_nodes);
-            println!("Higher nodes: {:?}", higher_nodes);
+    /// Probes `target` for a member that could not reach it and relays the ack
+    async fn relay_ping(&self, ping_req: PingReq) {
+        let target = Bytes::from(ping_req.target);
+        if !self.ping(&target).await {
+            return;
+        }
+        let ack = encode(Type::Ack(PingAck {
+            sender: self.sender_id.to_string(),
+            seq: ping_req.seq,
+        }));
+        self.send(&ack, &Bytes::from(ping_req.sender)).await;
+    }
 
-            let mut base = 12309;
-            let socket_addresses = stream::repeat(self.socket_addr);
-            let ports = stream::repeat_with(|| {
-                let tmp = base;
-                base += 1;
-                tmp
-            });
+    async fn suspect(&self, target: &Bytes) {
+        let mut guard = self.members.write().await;
+        if let Some(node) = guard.iter_mut().find(|node| node.id() == *target) {
+            if !node.fail() {
+                warn!(
+                    "Suspecting node after failed probes: {}",
+                    String::from_utf8_lossy(target)
+                );
+                node.suspect(Local::now().into());
+            }
+        }
+    }
 
-            println!("Contacting {} lower nodes", lower_nodes.len());
-            let contact_res: Vec<()> = stream::iter(lower_nodes.iter())
-                .zip(socket_addresses.zip(ports))
-                .filter_map(|(node, (mut sock_addr, port))| async move {
-                    sock_addr.set_port(port);
-                    let Ok(local_sock) = UdpSocket::bind(sock_addr).await else {
-                        return None;
-                    };
-                    let raw_id = node.id();
-                    let Some((ip, port)) = split_id_to_components(&raw_id) else {
-                        return None;
-                    };
-                    let msg = FailureDetection {
-                        r#type: Some(Type::Elect(Election {})),
-                    }
-                    .encode_to_vec();
+    /// Drops a member that left the group, false if it was already gone
+    async fn remove_left(&self, raw_id: &Bytes) -> bool {
+        let mut tombstones = self.tombstones.lock().await;
+        if tombstones.contains_key(raw_id) {
+            return false;
+        }
+        tombstones.insert(raw_id.clone(), Instant::now());
+        self.members
+            .write()
+            .await
+            .retain(|node| node.id() != *raw_id);
+        true
+    }
 
-                    if let Err(e) = local_sock.send_to(&msg, format!("{ip}:{port}")).await {
-                        println!("Unable to contact higher node: {}", e);
-                        return None;
-                    }
-                    let mut ok_buffer = [0; 1024];
-                    let Ok(size) = tokio::select! {
-                        res = local_sock.recv(&mut ok_buffer) => res,
-                        _ = sleep(Duration::from_secs(10)) => {
-                            println!("Timed out, no OK from lower node: {}", ip);
-                            Err(io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"))
-                        }
-                    } else {
-                        return None;
-                    };
-                    if let Err(e) = Ok::decode(&ok_buffer[..size]) {
-                        println!("Unable to decode OK message: {}", e);
-                        return None;
-                    }
-                    Some(())
-                })
+    /// Passes a LEFT notice on to a few members in case the leaving node's own notice was lost
+    async fn forward_left(&self, left: Left) {
+        let targets: Vec<Bytes> = {
+            let guard = self.members.read().await;
+            let candidates: Vec<Bytes> = guard
+                .iter()
+                .map(Node::id)
+                .filter(|id| id.as_ref() != self.sender_id.as_bytes())
+                .collect();
+            candidates
+                .choose_multiple(&mut rand::thread_rng(), LEFT_FANOUT)
+                .cloned()
                 .collect()
-                .await;
+        };
+        let message = encode(Type::Left(left));
+        for target in &targets {
+            self.send(&message, target).await;
+        }
+    }
 
-            if contact_res.is_empty() {
-                println!("No lower node, electing myself");
-                let raw_id = self.sender_id.as_bytes();
-                let Some((ip, _)) = split_id_to_components(&raw_id) else {
-                    continue;
-                };
-                *self.leader_ip.write().await = ip.to_string();
-                self.leader_wakeup.notify_one();
-            } else {
-                println!("Receiving COORDs from {} lower nodes", contact_res.len());
-                let mut received_ips = Vec::new();
-                let mut bruh = self.coord_rx.lock().await;
-                while let Some(ip) = tokio::select! {
-                    ip = bruh.recv() => {
-                        ip
-                    }
-                    _ = sleep(Duration::from_secs(25)) => {
-                        println!("Timed out, no COORD from a lower node");
-                        None
-                    }
-                } {
-                    received_ips.push(ip);
-                }
+    /// Removes suspects that did not refute in time, returning their ips
+    async fn expire_suspects(&self) -> Vec<String> {
+        let local_time: DateTime<FixedOffset> = Local::now().into();
+        let mut tombstones = self.tombstones.lock().await;
+        tombstones.retain(|_, failed_at| failed_at.elapsed() < TOMBSTONE_TTL);
+        let mut guard = self.members.write().await;
+        let mut failed_machines = Vec::new();
+        guard.retain(|node| {
+            let Some(suspected_at) = node.suspected_at() else {
+                return true;
+            };
+            if local_time < suspected_at + SUSPICION_TIMEOUT {
+                return true;
+            }
+            let raw_id = node.id();
+            warn!(
+                "Removing node that did not refute suspicion: {}",
+                String::from_utf8_lossy(&raw_id)
+            );
+            if let Some((ip, _)) = split_id_to_components(&raw_id) {
+                failed_machines.push(ip.to_string());
+            }
+            tombstones.insert(raw_id, Instant::now());
+            false
+        });
+        failed_machines
+    }
 
-                if received_ips.is_empty() {
-                    continue;
+    /// Each period probes the next member of a shuffled round, directly and then through
+    /// other members, and suspects it if nobody reaches it
+    #[instrument(name = "SWIM prober", level = "trace")]
+    async fn probe(&self, tx_leader: mpsc::Sender<Vec<String>>) {
+        let mut round: Vec<Bytes> = Vec::new();
+        loop {
+            let period_start = Instant::now();
+            let failed_machines = self.expire_suspects().await;
+            if !failed_machines.is_empty() {
+                // Followers only drain this once they become leader, never block on it
+                if tx_leader.try_send(failed_machines).is_err() {
+                    warn!("Leader failure queue full, dropping failure notice");
                 }
+            }
 
-                lower_nodes.retain(|node| {
-                    for ip in received_ips.iter() {
-                        if node.id().starts_with(ip.as_bytes()) {
-                            return true;
-                        }
+            if round.is_empty() {
+                round = self
+                    .members
+                    .read()
+                    .await
+                    .iter()
+                    .map(Node::id)
+                    .filter(|id| id.as_ref() != self.sender_id.as_bytes())
+                    .collect();
+                round.shuffle(&mut rand::thread_rng());
+            }
+            if let Some(target) = round.pop() {
+                let known = self
+                    .members
+                    .read()
+                    .await
+                    .iter()
+                    .any(|node| node.id() == target);
+                if known && !self.ping(&target).await {
+                    trace!(
+                        "No ack from {}, probing indirectly",
+                        String::from_utf8_lossy(&target)
+                    );
+                    let wait = PROTOCOL_PERIOD.saturating_sub(period_start.elapsed());
+                    if !self.ping_indirect(&target, wait).await {
+                        self.suspect(&target).await;
                     }
-                    false
-                });
+                }
+            }
+            sleep(PROTOCOL_PERIOD.saturating_sub(period_start.elapsed())).await;
+        }
+    }
+
+    /// Merges a gossiped member list. Higher incarnations win, and at equal incarnations
+    /// suspicion wins over alive.
+    #[instrument(name = "Process membership list", level = "trace")]
+    async fn merge(&self, received_member_list: &MemberList) {
+        let local_time: DateTime<FixedOffset> = Local::now().into();
+        let tombstones = self.tombstones.lock().await;
+        let mut guard = self.members.write().await;
 
-                lower_nodes.sort_unstable_by_key(|node| node.id());
+        let remote_sender = received_member_list.sender.as_str();
+        let remote_node_id = Bytes::copy_from_slice(remote_sender.as_bytes());
+        if tombstones.contains_key(&remote_node_id) {
+            info!("Ignoring gossip from failed node: {}", remote_sender);
+            return;
+        }
 
-                let _ = stream::iter(lower_nodes)
-                    .take_while(|node| async {
-                        let raw_id = node.id();
-                        let Some((ip, _)) = split_id_to_components(&raw_id) else {
-                            return true;
-                        };
-                        println!("Elected {} as new leader", ip);
-                        *self.leader_ip.write().await = ip.to_string();
-                        false
-                    })
-                    .collect::<Vec<_>>()
-                    .await;
+        match guard.iter_mut().find(|node| node.id() == remote_node_id) {
+            None => {
+                info!("Adding new node: {}", remote_sender);
+                guard.push(Node::new(remote_node_id, 1, local_time, false));
+            }
+            Some(i) => {
+                info!("Received heartbeat from: {}, updating", remote_sender);
+                i.set_heartbeat(i.heartbeat() + 1);
+                i.set_time(Local::now().into());
             }
+        };
 
-            let Ok(coord_sock) = UdpSocket::bind(self.socket_addr).await else {
-                println!("Unable to bind 12308");
-                return;
-            };
+        for received_member in &received_member_list.machines {
+            let received_id_bytes = Bytes::copy_from_slice(received_member.id.as_bytes());
 
-            println!(
-                "Sending Coord messages to {} higher nodes",
-                higher_nodes.len()
-            );
-            for node in higher_nodes {
-                let raw_id = node.id();
-                let Some((ip, port)) = split_id_to_components(&raw_id) else {
+            if *self.sender_id == received_member.id {
+                let Some(local_node) = guard.iter_mut().find(|node| node.id() == received_id_bytes)
+                else {
                     continue;
                 };
-
-                let msg = FailureDetection {
-                    r#type: Some(Type::Coord(Coordinator {
-                        leader_ip: self.leader_ip.read().await.clone(),
-                    })),
+                if received_member.suspect
+                    && received_member.incarnation >= local_node.incarnation()
+                {
+                    let incarnation = received_member.incarnation + 1;
+                    warn!("Refuting suspicion with incarnation {}", incarnation);
+                    local_node.set_incarnation(incarnation);
                 }
-                .encode_to_vec();
+                continue;
+            }
+            if tombstones.contains_key(&received_id_bytes) {
+                continue;
+            }
 
-                if let Err(e) = coord_sock.send_to(&msg, format!("{ip}:{port}")).await {
-                    println!("Unable to contact higher node: {}", e);
-                    return;
+            // Check if member exists in our local state
+            if let Some(local_node) = guard.iter_mut().find(|node| node.id() == received_id_bytes) {
+                if received_member.heartbeat > local_node.heartbeat() {
+                    info!("Updating node: {}", received_member.id);
+                    local_node.set_heartbeat(received_member.heartbeat);
+                    local_node.set_time(local_time);
+                }
+                let newer = received_member.incarnation > local_node.incarnation();
+                let same = received_member.incarnation == local_node.incarnation();
+                if newer && !received_member.suspect {
+                    if local_node.fail() {
+                        info!("Node refuted suspicion: {}", received_member.id);
+                    }
+                    local_node.set_incarnation(received_member.incarnation);
+                    local_node.clear_suspicion();
+                } else if received_member.suspect && (newer || same) {
+                    if !local_node.fail() {
+                        warn!("Node suspected by a peer: {}", received_member.id);
+                    }
+                    local_node.set_incarnation(received_member.incarnation);
+                    local_node.suspect(local_time);
+                }
+            } else {
+                let remote_time =
+                    DateTime::parse_from_rfc3339(&received_member.time).unwrap_or_default();
+                if remote_time + TF_CLEAN > local_time {
+                    info!("Adding new node: {}", received_member.id);
+                    let mut node = Node::new(
+                        received_id_bytes,
+                        received_member.heartbeat,
+                        remote_time,
+                        false,
+                    );
+                    node.set_incarnation(received_member.incarnation);
+                    if received_member.suspect {
+                        node.suspect(local_time);
+                    }
+                    guard.push(node);
                 }
             }
-
-            println!("Sent COORD to higher nodes, election should be complete");
-
-            break;
         }
-        *self.election_ongoing.lock().await = false;
     }
 }
 
@@ -215,37 +358,32 @@ pub async fn receiver(
     udp_socket: UdpSocket,
     leader_ip: Arc<RwLock<String>>,
     tx_leader: mpsc::Sender<Vec<String>>,
-    leader_wakeup: Arc<Notify>,
 ) {
-    let mut buffer = [0; 2048];
-    let proc_list_ip = leader_ip.clone();
-    let proc_list_tx_leader = tx_leader.clone();
-    let proc_list_members = members.clone();
-    let Ok(local_addr) = udp_socket.local_addr() else {
-        println!("Malformed local addr, unable to start failure listener");
-        return;
-    };
-    let election_ongoing = Arc::new(Mutex::new(false));
-    let (coord_tx, coord_rx) = mpsc::channel::<String>(10);
-    let leader_election = Arc::new(LeaderElection::new(
-        leader_ip.clone(),
-        members.clone(),
-        local_addr,
-        election_ongoing.clone(),
-        coord_rx,
-        sender_id.clone(),
-        leader_wakeup,
-    ));
-    let leader_election_proc = leader_election.clone();
-    tokio::spawn(async move {
-        process_list(
-            proc_list_members,
-            proc_list_ip,
-            proc_list_tx_leader,
-            leader_election_proc,
-        )
-        .await;
+    let udp_socket = Arc::new(udp_socket);
+    let swim = Arc::new(Swim {
+        socket: udp_socket.clone(),
+        members,
+        sender_id,
+        next_seq: AtomicU64::new(0),
+        acks: Mutex::new(HashMap::new()),
+        tombstones: Mutex::new(HashMap::new()),
     });
+    // Both stop together when the node leaves the group
+    tokio::join!(
+        swim.probe(tx_leader.clone()),
+        listen(swim.clone(), udp_socket, leader_ip, tx_leader)
+    );
+}
+
+async fn listen(
+    swim: Arc<Swim>,
+    udp_socket: Arc<UdpSocket>,
+    leader_ip: Arc<RwLock<String>>,
+    tx_leader: mpsc::Sender<Vec<String>>,
+) {
+    let mut buffer = [0; 2048];
+    // Term of the newest leader announcement, older ones are stale
+    let mut leader_term = 0;
     loop {
         let Ok((size, remote_addr)) = udp_socket.recv_from(&mut buffer).await else {
             warn!("Failed to read data from UDP socket");
@@ -265,169 +403,62 @@ pub async fn receiver(
         match received_message.r#type {
             Some(Type::Members(received_member_list)) => {
                 info!("Received member list");
-                process_received_data(&members, &received_member_list, &sender_id).await;
+                swim.merge(&received_member_list).await;
             }
-            Some(Type::Coord(new_leader)) => {
-                if !*election_ongoing.lock().await {
-                    println!("Received Coordinator message, updating leader");
-                    *leader_ip.write().await = new_leader.leader_ip;
-                } else {
-                    println!(
-                        "Received Coordinator message, election running, informing the elector"
-                    );
-                    let _ = coord_tx.send(new_leader.leader_ip).await;
+            Some(Type::Ping(ping)) => {
+                let ack = encode(Type::Ack(PingAck {
+                    sender: swim.sender_id.to_string(),
+                    seq: ping.seq,
+                }));
+                if let Err(e) = udp_socket.send_to(&ack, remote_addr).await {
+                    warn!("Unable to ack ping from {}: {}", ping.sender, e);
                 }
             }
-            Some(Type::Elect(_)) => {
-                let ok_message = Ok {}.encode_to_vec();
-                let _ = udp_socket.send_to(&ok_message, remote_addr).await;
-                let election_clone = leader_election.clone();
-                println!("Sent back OK message, running election");
+            Some(Type::PingReq(ping_req)) => {
+                let relay = swim.clone();
                 tokio::spawn(async move {
-                    election_clone.clone().run_election().await;
+                    relay.relay_ping(ping_req).await;
                 });
             }
-            None => {
-                println!("No message detected");
-            }
-        }
-        buffer.fill(0);
-    }
-}
-
-#[instrument(name = "Process membership list", level = "trace")]
-async fn process_received_data(
-    members: &Arc<RwLock<Vec<Node>>>,
-    received_member_list: &MemberList,
-    local_sender_id: &Arc<str>,
-) {
-    let local_time: DateTime<FixedOffset> = Local::now().into();
-    let mut guard = members.write().await;
-
-    let remote_sender = received_member_list.sender.as_str();
-    let remote_node_id = Bytes::copy_from_slice(remote_sender.as_bytes());
-
-    match guard.iter_mut().find(|node| node.id() == remote_node_id) {
-        None => {
-            info!("Adding new node: {}", remote_sender);
-            guard.push(Node::new(remote_node_id, 1, local_time, false));
-        }
-        Some(i) => {
-            info!("Received heartbeat from: {}, updating", remote_sender);
-            i.set_heartbeat(i.heartbeat() + 1);
-            i.set_time(Local::now().into());
-            i.set_fail(false);
-        }
-    };
-
-    for received_member in &received_member_list.machines {
-        let received_id_bytes = Bytes::copy_from_slice(received_member.id.as_bytes());
-
-        // Check if member exists in our local state
-        if let Some(local_node) = guard.iter_mut().find(|node| node.id() == received_id_bytes) {
-            let local_time: DateTime<FixedOffset> = Local::now().into();
-            // let remote_time = DateTime::parse_from_rfc3339(&received_member.time).unwrap_or_default();
-            if received_member.heartbeat > local_node.heartbeat() {
-                info!("Updating node: {}", received_member.id);
-                local_node.set_heartbeat(received_member.heartbeat);
-                local_node.set_fail(false);
-                local_node.set_time(local_time);
-            }
-        } else if **local_sender_id != received_member.id {
-            let remote_time =
-                DateTime::parse_from_rfc3339(&received_member.time).unwrap_or_default();
-            if remote_time + TF_CLEAN > local_time {
-                info!("Adding new node: {}", received_member.id);
-                guard.push(Node::new(
-                    received_id_bytes,
-                    received_member.heartbeat,
-                    remote_time,
-                    false,
-                ));
+            Some(Type::Ack(ack)) => {
+                swim.acked(ack.seq).await;
             }
-        }
-    }
-}
-
-async fn process_list(
-    members: Arc<RwLock<Vec<Node>>>,
-    leader_ip: Arc<RwLock<String>>,
-    tx_leader: mpsc::Sender<Vec<String>>,
-    _leader_election: Arc<LeaderElection>,
-) {
-    loop {
-        let local_time: DateTime<FixedOffset> = Local::now().into();
-
-        let mut fail_ids = Vec::new();
-        let mut clean_ids = Vec::new();
-
-        {
-            let read_guard = members.read().await;
-            for node in read_guard.iter() {
-                if local_time >= node.time() + TF_FAIL {
-                    fail_ids.push(node.id().clone());
+            Some(Type::Left(left)) => {
+                let raw_id = Bytes::copy_from_slice(left.id.as_bytes());
+                if *swim.sender_id == left.id || !swim.remove_left(&raw_id).await {
+                    continue;
                 }
-                if local_time >= node.time() + TF_CLEAN {
-                    clean_ids.push(node.id().clone());
+                info!("Node left the group: {}", left.id);
+                // The leader re-replicates what the node stored, without a failure on record
+                if let Some((ip, _)) = split_id_to_components(&raw_id) {
+                    if tx_leader.try_send(vec![ip.to_string()]).is_err() {
+                        warn!("Leader failure queue full, dropping leave notice");
+                    }
                 }
+                let forwarder = swim.clone();
+                tokio::spawn(async move {
+                    forwarder.forward_left(left).await;
+                });
             }
-        }
-
-        if !fail_ids.is_empty() {
-            trace!("Processing own membership list for suspicion");
-            let mut guard = members.write().await;
-            // Process own membership list for suspicion
-            guard.iter_mut().for_each(|node: &mut Node| {
-                //let duration_since_last_heartbeat = current_time.duration_since(node.time());
-                if fail_ids.contains(&node.id()) && !node.fail() {
-                    warn!(
-                        "Failing node due to timeout: {}",
-                        String::from_utf8_lossy(&node.id())
-                    );
-                    //println!("Node suspicious: {}", String::from_utf8_lossy(&node.id()));
-                    node.set_fail(true);
+            Some(Type::Coord(new_leader)) => {
+                if new_leader.term < leader_term {
+                    info!("Ignoring leader announcement from term {}", new_leader.term);
+                    continue;
                 }
-            });
-        }
-
-        let mut _should_run_election = false;
-
-        if !clean_ids.is_empty() {
-            // For normal gossip, just cleanup
-            let mut guard = members.write().await;
-            let mut failed_machines = Vec::new();
-            let mut new_guard = Vec::new();
-
-            for node in guard.iter() {
-                if clean_ids.contains(&node.id()) {
-                    let leader_guard = leader_ip.read().await;
-                    let raw_id = node.id();
-                    let Ok(node_id) = std::str::from_utf8(&raw_id) else {
-                        continue;
-                    };
-                    if let Some((left, _)) = node_id.split_once('_') {
-                        failed_machines.push(left.to_string());
-                    }
-                    warn!("Old time {}, new time {}", node.time(), local_time);
-                    warn!("Removing node due to cleanup: {}", node_id);
-                    //println!("Removing node due to cleanup: {}", node_id);
-                    if node.id().starts_with(leader_guard.as_bytes()) {
-                        _should_run_election = true;
-                    }
-                } else {
-                    new_guard.push(node.clone());
+                leader_term = new_leader.term;
+                let mut leader_ip = leader_ip.write().await;
+                if *leader_ip != new_leader.leader_ip {
+                    println!(
+                        "New leader {} for term {}",
+                        new_leader.leader_ip, new_leader.term
+                    );
+                    *leader_ip = new_leader.leader_ip;
                 }
             }
-            *guard = new_guard;
-            let _ = tx_leader.send(failed_machines).await;
+            None => {
+                println!("No message detected");
+            }
         }
-        // if should_run_election {
-        //     println!("Detected failure on leader, calling election");
-        //     let election_clone = leader_election.clone();
-        //     tokio::spawn(async move {
-        //         election_clone.clone().run_election().await;
-        //     });
-        // }
-        sleep(Duration::from_millis(100)).await;
+        buffer.fill(0);
     }
 }
@@ -8,7 +8,11 @@ use tokio::time::{sleep, Duration};
 use tokio::{net::UdpSocket, sync::RwLock};
 use tracing::{error, info, trace, trace_span, warn, Instrument};
 
-pub async fn sender(members: Arc<RwLock<Vec<Node>>>, sender_id: Arc<str>) {
+pub async fn sender(
+    members: Arc<RwLock<Vec<Node>>>,
+    sender_id: Arc<str>,
+    introducers: Vec<String>,
+) {
     let can_sock: Option<UdpSocket> = async {
         info!("Starting sender");
         let Ok(raw_host) = hostname::get() else {
@@ -28,6 +32,8 @@ pub async fn sender(members: Arc<RwLock<Vec<Node>>>, sender_id: Arc<str>) {
     .instrument(trace_span!("Sender start up"))
     .await;
     let mut start_node = 0;
+    // Introducers are tried in turn while this node has no one to gossip with
+    let mut next_introducer = 0;
     let Some(sock) = can_sock else {
         return;
     };
@@ -47,16 +53,15 @@ pub async fn sender(members: Arc<RwLock<Vec<Node>>>, sender_id: Arc<str>) {
             let member_list = FailureDetection {
                 r#type: Some(Type::Members(MemberList {
                     sender: sender_id.to_string(),
+                    // Suspects are gossiped too, so suspicion spreads and can be refuted
                     machines: (*guard)
                         .iter()
-                        .filter_map(|node| {
-                            (!node.fail()).then_some({
-                                Member {
-                                    id: String::from_utf8_lossy(&node.id()).into_owned(),
-                                    heartbeat: node.heartbeat(),
-                                    time: node.time().to_rfc3339().to_string(),
-                                }
-                            })
+                        .map(|node| Member {
+                            id: String::from_utf8_lossy(&node.id()).into_owned(),
+                            heartbeat: node.heartbeat(),
+                            time: node.time().to_rfc3339().to_string(),
+                            incarnation: node.incarnation(),
+                            suspect: node.fail(),
                         })
                         .collect::<Vec<_>>(),
                 })),
@@ -87,7 +92,11 @@ pub async fn sender(members: Arc<RwLock<Vec<Node>>>, sender_id: Arc<str>) {
                     }
                 }
             }
-            if length <= 1 {
+            if let (true, Some(introducer)) = (
+                length <= 1,
+                introducers.get(next_introducer % introducers.len().max(1)),
+            ) {
+                next_introducer += 1;
                 let member_list = FailureDetection {
                     r#type: Some(Type::Members(MemberList {
                         sender: sender_id.to_string(),
@@ -96,13 +105,13 @@ pub async fn sender(members: Arc<RwLock<Vec<Node>>>, sender_id: Arc<str>) {
                 }
                 .encode_to_vec();
                 if let Err(e) = sock
-                    .send_to(&member_list, "fa23-cs425-6805.cs.illinois.edu:12307")
+                    .send_to(&member_list, format!("{introducer}:12307"))
                     .await
                 {
-                    error!("Unable to contact introducer: {}", e);
+                    error!("Unable to contact introducer {}: {}", introducer, e);
                     continue;
                 }
-                info!("Contacted introducer again");
+                info!("Contacted introducer {} again", introducer);
             }
             start_node = if !(*guard).is_empty() {
                 (start_node + 3) % (*guard).len()
@@ -1,26 +1,95 @@
-use crate::helpers::{client_get_helper, write_to_buf, FileKey};
-use crate::message_types::{sdfs_command::Type, SdfsCommand};
+use crate::erasure;
+use crate::executor;
+use crate::helpers::{
+    byte_span, client_get_helper, clone_file, copy_range, decode_key, file_digest, file_digester,
+    parallel_get, quorum_read, send_span, write_leader_request, write_to_buf, Digester, FileKey,
+    Received, CHUNK_SIZE, STALE_EPOCH,
+};
+use crate::message_types::{get_req::Range, sdfs_command::Type, SdfsCommand};
 use crate::message_types::{
-    Ack, Delete, Fail, FileSizeReq, FileSizeRes, GetReq, LeaderMapReq, LeaderPutReq,
-    LeaderReduceReq, LeaderStoreRes, LsRes, MultiRead, MultiWrite, PutReq, ServerMapReq,
-    ServerMapRes, ServerRedRes, ServerReduceReq,
+    Ack, Delete, EncodeReq, ErasureLayout, Fail, FileSizeReq, FileSizeRes, GetReq, JobCounters,
+    KeyServers, LeaderMapReq, LeaderPutReq, LeaderReduceReq, LeaderStoreRes, LineRange, LinkReq,
+    LsRes, MultiRead, MultiWrite, PublishReq, PutReq, RebuildReq, RepairReq, ServerMapReq,
+    ServerMapRes, ServerRedRes, ServerReduceReq, TaskFailure, VersionReq, VersionRes,
 };
+use crate::namespace;
 use futures::{stream, StreamExt};
 use prost::Message;
-use std::{fmt, io::Write, process::Command, sync::Arc};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use std::{fmt, io::Write, path::Path, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::{fs, sync::Mutex};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, instrument, warn};
 
 #[derive(Debug, Clone)]
 pub struct LocalFileList {
     list: Vec<String>,
+    versions: HashMap<String, u64>, // version of each stored file, missing if unknown
+    // Digest state of stored files by the version it covers, so appends only digest new data
+    digesters: HashMap<String, (u64, Digester)>,
+    staged_digesters: HashMap<String, Digester>, // by staging id, until published
+    writers: HashMap<String, StagedWriter>,      // by staging id, until published
+}
+
+/// The PUT writing a staged copy. A resumed PUT cancels the one before it and waits for it to
+/// let go of the copy, so two writers never add data to the same copy.
+#[derive(Debug, Clone)]
+pub struct StagedWriter {
+    cancel: CancellationToken,
+    copy: Arc<Mutex<()>>,
 }
 
 impl LocalFileList {
     pub fn new() -> LocalFileList {
-        LocalFileList { list: Vec::new() }
+        LocalFileList {
+            list: Vec::new(),
+            versions: HashMap::new(),
+            digesters: HashMap::new(),
+            staged_digesters: HashMap::new(),
+            writers: HashMap::new(),
+        }
+    }
+    /// Cancels the writer of a staged copy, if any, and registers a new one
+    pub fn take_over(&mut self, staging: &str) -> StagedWriter {
+        let copy = match self.writers.get(staging) {
+            Some(previous) => {
+                previous.cancel.cancel();
+                previous.copy.clone()
+            }
+            None => Arc::new(Mutex::new(())),
+        };
+        let writer = StagedWriter {
+            cancel: CancellationToken::new(),
+            copy,
+        };
+        self.writers.insert(staging.to_string(), writer.clone());
+        writer
+    }
+    /// Forgets a staged copy that was published or discarded
+    pub fn drop_staged(&mut self, staging: &str) {
+        self.staged_digesters.remove(staging);
+        self.writers.remove(staging);
+    }
+    /// Digest state of the stored version of a file, if still known
+    pub fn digester(&self, file_name: &str) -> Option<Digester> {
+        let (version, digester) = self.digesters.get(file_name)?;
+        (*version == self.version(file_name)).then(|| digester.clone())
+    }
+    pub fn digesters_mut(&mut self) -> &mut HashMap<String, (u64, Digester)> {
+        &mut self.digesters
+    }
+    pub fn staged_digesters_mut(&mut self) -> &mut HashMap<String, Digester> {
+        &mut self.staged_digesters
+    }
+    pub fn version(&self, file_name: &str) -> u64 {
+        self.versions.get(file_name).copied().unwrap_or_default()
+    }
+    pub fn versions_mut(&mut self) -> &mut HashMap<String, u64> {
+        &mut self.versions
     }
     pub fn list_mut(&mut self) -> &mut Vec<String> {
         &mut self.list
@@ -39,18 +108,188 @@ impl fmt::Display for LocalFileList {
     }
 }
 
+// Tasks running on this server, numbered to keep their local key files apart
+static TASK_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Newest leader epoch this server has seen, commands from older leaders are refused
+static LEADER_EPOCH: AtomicU64 = AtomicU64::new(0);
+// Held while a newer epoch is written to disk
+static EPOCH_WRITE: Mutex<()> = Mutex::const_new(());
+
+// Keeps the newest epoch across restarts, so a restarted server still refuses older leaders
+const EPOCH_FILE: &str = "/home/sdfs/.epoch";
+
+/// Records the epoch of a leader command, false if a newer leader has already taken over.
+/// A newer epoch is on disk before any command from it is acted on.
+async fn accept_epoch(epoch: u64) -> bool {
+    let seen = LEADER_EPOCH.load(Ordering::SeqCst);
+    if epoch < seen {
+        warn!(
+            "Refusing command from leader epoch {}, now at {}",
+            epoch, seen
+        );
+        return false;
+    }
+    if epoch == seen {
+        return true;
+    }
+    let _write = EPOCH_WRITE.lock().await;
+    let seen = LEADER_EPOCH.load(Ordering::SeqCst);
+    if epoch < seen {
+        warn!(
+            "Refusing command from leader epoch {}, now at {}",
+            epoch, seen
+        );
+        return false;
+    }
+    if epoch > seen {
+        if let Err(e) = save_epoch(epoch).await {
+            error!("Unable to persist leader epoch {}: {}", epoch, e);
+            return false;
+        }
+        LEADER_EPOCH.store(epoch, Ordering::SeqCst);
+    }
+    true
+}
+
+async fn save_epoch(epoch: u64) -> std::io::Result<()> {
+    let tmp = format!("{EPOCH_FILE}.tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(epoch.to_string().as_bytes()).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, EPOCH_FILE).await?;
+    fs::File::open("/home/sdfs").await?.sync_all().await
+}
+
+/// Reads the newest epoch seen before a restart, 0 if there is none
+async fn load_epoch() -> std::io::Result<u64> {
+    match fs::read_to_string(EPOCH_FILE).await {
+        Ok(epoch) => epoch
+            .trim()
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes land here first and are renamed into /home/sdfs once complete
+const STAGING_DIR: &str = "/home/sdfs/.staging";
+// How long a staged write may sit untouched before the leader may drop it
+const STAGING_GRACE: Duration = Duration::from_secs(60 * 60);
+
+fn staging_path(staging: &str) -> String {
+    format!("{STAGING_DIR}/{staging}")
+}
+
+fn new_staging_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Creates the directories a stored file lives in
+async fn create_parent(path: &str) -> std::io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) => fs::create_dir_all(parent).await,
+        None => Ok(()),
+    }
+}
+
+/// Removes the directories of a deleted file that are left empty, up to /home/sdfs
+async fn prune_parents(file_name: &str) {
+    for dir in namespace::ancestors(file_name)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        // Fails on the first directory that still holds something
+        if fs::remove_dir(format!("/home/sdfs/{}", dir)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Atomically replaces a file with its staged copy, unless a newer version is already stored.
+/// The leader retries until every copy is published, so a staged copy already renamed into
+/// place counts as published.
+async fn publish(
+    file_name: &str,
+    staging: &str,
+    version: u64,
+    local_file_list: &Arc<Mutex<LocalFileList>>,
+) -> bool {
+    let mut file_list = local_file_list.lock().await;
+    if version > 0 && version < file_list.version(file_name) {
+        info!(
+            "Dropping version {} of {}, a newer one is stored",
+            version, file_name
+        );
+        let _ = fs::remove_file(staging_path(staging)).await;
+        file_list.drop_staged(staging);
+        return true;
+    }
+    let path = format!("/home/sdfs/{}", file_name);
+    if fs::metadata(staging_path(staging)).await.is_err() {
+        if fs::metadata(&path).await.is_err() {
+            // Replica repair restores the copy
+            warn!("Staged copy {} of {} is gone", staging, file_name);
+        }
+        return true;
+    }
+    if let Err(e) = create_parent(&path).await {
+        warn!("Unable to create the directory of {}: {}", file_name, e);
+        return false;
+    }
+    if let Err(e) = fs::rename(staging_path(staging), path).await {
+        warn!(
+            "Unable to publish {} from staging {}: {}",
+            file_name, staging, e
+        );
+        return false;
+    }
+    if !file_list.list().iter().any(|elem| elem == file_name) {
+        file_list.list_mut().push(file_name.to_string());
+    }
+    if version > 0 {
+        file_list
+            .versions_mut()
+            .insert(file_name.to_string(), version);
+    }
+    let staged_digester = file_list.staged_digesters_mut().remove(staging);
+    file_list.drop_staged(staging);
+    match staged_digester {
+        Some(digester) => {
+            let digester = (file_list.version(file_name), digester);
+            file_list
+                .digesters_mut()
+                .insert(file_name.to_string(), digester);
+        }
+        None => {
+            file_list.digesters_mut().remove(file_name);
+        }
+    }
+    true
+}
+
 enum ServerPutFlavor {
     Put,
-    Map,
+    Map(String), // local key file the map task wrote under its task prefix
     Reduce,
 }
 
-async fn put_from_server(file_name: String, ip: String, flavor: ServerPutFlavor) -> Result<(), ()> {
+async fn put_from_server(
+    file_name: String,
+    ip: String,
+    flavor: ServerPutFlavor,
+    version: u64,
+) -> Result<(), ()> {
     let get_req = GetReq {
         file_name: match flavor {
             ServerPutFlavor::Put => file_name.clone(),
-            ServerPutFlavor::Map | ServerPutFlavor::Reduce => format!("mrout/{file_name}"),
+            ServerPutFlavor::Map(ref local) => format!("mrout/{local}"),
+            ServerPutFlavor::Reduce => format!("mrout/{file_name}"),
         },
+        range: None,
+        ..Default::default()
     };
     info!("Connecting to the other server {}", ip);
     //Add server port 56552 to the end of the machine string
@@ -62,8 +301,12 @@ async fn put_from_server(file_name: String, ip: String, flavor: ServerPutFlavor)
     };
     let req_buf = SdfsCommand {
         r#type: match flavor {
-            ServerPutFlavor::Put => Some(Type::PutReq(PutReq { file_name })),
-            ServerPutFlavor::Map => Some(Type::ServerMapReq(ServerMapReq {
+            ServerPutFlavor::Put => Some(Type::PutReq(PutReq {
+                file_name,
+                version,
+                ..Default::default()
+            })),
+            ServerPutFlavor::Map(_) => Some(Type::ServerMapReq(ServerMapReq {
                 output_file: file_name,
             })),
             ServerPutFlavor::Reduce => Some(Type::ServerRedReq(ServerReduceReq {
@@ -83,7 +326,115 @@ async fn put_from_server(file_name: String, ip: String, flavor: ServerPutFlavor)
         return Err(());
     }
 
-    handle_get(get_req, inter_server_stream).await
+    handle_get(get_req, &mut inter_server_stream).await
+}
+
+/// How much of a PUT's data its staged copy holds. The staged copy of an append starts with
+/// the committed file, which stays in place until the append is published.
+async fn staged_offset(put_req: &PutReq) -> Option<u64> {
+    if put_req.staging.is_empty() {
+        return None;
+    }
+    let staged = fs::metadata(staging_path(&put_req.staging))
+        .await
+        .ok()?
+        .len();
+    let base = match put_req.append {
+        true => fs::metadata(format!("/home/sdfs/{}", put_req.file_name))
+            .await
+            .ok()?
+            .len(),
+        false => 0,
+    };
+    staged.checked_sub(base)
+}
+
+/// Passes a chain-replicated PUT on to the first server in its chain that answers, along with
+/// the rest of the chain
+async fn forward_to_chain(put_req: &PutReq) -> Option<(String, TcpStream)> {
+    for (index, machine) in put_req.chain.iter().enumerate() {
+        let Ok(mut next) = TcpStream::connect(format!("{machine}:56552")).await else {
+            warn!("Unable to reach {} in the chain, skipping it", machine);
+            continue;
+        };
+        let forward_req = SdfsCommand {
+            r#type: Some(Type::PutReq(PutReq {
+                chain: put_req.chain[index + 1..].to_vec(),
+                ..put_req.clone()
+            })),
+        }
+        .encode_to_vec();
+        if next.write_all(&forward_req).await.is_err() {
+            warn!("Unable to forward the PUT to {}, skipping it", machine);
+            continue;
+        }
+        let mut ack_buffer = [0; 1024];
+        match next.read(&mut ack_buffer).await {
+            Ok(n) if n > 0 && Ack::decode(&ack_buffer[..n]).is_ok() => {
+                return Some((machine.clone(), next));
+            }
+            _ => warn!("{} refused the PUT, skipping it", machine),
+        }
+    }
+    None
+}
+
+/// Writes the data of a PUT to its staged copy while forwarding it down the chain and adding
+/// it to the digest, until the writer is cancelled. A next server that fails is dropped, and
+/// the rest of the chain with it.
+async fn receive_put(
+    stream: &mut TcpStream,
+    file: &mut fs::File,
+    downstream: &mut Option<(String, TcpStream)>,
+    digester: &mut Digester,
+    cancel: &CancellationToken,
+) -> std::io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = tokio::select! {
+            read = stream.read(&mut buffer) => read?,
+            _ = cancel.cancelled() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "a resumed PUT took over",
+                ));
+            }
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        let data = &buffer[..n];
+        digester.update(data);
+        let Some((machine, next)) = downstream.as_mut() else {
+            file.write_all(data).await?;
+            continue;
+        };
+        let (written, forwarded) = tokio::join!(file.write_all(data), next.write_all(data));
+        written?;
+        if let Err(e) = forwarded {
+            warn!("Lost {} down the chain: {}", machine, e);
+            *downstream = None;
+        }
+    }
+}
+
+/// Waits for the rest of the chain to store the data, returning the servers that did
+async fn chain_stored(machine: String, mut next: TcpStream) -> Vec<String> {
+    let _ = next.shutdown().await;
+    let mut res = Vec::new();
+    let _ = next.read_to_end(&mut res).await;
+    match SdfsCommand::decode(res.as_slice()) {
+        Ok(SdfsCommand {
+            r#type: Some(Type::LsRes(stored)),
+        }) => [vec![machine], stored.machines].concat(),
+        _ => {
+            warn!(
+                "{} did not store the file, nor did the chain after it",
+                machine
+            );
+            Vec::new()
+        }
+    }
 }
 
 async fn handle_put(
@@ -92,41 +443,233 @@ async fn handle_put(
     local_file_list: Arc<Mutex<LocalFileList>>,
 ) {
     info!("Handling client PUT request");
-    let ack_buffer = Ack {
+    let mut ack = Ack {
         message: "File PUT acknowledged".to_string(),
+        ..Default::default()
+    };
+    if put_req.staging.contains('/') {
+        warn!("Refusing staging id {}", put_req.staging);
+        return;
     }
-    .encode_to_vec();
-    let _ = stream.write_all(&ack_buffer).await;
+    // Only staged copies the leader assigned are resumed
+    let writer = match put_req.staging.is_empty() {
+        true => None,
+        false => Some(local_file_list.lock().await.take_over(&put_req.staging)),
+    };
+    let _copy = match &writer {
+        Some(writer) => Some(writer.copy.clone().lock_owned().await),
+        None => None,
+    };
+    let cancel = writer.map_or_else(CancellationToken::new, |writer| writer.cancel);
+    let mut digester = Digester::default();
+    if put_req.resume {
+        // The staged copy of an append only extends the version it was cloned from
+        let version = local_file_list.lock().await.version(&put_req.file_name);
+        if put_req.append && version != put_req.version {
+            warn!(
+                "Unable to resume append to {}, holding version {} instead of {}",
+                put_req.file_name, version, put_req.version
+            );
+            return;
+        }
+        let Some(offset) = staged_offset(&put_req).await else {
+            warn!(
+                "Unable to resume PUT of {}, nothing staged",
+                put_req.file_name
+            );
+            return;
+        };
+        let Ok(staged) = file_digester(&staging_path(&put_req.staging)).await else {
+            warn!("Unable to digest the staged {}", put_req.file_name);
+            return;
+        };
+        info!("Resuming PUT of {} at byte {}", put_req.file_name, offset);
+        ack.offset = offset;
+        digester = staged;
+    } else if put_req.append {
+        // Every replica adds the data to the same committed version
+        let version = local_file_list.lock().await.version(&put_req.file_name);
+        if put_req.staging.is_empty() || version != put_req.version {
+            warn!(
+                "Refusing append to {}, holding version {} instead of {}",
+                put_req.file_name, version, put_req.version
+            );
+            return;
+        }
+        let path = format!("/home/sdfs/{}", put_req.file_name);
+        if let Err(e) = clone_file(&path, &staging_path(&put_req.staging)).await {
+            warn!("Unable to stage {} for append: {}", put_req.file_name, e);
+            return;
+        }
+        let size = fs::metadata(&path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        let known = local_file_list.lock().await.digester(&put_req.file_name);
+        digester = match known {
+            Some(known) if known.size() == size => known,
+            // Only files written before this server started are read again
+            _ => match file_digester(&path).await {
+                Ok(digester) => digester,
+                Err(e) => {
+                    warn!("Unable to digest {}: {}", put_req.file_name, e);
+                    return;
+                }
+            },
+        };
+    }
+    let _ = stream.write_all(&ack.encode_to_vec()).await;
 
-    let path = format!("/home/sdfs/{}", put_req.file_name);
+    // Leader-assigned staging ids wait for a publish, other writes go live once complete
+    let staging = match put_req.staging.as_str() {
+        "" => new_staging_id(),
+        staging => staging.to_string(),
+    };
     let Ok(mut file) = fs::OpenOptions::new()
         .write(true)
         .create(true)
-        .truncate(true)
-        .open(path)
+        .append(put_req.append || put_req.resume)
+        .truncate(!(put_req.append || put_req.resume))
+        .open(staging_path(&staging))
         .await
     else {
         error!("Unable to open file");
         return;
     };
 
-    write_to_buf(&mut file, stream, None).await;
-
+    let mut downstream = match put_req.chained {
+        true => forward_to_chain(&put_req).await,
+        false => None,
+    };
+    if let Err(e) = receive_put(
+        &mut stream,
+        &mut file,
+        &mut downstream,
+        &mut digester,
+        &cancel,
+    )
+    .await
+    {
+        error!("Unable to receive file {e}");
+        // A staged PUT can resume from what arrived, the leader drops it if it never commits
+        if put_req.staging.is_empty() {
+            let _ = fs::remove_file(staging_path(&staging)).await;
+        }
+        return;
+    }
     if let Err(e) = file.sync_all().await {
         error!("Unable to sync file {e}");
-    } else {
-        let mut file_list = local_file_list.lock().await;
-        file_list.list_mut().push(put_req.file_name);
+        let _ = fs::remove_file(staging_path(&staging)).await;
+        return;
+    }
+    // Acks flow back up the chain, so the tail's means every server before it stored the data
+    let stored_after = match downstream {
+        Some((machine, next)) => chain_stored(machine, next).await,
+        None => Vec::new(),
+    };
+    // Covers the committed file an append started from, so it reports the whole file
+    let digest = Some(digester.clone().finish());
+    local_file_list
+        .lock()
+        .await
+        .staged_digesters_mut()
+        .insert(staging.clone(), digester);
+    if put_req.staging.is_empty() {
+        if !publish(
+            &put_req.file_name,
+            &staging,
+            put_req.version,
+            &local_file_list,
+        )
+        .await
+        {
+            return;
+        }
         info!("Server handled client PUT successfully");
+    } else {
+        info!("Server staged {} as {}", put_req.file_name, staging);
     }
+    // Lets the writer know the data is durable
+    let stored_buffer = match put_req.chained {
+        true => SdfsCommand {
+            r#type: Some(Type::LsRes(LsRes {
+                machines: stored_after,
+                digest,
+                ..Default::default()
+            })),
+        }
+        .encode_to_vec(),
+        false => Ack {
+            message: "File PUT stored".to_string(),
+            digest,
+            ..Default::default()
+        }
+        .encode_to_vec(),
+    };
+    let _ = stream.write_all(&stored_buffer).await;
+    let _ = stream.shutdown().await;
 }
 
-async fn handle_leader_put(leader_put_req: LeaderPutReq, mut stream: TcpStream) {
+#[instrument(name = "Server Publish", level = "trace")]
+async fn handle_publish(
+    mut stream: TcpStream,
+    publish_req: PublishReq,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
+    if !accept_epoch(publish_req.epoch).await {
+        let _ = stream.write_all(STALE_EPOCH).await;
+        return;
+    }
+    if publish_req.staging.is_empty() || publish_req.staging.contains('/') {
+        warn!("Refusing staging id {}", publish_req.staging);
+        return;
+    }
+    if publish_req.discard {
+        let _ = fs::remove_file(staging_path(&publish_req.staging)).await;
+        local_file_list
+            .lock()
+            .await
+            .drop_staged(&publish_req.staging);
+        info!("Server discarded staged {}", publish_req.file_name);
+    } else if publish(
+        &publish_req.file_name,
+        &publish_req.staging,
+        publish_req.version,
+        &local_file_list,
+    )
+    .await
+    {
+        info!("Server published {}", publish_req.file_name);
+    } else {
+        return;
+    }
+    let ack = Ack {
+        message: "Staged file handled".to_string(),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let _ = stream.write_all(&ack).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn handle_leader_put(
+    leader_put_req: LeaderPutReq,
+    mut stream: TcpStream,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
     info!("Handling leader PUT request at server");
+    if !accept_epoch(leader_put_req.epoch).await {
+        let _ = stream.write_all(STALE_EPOCH).await;
+        return;
+    }
+    let version = local_file_list
+        .lock()
+        .await
+        .version(&leader_put_req.file_name);
     if put_from_server(
         leader_put_req.file_name,
         leader_put_req.machine,
         ServerPutFlavor::Put,
+        version,
     )
     .await
     .is_err()
@@ -138,36 +681,182 @@ async fn handle_leader_put(leader_put_req: LeaderPutReq, mut stream: TcpStream)
     info!("Server handled leader PUT successfully");
     let leader_ack = Ack {
         message: "Server PUT successful".to_string(),
+        ..Default::default()
     }
     .encode_to_vec();
     let _ = stream.write_all(&leader_ack).await;
     let _ = stream.shutdown().await;
 }
 
-#[instrument(name = "Server Get", level = "trace")]
-async fn handle_get(get_req: GetReq, mut stream: TcpStream) -> Result<(), ()> {
-    info!("Handling GET request");
-    let path = format!("/home/sdfs/{}", get_req.file_name);
-    let file = match fs::File::open(path).await {
-        Ok(file) => file,
+#[instrument(name = "Server Encode", level = "trace")]
+async fn handle_encode(mut stream: TcpStream, encode_req: EncodeReq) {
+    info!("Handling leader encode request at server");
+    if !accept_epoch(encode_req.epoch).await {
+        let _ = stream.write_all(STALE_EPOCH).await;
+        return;
+    }
+    let Some(mut layout) = encode_req.erasure else {
+        return;
+    };
+    let path = format!("/home/sdfs/{}", encode_req.file_name);
+    let opened = match fs::File::open(&path).await {
+        Ok(file) => file.metadata().await.map(|metadata| (file, metadata.len())),
+        Err(e) => Err(e),
+    };
+    let (mut file, size) = match opened {
+        Ok(opened) => opened,
         Err(e) => {
-            warn!("Unable to open file {} with error {}", get_req.file_name, e);
-            return Err(());
+            warn!("Unable to read {} to encode: {}", encode_req.file_name, e);
+            return;
         }
     };
+    layout.size = size;
+    let stored = erasure::put_file(
+        &mut file,
+        &encode_req.file_name,
+        &encode_req.machines,
+        "",
+        &layout,
+        &mut Digester::default(),
+    )
+    .await;
+    // The leader only records the fragments once every one of them is stored
+    match stored {
+        Ok(stored) if stored.iter().all(|machine| !machine.is_empty()) => {}
+        Ok(_) => {
+            warn!("Unable to store every fragment of {}", encode_req.file_name);
+            return;
+        }
+        Err(e) => {
+            warn!("Unable to encode {}: {}", encode_req.file_name, e);
+            return;
+        }
+    }
+    info!("Server encoded {}", encode_req.file_name);
+    let _ = stream.write_all(&layout.encode_to_vec()).await;
+    let _ = stream.shutdown().await;
+}
 
-    let mut file_buf = Vec::new();
-    let mut buf_reader = BufReader::new(file);
+/// Rebuilds a lost fragment a stripe at a time into `path`
+async fn rebuild_fragment(
+    rebuild_req: &RebuildReq,
+    layout: &ErasureLayout,
+    path: &str,
+) -> Result<(), String> {
+    let index = rebuild_req.fragment as usize;
+    let mut file = fs::File::create(path)
+        .await
+        .map_err(|e| format!("Unable to create {}: {}", path, e))?;
+    let mut reader = erasure::StripeReader::new(
+        &rebuild_req.file_name,
+        &rebuild_req.machines,
+        layout,
+        Some(index),
+    );
+    for stripe in 0..erasure::stripes(layout) {
+        let shards = reader.read_stripe(stripe).await?;
+        let block = erasure::rebuild(shards, layout, index)?;
+        file.write_all(&block)
+            .await
+            .map_err(|e| format!("Unable to write {}: {}", path, e))?;
+    }
+    file.sync_all()
+        .await
+        .map_err(|e| format!("Unable to sync {}: {}", path, e))
+}
 
-    info!("Server beginning send");
-    while let Ok(size) = buf_reader.read_until(b'\n', &mut file_buf).await {
-        if size == 0 {
-            break;
+#[instrument(name = "Server Rebuild", level = "trace")]
+async fn handle_rebuild(
+    mut stream: TcpStream,
+    rebuild_req: RebuildReq,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
+    info!("Handling leader rebuild request at server");
+    if !accept_epoch(rebuild_req.epoch).await {
+        let _ = stream.write_all(STALE_EPOCH).await;
+        return;
+    }
+    let Some(layout) = &rebuild_req.erasure else {
+        return;
+    };
+    let index = rebuild_req.fragment as usize;
+    let fragment_name = erasure::fragment_name(&rebuild_req.file_name, index);
+    let staging = new_staging_id();
+    if let Err(e) = rebuild_fragment(&rebuild_req, layout, &staging_path(&staging)).await {
+        warn!("Unable to rebuild {}: {}", fragment_name, e);
+        let _ = fs::remove_file(staging_path(&staging)).await;
+        return;
+    }
+    if !publish(&fragment_name, &staging, 0, &local_file_list).await {
+        return;
+    }
+    info!(
+        "Server rebuilt fragment {} of {}",
+        index, rebuild_req.file_name
+    );
+    let ack = Ack {
+        message: "Fragment rebuilt".to_string(),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let _ = stream.write_all(&ack).await;
+    let _ = stream.shutdown().await;
+}
+
+#[instrument(name = "Server Get", level = "trace")]
+async fn open_stored(file_name: &str) -> Result<fs::File, ()> {
+    let path = format!("/home/sdfs/{}", file_name);
+    fs::File::open(path)
+        .await
+        .map_err(|e| warn!("Unable to open file {} with error {}", file_name, e))
+}
+
+async fn handle_get(get_req: GetReq, stream: &mut TcpStream) -> Result<(), ()> {
+    info!("Handling GET request");
+    let file = open_stored(&get_req.file_name).await?;
+    send_file(file, &get_req, stream).await
+}
+
+/// Serves a GET for a version of a file, sending nothing if another version is stored
+async fn handle_client_get(
+    get_req: GetReq,
+    mut stream: TcpStream,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
+    info!("Handling GET request");
+    let file = {
+        let file_list = local_file_list.lock().await;
+        let stored = file_list.version(&get_req.file_name);
+        if get_req.version > 0 && stored != get_req.version {
+            warn!(
+                "Refusing GET of version {} of {}, holding version {}",
+                get_req.version, get_req.file_name, stored
+            );
+            return;
         }
-        if let Err(e) = stream.write_all(&file_buf).await {
-            warn!("Unable to write to client {}", e);
+        // Opened before a publish can replace the file
+        let Ok(file) = open_stored(&get_req.file_name).await else {
+            return;
+        };
+        file
+    };
+    let _ = send_file(file, &get_req, &mut stream).await;
+}
+
+async fn send_file(mut file: fs::File, get_req: &GetReq, stream: &mut TcpStream) -> Result<(), ()> {
+    info!("Server beginning send");
+    let len = file.metadata().await.map_or(0, |metadata| metadata.len());
+    // Only lines have to be found by reading, bytes go out with sendfile
+    let sent = match &get_req.range {
+        Some(Range::Lines(_)) => copy_range(&mut file, get_req.range.as_ref(), stream).await,
+        Some(Range::Bytes(bytes)) => {
+            let (start, length) = byte_span(bytes, len);
+            send_span(&file, start, length, stream).await
         }
-        file_buf.clear();
+        None => send_span(&file, 0, len, stream).await,
+    };
+    if let Err(e) = sent {
+        warn!("Unable to write to client {}", e);
     }
 
     info!("Server handled GET request successfully");
@@ -181,14 +870,22 @@ async fn handle_del(
     mut stream: TcpStream,
     local_file_list: Arc<Mutex<LocalFileList>>,
 ) {
+    if !accept_epoch(del_req.epoch).await {
+        let _ = stream.write_all(STALE_EPOCH).await;
+        return;
+    }
     let path = format!("/home/sdfs/{}", del_req.file_name);
     let _ = fs::remove_file(path).await;
+    prune_parents(&del_req.file_name).await;
     let mut file_list = local_file_list.lock().await;
     file_list
         .list_mut()
         .retain(|elem| *elem != del_req.file_name);
+    file_list.versions_mut().remove(&del_req.file_name);
+    file_list.digesters_mut().remove(&del_req.file_name);
     let ack_buffer = Ack {
         message: "File DELETE successful".to_string(),
+        ..Default::default()
     }
     .encode_to_vec();
     info!("Server deleted file {}", del_req.file_name);
@@ -196,11 +893,151 @@ async fn handle_del(
     let _ = stream.shutdown().await;
 }
 
+/// Gives a stored file a second name, the leader drops the old one once the rename commits
+#[instrument(name = "Server Link", level = "trace")]
+async fn handle_link(
+    mut stream: TcpStream,
+    link_req: LinkReq,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
+    if !accept_epoch(link_req.epoch).await {
+        let _ = stream.write_all(STALE_EPOCH).await;
+        return;
+    }
+    if !namespace::is_valid(&link_req.from) || !namespace::is_valid(&link_req.to) {
+        warn!("Refusing link from {} to {}", link_req.from, link_req.to);
+        return;
+    }
+    let from = format!("/home/sdfs/{}", link_req.from);
+    let to = format!("/home/sdfs/{}", link_req.to);
+    let mut file_list = local_file_list.lock().await;
+    if let Err(e) = create_parent(&to).await {
+        warn!("Unable to create the directory of {}: {}", link_req.to, e);
+        return;
+    }
+    let _ = fs::remove_file(&to).await;
+    if let Err(e) = fs::hard_link(&from, &to).await {
+        warn!("Unable to link {} to {}: {}", link_req.from, link_req.to, e);
+        return;
+    }
+    if !file_list.list().contains(&link_req.to) {
+        file_list.list_mut().push(link_req.to.clone());
+    }
+    let version = file_list.version(&link_req.from);
+    file_list
+        .versions_mut()
+        .insert(link_req.to.clone(), version);
+    drop(file_list);
+    info!("Server linked {} to {}", link_req.from, link_req.to);
+    let ack = Ack {
+        message: "File linked".to_string(),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let _ = stream.write_all(&ack).await;
+    let _ = stream.shutdown().await;
+}
+
+#[instrument(name = "Server Version", level = "trace")]
+async fn handle_version(
+    mut stream: TcpStream,
+    version_req: VersionReq,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
+    let file_list = local_file_list.lock().await;
+    let size = fs::metadata(format!("/home/sdfs/{}", version_req.file_name))
+        .await
+        .map_or(0, |metadata| metadata.len());
+    let resp = VersionRes {
+        stored: file_list.list().contains(&version_req.file_name),
+        version: file_list.version(&version_req.file_name),
+        size,
+    }
+    .encode_to_vec();
+    drop(file_list);
+    let _ = stream.write_all(&resp).await;
+    let _ = stream.shutdown().await;
+}
+
+#[instrument(name = "Server Repair", level = "trace")]
+async fn handle_repair(
+    mut stream: TcpStream,
+    repair_req: RepairReq,
+    local_file_list: Arc<Mutex<LocalFileList>>,
+) {
+    let file_list = local_file_list.lock().await;
+    let stored = file_list.list().contains(&repair_req.file_name);
+    let version = file_list.version(&repair_req.file_name);
+    drop(file_list);
+    if !stored {
+        warn!("Unable to repair {}, not stored here", repair_req.file_name);
+        return;
+    }
+    info!(
+        "Repairing {} on {} to version {}",
+        repair_req.file_name, repair_req.machine, version
+    );
+    if put_from_server(
+        repair_req.file_name,
+        repair_req.machine,
+        ServerPutFlavor::Put,
+        version,
+    )
+    .await
+    .is_err()
+    {
+        warn!("Unable to push the repaired copy");
+        return;
+    }
+    let ack = Ack {
+        message: "Replica repaired".to_string(),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let _ = stream.write_all(&ack).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Staging ids untouched for longer than STAGING_GRACE, which no write still in flight uses
+async fn stale_stagings() -> Vec<String> {
+    let mut staged = Vec::new();
+    let Ok(mut entries) = fs::read_dir(STAGING_DIR).await else {
+        return staged;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(modified) = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+        else {
+            continue;
+        };
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > STAGING_GRACE {
+            staged.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    staged
+}
+
 #[instrument(name = "Server Store", level = "trace")]
 async fn handle_leader_store(mut stream: TcpStream, local_file_list: Arc<Mutex<LocalFileList>>) {
     info!("Handling leader store request at server");
+    let files = local_file_list.lock().await.list().to_vec();
+    let mut sizes = Vec::with_capacity(files.len());
+    for file in &files {
+        let size = fs::metadata(format!("/home/sdfs/{}", file))
+            .await
+            .map_or(0, |metadata| metadata.len());
+        sizes.push(size);
+    }
+    let staged = stale_stagings().await;
     let resp = LeaderStoreRes {
-        files: local_file_list.lock().await.list().to_vec(),
+        files,
+        sizes,
+        staged,
     }
     .encode_to_vec();
     let _ = stream.write_all(&resp).await;
@@ -218,10 +1055,12 @@ async fn handle_multi_read(mut client_stream: TcpStream, multi_read_req: MultiRe
     let req_buffer = SdfsCommand {
         r#type: Some(Type::GetReq(GetReq {
             file_name: multi_read_req.sdfs_file_name.clone(),
+            range: None,
+            ..Default::default()
         })),
     }
     .encode_to_vec();
-    let _ = leader_stream.write_all(&req_buffer).await;
+    let _ = write_leader_request(&mut leader_stream, &req_buffer).await;
 
     let mut res_buffer = [0; 1024];
     let Ok(n) = leader_stream.read(&mut res_buffer).await else {
@@ -233,22 +1072,45 @@ async fn handle_multi_read(mut client_stream: TcpStream, multi_read_req: MultiRe
         return;
     };
 
-    if !machine_list.machines.is_empty() {
-        let _ = client_get_helper(
-            machine_list.machines,
+    if let Some(layout) = &machine_list.erasure {
+        let _ = erasure::get_file(
             &multi_read_req.sdfs_file_name,
+            &machine_list.machines,
+            layout,
             &multi_read_req.local_file_name,
-            None,
         )
         .await;
+    } else if !machine_list.machines.is_empty() {
+        match quorum_read(
+            machine_list.machines,
+            &multi_read_req.sdfs_file_name,
+            machine_list.read_quorum as usize,
+        )
+        .await
+        {
+            Ok(fresh) => {
+                let _ = parallel_get(
+                    fresh,
+                    &multi_read_req.sdfs_file_name,
+                    &multi_read_req.local_file_name,
+                )
+                .await;
+            }
+            Err(e) => warn!(
+                "Multi-read of {} failed: {}",
+                multi_read_req.sdfs_file_name, e
+            ),
+        }
     }
 
     let leader_ack_buffer = Ack {
         message: "Received list from server".to_string(),
+        ..Default::default()
     }
     .encode_to_vec();
     let client_ack_buffer = Ack {
         message: "Successfully read from server".to_string(),
+        ..Default::default()
     }
     .encode_to_vec();
     let _ = leader_stream.write_all(&leader_ack_buffer).await;
@@ -268,10 +1130,11 @@ async fn handle_multi_write(mut client_stream: TcpStream, multi_write_req: Multi
     let req_buffer = SdfsCommand {
         r#type: Some(Type::PutReq(PutReq {
             file_name: multi_write_req.sdfs_file_name.clone(),
+            ..Default::default()
         })),
     }
     .encode_to_vec();
-    let _ = leader_stream.write_all(&req_buffer).await;
+    let _ = write_leader_request(&mut leader_stream, &req_buffer).await;
 
     let mut res_buffer = [0; 1024];
     let Ok(n) = leader_stream.read(&mut res_buffer).await else {
@@ -283,21 +1146,27 @@ async fn handle_multi_write(mut client_stream: TcpStream, multi_write_req: Multi
         return;
     };
 
-    stream::iter(machine_list.machines)
-        .for_each_concurrent(None, |machine| async {
-            let machine_address = machine + ":56552";
+    let writers = machine_list.machines.len().max(1);
+    let staging = &machine_list.staging;
+    let stored: Vec<_> = stream::iter(machine_list.machines)
+        .map(|machine| async {
+            let machine_address = machine.clone() + ":56552";
             let Ok(mut inter_server_stream) = TcpStream::connect(machine_address).await else {
                 warn!("Unable to connect to the other server");
-                return;
+                return None;
             };
 
             let get_req = GetReq {
                 file_name: multi_write_req.local_file_name.clone(),
+                range: None,
+                ..Default::default()
             };
 
             let req_buf = SdfsCommand {
                 r#type: Some(Type::PutReq(PutReq {
                     file_name: multi_write_req.sdfs_file_name.clone(),
+                    staging: staging.clone(),
+                    ..Default::default()
                 })),
             }
             .encode_to_vec();
@@ -306,35 +1175,125 @@ async fn handle_multi_write(mut client_stream: TcpStream, multi_write_req: Multi
             let mut res_buf = [0; 1024];
             let Ok(n) = inter_server_stream.read(&mut res_buf).await else {
                 warn!("Failed to read ACK from the other server");
-                return;
+                return None;
             };
             if let Err(e) = Ack::decode(&res_buf[..n]) {
                 warn!("Unable to decode ACK message: {}", e);
-                return;
+                return None;
             }
 
-            let _ = handle_get(get_req, inter_server_stream).await;
+            handle_get(get_req, &mut inter_server_stream).await.ok()?;
+            let mut stored_buf = Vec::new();
+            let _ = inter_server_stream.read_to_end(&mut stored_buf).await;
+            let stored_ack = match Ack::decode(stored_buf.as_slice()) {
+                Ok(stored_ack) if !stored_buf.is_empty() => stored_ack,
+                _ => {
+                    warn!("{} did not store the file", machine);
+                    return None;
+                }
+            };
+            Some((machine, stored_ack.digest))
         })
+        .buffer_unordered(writers)
+        .filter_map(|stored| async { stored })
+        .collect()
         .await;
 
-    let leader_ack_buffer: Vec<u8> = Ack {
-        message: "Server PUT successful".to_string(),
+    // The leader publishes the staged copies on the servers that received them
+    let digest = stored.iter().find_map(|(_, digest)| digest.clone());
+    let leader_ls_buffer = LsRes {
+        machines: stored.into_iter().map(|(machine, _)| machine).collect(),
+        digest,
+        ..Default::default()
     }
     .encode_to_vec();
-    let _ = leader_stream.write_all(&leader_ack_buffer).await;
+    let _ = leader_stream.write_all(&leader_ls_buffer).await;
     let _ = leader_stream.shutdown().await;
+    // Answer the client only once the leader has published the new version
+    let mut commit_buffer = Vec::new();
+    let _ = leader_stream.read_to_end(&mut commit_buffer).await;
 
     let client_ack_buffer = Ack {
         message: "Successfully read from server".to_string(),
+        ..Default::default()
     }
     .encode_to_vec();
     let _ = client_stream.write_all(&client_ack_buffer).await;
     let _ = client_stream.shutdown().await;
 }
 
+/// Lines in a local file, counted chunk by chunk so long lines are never held whole
+async fn count_lines(path: &str) -> u64 {
+    file_digest(path)
+        .await
+        .map(|digest| digest.lines)
+        .unwrap_or_default()
+}
+
+/// Encoded keys of the files in the local map output directory written under `prefix`
+async fn list_key_files(prefix: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let Ok(mut entries) = fs::read_dir("/home/sdfs/mrout").await else {
+        warn!("Server map: unable to read the map output directory");
+        return keys;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(file_name) = entry.file_name().into_string() else {
+            warn!("Server map: ignoring output file with a non UTF-8 name");
+            continue;
+        };
+        let Some(key) = file_name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('_'))
+        else {
+            continue;
+        };
+        if decode_key(key).is_none() {
+            warn!("Server map: ignoring malformed key file {}", file_name);
+            continue;
+        }
+        keys.push(key.to_string());
+    }
+    keys
+}
+
+/// Fetches the lines of an input a map task covers. Fragments hold no whole lines, so an
+/// erasure-coded input is decoded whole first.
+async fn fetch_map_input(
+    file: &str,
+    servers: KeyServers,
+    local_file: &str,
+    lines: Range,
+) -> Result<(), String> {
+    let Some(layout) = servers.erasure else {
+        return client_get_helper(servers.servers, file, local_file, Some(lines)).await;
+    };
+    let decoded = format!("{local_file}.decoded");
+    erasure::get_file(file, &servers.servers, &layout, &decoded).await?;
+    let result = async {
+        let mut source = fs::File::open(&decoded).await?;
+        let mut sink = fs::File::create(local_file).await?;
+        copy_range(&mut source, Some(&lines), &mut sink).await?;
+        sink.flush().await
+    }
+    .await
+    .map_err(|e| format!("Unable to cut the task's lines out of {}: {}", file, e));
+    let _ = fs::remove_file(&decoded).await;
+    result
+}
+
 #[instrument(name = "Server Map", level = "trace")]
 async fn handle_map(mut leader_stream: TcpStream, map_req: LeaderMapReq) {
     info!("Server map: Processing map on server");
+    if !accept_epoch(map_req.epoch).await {
+        let leader_res_buffer = ServerMapRes {
+            failure: TaskFailure::StaleEpoch.into(),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let _ = leader_stream.write_all(&leader_res_buffer).await;
+        return;
+    }
     // run executable and on the file from map_req.file_name
 
     // First, fetch the file from the SDFS server
@@ -342,14 +1301,12 @@ async fn handle_map(mut leader_stream: TcpStream, map_req: LeaderMapReq) {
     let mut local_files = Vec::new();
     for (file, servers) in map_req.file_server_map.into_iter() {
         let local_file = format!("/home/sdfs/mrin/{file}");
-        if let Err(e) = client_get_helper(
-            servers.servers,
-            &file,
-            &local_file,
-            Some((map_req.start_line, map_req.end_line)),
-        )
-        .await
-        {
+        let lines = Range::Lines(LineRange {
+            start: map_req.start_line as u64,
+            count: (map_req.end_line - map_req.start_line) as u64 + 1,
+            from_end: false,
+        });
+        if let Err(e) = fetch_map_input(&file, servers, &local_file, lines).await {
             warn!(
                 "Server map: Unable to fetch file from server: {}, aborting",
                 e
@@ -367,42 +1324,62 @@ async fn handle_map(mut leader_stream: TcpStream, map_req: LeaderMapReq) {
 
     info!("Server map: Fetched files from servers");
     // run the executable and collect keys
-    // assume executable output keys to terminal
-    let Ok(raw_output) = tokio::task::block_in_place(|| {
-        Command::new("python3")
-            .args(
-                [
-                    &format!("/home/sdfs/{}", &map_req.executable),
-                    &files[0],
-                    &map_req.output_prefix,
-                ]
-                .into_iter()
-                .chain(&map_req.arguments)
-                .collect::<Vec<_>>(),
-            )
-            .output()
-    }) else {
-        warn!("Server map: unable to run executable");
-        return;
-    };
-    let Ok(output) = std::str::from_utf8(&raw_output.stdout) else {
-        warn!("Server map: unable to parse keys");
-        return;
+    let task_prefix = FileKey::task(
+        &map_req.output_prefix,
+        TASK_COUNTER.fetch_add(1, Ordering::Relaxed),
+    );
+    let limits = map_req.limits.unwrap_or_else(executor::default_limits);
+    let raw_output = match executor::run_executable(
+        &map_req.executable,
+        &[&files[0], &task_prefix]
+            .into_iter()
+            .chain(map_req.arguments.iter())
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        &limits,
+    )
+    .await
+    {
+        Ok(raw_output) => raw_output,
+        Err(e) => {
+            warn!("Server map: executor failed: {}", e);
+            for local_file in local_files {
+                let _ = fs::remove_file(local_file).await;
+            }
+            let leader_res_buffer = ServerMapRes {
+                failure: e.reason().into(),
+                ..Default::default()
+            }
+            .encode_to_vec();
+            let _ = leader_stream.write_all(&leader_res_buffer).await;
+            let _ = leader_stream.shutdown().await;
+            return;
+        }
     };
+    if let Ok(stdout) = std::str::from_utf8(&raw_output.stdout) {
+        info!("Server map: stdout {}", stdout);
+    }
     if let Ok(stderr) = std::str::from_utf8(&raw_output.stderr) {
         info!("Server map: stderr {}", stderr);
     }
-    let keys = output
-        .lines()
-        .map(|line| line.to_string())
-        .collect::<Vec<_>>();
+    if !raw_output.status.success() {
+        warn!("Server map: executor exited with {}", raw_output.status);
+    }
+    // Every key file the executor wrote under the task prefix is a key
+    let keys = list_key_files(&task_prefix).await;
 
     if keys.is_empty() {
         warn!("No keys output for map");
         return;
     }
 
+    let mut counters = JobCounters {
+        output_keys: keys.len() as u64,
+        custom: executor::parse_counters(&raw_output.stderr),
+        ..Default::default()
+    };
     for local_file in local_files {
+        counters.input_records += count_lines(&local_file).await;
         let _ = fs::remove_file(local_file).await;
     }
 
@@ -411,11 +1388,19 @@ async fn handle_map(mut leader_stream: TcpStream, map_req: LeaderMapReq) {
     let mut replicators: Vec<_> = map_req.target_servers;
     for key in &keys {
         let file_name = FileKey::new(&map_req.output_prefix, key);
+        let local_key = FileKey::new(&task_prefix, key);
+        let path = format!("/home/sdfs/mrout/{local_key}");
+        let size = fs::metadata(&path).await.map_or(0, |meta| meta.len());
         replicators = stream::iter(replicators)
             .filter_map(|server| async {
-                if put_from_server(file_name.to_string(), server.clone(), ServerPutFlavor::Map)
-                    .await
-                    .is_err()
+                if put_from_server(
+                    file_name.to_string(),
+                    server.clone(),
+                    ServerPutFlavor::Map(local_key.to_string()),
+                    0,
+                )
+                .await
+                .is_err()
                 {
                     return None;
                 }
@@ -423,13 +1408,19 @@ async fn handle_map(mut leader_stream: TcpStream, map_req: LeaderMapReq) {
             })
             .collect()
             .await;
-        let path = format!("/home/sdfs/mrout/{file_name}");
+        counters.bytes_shuffled += size * replicators.len() as u64;
         let _ = fs::remove_file(path).await;
     }
 
     info!("Server map: successfully put files on target servers");
     // ack the leader
-    let leader_ack_buffer = ServerMapRes { keys, replicators }.encode_to_vec();
+    let leader_ack_buffer = ServerMapRes {
+        keys,
+        replicators,
+        counters: Some(counters),
+        ..Default::default()
+    }
+    .encode_to_vec();
     let _ = leader_stream.write_all(&leader_ack_buffer).await;
     let _ = leader_stream.shutdown().await;
 }
@@ -437,11 +1428,29 @@ async fn handle_map(mut leader_stream: TcpStream, map_req: LeaderMapReq) {
 #[instrument(name = "Server Reduce", level = "trace")]
 async fn handle_reduce(mut leader_stream: TcpStream, red_req: LeaderReduceReq) {
     info!("Server reduce: Processing reduce on server");
-    // fetch files
+    if !accept_epoch(red_req.epoch).await {
+        let leader_res_buffer = ServerRedRes {
+            failure: TaskFailure::StaleEpoch.into(),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let _ = leader_stream.write_all(&leader_res_buffer).await;
+        return;
+    }
+    // fetch files, named under a task prefix so other tasks of the job on this server
+    // do not read them as their own
+    let task_id = TASK_COUNTER.fetch_add(1, Ordering::Relaxed);
     let mut files = Vec::new();
     let mut local_keys = Vec::new();
     for (key, servers) in red_req.key_server_map.into_iter() {
-        let local_key = format!("/home/sdfs/mrin/{key}");
+        let Some((namespace, encoded_key)) = FileKey::split(&key) else {
+            warn!("Server reduce: Malformed input file {}", key);
+            return;
+        };
+        let local_key = format!(
+            "/home/sdfs/mrin/{}",
+            FileKey::new(&FileKey::task(namespace, task_id), encoded_key)
+        );
         if let Err(e) = client_get_helper(servers.servers.clone(), &key, &local_key, None).await {
             error!("Unable to fetch key file: {}", e);
             return;
@@ -456,28 +1465,50 @@ async fn handle_reduce(mut leader_stream: TcpStream, red_req: LeaderReduceReq) {
     }
 
     // run executable and send to target server
-    let Some((prefix, _)) = files[0].split_once('_') else {
+    let Some((namespace, _)) = FileKey::split(&files[0]) else {
         warn!("Server reduce: Malformed input files, unable to get prefix");
         return;
     };
+    let prefix = &FileKey::task(namespace, task_id);
+
+    // Every line of a key file is one record the executor reads
+    let mut counters = JobCounters::default();
+    for local_key in &local_keys {
+        counters.input_records += count_lines(local_key).await;
+        counters.bytes_shuffled += fs::metadata(local_key).await.map_or(0, |meta| meta.len());
+    }
 
-    match tokio::task::block_in_place(|| {
-        Command::new("python3")
-            .args([
-                &format!("/home/sdfs/{}", &red_req.executable),
-                prefix,
-                &red_req.output_file,
-            ])
-            .output()
-    }) {
+    let limits = red_req.limits.unwrap_or_else(executor::default_limits);
+    match executor::run_executable(
+        &red_req.executable,
+        &[prefix, &red_req.output_file],
+        &limits,
+    )
+    .await
+    {
         Err(e) => {
             error!("Unable to run reduce executable: {}", e);
+            for local_key in local_keys {
+                let _ = fs::remove_file(local_key).await;
+            }
+            let _ = fs::remove_file(format!("/home/sdfs/mrout/{}", red_req.output_file)).await;
+            let leader_res_buffer = ServerRedRes {
+                failure: e.reason().into(),
+                ..Default::default()
+            }
+            .encode_to_vec();
+            let _ = leader_stream.write_all(&leader_res_buffer).await;
+            let _ = leader_stream.shutdown().await;
             return;
         }
         Ok(raw_output) => {
             if let Ok(stderr) = std::str::from_utf8(&raw_output.stderr) {
                 info!("Server reduce: stderr {}", stderr);
             }
+            if !raw_output.status.success() {
+                warn!("Server reduce: executor exited with {}", raw_output.status);
+            }
+            counters.custom = executor::parse_counters(&raw_output.stderr);
         }
     };
     info!("Finishing running executable");
@@ -493,6 +1524,7 @@ async fn handle_reduce(mut leader_stream: TcpStream, red_req: LeaderReduceReq) {
                 red_req.output_file.clone(),
                 server.clone(),
                 ServerPutFlavor::Reduce,
+                0,
             )
             .await
             .is_err()
@@ -509,7 +1541,12 @@ async fn handle_reduce(mut leader_stream: TcpStream, red_req: LeaderReduceReq) {
     let _ = fs::remove_file(path).await;
 
     // end request
-    let leader_ack_buffer = ServerRedRes { replicators }.encode_to_vec();
+    let leader_ack_buffer = ServerRedRes {
+        replicators,
+        counters: Some(counters),
+        ..Default::default()
+    }
+    .encode_to_vec();
     let _ = leader_stream.write_all(&leader_ack_buffer).await;
     let _ = leader_stream.shutdown().await;
 }
@@ -517,20 +1554,31 @@ async fn handle_reduce(mut leader_stream: TcpStream, red_req: LeaderReduceReq) {
 async fn handle_server_map_reduce(
     mut server_stream: TcpStream,
     output_file: String,
-    is_reduce: bool,
     local_file_list: Arc<Mutex<LocalFileList>>,
 ) {
     info!("Server M-R: Reading operation results");
     let ack_buffer = Ack {
         message: "Reduce acknowledged".to_string(),
+        ..Default::default()
     }
     .encode_to_vec();
     let _ = server_stream.write_all(&ack_buffer).await;
 
     let mut data_buffer = Vec::new();
-    write_to_buf(&mut data_buffer, server_stream, None).await;
+    if let Err(e) = write_to_buf(&mut data_buffer, server_stream, &mut Received::default(), 0).await
+    {
+        warn!("Server M-R receiver: Lost the output stream: {}", e);
+        return;
+    }
 
     let path = format!("/home/sdfs/{}", output_file);
+    if let Err(e) = create_parent(&path).await {
+        warn!(
+            "Server M-R receiver: Unable to create output directory: {}",
+            e
+        );
+        return;
+    }
     let Ok(file) = std::fs::OpenOptions::new()
         .append(true)
         .create(true)
@@ -548,39 +1596,31 @@ async fn handle_server_map_reduce(
                 "Server M-R receiver: Unable to append to file with error {}",
                 e
             );
-            return;
         };
     } else {
         error!("Server M-R receiver: Unable to acquire a file lock");
-        return;
     };
     }).await;
     info!("Server wrote map-reduce data successfully");
-    if is_reduce {
-        let mut file_list = local_file_list.lock().await;
+    // Key files are listed too so the leader can find namespaces left behind by failed jobs
+    let mut file_list = local_file_list.lock().await;
+    if !file_list.list().contains(&output_file) {
         file_list.list_mut().push(output_file);
     }
 }
 
 async fn handle_file_size(mut leader_stream: TcpStream, req: FileSizeReq) {
     let path = format!("/home/sdfs/{}", req.file_name);
-    let Ok(file) = tokio::fs::File::open(path).await else {
+    if fs::metadata(&path).await.is_err() {
         warn!("File size: Unable to open file");
         return;
-    };
-    let mut file_buf = Vec::new();
-    let mut buf_reader = BufReader::new(file);
-
-    let mut line_count = 0;
-    while let Ok(size) = buf_reader.read_until(b'\n', &mut file_buf).await {
-        if size == 0 {
-            break;
-        }
-        line_count += 1;
-        file_buf.clear();
     }
+    let line_count = count_lines(&path).await;
 
-    let response = FileSizeRes { size: line_count }.encode_to_vec();
+    let response = FileSizeRes {
+        size: line_count as u32,
+    }
+    .encode_to_vec();
     let _ = leader_stream.write_all(&response).await;
     let _ = leader_stream.shutdown().await;
 }
@@ -594,6 +1634,19 @@ pub async fn run_server(local_file_list: Arc<Mutex<LocalFileList>>) {
         return;
     };
     info!("Server listening on port 56552");
+    match load_epoch().await {
+        Ok(epoch) => LEADER_EPOCH.store(epoch, Ordering::SeqCst),
+        Err(e) => {
+            println!("Failed to load the leader epoch, aborting");
+            error!("Unable to read {}: {}", EPOCH_FILE, e);
+            return;
+        }
+    }
+    // Staged writes left behind by a crash are published or dropped by the leader
+    if let Err(e) = fs::create_dir_all(STAGING_DIR).await {
+        error!("Unable to create staging directory: {}", e);
+    }
+    executor::install_key_helper().await;
 
     let mut buffer = [0; 51200];
     loop {
@@ -632,8 +1685,9 @@ pub async fn run_server(local_file_list: Arc<Mutex<LocalFileList>>) {
                         info!("Received GetData command from client");
                         // Better error handling instead of unwrap()
                         // In your server's GetData and PutData handling, construct the file path like this:
+                        let file_list = local_file_list.clone();
                         tokio::spawn(async move {
-                            let _ = handle_get(get_req, stream).await;
+                            handle_client_get(get_req, stream, file_list).await;
                         });
                     }
                     Some(Type::Del(del_req)) => {
@@ -645,8 +1699,9 @@ pub async fn run_server(local_file_list: Arc<Mutex<LocalFileList>>) {
                     }
                     Some(Type::LeaderPutReq(leader_put_req)) => {
                         info!("Received Put command from the leader");
+                        let file_list = local_file_list.clone();
                         tokio::spawn(async move {
-                            handle_leader_put(leader_put_req, stream).await;
+                            handle_leader_put(leader_put_req, stream, file_list).await;
                         });
                     }
                     Some(Type::LeaderStoreReq(_)) => {
@@ -683,15 +1738,13 @@ pub async fn run_server(local_file_list: Arc<Mutex<LocalFileList>>) {
                     Some(Type::ServerRedReq(req)) => {
                         let file_list = local_file_list.clone();
                         tokio::spawn(async move {
-                            handle_server_map_reduce(stream, req.output_file, true, file_list)
-                                .await;
+                            handle_server_map_reduce(stream, req.output_file, file_list).await;
                         });
                     }
                     Some(Type::ServerMapReq(req)) => {
                         let file_list = local_file_list.clone();
                         tokio::spawn(async move {
-                            handle_server_map_reduce(stream, req.output_file, false, file_list)
-                                .await;
+                            handle_server_map_reduce(stream, req.output_file, file_list).await;
                         });
                     }
                     Some(Type::FileSizeReq(req)) => {
@@ -699,6 +1752,46 @@ pub async fn run_server(local_file_list: Arc<Mutex<LocalFileList>>) {
                             handle_file_size(stream, req).await;
                         });
                     }
+                    Some(Type::EncodeReq(encode_req)) => {
+                        info!("Received Encode request from the leader");
+                        tokio::spawn(async move {
+                            handle_encode(stream, encode_req).await;
+                        });
+                    }
+                    Some(Type::PublishReq(publish_req)) => {
+                        info!("Received Publish request from the leader");
+                        let file_list = local_file_list.clone();
+                        tokio::spawn(async move {
+                            handle_publish(stream, publish_req, file_list).await;
+                        });
+                    }
+                    Some(Type::VersionReq(version_req)) => {
+                        let file_list = local_file_list.clone();
+                        tokio::spawn(async move {
+                            handle_version(stream, version_req, file_list).await;
+                        });
+                    }
+                    Some(Type::LinkReq(link_req)) => {
+                        info!("Received Link request from the leader");
+                        let file_list = local_file_list.clone();
+                        tokio::spawn(async move {
+                            handle_link(stream, link_req, file_list).await;
+                        });
+                    }
+                    Some(Type::RepairReq(repair_req)) => {
+                        info!("Received Repair request");
+                        let file_list = local_file_list.clone();
+                        tokio::spawn(async move {
+                            handle_repair(stream, repair_req, file_list).await;
+                        });
+                    }
+                    Some(Type::RebuildReq(rebuild_req)) => {
+                        info!("Received Rebuild request from the leader");
+                        let file_list = local_file_list.clone();
+                        tokio::spawn(async move {
+                            handle_rebuild(stream, rebuild_req, file_list).await;
+                        });
+                    }
                     _ => {
                         // Other types of commands are not handled here
                         tokio::spawn(async move {